@@ -0,0 +1,84 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*!
+ * Statemaps are a software visualization in which time is on the X axis and
+ * timelines for discrete entities are stacked on the Y axis, with different
+ * states for the discrete entities rendered in different colors.
+ *
+ * This crate ingests statemap instrumentation data (concatenated JSON, as
+ * described in the README), coalesces it into a bounded number of
+ * rectangles, and renders the result.  For example:
+ *
+ * ```
+ * use statemap::{Config, Statemap};
+ *
+ * let data = r##"{
+ *     "start": [ 1579579142, 0 ],
+ *     "title": "Example",
+ *     "states": {
+ *         "on-cpu": { "value": 0, "color": "#2e9107" },
+ *         "off-cpu": { "value": 1, "color": "#f9f9f9" }
+ *     }
+ * }
+ * { "time": "0", "entity": "thread-1", "state": 0 }
+ * { "time": "1000", "entity": "thread-1", "state": 1 }
+ * { "time": "3000", "entity": "thread-1", "state": 0 }
+ * "##;
+ *
+ * let config: Config = Default::default();
+ * let mut statemap = Statemap::new(&config);
+ *
+ * statemap.ingest_stream(data.as_bytes()).unwrap();
+ *
+ * let rects = statemap.entity("thread-1").unwrap().rects();
+ *
+ * assert_eq!(rects.len(), 2);
+ * assert_eq!(rects[1].start(), 1000);
+ * assert_eq!(rects[1].duration(), 2000);
+ * assert_eq!(rects[1].states()[1], 2000);
+ * assert_eq!(statemap.states()[1].name(), "off-cpu");
+ * ```
+ *
+ * Statemaps can also be constructed programmatically, without JSON; data
+ * presented this way is coalesced exactly as ingested data would be:
+ *
+ * ```
+ * use statemap::Statemap;
+ *
+ * let mut statemap = Statemap::new(&Default::default());
+ *
+ * statemap.set_title("Example");
+ * statemap.set_start(1579579142 * 1_000_000_000).unwrap();
+ *
+ * let on = statemap.add_state("on-cpu", Some("#2e9107")).unwrap();
+ * let off = statemap.add_state("off-cpu", Some("#f9f9f9")).unwrap();
+ *
+ * statemap.describe_entity("thread-1", "the first thread");
+ * statemap.transition("thread-1", 0, on, None).unwrap();
+ * statemap.transition("thread-1", 1000, off, None).unwrap();
+ * statemap.transition("thread-1", 3000, on, None).unwrap();
+ * statemap.finish();
+ *
+ * let rects = statemap.entity("thread-1").unwrap().rects();
+ *
+ * assert_eq!(rects.len(), 2);
+ * assert_eq!(rects[1].states()[1], 2000);
+ * ```
+ */
+
+/*
+ * We don't want to get away with not using values that we must use.
+ */
+#![deny(unused_must_use)]
+
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
+extern crate serde_json;
+
+mod statemap;
+
+pub use statemap::*;
@@ -11,16 +11,41 @@ extern crate getopts;
 use getopts::Options;
 use getopts::HasArg;
 use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
 
-#[macro_use]
-extern crate serde_derive;
+extern crate statemap;
 
-#[macro_use]
-extern crate serde_json;
+use statemap::*;
 
-mod statemap;
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum Format {
+    SVG,                                    // interactive SVG
+    PNG,                                    // static PNG
+    HTML,                                   // canvas-based HTML
+    JSON,                                   // coalesced rectangles as JSON
+    Stats(StatemapStatsFormat),             // report of time in each state
+    Transitions(StatemapTransitionsFormat), // report of state transitions
+    Diff(StatemapDiffFormat),               // report of change in each state
+    Delta,                                  // strips of change in each state
+}
 
-use statemap::*;
+#[derive(Clone,Debug,PartialEq)]
+enum Align {
+    Absolute,                               // by absolute time
+    Relative,                               // relative to each start
+    Event(String),                          // by first event of a type
+}
+
+/*
+ * Each finer level of detail has four times the buckets of the level before
+ * it; we limit the number of levels to keep memory and output in check.
+ */
+const MAX_LEVELS: u32 = 4;
 
 macro_rules! fatal {
     ($fmt:expr) => ({
@@ -34,50 +59,51 @@ macro_rules! fatal {
 }
 
 fn usage(opts: Options) {
-    println!("{}", opts.usage("Usage: statemap [options] FILE"));
+    println!("{}", opts.usage(concat!("Usage: statemap [options] FILE...\n",
+        "       statemap diff [options] FILE FILE")));
     ::std::process::exit(0);
 }
 
-fn parse_offset(matches: &getopts::Matches, opt: &str) -> i64 {
-    fn parse_offset_val(val: &str) -> Option<i64> {
-        let mut mult: i64 = 1;
-        let mut num = val;
-
-        let suffixes: &[(&'static str, i64)] = &[
-            ("ns", 1), ("us", 1_000), ("ms", 1_000_000),
-            ("s", 1_000_000_000), ("sec", 1_000_000_000)
-        ];
-
-        for suffix in suffixes {
-            if val.ends_with(suffix.0) {
-                mult = suffix.1;
-                num = &val[..val.len() - suffix.0.len()];
-                break;
-            }
+fn parse_offset_val(val: &str) -> Option<i64> {
+    let mut mult: i64 = 1;
+    let mut num = val;
+
+    let suffixes: &[(&'static str, i64)] = &[
+        ("ns", 1), ("us", 1_000), ("ms", 1_000_000),
+        ("s", 1_000_000_000), ("sec", 1_000_000_000)
+    ];
+
+    for suffix in suffixes {
+        if val.ends_with(suffix.0) {
+            mult = suffix.1;
+            num = &val[..val.len() - suffix.0.len()];
+            break;
         }
+    }
 
-        /*
-         * First attempt to parse our number as an integer, falling back
-         * on parsing it as floating point if that fails (and being sure
-         * to not allow some joker to specify "NaNms").
-         */
-        match num.parse::<i64>() {
-            Err(_err) => {
-                match num.parse::<f64>() {
-                    Err(_err) => None,
-                    Ok(val) => {
-                        if val.is_nan() {
-                            None
-                        } else {
-                            Some((val * mult as f64) as i64)
-                        }
+    /*
+     * First attempt to parse our number as an integer, falling back
+     * on parsing it as floating point if that fails (and being sure
+     * to not allow some joker to specify "NaNms").
+     */
+    match num.parse::<i64>() {
+        Err(_err) => {
+            match num.parse::<f64>() {
+                Err(_err) => None,
+                Ok(val) => {
+                    if val.is_nan() {
+                        None
+                    } else {
+                        Some((val * mult as f64) as i64)
                     }
                 }
-            },
-            Ok(val) => Some(val * mult)
-        }
+            }
+        },
+        Ok(val) => Some(val * mult)
     }
+}
 
+fn parse_offset(matches: &getopts::Matches, opt: &str) -> i64 {
     /*
      * We can safely unwrap here because we should only be here if the option
      * has been set.
@@ -129,6 +155,126 @@ fn main() {
             hasarg: HasArg::Yes,
             alias: None,
         },
+        Opt {
+            name: ("g", "global-coalesce"),
+            help: "apply coalesce target to all statemaps in total",
+            hint: "",
+            hasarg: HasArg::No,
+            alias: None,
+        },
+        Opt {
+            name: ("", "coalesce-mode"),
+            help: "manner of coalescing (\"weight\" or \"buckets\")",
+            hint: "MODE",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "resolution"),
+            help: "width of each bucket when coalescing into buckets",
+            hint: "TIME",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "durations"),
+            help: concat!("record durations of visits to each state ",
+                "(\"exact\" or \"histogram\")"),
+            hint: "MODE",
+            hasarg: HasArg::Maybe,
+            alias: None,
+        },
+        Opt {
+            name: ("", "utilization"),
+            help: "show the number of entities in each state over time",
+            hint: "",
+            hasarg: HasArg::No,
+            alias: None,
+        },
+        Opt {
+            name: ("", "align"),
+            help: concat!("alignment of stacked statemaps (\"absolute\", ",
+                "\"relative\" or \"event:EVENT\")"),
+            hint: "ALIGN",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "layout"),
+            help: "layout of multiple statemaps (\"stacked\" or \"tabs\")",
+            hint: "LAYOUT",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "clock-offset"),
+            help: concat!("comma-separated clock offsets to apply to each ",
+                "file, in order"),
+            hint: "TIMES",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "sched"),
+            help: concat!("entities of a statemap imported from a scheduler ",
+                "trace (\"cpu\" or \"thread\")"),
+            hint: "ENTITY",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "max-states"),
+            help: concat!("maximum number of span names imported from a ",
+                "trace as states"),
+            hint: "STATES",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "format"),
+            help: concat!("output format (\"svg\", \"png\", \"html\" ",
+                "or \"json\"; \"delta\" when comparing)"),
+            hint: "FORMAT",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "width"),
+            help: "total width of PNG output, in pixels",
+            hint: "PIXELS",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "height"),
+            help: "total height of PNG output, in pixels",
+            hint: "PIXELS",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "levels"),
+            help: "number of finer levels of detail to reveal when zooming",
+            hint: "LEVELS",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
+        Opt {
+            name: ("", "stats"),
+            help: concat!("report time in each state instead of rendering ",
+                "(\"text\", \"csv\" or \"json\")"),
+            hint: "FORMAT",
+            hasarg: HasArg::Maybe,
+            alias: None,
+        },
+        Opt {
+            name: ("", "transitions"),
+            help: concat!("report transitions between states instead of ",
+                "rendering (\"text\", \"json\" or \"dot\")"),
+            hint: "FORMAT",
+            hasarg: HasArg::Maybe,
+            alias: None,
+        },
         Opt {
             name: ("?", "help"),
             help: "print this usage message",
@@ -136,6 +282,13 @@ fn main() {
             hasarg: HasArg::No,
             alias: None,
         },
+        Opt {
+            name: ("", "salient"),
+            help: "comma-separated states to protect from coalescing",
+            hint: "STATES",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
         Opt {
             name: ("s", "sortby"),
             help: "state to sort by (defaults to entity name)",
@@ -164,6 +317,13 @@ fn main() {
             hasarg: HasArg::Yes,
             alias: Some("stateHeight"),
         },
+        Opt {
+            name: ("o", "output"),
+            help: "file to write output to (defaults to standard output)",
+            hint: "FILE",
+            hasarg: HasArg::Yes,
+            alias: None,
+        },
         Opt {
             name: ("n", "dry-run"),
             help: "ingest data, but do not generate output",
@@ -190,6 +350,15 @@ fn main() {
         }
     }
 
+    /*
+     * If our first argument is "diff", we are comparing two statemaps.
+     */
+    let diff = args.len() > 1 && args[1] == "diff";
+
+    if diff {
+        args.remove(1);
+    }
+
     let mut parser = Options::new();
 
     /*
@@ -272,8 +441,235 @@ fn main() {
         _ => {}
     }
 
+    let maxrect = config.maxrect;
+
+    match matches.opt_str("sched") {
+        Some(ref entity) if entity == "cpu" => {}
+        Some(ref entity) if entity == "thread" => {
+            config.sched = StatemapSchedEntity::Thread;
+        }
+        Some(entity) => fatal!("unknown scheduler entity \"{}\"", entity),
+        None => {}
+    }
+
+    if let Some(str) = matches.opt_str("max-states") {
+        match str.parse::<u32>() {
+            Ok(val) if val > 0 => config.maxstates = val,
+            _ => fatal!("maximum states must be a positive integer"),
+        }
+    }
+
     let mut svgconf: StatemapSVGConfig = Default::default();
 
+    /*
+     * Our target number of buckets (which is used both when coalescing into
+     * buckets and for any finer levels of detail) is one per pixel.
+     */
+    config.nbuckets = svgconf.stripWidth as u64;
+
+    match matches.opt_str("coalesce-mode") {
+        Some(ref mode) if mode == "weight" => {}
+        Some(ref mode) if mode == "buckets" => {
+            config.coalesce = StatemapCoalesce::Buckets;
+        }
+        Some(mode) => fatal!("unknown coalesce mode \"{}\"", mode),
+        None => {}
+    }
+
+    if matches.opt_present("global-coalesce") &&
+        config.coalesce != StatemapCoalesce::Weight {
+        fatal!("global coalescing requires a coalesce mode of \"weight\"");
+    }
+
+    if matches.opt_present("resolution") {
+        if config.coalesce != StatemapCoalesce::Buckets {
+            fatal!("resolution requires a coalesce mode of \"buckets\"");
+        }
+
+        match parse_offset(&matches, "resolution") {
+            val if val <= 0 => fatal!("resolution must be positive"),
+            val => config.resolution = val as u64
+        }
+    }
+
+    if let Some(str) = matches.opt_str("levels") {
+        match str.parse::<u32>() {
+            Ok(val) if val <= MAX_LEVELS => config.levels = val,
+            _ => fatal!("levels must be an integer no greater than {}",
+                MAX_LEVELS)
+        }
+    }
+
+    let mut format = match matches.opt_str("format") {
+        Some(ref format) if format == "svg" => Format::SVG,
+        Some(ref format) if format == "png" => Format::PNG,
+        Some(ref format) if format == "html" => Format::HTML,
+        Some(ref format) if format == "json" => Format::JSON,
+        Some(ref format) if format == "delta" => {
+            if !diff {
+                fatal!("delta format can only be used when comparing");
+            }
+
+            Format::Delta
+        }
+        Some(format) => fatal!("unknown format \"{}\"", format),
+        None => Format::SVG
+    };
+
+    if matches.opt_present("stats") {
+        if matches.opt_present("format") {
+            fatal!("stats cannot be combined with an output format");
+        }
+
+        format = Format::Stats(match matches.opt_str("stats") {
+            Some(ref stats) if stats == "text" => StatemapStatsFormat::Text,
+            Some(ref stats) if stats == "csv" => StatemapStatsFormat::CSV,
+            Some(ref stats) if stats == "json" => StatemapStatsFormat::JSON,
+            Some(stats) => fatal!("unknown stats format \"{}\"", stats),
+            None => StatemapStatsFormat::Text
+        });
+    }
+
+    if matches.opt_present("transitions") {
+        if matches.opt_present("format") || matches.opt_present("stats") {
+            fatal!(concat!("transitions cannot be combined with an output ",
+                "format or with stats"));
+        }
+
+        format = Format::Transitions(match matches.opt_str("transitions") {
+            Some(ref t) if t == "text" => StatemapTransitionsFormat::Text,
+            Some(ref t) if t == "json" => StatemapTransitionsFormat::JSON,
+            Some(ref t) if t == "dot" => StatemapTransitionsFormat::DOT,
+            Some(t) => fatal!("unknown transitions format \"{}\"", t),
+            None => StatemapTransitionsFormat::Text
+        });
+    }
+
+    if matches.opt_present("durations") {
+        config.durations = Some(match matches.opt_str("durations") {
+            Some(ref mode) if mode == "exact" => StatemapDurations::Exact,
+            Some(ref mode) if mode == "histogram" => {
+                StatemapDurations::Histogram
+            }
+            Some(mode) => fatal!("unknown durations mode \"{}\"", mode),
+            None => StatemapDurations::Exact
+        });
+
+        match format {
+            Format::SVG => {}
+            Format::Stats(StatemapStatsFormat::Text) => {}
+            Format::Stats(StatemapStatsFormat::JSON) => {}
+            _ => fatal!(concat!("durations can only be reported in SVG ",
+                "output or in stats as text or JSON"))
+        }
+    }
+
+    if matches.opt_present("utilization") {
+        if format != Format::SVG {
+            fatal!("utilization can only be shown in SVG output");
+        }
+
+        config.utilization = true;
+    }
+
+    let align = match matches.opt_str("align") {
+        Some(ref align) if align == "absolute" => Align::Absolute,
+        Some(ref align) if align == "relative" => Align::Relative,
+        Some(ref align) if align.starts_with("event:") && align.len() > 6 => {
+            Align::Event(align[6..].to_string())
+        }
+        Some(align) => fatal!("unknown alignment \"{}\"", align),
+        None => Align::Absolute
+    };
+
+    let offsets: Vec<i64> = match matches.opt_str("clock-offset") {
+        Some(str) => str.split(',').map(|val| {
+            match parse_offset_val(val) {
+                Some(val) => val,
+                None => fatal!(concat!("value for clock-offset is not a ",
+                    "valid expression of time: \"{}\""), val)
+            }
+        }).collect(),
+        None => vec![]
+    };
+
+    if offsets.len() > matches.free.len() {
+        fatal!("more clock offsets specified than data files");
+    }
+
+    if diff {
+        if matches.opt_present("align") {
+            fatal!("alignment cannot be specified in a diff");
+        }
+
+        if matches.opt_present("transitions") || config.durations.is_some() {
+            fatal!("transitions and durations cannot be reported in a diff");
+        }
+
+        if config.utilization {
+            fatal!("utilization cannot be shown in a diff");
+        }
+
+        if matches.free.len() != 2 {
+            fatal!("must specify exactly two data files to compare");
+        }
+
+        /*
+         * When comparing, we report the change in the time spent in each
+         * state unless we have been asked for an output format.
+         */
+        format = match format {
+            Format::Stats(StatemapStatsFormat::Text) => {
+                Format::Diff(StatemapDiffFormat::Text)
+            }
+            Format::Stats(StatemapStatsFormat::CSV) => {
+                Format::Diff(StatemapDiffFormat::CSV)
+            }
+            Format::Stats(StatemapStatsFormat::JSON) => {
+                Format::Diff(StatemapDiffFormat::JSON)
+            }
+            _ if !matches.opt_present("format") => {
+                Format::Diff(StatemapDiffFormat::Text)
+            }
+            format => format
+        };
+    }
+
+    let mut pngconf: StatemapPNGConfig = Default::default();
+
+    if let Some(str) = matches.opt_str("width") {
+        match str.parse::<u32>() {
+            Ok(val) if val > 0 => pngconf.width = val,
+            _ => fatal!("width must be a positive integer"),
+        }
+    }
+
+    if let Some(str) = matches.opt_str("height") {
+        match str.parse::<u32>() {
+            Ok(val) if val > 0 => pngconf.height = val,
+            _ => fatal!("height must be a positive integer"),
+        }
+    }
+
+    if (pngconf.width != 0 || pngconf.height != 0) && format != Format::PNG {
+        fatal!("width and height can only be specified for PNG output");
+    }
+
+    if config.levels > 0 && format == Format::PNG {
+        fatal!("levels of detail cannot be specified for PNG output");
+    }
+
+    svgconf.layout = match matches.opt_str("layout") {
+        Some(ref layout) if layout == "stacked" => StatemapLayout::Stacked,
+        Some(ref layout) if layout == "tabs" => StatemapLayout::Tabbed,
+        Some(layout) => fatal!("unknown layout \"{}\"", layout),
+        None => StatemapLayout::Stacked
+    };
+
+    if svgconf.layout != StatemapLayout::Stacked && format != Format::SVG {
+        fatal!("layout can only be specified for SVG output");
+    }
+
     svgconf.sortby = matches.opt_str("sortby");
     svgconf.stacksortby = matches.opt_str("stacksortby");
 
@@ -284,41 +680,212 @@ fn main() {
         }
     }
 
-    let mut statemaps: Vec<Statemap> = vec![];
+    let salient: Vec<String> = match matches.opt_str("salient") {
+        Some(str) => str.split(',').map(|s| s.to_string()).collect(),
+        None => vec![]
+    };
 
-    for i in 0..matches.free.len() {
-        let mut statemap = Statemap::new(&config);
-        let filename = &matches.free[i];
+    let ingest = |config: &Config, filename: &str| -> Statemap {
+        let mut statemap = Statemap::new(config);
+
+        for state in &salient {
+            match statemap.set_salient(state) {
+                Err(f) => { fatal!("{}", f); }
+                Ok(k) => { k }
+            }
+        }
 
         match statemap.ingest(filename) {
             Err(f) => { fatal!("could not ingest {}: {}", filename, f); }
             Ok(k) => { k }
         }
 
-        if !config.abstime {
+        statemap
+    };
+
+    let mut statemaps: Vec<Statemap> = vec![];
+    let mut anchor: i64 = 0;
+
+    for i in 0..matches.free.len() {
+        let filename = &matches.free[i];
+        let mut fileconf = config;
+
+        /*
+         * If a clock offset has been specified for this file, it corrects
+         * the file's start time before any absolute alignment.
+         */
+        if let Some(offset) = offsets.get(i) {
+            fileconf.offset = *offset;
+        }
+
+        /*
+         * If we are aligning statemaps by an event, we must first find the
+         * time of the event's first occurrence in this file (which requires
+         * an additional pass over it); each statemap after the first is
+         * shifted by the difference between its time and the first's.
+         * As a stream can only be read once, each file must be a regular
+         * file.
+         */
+        if let Align::Event(ref event) = align {
+            if filename == "-" ||
+                fs::metadata(filename).map_or(false, |m| !m.is_file()) {
+                fatal!(concat!("cannot align {} by event: aligning by event ",
+                    "requires a regular file"), filename);
+            }
+
+            let probe = Config {
+                begin: 0,
+                end: 0,
+                levels: 0,
+                durations: None,
+                .. config
+            };
+
+            let time = match ingest(&probe, filename).event_time(event) {
+                Some(time) => time as i64,
+                None => fatal!("event \"{}\" not found in {}", event, filename)
+            };
+
+            if i == 0 {
+                anchor = time;
+            } else {
+                fileconf.begin += time - anchor;
+                fileconf.end += time - anchor;
+            }
+        }
+
+        let statemap = ingest(&fileconf, filename);
+
+        eprintln!("{}: {} records processed, {} rectangles",
+            match Path::new(filename).file_name() {
+                Some(name) => name.to_string_lossy(),
+                None => filename.into()
+            }, statemap.nrecs(), statemap.nrects());
+
+        if diff {
             /*
-             * If our time configuration is not absolute, we just processed
-             * our first statemap; change our time configuration to now be
-             * absolute to key the time for every subsequent statemap based
-             * on this first statemap.
+             * If we are comparing statemaps, each remains relative to its
+             * own start time -- but we still use the same resolution for
+             * both if we are coalescing into buckets.
              */
-            assert!(i == 0);
-            config.abstime = true;
+            if let Some(resolution) = statemap.resolution() {
+                config.resolution = resolution;
+            }
+        } else if i == 0 {
             let timebounds = statemap.timebounds();
-            config.begin = timebounds.0 as i64;
-            config.end = timebounds.1 as i64;
+
+            if align == Align::Absolute {
+                /*
+                 * We just processed our first statemap; change our time
+                 * configuration to now be absolute to key the time for
+                 * every subsequent statemap based on this first statemap.
+                 */
+                config.abstime = true;
+                config.begin = timebounds.0 as i64;
+                config.end = timebounds.1 as i64;
+            } else {
+                /*
+                 * If we are aligning each statemap relative to its own
+                 * start (or to an event), our time configuration remains
+                 * relative -- but each subsequent statemap is bounded by
+                 * the span of this first statemap.
+                 */
+                config.end = config.begin +
+                    (timebounds.1 - timebounds.0) as i64;
+            }
+
+            /*
+             * If we are coalescing into buckets, use the same resolution
+             * for every statemap so their buckets line up.
+             */
+            if let Some(resolution) = statemap.resolution() {
+                config.resolution = resolution;
+            }
         }
 
         statemaps.push(statemap);
     }
 
+    for state in &salient {
+        if !statemaps.iter().any(|s| s.states().iter()
+            .any(|st| st.name() == state)) {
+            fatal!("salient state \"{}\" is not a valid state", state);
+        }
+    }
+
+    /*
+     * If our coalescing target is global, we trim across all statemaps
+     * now that they have all been ingested.
+     */
+    if matches.opt_present("global-coalesce") {
+        match Statemap::coalesce(&mut statemaps, maxrect) {
+            Err(f) => { fatal!("{}", f); }
+            Ok(k) => { k }
+        }
+
+        eprintln!("{} statemaps: {} rectangles", statemaps.len(),
+            statemaps.iter().fold(0, |n, s| n + s.nrects()));
+    }
+
     if matches.opt_present("dry-run") {
         return;
     }
 
-    let svg = StatemapSVG::new(&svgconf);
+    /*
+     * If we are rendering a comparison, we render the statemap that
+     * combines both of the statemaps being compared.
+     */
+    if diff {
+        match format {
+            Format::Diff(_) | Format::Delta => {}
+            _ => {
+                let combined = StatemapDiff::new(&statemaps[0],
+                    &statemaps[1]).and_then(|diff| diff.combined());
+
+                statemaps = match combined {
+                    Err(f) => { fatal!("{}", f); }
+                    Ok(combined) => vec![combined]
+                };
+            }
+        }
+    }
+
+    let stdout = io::stdout();
+
+    let mut out: Box<dyn Write> = match matches.opt_str("output") {
+        Some(filename) => match File::create(&filename) {
+            Err(f) => { fatal!("could not create {}: {}", filename, f); }
+            Ok(file) => Box::new(BufWriter::new(file))
+        },
+        None => Box::new(BufWriter::new(stdout.lock()))
+    };
+
+    let result = match format {
+        Format::SVG => StatemapSVG::new(&svgconf).output(&statemaps, &mut out),
+        Format::PNG => {
+            StatemapPNG::new(&svgconf, &pngconf).output(&statemaps, &mut out)
+        }
+        Format::HTML => {
+            StatemapHTML::new(&svgconf).output(&statemaps, &mut out)
+        }
+        Format::JSON => StatemapJSON::new().output(&statemaps, &mut out),
+        Format::Stats(stats) => {
+            StatemapStats::new(stats).output(&statemaps, &mut out)
+        }
+        Format::Transitions(transitions) => {
+            StatemapTransitions::new(transitions).output(&statemaps, &mut out)
+        }
+        Format::Diff(report) => {
+            StatemapDiff::new(&statemaps[0], &statemaps[1])
+                .and_then(|diff| diff.output(report, &mut out))
+        }
+        Format::Delta => {
+            StatemapDiff::new(&statemaps[0], &statemaps[1])
+                .and_then(|diff| diff.output_delta(&svgconf, &mut out))
+        }
+    };
 
-    match svg.output(&statemaps) {
+    match result.and_then(|_| Ok(out.flush()?)) {
         Err(f) => { fatal!("{}", f); }
         Ok(k) => { k }
     }
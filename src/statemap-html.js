@@ -0,0 +1,1438 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * This file is dropped into the generated HTML -- and if you're looking at
+ * the generated HTML and wondering where this comes from, look for
+ * statemap-html.js in statemap's src directory.  Unlike statemap-svg.js,
+ * which manipulates an element for every rectangle, this viewer draws the
+ * statemap on a canvas -- and draws only those rectangles that are visible.
+ */
+
+var g_canvas;					/* our canvas */
+var g_context;					/* 2D context of our canvas */
+var g_zoom = 1;					/* horizontal scale */
+var g_pan = 0;					/* horizontal offset, in pixels */
+var g_height;					/* pixel height of statemap */
+var g_width;					/* pixel width of statemap */
+var g_timebar;					/* timebar, if any */
+var g_statebar;					/* statebar, if any */
+var g_statesel;					/* state selection, if any */
+var g_tagsel;					/* tag selection, if any */
+var g_tagvalsel;				/* tag val selection, if any */
+var g_arrows = true;				/* event arrows are shown */
+var g_entities = [];				/* entities, by position */
+var g_hits = [];				/* clickable regions of canvas */
+
+var g_statemaps = [];				/* array of statemaps */
+
+var g_font = 'Verdana, Arial, Helvetica, sans-serif';
+
+var timeunits = function (timeval)
+{
+	var i, rem;
+	var suffixes = [ 'ns', 'μs', 'ms', 's' ];
+
+	if (timeval === 0)
+		return ('0');
+
+	for (i = 0; (timeval > 1000 || timeval < -1000) &&
+	    i < suffixes.length - 1; i++)
+		timeval /= 1000;
+
+	rem = Math.floor((timeval - Math.floor(timeval)) * 1000);
+
+	return (Math.floor(timeval) + '.' +
+	    (rem < 100 ? '0' : '') + (rem < 10 ? '0' : '') + rem +
+	    suffixes[i]);
+};
+
+var timeFromMapX = function (mapX)
+{
+	var timeWidth = globals.timeWidth;
+
+	/*
+	 * As with the SVG, our base is our pan as a ratio of our total (scaled)
+	 * width, and our offset is the X offset within the statemap as a
+	 * ratio of the statemap width, times the nanoseconds visible.
+	 */
+	return ((-g_pan / (g_zoom * g_width)) * timeWidth +
+	    (mapX / g_width) * (timeWidth / g_zoom));
+};
+
+var timeToMapX = function (time)
+{
+	return (((time / globals.timeWidth) * g_width * g_zoom) + g_pan);
+};
+
+var timeToText = function (time)
+{
+	var t;
+
+	if (g_zoom === 1 && globals.begin === 0) {
+		t = 'offset = ' + timeunits(time);
+	} else {
+		t = 'offset = ' + timeunits(time) + ', ' +
+		    timeunits(time + globals.begin) + ' overall';
+	}
+
+	if (globals.start) {
+		var s = globals.start[0] +
+		    (time + globals.start[1]) / 1000000000;
+
+		t += ' (Epoch + ' + Math.floor(s) + 's)';
+	}
+
+	return (t);
+};
+
+var timeSpanLabel = function ()
+{
+	var t = 'span = ' + timeunits(globals.timeWidth / g_zoom);
+
+	if (g_zoom != 1 || globals.begin !== 0)
+		t += '; ' + timeToText(timeFromMapX(0));
+
+	return (t);
+};
+
+/*
+ * Return the vertical offset of the top of the specified entity's strip.
+ */
+var entityY = function (entity)
+{
+	return (globals.tmargin + (entity.position * globals.stripHeight) +
+	    (entity.statemap * globals.smargin));
+};
+
+/*
+ * Return the entity (if any) whose strip contains the specified vertical
+ * offset.
+ */
+var entityAt = function (y)
+{
+	var i, top;
+
+	for (i = 0; i < g_entities.length; i++) {
+		top = entityY(g_entities[i]);
+
+		if (y >= top && y < top + globals.stripHeight)
+			return (g_entities[i]);
+	}
+
+	return (undefined);
+};
+
+/*
+ * Binary search the specified data for the datum that contains the
+ * specified time, returning its index (or -1 if the data starts after it).
+ */
+var datumFind = function (data, time)
+{
+	var floor = 0, ceil = data.length, idx = -1, i;
+
+	while (floor < ceil) {
+		i = floor + Math.floor((ceil - floor) / 2);
+
+		if (data[i].t <= time) {
+			idx = i;
+			floor = i + 1;
+		} else {
+			ceil = i;
+		}
+	}
+
+	return (idx);
+};
+
+var entityForEachDatum = function (entity, time, etime, func)
+{
+	var data = g_statemaps[entity.statemap].data[entity.name] || [];
+	var idx, length = data.length;
+	var datum, t, span;
+
+	if (length === 0 || (data[0].t > time && !etime))
+		return;
+
+	idx = Math.max(datumFind(data, time), 0);
+
+	/*
+	 * If we don't have a specified etime, we have found the datum that
+	 * contains the time; just call our function and return.
+	 */
+	if (!etime) {
+		func(data[idx], idx, 1);
+		return;
+	}
+
+	/*
+	 * Now we're going to iterate forward, calling our function until we
+	 * get past our specified etime.
+	 */
+	for (; idx < length; idx++) {
+		datum = data[idx];
+
+		if (datum.t > etime)
+			return;
+
+		if ((t = datum.t) < time)
+			t = time;
+
+		if (idx + 1 == length || data[idx + 1].t > etime) {
+			span = etime - t;
+		} else {
+			span = data[idx + 1].t - t;
+		}
+
+		func(datum, idx, span);
+	}
+};
+
+var entityDatum = function (entity, idx)
+{
+	var data = g_statemaps[entity.statemap].data[entity.name];
+	var datum = data[idx];
+	var rval = { time: datum.t };
+
+	if (datum.s instanceof Object) {
+		rval.states = datum.s;
+	} else {
+		rval.state = datum.s;
+	}
+
+	rval.etime = datum.t + datum.d;
+
+	return (rval);
+};
+
+var entityBreakdown = function (entity, time)
+{
+	var data = g_statemaps[entity.statemap].data[entity.name] || [];
+	var rval = {};
+	var idx, datum;
+
+	time += g_statemaps[entity.statemap].offset;
+
+	if ((idx = datumFind(data, time)) === -1)
+		return ({});
+
+	datum = data[idx];
+
+	if (datum.s instanceof Object)
+		return (datum.s);
+
+	rval[datum.s] = 1.0;
+
+	return (rval);
+};
+
+/*
+ * Draw text on our canvas, returning the width of the text.
+ */
+var canvasText = function (t, x, y, font, align, color)
+{
+	g_context.font = font;
+	g_context.textAlign = align || 'start';
+	g_context.fillStyle = color || 'black';
+	g_context.fillText(t, x, y);
+
+	return (g_context.measureText(t).width);
+};
+
+var canvasLine = function (x1, y1, x2, y2, width, color, dash)
+{
+	g_context.beginPath();
+	g_context.setLineDash(dash || []);
+	g_context.lineWidth = width;
+	g_context.strokeStyle = color || 'black';
+	g_context.moveTo(x1, y1);
+	g_context.lineTo(x2, y2);
+	g_context.stroke();
+	g_context.setLineDash([]);
+};
+
+/*
+ * Draw an arrowhead with its tip at the specified point, pointing in the
+ * specified direction.
+ */
+var canvasArrowhead = function (x, y, dx, dy)
+{
+	g_context.beginPath();
+	g_context.fillStyle = 'black';
+	g_context.moveTo(x, y);
+	g_context.lineTo(x - 5 * dx - 2.5 * dy, y - 5 * dy + 2.5 * dx);
+	g_context.lineTo(x - 5 * dx + 2.5 * dy, y - 5 * dy - 2.5 * dx);
+	g_context.closePath();
+	g_context.fill();
+};
+
+/*
+ * Note a region of the canvas that should respond to a click.
+ */
+var hitAdd = function (x1, y1, x2, y2, func)
+{
+	g_hits.push({ x1: Math.min(x1, x2), y1: Math.min(y1, y2),
+	    x2: Math.max(x1, x2), y2: Math.max(y1, y2), func: func });
+};
+
+var initStatemap = function (statemap, position)
+{
+	var s = g_statemaps[statemap];
+	var i, entity;
+
+	s.position = position;
+	s.nentities = s.order.length;
+	s.base = s.data;
+	s.level = -1;
+
+	for (i = 0; i < s.order.length; i++) {
+		entity = {
+			name: s.order[i],
+			position: position++,
+			statemap: statemap
+		};
+
+		entity.description = s.entities[entity.name] ?
+		    s.entities[entity.name].description : undefined;
+
+		g_entities.push(entity);
+	}
+
+	return (position);
+};
+
+var initLegend = function ()
+{
+	var legend = document.getElementById('statemap-legend');
+	var x = 20;
+	var y = globals.tmargin + 45;
+	var width = globals.lmargin - 50;
+	var height = 15, spacing = 10;
+	var shared = true;
+	var i, j, s, elem, label;
+
+	var click = function (statemap, state) {
+		return (function (evt) { legendclick(evt, statemap, state); });
+	};
+
+	var position = function (e, left, top, w, h) {
+		e.style.left = left + 'px';
+		e.style.top = top + 'px';
+
+		if (w !== undefined)
+			e.style.width = w + 'px';
+
+		if (h !== undefined)
+			e.style.height = h + 'px';
+	};
+
+	for (i = 0; i < g_statemaps.length; i++) {
+		if (g_statemaps[i].legend !== g_statemaps[0].legend)
+			shared = false;
+	}
+
+	for (i = 0; i < g_statemaps.length; i++) {
+		s = g_statemaps[i];
+
+		if (s.legend !== i) {
+			if (!shared)
+				y += spacing * 2;
+			continue;
+		}
+
+		for (j = 0; j < s.states.length; j++) {
+			elem = document.createElement('div');
+			elem.id = 'statemap-legend-' + i + '-' + j;
+			elem.className = 'statemap-legend';
+			elem.style.background = s.colors[j];
+			elem.addEventListener('click', click(i, j));
+			position(elem, x, y, width, height);
+			legend.appendChild(elem);
+			y += height + spacing;
+
+			label = document.createElement('div');
+			label.className = 'statemap-legendlabel';
+			label.textContent = s.states[j].name;
+			position(label, x - spacing, y - spacing,
+			    width + 2 * spacing, spacing);
+			legend.appendChild(label);
+			y += spacing;
+		}
+
+		/*
+		 * If we have events, list each event type (along with its
+		 * glyph) below our states.
+		 */
+		if (s.eventTypes && s.eventTypes.length > 0)
+			y += spacing;
+
+		for (j = 0; s.eventTypes && j < s.eventTypes.length; j++) {
+			elem = document.createElement('div');
+			elem.className = 'statemap-event-legend';
+			elem.style.background = eventColor(j);
+			position(elem, x + 2, y + 4, 7, 7);
+			legend.appendChild(elem);
+
+			label = document.createElement('div');
+			label.className = 'statemap-eventlabel';
+			label.textContent = s.eventTypes[j];
+			position(label, x + 15, y + 2);
+			legend.appendChild(label);
+			y += height;
+		}
+
+		/*
+		 * If any events are drawn as arrows, add a toggle for them.
+		 */
+		if (statemapHasArrows(i)) {
+			elem = document.createElement('div');
+			elem.className = 'statemap-arrow-legend';
+			position(elem, x, y + 7, 8, 0);
+			legend.appendChild(elem);
+
+			label = document.createElement('div');
+			label.id = 'statemap-arrows-legend-' + i;
+			label.className = 'statemap-eventlabel';
+			label.textContent = 'hide arrows';
+			label.addEventListener('click', arrowsclick);
+			position(label, x + 15, y + 2);
+			legend.appendChild(label);
+			y += height;
+		}
+
+		if (!shared)
+			y += spacing * 2;
+	}
+};
+
+var init = function ()
+{
+	var i = 0, position = 0;
+	var ratio = window.devicePixelRatio || 1;
+	var width = globals.lmargin + globals.pixelWidth + globals.tagWidth;
+
+	g_canvas = document.getElementById('statemap-canvas');
+	g_canvas.width = width * ratio;
+	g_canvas.height = globals.totalHeight * ratio;
+	g_canvas.style.width = width + 'px';
+	g_canvas.style.height = globals.totalHeight + 'px';
+
+	g_context = g_canvas.getContext('2d');
+	g_context.scale(ratio, ratio);
+
+	g_entities = [];
+
+	for (i = 0; i < g_statemaps.length; i++)
+		position = initStatemap(i, position);
+
+	g_height = globals.pixelHeight;
+	g_width = globals.pixelWidth;
+
+	initLegend();
+
+	g_canvas.addEventListener('click', canvasclick);
+	g_timebar = undefined;
+
+	statemapsUpdate();
+};
+
+var eventColor = function (event)
+{
+	return (globals.eventColors[event % globals.eventColors.length]);
+};
+
+var statemapHasArrows = function (statemap)
+{
+	var s = g_statemaps[statemap];
+	var i;
+
+	for (i = 0; s.events && i < s.events.length; i++) {
+		if (s.events[i].hasOwnProperty('target') &&
+		    s.entities.hasOwnProperty(s.events[i].target))
+			return (true);
+	}
+
+	return (false);
+};
+
+/*
+ * As in the SVG, pick the coarsest level with buckets that are no wider than
+ * two pixels (with -1 denoting the statemap's own rectangles).
+ */
+var levelSelect = function (statemap)
+{
+	var levels = g_statemaps[statemap].levels;
+	var pixel = globals.timeWidth / (g_width * g_zoom);
+	var i;
+
+	if (!levels || levels.length === 0 ||
+	    globals.timeWidth / g_width <= 2 * pixel)
+		return (-1);
+
+	for (i = 0; i < levels.length - 1; i++) {
+		if (levels[i].resolution <= 2 * pixel)
+			break;
+	}
+
+	return (i);
+};
+
+var levelsUpdate = function (statemap)
+{
+	var s = g_statemaps[statemap];
+	var level = levelSelect(statemap);
+
+	if (level === s.level)
+		return;
+
+	s.data = level === -1 ? s.base : s.levels[level].data;
+	s.level = level;
+};
+
+/*
+ * If we have a tag value selection, return the fraction of the specified
+ * datum that has the selected tag value.
+ */
+var tagvalRatio = function (statemap, datum)
+{
+	var state, tagdefs, tags, i, tag, ratio = 0;
+
+	if (!g_tagvalsel || !g_statesel || !datum.g)
+		return (0);
+
+	if (g_statemaps[statemap].legend !==
+	    g_statemaps[g_statesel.statemap].legend)
+		return (0);
+
+	state = g_statesel.state;
+
+	if (datum.s instanceof Object) {
+		if (!datum.s[state])
+			return (0);
+	} else if (datum.s != state) {
+		return (0);
+	}
+
+	/*
+	 * Tag IDs are specific to each statemap, so we determine the tags
+	 * that match our selection on a per-statemap basis.
+	 */
+	if (!(tagdefs = g_tagvalsel.tagdefs[statemap])) {
+		tagdefs = g_tagvalsel.tagdefs[statemap] = {};
+		tags = g_statemaps[statemap].tags || [];
+
+		for (i = 0; i < tags.length; i++) {
+			if (tags[i].state == state &&
+			    tags[i][g_tagvalsel.tag] == g_tagvalsel.tagval)
+				tagdefs[i] = true;
+		}
+	}
+
+	for (tag in datum.g) {
+		if (tagdefs[tag])
+			ratio += datum.g[tag];
+	}
+
+	return (ratio);
+};
+
+var entityDraw = function (entity)
+{
+	var s = g_statemaps[entity.statemap];
+	var data = s.data[entity.name] || [];
+	var base = globals.begin + s.offset;
+	var y = entityY(entity);
+	var height = globals.stripHeight;
+	var etime = timeFromMapX(g_width) + base;
+	var scale = (g_width * g_zoom) / globals.timeWidth;
+	var i, x, w, datum, ratio;
+
+	g_context.fillStyle = globals.background;
+	g_context.fillRect(globals.lmargin, y, g_width, height);
+
+	i = Math.max(datumFind(data, timeFromMapX(0) + base), 0);
+
+	for (; i < data.length && data[i].t < etime; i++) {
+		datum = data[i];
+		x = globals.lmargin + timeToMapX(datum.t - base);
+
+		/*
+		 * As with the rectangles in the SVG, we add a fuzz factor to
+		 * our width to keep the background from showing through.
+		 */
+		w = (datum.d * scale) + 0.4;
+
+		if ((ratio = tagvalRatio(entity.statemap, datum)) > 0) {
+			g_context.fillStyle = 'blue';
+			g_context.fillRect(x, y, w, height);
+			g_context.globalAlpha = 1 - ratio;
+		}
+
+		g_context.fillStyle = datum.c;
+		g_context.fillRect(x, y, w, height);
+		g_context.globalAlpha = 1;
+
+		/*
+		 * A blended rectangle with time in a salient state is marked
+		 * with a bar in the color of that state.
+		 */
+		if (datum.m) {
+			g_context.fillStyle = datum.m;
+			g_context.fillRect(x, y, Math.max(w, 1),
+			    Math.max(Math.floor(height / 4), 1));
+		}
+	}
+};
+
+var eventsDraw = function (statemap)
+{
+	var s = g_statemaps[statemap];
+	var base = globals.begin + s.offset;
+	var size = Math.min(globals.stripHeight / 2, 5);
+	var ys = {}, i, event, x, y, y1, y2;
+
+	var click = function (idx) {
+		return (function (evt) { eventclick(evt, statemap, idx); });
+	};
+
+	if (!s.events)
+		return;
+
+	for (i = 0; i < g_entities.length; i++) {
+		if (g_entities[i].statemap == statemap)
+			ys[g_entities[i].name] = entityY(g_entities[i]);
+	}
+
+	/*
+	 * Arrows are drawn first, so the event glyphs sit atop them.
+	 */
+	for (i = 0; g_arrows && i < s.events.length; i++) {
+		event = s.events[i];
+
+		if (!event.hasOwnProperty('target') ||
+		    !ys.hasOwnProperty(event.target) ||
+		    ys[event.target] == ys[event.n])
+			continue;
+
+		x = globals.lmargin + timeToMapX(event.t - base);
+
+		if (x < globals.lmargin || x > globals.lmargin + g_width)
+			continue;
+
+		y1 = ys[event.n] + globals.stripHeight / 2;
+		y2 = ys[event.target] > ys[event.n] ? ys[event.target] :
+		    ys[event.target] + globals.stripHeight;
+
+		canvasLine(x, y1, x, y2, 0.75);
+		canvasArrowhead(x, y2, 0, y2 > y1 ? 1 : -1);
+	}
+
+	for (i = 0; i < s.events.length; i++) {
+		event = s.events[i];
+		x = globals.lmargin + timeToMapX(event.t - base);
+		y = ys[event.n] + globals.stripHeight / 2;
+
+		if (x < globals.lmargin || x > globals.lmargin + g_width)
+			continue;
+
+		g_context.beginPath();
+		g_context.moveTo(x, y - size);
+		g_context.lineTo(x + size, y);
+		g_context.lineTo(x, y + size);
+		g_context.lineTo(x - size, y);
+		g_context.closePath();
+		g_context.fillStyle = eventColor(event.e);
+		g_context.fill();
+		g_context.lineWidth = 0.75;
+		g_context.strokeStyle = 'white';
+		g_context.stroke();
+
+		hitAdd(x - size, y - size, x + size, y + size, click(i));
+	}
+};
+
+var timebarDraw = function (timebar)
+{
+	var mapX, x, absX, t, width, subbar;
+	var nubheight = 15;
+	var nudge = { x: 3, y: 5 };
+	var font = '8pt ' + g_font;
+
+	if (!timebar)
+		return;
+
+	mapX = timeToMapX(timebar.time);
+
+	if (mapX < 0 || mapX >= g_width)
+		return;
+
+	absX = mapX + globals.lmargin;
+
+	canvasLine(absX, globals.tmargin - nubheight,
+	    absX, globals.tmargin + g_height, 1, 'blue');
+
+	/*
+	 * The side of the timebar that we render the text containing the
+	 * offset and the time depends on the location of our timebar with
+	 * respect to the center of the visible statemap.
+	 */
+	t = timeToText(timebar.time);
+
+	if (mapX < (g_width / 2)) {
+		x = absX + nudge.x;
+		width = canvasText(t, x, globals.tmargin - nudge.y,
+		    font, 'start', 'blue');
+	} else {
+		x = absX - nudge.x;
+		width = -canvasText(t, x, globals.tmargin - nudge.y,
+		    font, 'end', 'blue');
+	}
+
+	hitAdd(x, globals.tmargin - nudge.y - 11, x + width,
+	    globals.tmargin - nudge.y + 2, function () {
+		timebarRemove();
+		stateselUpdate();
+		statemapsUpdate();
+	});
+
+	if (!(subbar = timebar.subbar))
+		return;
+
+	mapX = timeToMapX(subbar.time);
+
+	if (mapX < 0 || mapX >= g_width)
+		return;
+
+	x = mapX + globals.lmargin;
+
+	canvasLine(x, globals.tmargin, x, globals.tmargin + g_height,
+	    1, 'blue', [5.7]);
+	canvasLine(absX, subbar.y, x, subbar.y, 0.75, 'blue', [1]);
+
+	g_context.lineWidth = 0.3;
+	g_context.strokeStyle = 'white';
+	g_context.font = 'bold ' + font;
+	g_context.textAlign = 'center';
+	t = timeunits(Math.abs(timebar.time - subbar.time));
+	g_context.strokeText(t, (absX + x) / 2, subbar.y + 10);
+	canvasText(t, (absX + x) / 2, subbar.y + 10,
+	    'bold ' + font, 'center', 'blue');
+};
+
+/*
+ * Draw text for our statebar, rotated by the specified angle.
+ */
+var statebarText = function (t, x, y, angle, align)
+{
+	var width;
+
+	g_context.save();
+	g_context.translate(x, y);
+	g_context.rotate(angle);
+	width = canvasText(t, 0, 0, '8pt ' + g_font, align, 'blue');
+	g_context.restore();
+
+	return (width);
+};
+
+var statebarDraw = function (statebar)
+{
+	var entity, statemap, states, datum, pos, x, y, t, width;
+	var elbow = { x: 8, y: 10 };
+	var nudge = { x: 3, y: 2 };
+	var direction, anchor;
+	var anchors = [ 'start', 'end' ];
+
+	if (!statebar)
+		return;
+
+	entity = statebar.entity;
+	statemap = g_statemaps[entity.statemap];
+	states = statemap.states;
+	datum = statebar.datum;
+	pos = (entity.position * globals.stripHeight) +
+	    (entity.statemap * globals.smargin);
+	x = globals.lmargin - 2;
+	y = globals.tmargin + pos;
+
+	if (pos < (globals.totalHeight - globals.tmargin) / 2) {
+		direction = 1;
+		anchor = 1;
+	} else {
+		direction = -1;
+		anchor = 0;
+	}
+
+	/*
+	 * We have three bars to draw:  our bar that runs the height of the
+	 * strip, followed by our elbow.
+	 */
+	canvasLine(x, y, x, y + globals.stripHeight, 1, 'blue');
+
+	y += 0.5 * globals.stripHeight;
+	canvasLine(x - elbow.x, y, x, y, 1, 'blue');
+
+	x -= elbow.x;
+	canvasLine(x, y, x, y + (elbow.y * direction), 1, 'blue');
+
+	y += (elbow.y + nudge.y) * direction;
+	x += nudge.x;
+
+	t = statemap.entityKind + ' ' + entity.name;
+
+	if (entity.description)
+		t += ' (' + entity.description + ')';
+
+	if (datum.hasOwnProperty('state')) {
+		t += ', ' + states[datum.state].name;
+	} else {
+		var i, total = 0, max = 0, maxstate;
+
+		for (i in datum.states) {
+			total += datum.states[i];
+
+			if (datum.states[i] > max) {
+				maxstate = i;
+				max = datum.states[i];
+			}
+		}
+
+		t += ', ' + Math.floor((datum.states[maxstate] / total) * 100);
+		t += '% ' + states[maxstate].name;
+	}
+
+	t += ' at ' + timeunits(datum.time);
+	t += ' for ' + timeunits(datum.etime - datum.time);
+
+	/*
+	 * Our text reads upward, and is clickable to remove the statebar.
+	 */
+	width = statebarText(t, x, y, -Math.PI / 2, anchors[anchor]);
+	hitAdd(x - 11, y, x + 2, y + (width * direction), statebarclick);
+
+	if (g_statemaps.length == 1)
+		return;
+
+	/*
+	 * If we have more than one statemap, we want to add a bar to the right
+	 * side to indicate which statemap this is.
+	 */
+	var height = statemap.nentities * globals.stripHeight;
+
+	x = globals.lmargin + g_width + 2;
+	y = globals.tmargin + (statemap.position * globals.stripHeight) +
+	    (entity.statemap * globals.smargin);
+
+	canvasLine(x, y, x, y + height, 1, 'blue');
+
+	y += 0.5 * height;
+	canvasLine(x + elbow.x, y, x, y, 1, 'blue');
+
+	x += elbow.x;
+	canvasLine(x, y, x, y + (elbow.y * direction), 1, 'blue');
+
+	y += (elbow.y + nudge.y) * direction;
+	x -= nudge.x;
+
+	statebarText(statemap.title, x, y, Math.PI / 2, anchors[anchor ^ 1]);
+};
+
+var statebarclick = function ()
+{
+	g_statebar = undefined;
+	stateselUpdate();
+	statemapsUpdate();
+};
+
+/*
+ * Redraw our canvas in its entirety.
+ */
+var statemapsDraw = function ()
+{
+	var width = globals.lmargin + globals.pixelWidth + globals.tagWidth;
+	var left = globals.lmargin, right = globals.lmargin + g_width;
+	var i, s, top, height;
+
+	g_hits = [];
+	g_context.clearRect(0, 0, width, globals.totalHeight);
+
+	canvasText(globals.title, left + (g_width / 2), 16,
+	    'bold 10pt ' + g_font, 'center');
+	canvasText(timeSpanLabel(), left + (g_width / 2), 34,
+	    '8pt ' + g_font, 'center');
+
+	canvasLine(left + 10, 40, right - 10, 40, 0.67);
+	canvasArrowhead(left + 5, 40, -1, 0);
+	canvasArrowhead(right - 5, 40, 1, 0);
+
+	g_context.save();
+	g_context.beginPath();
+	g_context.rect(left, globals.tmargin, g_width, g_height);
+	g_context.clip();
+
+	for (i = 0; i < g_entities.length; i++)
+		entityDraw(g_entities[i]);
+
+	for (i = 0; i < g_statemaps.length; i++)
+		eventsDraw(i);
+
+	g_context.restore();
+
+	/*
+	 * The border around each statemap.
+	 */
+	for (i = 0; i < g_statemaps.length; i++) {
+		s = g_statemaps[i];
+		top = globals.tmargin + (s.position * globals.stripHeight) +
+		    (i * globals.smargin);
+		height = s.nentities * globals.stripHeight;
+
+		g_context.lineWidth = 0.5;
+		g_context.strokeStyle = 'black';
+		g_context.strokeRect(left, top, g_width, height);
+	}
+
+	timebarDraw(g_timebar);
+	statebarDraw(g_statebar);
+};
+
+var statemapsUpdate = function ()
+{
+	var i;
+
+	for (i = 0; i < g_statemaps.length; i++)
+		levelsUpdate(i);
+
+	statemapsDraw();
+};
+
+var timebarRemove = function ()
+{
+	var i, elem;
+
+	if (!g_timebar)
+		return;
+
+	for (i = 0; i < g_timebar.breakdown.length; i++) {
+		elem = g_timebar.breakdown[i];
+		elem.parentNode.removeChild(elem);
+	}
+
+	g_timebar = undefined;
+};
+
+var timebarSetBreakdown = function (time)
+{
+	var breakdown, state, total = [];
+	var i, statemap, entity, legend, text;
+	var sum = {};
+	var rval = [];
+
+	var click = function (s, state) {
+		return (function (evt) {
+			evt.stopPropagation();
+			legendclick(evt, s, state);
+		});
+	};
+
+	time += globals.begin;
+
+	for (i = 0; i < g_entities.length; i++) {
+		entity = g_entities[i];
+		statemap = g_statemaps[entity.statemap].legend;
+
+		breakdown = entityBreakdown(entity, time);
+
+		if (!total[statemap]) {
+			total[statemap] = {};
+			sum[statemap] = 0;
+		}
+
+		for (state in breakdown) {
+			if (!total[statemap].hasOwnProperty(state))
+				total[statemap][state] = 0;
+
+			sum[statemap] += breakdown[state];
+			total[statemap][state] += breakdown[state];
+		}
+	}
+
+	for (statemap in total) {
+		for (state in total[statemap]) {
+			legend = document.getElementById('statemap-legend-' +
+			    statemap + '-' + state);
+			text = document.createElement('div');
+
+			text.className = 'statemap-timebreaktext';
+			text.appendChild(document.createTextNode(
+			    Math.floor(total[statemap][state]) + ' (' +
+			    Math.floor((total[statemap][state] /
+			    sum[statemap]) * 100) + '%)'));
+			text.addEventListener('click',
+			    click(parseInt(statemap, 10), parseInt(state, 10)));
+
+			legend.appendChild(text);
+			rval.push(text);
+		}
+	}
+
+	return (rval);
+};
+
+var tagboxClear = function (elem)
+{
+	while (elem.childNodes.length > 0)
+		elem.removeChild(elem.childNodes[0]);
+};
+
+var tagboxText = function (elem, t, className, click)
+{
+	var text = document.createElement('div');
+
+	text.className = className;
+	text.appendChild(document.createTextNode(t));
+
+	if (click)
+		text.addEventListener('click', click);
+
+	elem.appendChild(text);
+
+	return (text);
+};
+
+var stateselTagvalSelect = function (evt, tagval)
+{
+	if (g_statesel == undefined)
+		return;
+
+	if (g_tagvalsel && g_tagvalsel.tag == g_tagsel.tag &&
+	    g_tagvalsel.tagval == tagval) {
+		g_tagvalsel = undefined;
+	} else {
+		g_tagvalsel = { tag: g_tagsel.tag, tagval: tagval,
+		    tagdefs: {} };
+	}
+
+	stateselUpdate();
+	statemapsUpdate();
+};
+
+var stateselUpdate = function ()
+{
+	var base, etime, nentities = 0;
+	var state, entity;
+	var bytag = {}, tagval;
+	var header, i, tags;
+	var highlight = 'statemap-tagbox-select-highlighted';
+
+	var elem = document.getElementById('statemap-tagbox-select');
+
+	tagboxClear(elem);
+
+	if (g_statesel == undefined || !g_tagsel)
+		return;
+
+	state = g_statesel.state;
+
+	var sum = function (datum, id, span) {
+		var tid, tag;
+
+		if (!(datum.s instanceof Object)) {
+			if (datum.s != state)
+				return;
+		} else {
+			if (!datum.s[state])
+				return;
+		}
+
+		if (!datum.g)
+			return;
+
+		for (tid in datum.g) {
+			tag = tags[tid];
+
+			if (tag.state != state)
+				continue;
+
+			if (!(tagval = tag[g_tagsel.tag]))
+				continue;
+
+			if (!bytag[tagval])
+				bytag[tagval] = 0;
+
+			bytag[tagval] += span * datum.g[tid];
+		}
+	};
+
+	header = '';
+
+	if (g_statebar) {
+		header = g_statemaps[g_statesel.statemap].entityKind + ' ' +
+		    g_statebar.entity.name + ' ';
+	}
+
+	header += 'by ' + g_tagsel.tag + ' ';
+
+	if (g_timebar) {
+		base = g_timebar.time + globals.begin;
+		etime = 0;
+		header += 'at ' + timeunits(g_timebar.time);
+	} else {
+		base = timeFromMapX(0) + globals.begin;
+		etime = timeFromMapX(g_width) + globals.begin;
+		header += 'over span';
+	}
+
+	header = header.charAt(0).toUpperCase() + header.substr(1) + ':';
+
+	/*
+	 * For each entity in a statemap that shares our legend (or just the
+	 * entity with a statebar, if there is one), we need to determine the
+	 * amount of time in our selected state.
+	 */
+	for (i = 0; i < g_entities.length; i++) {
+		entity = g_entities[i];
+
+		if (g_statebar && g_statebar.entity !== entity)
+			continue;
+
+		if (g_statemaps[entity.statemap].legend !==
+		    g_statemaps[g_statesel.statemap].legend)
+			continue;
+
+		tags = g_statemaps[entity.statemap].tags || [];
+		entityForEachDatum(entity, base, etime, sum);
+		nentities++;
+	}
+
+	var sorted = Object.keys(bytag).sort(function (lhs, rhs) {
+		if (bytag[lhs] < bytag[rhs]) {
+			return (1);
+		} else if (bytag[lhs] > bytag[rhs]) {
+			return (-1);
+		} else {
+			return (0);
+		}
+	});
+
+	var divisor;
+
+	if (etime === 0) {
+		divisor = nentities;
+	} else {
+		divisor = (etime - base) * nentities;
+	}
+
+	var y = g_statesel.y + 27;
+	var bmargin = 60;
+	var ttl = 0;
+	var ellipsis = false;
+
+	tagboxText(elem, header, 'statemap-tagbox-select-header');
+
+	var click = function (tv) {
+		return (function (evt) { stateselTagvalSelect(evt, tv); });
+	};
+
+	for (i = 0; i <= sorted.length; i++) {
+		var t, perc, row;
+
+		if (i < sorted.length) {
+			perc = (bytag[sorted[i]] / divisor) * 100.0;
+			tagval = sorted[i];
+			ttl += perc;
+
+			if (y > globals.totalHeight - bmargin) {
+				if (ellipsis)
+					continue;
+
+				ellipsis = true;
+				tagval = '...';
+			}
+		} else {
+			perc = ttl;
+			tagval = 'total';
+		}
+
+		if (i != sorted.length && ellipsis) {
+			t = '...';
+		} else {
+			t = Math.trunc(perc) + '.' +
+			    (Math.round(perc * 100) % 100) + '%';
+		}
+
+		row = document.createElement('div');
+		row.className = i == sorted.length ?
+		    'statemap-tagbox-select-sum' : 'statemap-tagbox-select-row';
+		tagboxText(row, t, 'statemap-tagbox-select-perc');
+
+		var text = tagboxText(row, tagval, 'statemap-tagbox-select',
+		    click(tagval));
+
+		/*
+		 * If we already have a tag value selection and it matches
+		 * what we're about to display, indicate as much by
+		 * highlighting it.
+		 */
+		if (g_tagvalsel && g_tagvalsel.tag == g_tagsel.tag &&
+		    g_tagvalsel.tagval == tagval)
+			text.classList.add(highlight);
+
+		row.title = t;
+		elem.appendChild(row);
+		y += 15;
+	}
+};
+
+var stateselTagSelect = function (evt, tag)
+{
+	var elem, prefix = 'statemap-tagbox-tag-';
+
+	if (g_tagsel) {
+		elem = document.getElementById(prefix + g_tagsel.tag);
+		elem.classList.remove(prefix + 'highlighted');
+
+		if (g_tagsel.tag == tag) {
+			g_tagsel = undefined;
+			stateselUpdate();
+			return;
+		}
+	}
+
+	elem = document.getElementById(prefix + tag);
+	elem.classList.add(prefix + 'highlighted');
+	g_tagsel = { tag: tag };
+	stateselUpdate();
+};
+
+var stateselClearTagbox = function ()
+{
+	tagboxClear(document.getElementById('statemap-tagbox'));
+	tagboxClear(document.getElementById('statemap-tagbox-select'));
+};
+
+var stateselSelect = function (statemap, state)
+{
+	var legend = document.getElementById('statemap-legend-' +
+	    g_statemaps[statemap].legend + '-' + state);
+	var states = g_statemaps[statemap].states;
+	var alltags = g_statemaps[statemap].tags || [];
+	var tagbox = document.getElementById('statemap-tagbox');
+	var tags = {};
+	var i, t, text;
+
+	var click = function (tag) {
+		return (function (evt) { stateselTagSelect(evt, tag); });
+	};
+
+	legend.classList.add('statemap-legend-highlighted');
+	stateselClearTagbox();
+
+	tagboxText(tagbox, 'tags for ' + states[state].name,
+	    'statemap-tagbox-header');
+
+	/*
+	 * Now add text for each possible tag for this state.
+	 */
+	for (i = 0; i < alltags.length; i++) {
+		if (alltags[i].state !== state)
+			continue;
+
+		for (t in alltags[i]) {
+			if (t == 'state' || t == 'tag')
+				continue;
+
+			tags[t] = true;
+		}
+	}
+
+	tags = Object.keys(tags).sort();
+
+	for (i = 0; i < tags.length; i++) {
+		text = tagboxText(tagbox, tags[i], 'statemap-tagbox-tag',
+		    click(tags[i]));
+		text.id = 'statemap-tagbox-tag-' + tags[i];
+	}
+
+	g_statesel = { statemap: statemap, state: state,
+	    y: globals.tmargin + 30 + (tags.length * 18) };
+};
+
+var stateselClear = function ()
+{
+	var state, legend;
+
+	if (g_statesel == undefined)
+		return (-1);
+
+	state = g_statesel.state;
+	legend = document.getElementById('statemap-legend-' +
+	    g_statemaps[g_statesel.statemap].legend + '-' + state);
+	legend.classList.remove('statemap-legend-highlighted');
+
+	stateselClearTagbox();
+	g_statesel = undefined;
+	g_tagsel = undefined;
+
+	if (g_tagvalsel) {
+		g_tagvalsel = undefined;
+		statemapsUpdate();
+	}
+
+	return (state);
+};
+
+/*
+ * All of the following *click() functions are either added at the time of
+ * statemap generation or by the viewer as it draws the statemap.
+ */
+var legendclick = function (evt, statemap, state)
+{
+	if (globals.notags || stateselClear() == state)
+		return;
+
+	stateselSelect(statemap, state);
+	stateselUpdate();
+};
+
+var eventclick = function (evt, statemap, idx)
+{
+	var tagbox = document.getElementById('statemap-tagbox');
+	var event = g_statemaps[statemap].events[idx];
+	var lines = [], i;
+
+	stateselClear();
+	stateselClearTagbox();
+
+	tagboxText(tagbox, 'event ' + g_statemaps[statemap].eventTypes[event.e],
+	    'statemap-tagbox-header');
+
+	lines.push(g_statemaps[statemap].entityKind + ' ' + event.n);
+	lines.push('at ' + timeunits(event.t));
+
+	if (event.hasOwnProperty('target'))
+		lines.push('target ' + event.target);
+
+	for (i = 0; i < lines.length; i++)
+		tagboxText(tagbox, lines[i], 'statemap-tagbox-tag');
+};
+
+var arrowsclick = function (evt)
+{
+	var i, elem;
+
+	g_arrows = !g_arrows;
+
+	for (i = 0; i < g_statemaps.length; i++) {
+		elem = document.getElementById('statemap-arrows-legend-' + i);
+
+		if (elem) {
+			elem.childNodes[0].textContent =
+			    (g_arrows ? 'hide' : 'show') + ' arrows';
+		}
+	}
+
+	statemapsUpdate();
+};
+
+var mapclick = function (evt, entity, mapX, absY)
+{
+	var s = g_statemaps[entity.statemap];
+	var time = timeFromMapX(mapX);
+	var data = s.data[entity.name] || [];
+	var idx = datumFind(data, time + globals.begin + s.offset);
+
+	if (evt.shiftKey || evt.altKey) {
+		if (!g_timebar)
+			return;
+
+		g_timebar.subbar = { time: time, y: absY };
+		statemapsUpdate();
+		return;
+	}
+
+	/*
+	 * As with the background of the SVG, a click on a strip where it has
+	 * no data is ignored.
+	 */
+	if (idx === -1)
+		return;
+
+	timebarRemove();
+	g_timebar = { time: time };
+	g_timebar.breakdown = timebarSetBreakdown(time);
+
+	g_statebar = { entity: entity, datum: entityDatum(entity, idx) };
+
+	stateselUpdate();
+	statemapsUpdate();
+};
+
+var canvasclick = function (evt)
+{
+	var bounds = g_canvas.getBoundingClientRect();
+	var x = evt.clientX - bounds.left;
+	var y = evt.clientY - bounds.top;
+	var i, hit, entity;
+
+	for (i = g_hits.length - 1; i >= 0; i--) {
+		hit = g_hits[i];
+
+		if (x >= hit.x1 && x <= hit.x2 && y >= hit.y1 && y <= hit.y2) {
+			hit.func(evt);
+			return;
+		}
+	}
+
+	if (x < globals.lmargin || x >= globals.lmargin + g_width)
+		return;
+
+	if ((entity = entityAt(y)) !== undefined)
+		mapclick(evt, entity, x - globals.lmargin, y);
+};
+
+var panBound = function ()
+{
+	var minX = -(g_width * g_zoom - g_width);
+
+	if (g_pan > 0)
+		g_pan = 0;
+
+	if (g_pan < minX)
+		g_pan = minX;
+};
+
+var panclick = function (dx, dy)
+{
+	g_pan += dx;
+	panBound();
+
+	statemapsUpdate();
+	stateselUpdate();
+};
+
+var zoomclick = function (scale)
+{
+	g_zoom *= scale;
+	g_pan *= scale;
+	g_pan += (1 - scale) * g_width / 2;
+
+	/*
+	 * If we have a timebar, we keep it in the middle of the statemap.
+	 */
+	if (g_timebar) {
+		g_pan = -(((g_timebar.time / globals.timeWidth) *
+		    g_width * g_zoom) - (g_width / 2));
+	}
+
+	panBound();
+
+	if (g_zoom < 1) {
+		g_zoom = 1;
+		g_pan = 0;
+	}
+
+	statemapsUpdate();
+	stateselUpdate();
+};
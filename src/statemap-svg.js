@@ -10,6 +10,7 @@
 
 var g_transMatrix = [1, 0, 0, 1, 0, 0];		/* transform of statemap */
 var g_svgDoc;					/* our SVG document */
+var g_svg;					/* our SVG element */
 var g_offset;					/* x offset of statemap */
 var g_timelabel;				/* label for time spanned */
 var g_timebar;					/* timebar, if any */
@@ -19,6 +20,8 @@ var g_width;					/* pixel width of statemap */
 var g_statesel;					/* state selection, if any */
 var g_tagsel;					/* tag selection, if any */
 var g_tagvalsel;				/* tag val selection, if any */
+var g_arrows = true;				/* event arrows are shown */
+var g_tab = 0;					/* selected tab, if tabbed */
 
 var g_statemaps = [];				/* array of statemaps */
 
@@ -105,14 +108,33 @@ var timeSetSpanLabel = function ()
 	g_timelabel.textContent = t;
 };
 
+/*
+ * Return the pixel offset (below the top margin) of the strip at the
+ * specified position in the specified statemap.  If our statemaps are
+ * tabbed, each begins at the top margin; if we have utilization bands,
+ * each statemap's strips are below its band.
+ */
+var stripOffset = function (statemap, position)
+{
+	var band = globals.utilizationHeight;
+
+	if (globals.layout == 'Tabbed')
+		return ((position * globals.stripHeight) + band);
+
+	return ((position * globals.stripHeight) +
+	    (statemap * (globals.smargin + band)) + band);
+};
+
 var initStatemap = function (statemap, elem, position)
 {
-	var i, highlight;
+	var i, j, highlight, events, glyph, matrix;
 	var prefix = globals.entityPrefix + statemap + '-';
 
 	g_statemaps[statemap].elem = elem;
 	g_statemaps[statemap].position = position;
 	g_statemaps[statemap].nentities = 0;
+	g_statemaps[statemap].base = g_statemaps[statemap].data;
+	g_statemaps[statemap].level = -1;
 
 	/*
 	 * Iterate over this statemap's children, looking for entities.
@@ -127,9 +149,20 @@ var initStatemap = function (statemap, elem, position)
 			name: id.substr(prefix.length),
 			element: elem.childNodes[i],
 			position: position++,
-			statemap: statemap
+			statemap: statemap,
+			rects: [],
+			first: 0
 		};
 
+		/*
+		 * Hang on to our rectangles, as they will be replaced should
+		 * we draw a finer level of detail.
+		 */
+		for (j = 0; j < entity.element.childNodes.length; j++) {
+			if (entity.element.childNodes[j].nodeName == 'rect')
+				entity.rects.push(entity.element.childNodes[j]);
+		}
+
 		entity.description =
 		    g_statemaps[statemap].entities[entity.name].description;
 
@@ -156,6 +189,34 @@ var initStatemap = function (statemap, elem, position)
 	    statemap + '-highlight');
 	highlight.classList.add('statemap-highlight');
 
+	/*
+	 * Gather any event glyphs and arrows, noting their untransformed
+	 * positions so they can be moved (but not stretched) as we zoom and
+	 * pan.
+	 */
+	g_statemaps[statemap].glyphs = [];
+
+	for (j = 0; j < 2; j++) {
+		events = g_svgDoc.getElementById('statemap-' + statemap +
+		    (j === 0 ? '-arrows' : '-events'));
+
+		for (i = 0; events && i < events.childNodes.length; i++) {
+			glyph = events.childNodes[i];
+
+			if (glyph.nodeName != 'path' &&
+			    glyph.nodeName != 'line')
+				continue;
+
+			matrix = glyph.transform.baseVal.getItem(0).matrix;
+
+			g_statemaps[statemap].glyphs.push({
+				element: glyph,
+				x: matrix.e,
+				y: matrix.f
+			});
+		}
+	}
+
 	return (position);
 };
 
@@ -164,10 +225,15 @@ var init = function (evt)
 	var i = 0, position = 0, statemap;
 
 	g_svgDoc = evt.target.ownerDocument;
+	g_svg = evt.target;
 	g_entities = [];
 
-	while ((statemap = g_svgDoc.getElementById('statemap-' + i)) != null)
+	while ((statemap = g_svgDoc.getElementById('statemap-' + i)) != null) {
+		if (globals.layout == 'Tabbed')
+			position = 0;
+
 		position = initStatemap(i++, statemap, position);
+	}
 
 	g_height = globals.pixelHeight;
 	g_width = globals.pixelWidth;
@@ -394,14 +460,13 @@ var statebarCreateBar = function (statebar, x1, y1, x2, y2)
 
 var statebarCreate = function (elem, idx)
 {
-	var parent = g_statemaps[0].elem.parentNode.parentNode;
+	var parent = g_svg;
 	var statebar = { parent: parent, hidden: false };
 	var entity = g_entities[elem.parentNode.id];
 	var statemap = g_statemaps[entity.statemap];
 	var states = statemap.states;
 	var datum = entityDatum(entity, idx);
-	var pos = (entity.position * globals.stripHeight) +
-	    (entity.statemap * globals.smargin);
+	var pos = stripOffset(entity.statemap, entity.position);
 	var x = globals.lmargin - 2;
 	var y = globals.tmargin + pos;
 	var elbow = { x: 8, y: 10 };
@@ -490,14 +555,12 @@ var statebarCreate = function (elem, idx)
 	 * If we have more than one statemap, we want to add a bar to the right
 	 * side to indicate which statemap this is.
 	 */
-	var pos = (statemap.position * globals.stripHeight) +
-	    (entity.statemap * globals.smargin);
+	var pos = stripOffset(entity.statemap, statemap.position);
 
 	x = globals.lmargin + g_width + 2;
 	y = globals.tmargin + pos;
 
-	var pos = (statemap.position * globals.stripHeight) +
-	    (entity.statemap * globals.smargin);
+	var pos = stripOffset(entity.statemap, statemap.position);
 
 	var height = statemap.nentities * globals.stripHeight;
 
@@ -809,7 +872,7 @@ var timebarSetBreakdown = function (time)
 
 var timebarCreate = function (mapX)
 {
-	var parent = g_statemaps[0].elem.parentNode.parentNode;
+	var parent = g_svg;
 	var bar, text;
 	var timebar = { parent: parent, hidden: false };
 
@@ -888,45 +951,16 @@ var timebarRemoveSubbar = function (timebar)
 	timebar.subbar = undefined;
 }
 
-var stateselTagvalSelect = function (evt, tagval)
+var stateselTagvalHighlight = function ()
 {
 	var tagdefs = {};
-	var i, entity;
-	var state, tags;
+	var i, id;
+	var state, tags, tagval;
 	var child;
-	var highlight = 'statemap-tagbox-select-highlighted';
-
-	if (g_statesel == undefined)
-		return;
 
 	state = g_statesel.state;
 	tags = g_statemaps[g_statesel.statemap].tags;
-
-	if (g_tagvalsel && g_tagvalsel.selected) {
-		for (i = 0; i < g_tagvalsel.selected.length; i++) {
-			child = g_tagvalsel.selected[i];
-			child.removeAttribute('fill-opacity');
-		}
-
-		if (g_tagvalsel.element)
-			g_tagvalsel.element.classList.remove(highlight);
-
-		/*
-		 * If our selection matches the selection that we have already
-		 * made, then we are unselecting this tag value; we need only
-		 * return.
-		 */
-		if (g_tagvalsel.tag == g_tagsel.tag &&
-		    g_tagvalsel.tagval == tagval) {
-			g_tagvalsel = undefined;
-			return;
-		}
-	}
-
-	g_tagvalsel = { selected: [], tag: g_tagsel.tag, tagval: tagval };
-
-	evt.target.classList.add(highlight);
-	g_tagvalsel.element = evt.target;
+	tagval = g_tagvalsel.tagval;
 
 	/*
 	 * Iterate over all of our tag definitions, looking for a match where
@@ -937,20 +971,22 @@ var stateselTagvalSelect = function (evt, tagval)
 		if (tags[i].state != state)
 			continue;
 
-		if (tags[i][g_tagsel.tag] != tagval)
+		if (tags[i][g_tagvalsel.tag] != tagval)
 			continue;
 
 		tagdefs[i] = true;
 	}
 
 	/*
-	 * Now for each entity, we will plow through every rectangle.
+	 * Now for each entity, we will plow through every rectangle.  If
+	 * the entity is being drawn at a finer level of detail, its first
+	 * rectangle need not correspond to its first datum.
 	 */
 	for (id in g_entities) {
 		var entity = g_entities[id];
 		var elem = entity.element;
 		var data = g_statemaps[entity.statemap].data[entity.name];
-		var j = 0;
+		var j = entity.first;
 
 		for (i = 0; i < elem.childNodes.length; i++) {
 			child = elem.childNodes[i];
@@ -993,6 +1029,44 @@ var stateselTagvalSelect = function (evt, tagval)
 	}
 };
 
+var stateselTagvalSelect = function (evt, tagval)
+{
+	var i;
+	var child;
+	var highlight = 'statemap-tagbox-select-highlighted';
+
+	if (g_statesel == undefined)
+		return;
+
+	if (g_tagvalsel && g_tagvalsel.selected) {
+		for (i = 0; i < g_tagvalsel.selected.length; i++) {
+			child = g_tagvalsel.selected[i];
+			child.removeAttribute('fill-opacity');
+		}
+
+		if (g_tagvalsel.element)
+			g_tagvalsel.element.classList.remove(highlight);
+
+		/*
+		 * If our selection matches the selection that we have already
+		 * made, then we are unselecting this tag value; we need only
+		 * return.
+		 */
+		if (g_tagvalsel.tag == g_tagsel.tag &&
+		    g_tagvalsel.tagval == tagval) {
+			g_tagvalsel = undefined;
+			return;
+		}
+	}
+
+	g_tagvalsel = { selected: [], tag: g_tagsel.tag, tagval: tagval };
+
+	evt.target.classList.add(highlight);
+	g_tagvalsel.element = evt.target;
+
+	stateselTagvalHighlight();
+};
+
 var stateselUpdate = function ()
 {
 	var base, etime, nentities = 0;
@@ -1243,6 +1317,101 @@ var stateselClearTagbox = function ()
 		elem.removeChild(elem.childNodes[0]);
 };
 
+var tagboxHeader = function (tagbox, t, x, y, x2)
+{
+	var text = g_svgDoc.createElementNS(tagbox.namespaceURI, 'text');
+	text.classList.add('statemap-tagbox-header');
+	text.classList.add('sansserif');
+
+	text.appendChild(g_svgDoc.createTextNode(t));
+	text.setAttributeNS(null, 'x', x);
+	text.setAttributeNS(null, 'y', y);
+	tagbox.appendChild(text);
+	y += 10;
+
+	var line = g_svgDoc.createElementNS(tagbox.namespaceURI, 'line');
+	line.classList.add('statemap-tagbox-header-line');
+	line.x1.baseVal.value = x - 2;
+	line.y1.baseVal.value = y;
+	line.x2.baseVal.value = x2;
+	line.y2.baseVal.value = y;
+	tagbox.appendChild(line);
+
+	return (y + 20);
+};
+
+/*
+ * If we have recorded the durations of visits to the specified state, draw
+ * their distribution in the tagbox as a histogram with a bar for each power
+ * of two, followed by a summary of them.  Returns the new Y offset.
+ */
+var tagboxDurations = function (tagbox, statemap, state, x, y, x2, color)
+{
+	var durations = g_statemaps[statemap].durations;
+	var height = 30, max = 0, first = 0;
+	var dist, octaves, width, i, h;
+	var rect, title, text;
+
+	if (!durations || !durations[state] || durations[state].count === 0)
+		return (y);
+
+	dist = durations[state];
+	octaves = dist.octaves;
+
+	for (i = 0; i < octaves.length; i++)
+		max = Math.max(max, octaves[i]);
+
+	while (octaves[first] === 0)
+		first++;
+
+	width = (x2 - x) / (octaves.length - first);
+	y -= 10;
+
+	for (i = first; i < octaves.length; i++) {
+		if (octaves[i] === 0)
+			continue;
+
+		h = Math.max((octaves[i] / max) * height, 1);
+
+		rect = g_svgDoc.createElementNS(tagbox.namespaceURI, 'rect');
+		rect.classList.add('statemap-tagbox-durations');
+		rect.setAttributeNS(null, 'x', x + ((i - first) * width));
+		rect.setAttributeNS(null, 'y', y + height - h);
+		rect.setAttributeNS(null, 'width', Math.max(width - 1, 1));
+		rect.setAttributeNS(null, 'height', h);
+		rect.setAttributeNS(null, 'style', 'fill:' + color);
+
+		title = g_svgDoc.createElementNS(tagbox.namespaceURI, 'title');
+		title.appendChild(g_svgDoc.createTextNode(octaves[i] +
+		    ' visits of ' + timeunits(Math.pow(2, i)) + ' to ' +
+		    timeunits(Math.pow(2, i + 1))));
+		rect.appendChild(title);
+
+		tagbox.appendChild(rect);
+	}
+
+	y += height + 22;
+
+	var summary = [
+	    dist.count + ' visits, mean ' + timeunits(Math.round(dist.mean)),
+	    'p50 ' + timeunits(dist.p50) + ', p90 ' + timeunits(dist.p90),
+	    'p99 ' + timeunits(dist.p99) + ', max ' + timeunits(dist.max)
+	];
+
+	for (i = 0; i < summary.length; i++) {
+		text = g_svgDoc.createElementNS(tagbox.namespaceURI, 'text');
+		text.classList.add('statemap-tagbox-select');
+		text.classList.add('sansserif');
+		text.appendChild(g_svgDoc.createTextNode(summary[i]));
+		text.setAttributeNS(null, 'x', x);
+		text.setAttributeNS(null, 'y', y);
+		tagbox.appendChild(text);
+		y += 12;
+	}
+
+	return (y + 16);
+};
+
 var stateselSelect = function (statemap, state)
 {
 	var legend = g_svgDoc.getElementById('statemap-legend-' +
@@ -1263,25 +1432,11 @@ var stateselSelect = function (statemap, state)
 	var x = offset + lmargin;
 	var y = globals.tmargin;
 	var x2 = x + (globals.tagWidth - lmargin);
+	var text;
 
-	var text = g_svgDoc.createElementNS(tagbox.namespaceURI, 'text');
-	text.classList.add('statemap-tagbox-header');
-	text.classList.add('sansserif');
-
-	text.appendChild(g_svgDoc.createTextNode(t));
-	text.setAttributeNS(null, 'x', x);
-	text.setAttributeNS(null, 'y', y);
-	tagbox.appendChild(text);
-	y += 10;
-
-	var line = g_svgDoc.createElementNS(tagbox.namespaceURI, 'line');
-	line.classList.add('statemap-tagbox-header-line');
-	line.x1.baseVal.value = x - 2;
-	line.y1.baseVal.value = y;
-	line.x2.baseVal.value = x2;
-	line.y2.baseVal.value = y;
-	tagbox.appendChild(line);
-	y += 20;
+	y = tagboxHeader(tagbox, t, x, y, x2);
+	y = tagboxDurations(tagbox, statemap, state, x, y, x2,
+	    legend.style.fill);
 
 	/*
 	 * Now add text for each possible tag for this state.
@@ -1343,14 +1498,203 @@ var stateselClear = function ()
 	return (state);
 };
 
-var statemapsUpdate = function ()
+var eventsUpdate = function (statemap)
+{
+	var i, glyph;
+	var glyphs = g_statemaps[statemap].glyphs;
+
+	for (i = 0; i < glyphs.length; i++) {
+		glyph = glyphs[i];
+
+		glyph.element.setAttributeNS(null, 'transform', 'translate(' +
+		    (glyph.x * g_transMatrix[0] + g_transMatrix[4]) + ',' +
+		    (glyph.y + g_transMatrix[5]) + ')');
+	}
+};
+
+/*
+ * If a statemap has been rendered with finer levels of detail, we pick the
+ * level to draw based on our zoom:  the coarsest level with buckets that
+ * are no wider than two pixels.  (A level of -1 denotes the statemap's own
+ * rectangles, which are nominally a pixel wide when we aren't zoomed.)
+ */
+var levelSelect = function (statemap)
+{
+	var levels = g_statemaps[statemap].levels;
+	var pixel = globals.timeWidth / (g_width * g_transMatrix[0]);
+	var i;
+
+	if (!levels || levels.length === 0 ||
+	    globals.timeWidth / g_width <= 2 * pixel)
+		return (-1);
+
+	for (i = 0; i < levels.length - 1; i++) {
+		if (levels[i].resolution <= 2 * pixel)
+			break;
+	}
+
+	return (i);
+};
+
+var entityClear = function (entity)
 {
+	var elem = entity.element;
 	var i;
+
+	for (i = elem.childNodes.length - 1; i >= 0; i--) {
+		if (elem.childNodes[i].nodeName == 'rect')
+			elem.removeChild(elem.childNodes[i]);
+	}
+};
+
+/*
+ * Draw the specified entity's data from a finer level of detail, but only
+ * those rectangles that intersect the specified time range.
+ */
+var entityRender = function (entity, data, time, etime)
+{
+	var elem = entity.element;
+	var statemap = g_statemaps[entity.statemap];
+	var base = globals.begin + statemap.offset;
+	var scale = globals.pixelWidth / globals.timeWidth;
+	var floor = 0, ceil = data.length;
+	var i, y, rect, datum;
+
+	if (entity.rects.length === 0)
+		return;
+
+	y = entity.rects[0].getAttributeNS(null, 'y');
+	entityClear(entity);
+
+	/*
+	 * Binary search our data to find the datum that contains our start.
+	 */
+	entity.first = 0;
+
+	while (floor < ceil) {
+		i = floor + Math.floor((ceil - floor) / 2);
+
+		if (data[i].t <= time) {
+			entity.first = i;
+			floor = i + 1;
+		} else {
+			ceil = i;
+		}
+	}
+
+	for (i = entity.first; i < data.length && data[i].t < etime; i++) {
+		datum = data[i];
+
+		/*
+		 * As with the rectangles in the SVG, we add a fuzz factor to
+		 * our width to keep the background from showing through.
+		 */
+		rect = g_svgDoc.createElementNS(elem.namespaceURI, 'rect');
+		rect.setAttributeNS(null, 'x', (datum.t - base) * scale);
+		rect.setAttributeNS(null, 'y', y);
+		rect.setAttributeNS(null, 'width', (datum.d * scale) + 0.4);
+		rect.setAttributeNS(null, 'height', globals.stripHeight);
+		rect.setAttributeNS(null, 'onclick',
+		    'mapclick(evt, ' + i + ')');
+		rect.setAttributeNS(null, 'style', 'fill:' + datum.c);
+		elem.appendChild(rect);
+	}
+};
+
+var entityRestore = function (entity)
+{
+	var i;
+
+	entityClear(entity);
+
+	for (i = 0; i < entity.rects.length; i++)
+		entity.element.appendChild(entity.rects[i]);
+
+	entity.first = 0;
+};
+
+/*
+ * Draw the appropriate level of detail for the specified statemap.  When
+ * drawing a finer level, we draw only the visible slice of it (along with
+ * a view's worth of time on either side of it, to allow for some panning
+ * without redrawing).  Returns true if the statemap was redrawn.
+ */
+var levelsUpdate = function (statemap)
+{
+	var s = g_statemaps[statemap];
+	var level = levelSelect(statemap);
+	var span = globals.timeWidth / g_transMatrix[0];
+	var time = timeFromMapX(0) + globals.begin + s.offset;
+	var etime = time + span;
+	var id, entity, salient;
+
+	if (level === -1) {
+		if (s.level === -1)
+			return (false);
+
+		s.data = s.base;
+	} else {
+		if (s.level === level && time >= s.time && etime <= s.etime)
+			return (false);
+
+		s.data = s.levels[level].data;
+		s.time = time - span;
+		s.etime = etime + span;
+	}
+
+	for (id in g_entities) {
+		entity = g_entities[id];
+
+		if (entity.statemap != statemap)
+			continue;
+
+		if (level === -1) {
+			entityRestore(entity);
+		} else {
+			entityRender(entity, s.data[entity.name] || [],
+			    s.time, s.etime);
+		}
+	}
+
+	/*
+	 * Our markers of salient states apply only to our own rectangles.
+	 */
+	salient = g_svgDoc.getElementById('statemap-' + statemap + '-salient');
+
+	if (salient) {
+		salient.setAttributeNS(null, 'visibility',
+		    level === -1 ? 'visible' : 'hidden');
+	}
+
+	s.level = level;
+
+	return (true);
+};
+
+var statemapsUpdate = function ()
+{
+	var i, redrawn = false;
 	var newMatrix = 'matrix(' +  g_transMatrix.join(' ') + ')';
 
 	for (i = 0; i < g_statemaps.length; i++) {
+		if (levelsUpdate(i))
+			redrawn = true;
+
 		g_statemaps[i].elem.setAttributeNS(null,
 		    'transform', newMatrix);
+		eventsUpdate(i);
+	}
+
+	/*
+	 * If we redrew any statemap and have selected a tag value, we need
+	 * to highlight the rectangles that correspond to it anew.
+	 */
+	if (redrawn && g_tagvalsel && g_statesel) {
+		for (i = 0; i < g_tagvalsel.selected.length; i++)
+			g_tagvalsel.selected[i].removeAttribute('fill-opacity');
+
+		g_tagvalsel.selected = [];
+		stateselTagvalHighlight();
 	}
 };
 
@@ -1367,6 +1711,109 @@ var legendclick = function (evt, statemap, state)
 	stateselUpdate();
 };
 
+var utilizationclick = function (evt, statemap, state)
+{
+	legendclick(evt, g_statemaps[statemap].legend, state);
+};
+
+var eventclick = function (evt, statemap, idx)
+{
+	var tagbox = g_svgDoc.getElementById('statemap-tagbox');
+	var event = g_statemaps[statemap].events[idx];
+	var lmargin = 20;
+	var x = globals.lmargin + globals.pixelWidth + lmargin;
+	var y = globals.tmargin;
+	var x2 = x + (globals.tagWidth - lmargin);
+	var lines = [], text, i;
+
+	if (!tagbox)
+		return;
+
+	stateselClear();
+	stateselClearTagbox();
+
+	y = tagboxHeader(tagbox, 'event ' +
+	    g_statemaps[statemap].eventTypes[event.e], x, y, x2);
+
+	lines.push(g_statemaps[statemap].entityKind + ' ' + event.n);
+	lines.push('at ' + timeunits(event.t));
+
+	if (event.hasOwnProperty('target'))
+		lines.push('target ' + event.target);
+
+	for (i = 0; i < lines.length; i++) {
+		text = g_svgDoc.createElementNS(tagbox.namespaceURI, 'text');
+		text.classList.add('statemap-tagbox-tag');
+		text.classList.add('sansserif');
+		text.appendChild(g_svgDoc.createTextNode(lines[i]));
+		text.setAttributeNS(null, 'x', x);
+		text.setAttributeNS(null, 'y', y);
+		tagbox.appendChild(text);
+		y += 18;
+	}
+};
+
+var arrowsclick = function (evt)
+{
+	var i, elem;
+
+	g_arrows = !g_arrows;
+
+	for (i = 0; i < g_statemaps.length; i++) {
+		elem = g_svgDoc.getElementById('statemap-' + i + '-arrows');
+
+		if (elem) {
+			elem.setAttributeNS(null, 'visibility',
+			    g_arrows ? 'visible' : 'hidden');
+		}
+
+		elem = g_svgDoc.getElementById('statemap-arrows-legend-' + i);
+
+		if (elem) {
+			elem.childNodes[0].textContent =
+			    (g_arrows ? 'hide' : 'show') + ' arrows';
+		}
+	}
+};
+
+var tabclick = function (evt, tab)
+{
+	var i, display, elem;
+
+	if (tab == g_tab)
+		return;
+
+	/*
+	 * Our tag box and any statebar pertain to the statemap in the tab
+	 * that we are leaving, so clear them.  (Our timebar, like our zoom
+	 * and pan, is shared by all tabs.)
+	 */
+	stateselClear();
+	stateselClearTagbox();
+	statebarRemove(g_statebar);
+	g_statebar = undefined;
+
+	for (i = 0; i < g_statemaps.length; i++) {
+		display = (i == tab ? 'inline' : 'none');
+
+		elem = g_svgDoc.getElementById('statemap-tab-' + i);
+		elem.setAttributeNS(null, 'display', display);
+
+		elem = g_svgDoc.getElementById('statemap-tab-' + i + '-legend');
+		elem.setAttributeNS(null, 'display', display);
+
+		elem = g_svgDoc.getElementById('statemap-tab-' + i + '-button');
+
+		if (i == tab) {
+			elem.classList.add('statemap-tab-selected');
+		} else {
+			elem.classList.remove('statemap-tab-selected');
+		}
+	}
+
+	g_tab = tab;
+};
+
 var mapclick = function (evt, idx)
 {
 	var x = evt.clientX - g_offset;
@@ -8,6 +8,31 @@ extern crate serde_json;
 extern crate natord;
 extern crate palette;
 extern crate rand;
+extern crate flate2;
+extern crate zstd;
+extern crate xz2;
+extern crate png;
+
+mod raster;
+mod html;
+mod json;
+mod stats;
+mod durations;
+mod transitions;
+mod diff;
+mod utilization;
+mod sched;
+mod chrome;
+
+pub use self::raster::{StatemapPNG, StatemapPNGConfig};
+pub use self::html::StatemapHTML;
+pub use self::json::StatemapJSON;
+pub use self::stats::{StatemapStats, StatemapStatsFormat};
+pub use self::durations::{StatemapDistribution, StatemapDurations};
+pub use self::transitions::{StatemapTransitions, StatemapTransitionsFormat};
+pub use self::diff::{StatemapDiff, StatemapDiffFormat, StatemapDelta};
+pub use self::utilization::StatemapUtilization;
+pub use self::sched::StatemapSchedEntity;
 
 /*
  * The StatemapInput* types denote the structure of the concatenated JSON
@@ -18,6 +43,7 @@ extern crate rand;
 struct StatemapInputState {
     color: Option<String>,                  // color for state, if any
     value: usize,                           // value for state
+    salient: Option<bool>,                  // state is salient, if set
 }
 
 #[derive(Deserialize, Debug)]
@@ -45,13 +71,15 @@ struct StatemapInputMetadata {
     title: String,
     host: Option<String>,
     entityKind: Option<String>,
+    clockOffset: Option<i64>,
     states: HashMap<String, StatemapInputState>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 struct StatemapInputEvent {
-    time: String,                           // time of this datum
+    #[serde(deserialize_with = "datum_time_from_string")]
+    time: u64,                              // time of this datum
     entity: String,                         // name of entity
     event: String,                          // type of event
     target: Option<String>,                 // target for event, if any
@@ -63,6 +91,18 @@ struct StatemapInputTag {
     tag: String,                            // tag itself
 }
 
+/**
+ * The manner in which rectangles are coalesced.  By default, the rectangles
+ * of least weight are coalesced into their neighbors until the number of
+ * rectangles is below a specified maximum; alternatively, time can be
+ * divided into fixed buckets, with each bucket becoming a rectangle.
+ */
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum StatemapCoalesce {
+    Weight,                                 // coalesce by rectangle weight
+    Buckets,                                // coalesce into time buckets
+}
+
 #[derive(Copy,Clone,Debug)]
 pub struct Config {
     pub maxrect: u64,                       // maximum number of rectangles
@@ -70,6 +110,26 @@ pub struct Config {
     pub begin: i64,                         // absolute/relative time to begin
     pub end: i64,                           // absolute/relative time to end
     pub notags: bool,                       // do not include tags
+    pub coalesce: StatemapCoalesce,         // manner of coalescing
+    pub resolution: u64,                    // bucket width, if any
+    pub nbuckets: u64,                      // target number of buckets
+    pub levels: u32,                        // finer levels of detail
+    pub durations: Option<StatemapDurations>, // record visit durations
+    pub offset: i64,                        // clock offset, in nanoseconds
+    pub utilization: bool,                  // record state occupancy
+    pub sched: StatemapSchedEntity,         // entities of scheduler traces
+    pub maxstates: u32,                     // maximum states of span names
+}
+
+/**
+ * The manner in which multiple statemaps are laid out in an SVG:  either
+ * stacked one above another or each in its own tab, with only one visible
+ * at a time.
+ */
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
+pub enum StatemapLayout {
+    Stacked,                                // statemaps stacked vertically
+    Tabbed,                                 // each statemap in its own tab
 }
 
 /*
@@ -85,6 +145,7 @@ pub struct StatemapSVGConfig {
     pub background: String,
     pub sortby: Option<String>,
     pub stacksortby: Option<String>,
+    pub layout: StatemapLayout,
 }
 
 #[derive(Copy,Clone,Debug)]
@@ -92,12 +153,18 @@ struct StatemapColor {
     color: Color,                           // underlying color
 }
 
-#[derive(Debug)]
-struct StatemapRect {
+/**
+ * A rectangle in a statemap, denoting the time that an entity spent in each
+ * state (and with each tag) over a span of time.  Rectangles that have been
+ * coalesced will have time in more than one state.
+ */
+#[derive(Debug,Clone)]
+pub struct StatemapRect {
     start: u64,                             // nanosecond offset
     duration: u64,                          // nanosecond duration
     weight: u64,                            // my weight + neighbors
     states: Vec<u64>,                       // time spent in each state
+    salient: u64,                           // time spent in salient states
     prev: Option<u64>,                      // previous rectangle
     next: Option<u64>,                      // next rectangle
     tags: Option<HashMap<usize, u64>>,      // tags, if any
@@ -110,23 +177,58 @@ struct StatemapRectWeight {
     entity: usize,                          // entity for this rect
 }
 
+/**
+ * A state, as defined in the metadata of a statemap.
+ */
 #[derive(Default,Clone,PartialEq,Eq,Debug,Serialize)]
-struct StatemapState {
+pub struct StatemapState {
     name: String,                           // name of this state
     value: usize,                           // value for this state
     color: Option<String>,                  // color of this state, if any
+    salient: bool,                          // state should not be lost
+}
+
+/**
+ * The transitions of an entity (or of all entities) from one state to
+ * another, along with the total time spent in the former state before
+ * transitioning to the latter.
+ */
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct StatemapTransition {
+    from: usize,                            // state transitioned from
+    to: usize,                              // state transitioned to
+    count: u64,                             // number of transitions
+    dwell: u64,                             // total time in from state
+}
+
+#[derive(Debug)]
+struct StatemapEvent {
+    time: u64,                              // time of event
+    event: usize,                           // identifier of event type
+    target: Option<String>,                 // target entity, if any
 }
 
+/**
+ * An entity in a statemap, consisting of its (coalesced) rectangles.
+ */
 #[derive(Debug)]
-struct StatemapEntity {
+pub struct StatemapEntity {
     name: String,                           // name of this entity
     id: usize,                              // identifier
     description: Option<String>,            // description, if any
     last: Option<u64>,                      // last start time
     start: Option<u64>,                     // current start time
     state: Option<u32>,                     // current state
+    salient: bool,                          // current state is salient
     tag: Option<usize>,                     // current tag, if any
     rects: HashMap<u64, RefCell<StatemapRect>>, // rectangles for this entity
+    buckets: Vec<Vec<Option<StatemapRect>>>, // buckets, by level
+    levels: Vec<Vec<StatemapRect>>,         // finer levels of detail
+    events: Vec<StatemapEvent>,             // events for this entity
+    totals: Vec<u64>,                       // exact time in each state
+    visit: Option<u64>,                     // start of current visit
+    durations: Vec<StatemapDistribution>,   // visit durations, if any
+    transitions: HashMap<(u32, u32), (u64, u64)>, // count and dwell
 }
 
 #[derive(Debug)]
@@ -140,6 +242,10 @@ pub struct Statemap {
     byid: Vec<String>,                      // entities by ID
     byweight: BTreeSet<StatemapRectWeight>, // rectangles by weight
     tags: HashMap<(u32, String), (Value, usize)>, // tags, if any
+    events: HashMap<String, usize>,         // event types, if any
+    resolutions: Vec<u64>,                  // bucket width, by level
+    utilization: Option<StatemapUtilization>, // state occupancy, if any
+    salient: Vec<String>,                   // states designated salient
     begin: u64,                             // begin time, as ns since epoch
     end: u64,                               // end time, as ns since epoch
     last: u64,                              // last time seen
@@ -159,6 +265,7 @@ struct StatemapSVGGlobals<'a> {
     pixelHeight: u32,
     pixelWidth: u32,
     totalHeight: u32,
+    utilizationHeight: u32,
     timeWidth: u64,
     lmargin: u32,
     tmargin: u32,
@@ -177,11 +284,28 @@ struct StatemapSVGLocals<'a> {
     title: String,
 }
 
+/*
+ * The state of rendering a single statemap, shared by each of its entities.
+ */
+struct StatemapSVGRender<'a> {
+    id: usize,                              // index of statemap
+    begin: i64,                             // begin time of statemap
+    config: &'a StatemapSVGConfig,          // configuration of rendering
+    globals: &'a StatemapSVGGlobals<'a>,    // globals of rendering
+    locals: &'a StatemapSVGLocals<'a>,      // locals of statemap
+    colors: &'a Vec<StatemapColor>,         // colors of states
+}
+
 pub struct StatemapSVG<'a> {
     config: &'a StatemapSVGConfig,
 }
 
 use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::BufReader;
+use std::io::BufRead;
+use std::io::Write;
 use std::str;
 use std::error::Error;
 use std::fmt;
@@ -191,11 +315,56 @@ use std::collections::BTreeSet;
 use std::str::FromStr;
 use std::cell::RefCell;
 use std::cmp;
-use std::path::Path;
+use std::mem;
 
 use self::memmap::MmapOptions;
+use self::flate2::read::MultiGzDecoder;
+use self::xz2::read::XzDecoder;
 use self::palette::{Srgb, Color, Mix};
 use self::serde_json::Value;
+use self::chrome::CHROME_KEYS;
+use self::sched::sched_detect;
+
+/*
+ * Event glyphs are drawn atop states of arbitrary color; rather than pick
+ * random colors for them, we cycle through a palette of dark colors that
+ * should remain visible.
+ */
+const EVENT_COLORS: &[&str] = &[
+    "#000000", "#d62728", "#1f77b4", "#9467bd",
+    "#8c564b", "#e377c2", "#17becf", "#bcbd22",
+];
+
+fn event_color(event: usize) -> &'static str {
+    EVENT_COLORS[event % EVENT_COLORS.len()]
+}
+
+/*
+ * Time spent in a salient state counts this many times over when weighing
+ * rectangles for coalescing, making salient rectangles much less likely to
+ * be subsumed -- and making rectangles adjacent to salient rectangles more
+ * likely to be subsumed by a neighbor that isn't salient.
+ */
+const SALIENT_WEIGHT: u64 = 1000;
+
+/*
+ * Each finer level of detail has this many times the number of buckets of
+ * the level before it.
+ */
+const LEVEL_FACTOR: u64 = 4;
+
+/*
+ * The largest number of buckets that a specified resolution may require of
+ * the coarsest level of detail.
+ */
+const MAX_BUCKETS: u64 = 1_000_000;
+
+/*
+ * The height of the band showing the occupancy of each state above each
+ * statemap (if we are recording it), including its margin.
+ */
+const UTILIZATION_HEIGHT: u32 = 44;
+const UTILIZATION_MARGIN: u32 = 4;
 
 impl Default for Config {
     fn default() -> Config {
@@ -205,6 +374,15 @@ impl Default for Config {
             end: 0,
             notags: false,
             abstime: false,
+            coalesce: StatemapCoalesce::Weight,
+            resolution: 0,
+            nbuckets: 862,
+            levels: 0,
+            durations: None,
+            offset: 0,
+            utilization: false,
+            sched: StatemapSchedEntity::CPU,
+            maxstates: 32,
         }
     }
 }
@@ -219,6 +397,7 @@ impl Default for StatemapSVGConfig {
             background: "#f0f0f0".to_string(),
             sortby: None,
             stacksortby: None,
+            layout: StatemapLayout::Stacked,
         }
     }
 }
@@ -280,14 +459,18 @@ impl FromStr for StatemapColor {
 
 impl fmt::Display for StatemapColor {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let rgb = Srgb::from_linear(self.color.into()).into_components();
-
-        write!(f, "rgb({}, {}, {})", (rgb.0 * 256.0) as u8,
-            (rgb.1 * 256.0) as u8, (rgb.2 * 256.0) as u8)
+        let rgb = self.rgb();
+        write!(f, "rgb({}, {}, {})", rgb.0, rgb.1, rgb.2)
     }
 }
 
 impl StatemapColor {
+    fn rgb(&self) -> (u8, u8, u8) {
+        let rgb = Srgb::from_linear(self.color.into()).into_components();
+
+        ((rgb.0 * 256.0) as u8, (rgb.1 * 256.0) as u8, (rgb.2 * 256.0) as u8)
+    }
+
     fn random() -> Self {
         let rgb = Srgb::new(rand::random::<u8>(), rand::random::<u8>(),
             rand::random::<u8>());
@@ -320,11 +503,47 @@ impl StatemapColor {
 }
 
 impl StatemapRect {
+    /**
+     * The start of this rectangle, as a nanosecond offset.
+     */
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /**
+     * The duration of this rectangle, in nanoseconds.
+     */
+    pub fn duration(&self) -> u64 {
+        self.duration
+    }
+
+    /**
+     * The time spent in each state, indexed by state value.
+     */
+    pub fn states(&self) -> &[u64] {
+        &self.states
+    }
+
+    /**
+     * The time spent with each tag, as tuples of tag identifier (see
+     * `Statemap::tags`) and duration, sorted by tag identifier.
+     */
+    pub fn tags(&self) -> Vec<(usize, u64)> {
+        let mut tags: Vec<(usize, u64)> = match self.tags {
+            Some(ref tags) => tags.iter().map(|(&id, &d)| (id, d)).collect(),
+            None => vec![]
+        };
+
+        tags.sort_unstable();
+        tags
+    }
+
     fn new(start: u64, duration: u64, state: u32, nstates: u32) -> Self {
         let mut r = StatemapRect {
             start: start,
             duration: duration,
             states: vec![0; nstates as usize],
+            salient: 0,
             prev: None,
             next: None,
             weight: duration,
@@ -334,6 +553,78 @@ impl StatemapRect {
         r.states[state as usize] = duration;
         r
     }
+
+    /*
+     * The mass of a rectangle is its contribution to its own weight and
+     * to that of its neighbors:  its duration, with time spent in salient
+     * states weighted more heavily.
+     */
+    fn mass(&self) -> u64 {
+        (self.duration - self.salient) + (self.salient * SALIENT_WEIGHT)
+    }
+
+    /*
+     * Fold the time (and tags) of another rectangle into this one.
+     */
+    fn absorb(&mut self, other: &mut StatemapRect) {
+        self.duration += other.duration;
+        self.salient += other.salient;
+
+        for i in 0..other.states.len() {
+            self.states[i] += other.states[i];
+        }
+
+        if other.tags.is_some() && self.tags.is_none() {
+            self.tags = Some(HashMap::new());
+        }
+
+        match self.tags {
+            Some(ref mut stags) => {
+                match other.tags {
+                    Some(ref mut otags) => { subsume_tags(stags, otags); },
+                    None => {}
+                }
+            },
+            None => {}
+        }
+    }
+
+    /*
+     * If this rectangle consists entirely of time in one state -- and is
+     * either entirely untagged or entirely with a single tag -- return the
+     * state and tag.
+     */
+    fn uniform(&self) -> Option<(usize, Option<usize>)> {
+        let mut state = None;
+
+        for i in 0..self.states.len() {
+            if self.states[i] != 0 {
+                if state.is_some() {
+                    return None;
+                }
+
+                state = Some(i);
+            }
+        }
+
+        let tag = match self.tags {
+            None => None,
+            Some(ref tags) => {
+                if tags.len() != 1 {
+                    return None;
+                }
+
+                match tags.iter().next() {
+                    Some((&id, &duration)) if duration == self.duration => {
+                        Some(id)
+                    }
+                    _ => return None
+                }
+            }
+        };
+
+        state.map(|state| (state, tag))
+    }
 }
 
 fn subsume_tags(stags: &mut HashMap<usize, u64>,
@@ -349,1315 +640,1564 @@ fn subsume_tags(stags: &mut HashMap<usize, u64>,
     }
 }
 
-impl StatemapEntity {
-    fn new(name: &str, id: usize) -> Self {
-        StatemapEntity {
-            name: name.to_string(),
-            start: None,
-            description: None,
-            last: None,
-            state: None,
-            tag: None,
-            rects: HashMap::new(),
-            id: id,
+/*
+ * Fold each pair of adjacent buckets into one, as when the width of buckets
+ * is doubled, merging the second of each pair into the first.
+ */
+fn fold_buckets<T, F>(buckets: &mut Vec<T>, mut merge: F)
+    where F: FnMut(&mut T, T)
+{
+    let old = mem::take(buckets);
+
+    for (ndx, bucket) in old.into_iter().enumerate() {
+        if ndx % 2 == 0 {
+            buckets.push(bucket);
+        } else {
+            merge(buckets.last_mut().unwrap(), bucket);
         }
     }
+}
 
-    fn newrect(&mut self, end: u64, nstates: u32)
-        -> (Option<(u64, u64, u64)>, (u64, u64))
-    {
-        let start = self.start.unwrap();
-        let state = self.state.unwrap();
-        let lhs: Option<(u64, u64, u64)>;
-        let rhs: (u64, u64);
-        let mut rect = StatemapRect::new(start, end - start, state, nstates);
+/*
+ * Merge buckets into rectangles, combining adjacent buckets that are each
+ * entirely in the same state (and with the same tag, if any).
+ */
+fn merge_buckets<I>(buckets: I) -> Vec<StatemapRect>
+    where I: Iterator<Item = Option<StatemapRect>>
+{
+    let mut rects: Vec<StatemapRect> = vec![];
 
-        match self.tag {
-            Some(id) => {
-                let mut hash: HashMap<usize, u64> = HashMap::new();
-                hash.insert(id, end - start);
-                rect.tags = Some(hash);
+    for bucket in buckets {
+        let mut bucket = match bucket {
+            Some(bucket) => bucket,
+            None => continue
+        };
+
+        if let Some(last) = rects.last_mut() {
+            if last.start + last.duration == bucket.start &&
+                last.uniform().is_some() &&
+                last.uniform() == bucket.uniform() {
+                last.absorb(&mut bucket);
+                continue;
             }
-            _ => {}
         }
 
-        rect.prev = self.last;
+        rects.push(bucket);
+    }
 
-        match self.last {
-            Some(last) => {
-                let mut lrect = self.rects.get(&last).unwrap().borrow_mut();
-                let old = lrect.weight;
+    rects
+}
 
-                lrect.next = Some(start);
-                rect.weight += lrect.duration;
-                lrect.weight += rect.duration;
+/*
+ * Return the datum that the viewer uses to describe a rectangle (absent
+ * its closing brace, allowing the caller to add to it), along with the
+ * color of the rectangle.
+ */
+fn rect_datum(rect: &StatemapRect, colors: &Vec<StatemapColor>)
+    -> (String, StatemapColor)
+{
+    let output_tags = |datum: &mut String| {
+        /*
+         * If we have tags, we emit them in ID order.
+         */
+        if let Some(ref tags) = rect.tags {
+            let mut g: Vec<(usize, u64)>;
 
-                lhs = Some((lrect.start, old, lrect.weight));
+            datum.push_str(", g: {");
+
+            g = tags.iter()
+                .map(|(&id, &duration)| { (id, duration) })
+                .collect();
+
+            g.sort_unstable();
+
+            for j in 0..g.len() {
+                let ratio = g[j].1 as f64 / rect.duration as f64;
+                datum.push_str(&format!("'{}': {:.3}{}", g[j].0, ratio,
+                    if j < g.len() - 1 { "," } else { "" }));
             }
-            _ => { lhs = None; }
+
+            datum.push_str("}");
         }
+    };
 
-        rhs = (rect.start, rect.weight);
-        self.rects.insert(start, RefCell::new(rect));
-        (lhs, rhs)
+    let mut state = None;
+    let mut blended = false;
+
+    for j in 0..rect.states.len() {
+        if rect.states[j] != 0 {
+            match state {
+                None => { state = Some(j) },
+                Some(_s) => {
+                    blended = true;
+                    break;
+                }
+            }
+        }
     }
 
-    fn addto(&mut self, rect: u64, delta: u64) -> u64 {
-        let mut r = self.rects.get(&rect).unwrap().borrow_mut();
-        let old = r.weight;
+    if !blended {
+        assert!(state.is_some());
 
-        r.weight += delta;
-        old
+        let mut datum = format!("{{ \"t\": {}, \"s\": {}", rect.start,
+            state.unwrap());
+
+        output_tags(&mut datum);
+
+        return (datum, colors[state.unwrap()]);
     }
 
-    fn subsume(&mut self, victim: u64)
-        -> ((Option<u64>, u64), (u64, u64), (u64, u64), (Option<u64>, u64))
-    {
-        let mut last = self.last;
-        let subsumed: u64;
-        let rval;
+    let mut datum = format!("{{ t: {}, s: {{ ", rect.start);
+    let mut comma = "";
 
-        /*
-         * We return three weights that need to be adjusted: that of the
-         * rectangle to the left (post-subsume), that of the rectangle to
-         * the right (post-subsume) and that of the center rectangle.  Each
-         * of these adjustments is described as a start plus a weight to be
-         * added -- and all three are returned as a tuple that also includes
-         * the subsumed rectangle that needs to be removed.
-         */
-        let ldelta: (Option<u64>, u64);
-        let cdelta: (u64, u64);
-        let rdelta: (Option<u64>, u64);
+    for j in 0..rect.states.len() {
+        if rect.states[j] == 0 {
+            continue;
+        }
 
-        /*
-         * We create a scope here to help out the borrow checker in terms of
-         * knowing that our immutable borrow of self.rects is being dropped
-         * before our mutable borrow of it, below.
-         */
-        {
-            let left: &RefCell<StatemapRect>;
-            let right: &RefCell<StatemapRect>;
+        let ratio = rect.states[j] as f64 / rect.duration as f64;
 
-            /*
-             * We create a scope here to allow the borrow of the victim
-             * cell fall out of scope as we may need it to be mutable, below.
-             */
-            {
-                let vcell = self.rects.get(&victim).unwrap();
-                let v = vcell.borrow();
+        datum.push_str(&format!("{}'{}': {:.3}", comma, j, ratio));
+        comma = ", ";
+    }
 
-                match (v.prev, v.next) {
-                    (None, None) => panic!("nothing to subsume"),
-                    (Some(prev), None) => {
-                        left = self.rects.get(&prev).unwrap();
-                        right = vcell;
+    datum.push_str("}");
+    output_tags(&mut datum);
 
-                        let lref = left.borrow();
-                        ldelta = (lref.prev, v.duration);
-                        cdelta = (lref.start, 0);
-                        rdelta = (None, 0);
-                    }
-                    (None, Some(next)) => {
-                        left = vcell;
-                        right = self.rects.get(&next).unwrap();
+    (datum, rect_color(rect, colors))
+}
 
-                        /*
-                         * We want the weight of the remaining (center)
-                         * rectangle to be the weight of our right rectangle;
-                         * to express this as a delta, we express it as the
-                         * difference between the two.
-                         */
-                        let rref = right.borrow();
-                        ldelta = (None, 0);
-                        cdelta = (v.start, rref.weight - v.weight);
-                        rdelta = (rref.next, v.duration);
-                    }
-                    (Some(prev), Some(next)) => {
-                        /*
-                         * We want whichever of our neighboring rectangles is
-                         * shorter to subsume us.
-                         */
-                        let l = self.rects.get(&prev).unwrap();
-                        let r = self.rects.get(&next).unwrap();
+/*
+ * If a blended rectangle contains time in a salient state, its color may
+ * well not reflect it; return the salient state that it contains the most
+ * of, if any.
+ */
+fn rect_salient(rect: &StatemapRect, states: &Vec<StatemapState>)
+    -> Option<usize>
+{
+    let nstates = rect.states.iter().filter(|&&t| t != 0).count();
 
-                        let lref = l.borrow();
-                        let rref = r.borrow();
+    if rect.salient == 0 || nstates < 2 {
+        return None;
+    }
 
-                        if lref.duration < rref.duration {
-                            left = l;
-                            right = vcell;
+    (0..rect.states.len())
+        .filter(|&j| states[j].salient)
+        .max_by_key(|&j| rect.states[j])
+}
 
-                            ldelta = (lref.prev, v.duration);
-                            cdelta = (lref.start, v.weight -
-                                (lref.duration + v.duration));
-                            rdelta = (Some(rref.start), lref.duration);
-                        } else {
-                            left = vcell;
-                            right = r;
+/*
+ * Write the data for each entity as an object, with each entity's data as
+ * an array.
+ */
+fn output_data(out: &mut dyn Write, data: &HashMap<&String, Vec<String>>)
+    -> io::Result<()>
+{
+    writeln!(out, "{{ ")?;
+    let mut comma = "";
 
-                            ldelta = (Some(lref.start), rref.duration);
-                            cdelta = (v.start, rref.weight -
-                                (rref.duration + v.duration));
-                            rdelta = (rref.next, v.duration);
-                        }
-                    }
-                }
+    for entity in data.keys() {
+        writeln!(out, "{}\"{}\": [", comma, entity)?;
+
+        let datum = data.get(entity).unwrap();
+
+        if datum.len() > 0 {
+            for i in 0..datum.len() - 1 {
+                writeln!(out, "{},", datum[i])?;
             }
 
-            let mut s = left.borrow_mut();
-            let mut v = right.borrow_mut();
+            writeln!(out, "{}", datum[datum.len() - 1])?;
+        }
 
-            s.next = v.next;
+        writeln!(out, "]")?;
+        comma = ",";
+    }
 
-            /*
-             * Set our subsumed next rectangle's previous to point back to us
-             * rather than the subsumed rectangle.
-             */
-            match s.next {
-                Some(next) => {
-                    self.rects.get(&next).unwrap()
-                        .borrow_mut().prev = Some(s.start);
-                }
-                None => {
-                    last = Some(s.start);
-                }
-            }
+    write!(out, "}}")
+}
 
-            /*
-             * Add our duration, and then sum the value in each of the states.
-             */
-            s.duration += v.duration;
+/*
+ * Return the datum that the viewer uses to describe an event.
+ */
+fn event_datum(entity: &StatemapEntity, event: &StatemapEvent) -> String {
+    let mut datum = json!({
+        "t": event.time,
+        "e": event.event,
+        "n": entity.name,
+    });
+
+    if let Some(ref target) = event.target {
+        datum["target"] = json!(target);
+    }
 
-            for i in 0..v.states.len() {
-                s.states[i] += v.states[i];
-            }
+    datum.to_string()
+}
 
-            /*
-             * If our victim has tags, we need to fold them in.
-             */
-            if v.tags.is_some() && s.tags.is_none() {
-                s.tags = Some(HashMap::new());
-            }
+/*
+ * Return a summary of a distribution of durations:  the number of visits,
+ * their mean, minimum and maximum, and select percentiles.
+ */
+fn distribution_datum(dist: &StatemapDistribution) -> Value {
+    let p = dist.percentiles(&[50.0, 90.0, 99.0]);
+
+    json!({
+        "count": dist.count(),
+        "mean": dist.mean(),
+        "min": dist.min(),
+        "p50": p[0],
+        "p90": p[1],
+        "p99": p[2],
+        "max": dist.max(),
+    })
+}
 
-            match s.tags {
-                Some(ref mut stags) => {
-                    match v.tags {
-                        Some(ref mut vtags) => { subsume_tags(stags, vtags); },
-                        None => {}
-                    }
-                },
-                None => {}
-            }
+/*
+ * Return a duration (in nanoseconds) as text, in the most appropriate unit.
+ */
+fn duration_text(duration: f64) -> String {
+    let units = [("ns", 1.0), ("us", 1e3), ("ms", 1e6), ("s", 1e9)];
+    let mut i = 0;
 
-            subsumed = v.start;
-            rval = (v.start, v.weight);
-        }
+    while i < units.len() - 1 && duration >= units[i + 1].1 {
+        i += 1;
+    }
 
-        /*
-         * Okay, we're done subsuming! We can remove the subsumed rectangle.
-         */
-        self.rects.remove(&subsumed);
-        self.last = last;
+    if i == 0 {
+        format!("{}ns", duration.round())
+    } else {
+        format!("{:.3}{}", duration / units[i].1, units[i].0)
+    }
+}
 
-        (ldelta, cdelta, rval, rdelta)
+/*
+ * Return the title of a statemap imported from a trace, noting the time of
+ * the trace's first event -- which is relative to the trace's own clock
+ * (typically, time since boot) rather than to the Epoch.
+ */
+fn trace_title(title: &str, origin: u64) -> String {
+    format!("{} (trace time {}.{:06}s)", title, origin / 1_000_000_000,
+        origin % 1_000_000_000 / 1_000)
+}
+
+/*
+ * Escape text for inclusion in SVG or HTML, either as content or as the
+ * value of an attribute.
+ */
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/*
+ * Quote a field for CSV output, if it needs it.
+ */
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
     }
+}
 
-    #[must_use]
-    fn apply(&mut self, deltas: ((Option<u64>, u64),
-        (u64, u64), (u64, u64), (Option<u64>, u64))) ->
-        Vec<(u64, u64, Option<u64>)>
-    {
-        let mut updates: Vec<(u64, u64, Option<u64>)> = vec![];
+/*
+ * Return the color of a rectangle:  the color of the state in which it
+ * spent the most time, mixed with the colors of any other states in
+ * proportion to the time spent in them.
+ */
+fn rect_color(rect: &StatemapRect, colors: &Vec<StatemapColor>)
+    -> StatemapColor
+{
+    let max = rect.states.iter().enumerate()
+        .max_by(|&(_, lhs), &(_, rhs)| lhs.cmp(rhs)).unwrap().0;
 
-        /*
-         * Handle the left delta.
-         */
-        match (deltas.0).0 {
-            Some(rect) => {
-                let delta = (deltas.0).1;
-                updates.push((rect, self.addto(rect, delta), Some(delta)));
-            }
-            None => {}
+    let mut color = colors[max];
+
+    for j in 0..rect.states.len() {
+        if rect.states[j] == 0 || j == max {
+            continue;
         }
 
-        /*
-         * Handle the center delta.
-         */
-        let rect = (deltas.1).0;
-        let delta = (deltas.1).1;
-        updates.push((rect, self.addto(rect, delta), Some(delta)));
+        let ratio = rect.states[j] as f64 / rect.duration as f64;
+        color = color.mix_nonlinear(&colors[j], ratio as f32);
+    }
 
-        /*
-         * Handle the subsumed rectangle by pushing a delta update of None.
-         */
-        updates.push(((deltas.2).0, (deltas.2).1, None));
+    color
+}
 
-        /*
-         * And finally, the right delta.
-         */
-        match (deltas.3).0 {
-            Some(rect) => {
-                let delta = (deltas.3).1;
-                updates.push((rect, self.addto(rect, delta), Some(delta)));
-            }
-            None => {}
-        }
+impl StatemapTransition {
+    /**
+     * The state transitioned from, by value.
+     */
+    pub fn from(&self) -> usize {
+        self.from
+    }
 
-        updates
+    /**
+     * The state transitioned to, by value.
+     */
+    pub fn to(&self) -> usize {
+        self.to
     }
 
-    fn output_svg(&self, id: usize, begin: i64, config: &StatemapSVGConfig,
-        globals: &StatemapSVGGlobals, locals: &StatemapSVGLocals,
-        colors: &Vec<StatemapColor>, y: u32) -> Vec<String>
-    {
-        let rect_width = |rect: &StatemapRect| -> f64 {
-            /*
-             * We add a fuzz factor to our width to assure it will always be
-             * nearly (but not quite!) half a pixel wider than it should be.
-             * This assures that none of the background (which is deliberately a
-             * bright color) comes through at the border of rectangles, without
-             * losing any accuracy (the next rectangle will tile over ours at
-             * an unadjusted offset).
-             */
-            ((rect.duration as f64 / globals.timeWidth as f64) *
-                globals.pixelWidth as f64) + 0.4 as f64
-        };
+    /**
+     * The number of transitions.
+     */
+    pub fn count(&self) -> u64 {
+        self.count
+    }
 
-        let output_tags = |rect: &StatemapRect, datum: &mut String| {
-            /*
-             * If we have tags, we emit them in ID order.
-             */
-            if let Some(ref tags) = rect.tags {
-                let mut g: Vec<(usize, u64)>;
+    /**
+     * The total time (in nanoseconds) spent in the state transitioned from
+     * before each transition.
+     */
+    pub fn dwell(&self) -> u64 {
+        self.dwell
+    }
+
+    /**
+     * The mean time (in nanoseconds) spent in the state transitioned from
+     * before a transition.
+     */
+    pub fn mean_dwell(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.dwell as f64 / self.count as f64
+        }
+    }
+}
 
-                datum.push_str(", g: {");
+impl StatemapState {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-                g = tags.iter()
-                    .map(|(&id, &duration)| { (id, duration) })
-                    .collect();
+    pub fn value(&self) -> usize {
+        self.value
+    }
 
-                g.sort_unstable();
+    pub fn color(&self) -> Option<&str> {
+        self.color.as_ref().map(|color| color.as_str())
+    }
 
-                for j in 0..g.len() {
-                    let ratio = g[j].1 as f64 / rect.duration as f64;
-                    datum.push_str(&format!("'{}': {:.3}{}", g[j].0, ratio,
-                        if j < g.len() - 1 { "," } else { "" }));
-                }
+    /**
+     * Whether this state has been designated as salient.
+     */
+    pub fn salient(&self) -> bool {
+        self.salient
+    }
+}
 
-                datum.push_str("}");
-            }
-        };
+impl StatemapEntity {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-        let background = |x: f64, width: f64| {
-            if width > 0.0 {
-                println!(r##"<rect x="{}" y="{}" width="{}"
-                    height="{}" style="fill:{}" />"##, x, y, width,
-                    config.stripHeight, config.background);
-            }
-        };
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_ref().map(|d| d.as_str())
+    }
 
-        let mut x: f64 = 0.0;
-        let mut map: Vec<i64>;
-        let mut data: Vec<String> = vec![];
+    /**
+     * Return this entity's rectangles, sorted by start time.
+     */
+    pub fn rects(&self) -> Vec<StatemapRect> {
+        let mut rects: Vec<StatemapRect> = self.rects.values()
+            .map(|r| r.borrow().clone())
+            .collect();
 
-        map = self.rects.values().map(|r| r.borrow().start as i64).collect();
-        map.sort();
+        rects.sort_by_key(|r| r.start);
+        rects
+    }
 
-        if map.len() >= 1 && map[0] > begin {
-            background(0.0, ((map[0] - begin) as f64 /
-                globals.timeWidth as f64) * globals.pixelWidth as f64);
+    /**
+     * Return this entity's rectangles at the specified finer level of
+     * detail (see `Statemap::levels`), sorted by start time.
+     */
+    pub fn level(&self, level: usize) -> &[StatemapRect] {
+        match self.levels.get(level) {
+            Some(rects) => rects,
+            None => &[]
         }
+    }
 
-        println!(r##"<g id="{}{}-{}"><title>{} {}</title>"##,
-            globals.entityPrefix, id, self.name, locals.entityKind, self.name);
-
-        for i in 0..map.len() {
-            let rect = self.rects.get(&(map[i] as u64)).unwrap().borrow();
-            let mut state = None;
-            let mut blended = false;
-            let w = rect_width(&rect);
+    /**
+     * The total time this entity spent in each state, indexed by state
+     * value.  These totals are tallied from the transitions themselves
+     * (within any specified time bounds) before they are coalesced.
+     */
+    pub fn totals(&self) -> &[u64] {
+        &self.totals
+    }
 
-            x = ((map[i] - begin) as f64 /
-                globals.timeWidth as f64) * globals.pixelWidth as f64;
+    /**
+     * The distribution of the durations of this entity's visits to each
+     * state, indexed by state value.  This is empty unless durations are
+     * being recorded (see `Config::durations`).
+     */
+    pub fn durations(&self) -> &[StatemapDistribution] {
+        &self.durations
+    }
 
-            for j in 0..rect.states.len() {
-                if rect.states[j] != 0 {
-                    match state {
-                        None => { state = Some(j) },
-                        Some(_s) => {
-                            blended = true;
-                            break;
-                        }
-                    }
+    fn new(name: &str, id: usize, nstates: u32,
+        durations: Option<StatemapDurations>) -> Self
+    {
+        StatemapEntity {
+            name: name.to_string(),
+            start: None,
+            description: None,
+            last: None,
+            state: None,
+            salient: false,
+            tag: None,
+            rects: HashMap::new(),
+            buckets: Vec::new(),
+            levels: Vec::new(),
+            events: Vec::new(),
+            totals: vec![0; nstates as usize],
+            visit: None,
+            transitions: HashMap::new(),
+            durations: match durations {
+                Some(durations) => {
+                    vec![StatemapDistribution::new(durations); nstates as usize]
                 }
-            }
+                None => vec![]
+            },
+            id: id,
+        }
+    }
+
+    /*
+     * Tally the time in our current state (from our start to the specified
+     * end).  This is done before the time is made into a rectangle (or is
+     * added to a bucket), so it is exact regardless of coalescing.
+     */
+    fn tally(&mut self, end: u64) {
+        let start = self.start.unwrap();
+        let state = self.state.unwrap() as usize;
 
-            if !blended {
-                assert!(state.is_some());
+        self.totals[state] += end - start;
+    }
 
-                let mut datum = format!("{{ \"t\": {}, \"s\": {}", rect.start,
-                    state.unwrap());
+    /**
+     * This entity's transitions from one state to another, sorted by the
+     * state transitioned from and then by the state transitioned to.
+     */
+    pub fn transitions(&self) -> Vec<StatemapTransition> {
+        let mut transitions: Vec<StatemapTransition> = self.transitions
+            .iter()
+            .map(|(&(from, to), &(count, dwell))| StatemapTransition {
+                from: from as usize,
+                to: to as usize,
+                count: count,
+                dwell: dwell,
+            }).collect();
+
+        transitions.sort_by_key(|t| (t.from, t.to));
+        transitions
+    }
 
-                output_tags(&rect, &mut datum);
-                datum.push_str("}");
-                data.push(datum);
+    /*
+     * Note that we are entering the specified state at the specified time.
+     * If this is a transition within our time bounds, we count it (along
+     * with the time spent in the state we are leaving); if it ends a visit
+     * to our current state that began within our time bounds, we record its
+     * duration.
+     */
+    fn visit(&mut self, time: u64, state: u32, begin: i64) {
+        if self.state == Some(state) {
+            return;
+        }
 
-                println!(concat!(r##"<rect x="{}" y="{}" width="{}" "##,
-                    r##"height="{}" onclick="mapclick(evt, {})" "##,
-                    r##"style="fill:{}" />"##), x, y, w, config.stripHeight,
-                    data.len() - 1, colors[state.unwrap()]);
-                x += w;
+        if let (Some(current), Some(start)) = (self.state, self.visit) {
+            if time as i64 >= begin {
+                let transition = self.transitions.entry((current, state))
+                    .or_insert((0, 0));
 
-                continue;
+                transition.0 += 1;
+                transition.1 += time - start;
             }
 
-            let max = rect.states.iter().enumerate()
-                .max_by(|&(_, lhs), &(_, rhs)| lhs.cmp(rhs)).unwrap().0;
+            if !self.durations.is_empty() && start as i64 >= begin {
+                self.durations[current as usize].record(time - start);
+            }
+        }
 
-            let mut color = colors[max];
-            let mut datum = format!("{{ t: {}, s: {{ ", rect.start);
-            let mut comma = "";
-            
-            for j in 0..rect.states.len() {
-                if rect.states[j] == 0 {
-                    continue;
-                }
+        self.visit = Some(time);
+    }
 
-                let ratio = rect.states[j] as f64 / rect.duration as f64;
+    fn newrect(&mut self, end: u64, nstates: u32)
+        -> (Option<(u64, u64, u64)>, (u64, u64))
+    {
+        let start = self.start.unwrap();
+        let state = self.state.unwrap();
+        let lhs: Option<(u64, u64, u64)>;
+        let rhs: (u64, u64);
+        let mut rect = StatemapRect::new(start, end - start, state, nstates);
 
-                datum.push_str(&format!("{}'{}': {:.3}", comma, j, ratio));
-                comma = ", ";
+        if self.salient {
+            rect.salient = rect.duration;
+            rect.weight = rect.mass();
+        }
 
-                if j != max {
-                    color = color.mix_nonlinear(&colors[j], ratio as f32);
-                }
+        match self.tag {
+            Some(id) => {
+                let mut hash: HashMap<usize, u64> = HashMap::new();
+                hash.insert(id, end - start);
+                rect.tags = Some(hash);
             }
+            _ => {}
+        }
 
-            datum.push_str("}");
-
-            output_tags(&rect, &mut datum);
-            datum.push_str("}");
-            data.push(datum);
+        rect.prev = self.last;
 
-            println!(concat!(r##"<rect x="{}" y="{}" width="{}" "##,
-                r##"height="{}" onclick="mapclick(evt, {})" "##,
-                r##"style="fill:{}" />"##), x, y, w,
-                config.stripHeight, data.len() - 1, color);
-            x += w;
-        }
+        match self.last {
+            Some(last) => {
+                let mut lrect = self.rects.get(&last).unwrap().borrow_mut();
+                let old = lrect.weight;
 
-        println!("</g>");
+                lrect.next = Some(start);
+                rect.weight += lrect.mass();
+                lrect.weight += rect.mass();
 
-        /*
-         * Finally, add a background rectangle that covers whatever remains
-         * of our width.
-         */
-        background(x, globals.pixelWidth as f64 - x);
+                lhs = Some((lrect.start, old, lrect.weight));
+            }
+            _ => { lhs = None; }
+        }
 
-        data
+        rhs = (rect.start, rect.weight);
+        self.rects.insert(start, RefCell::new(rect));
+        (lhs, rhs)
     }
 
-    #[cfg(test)]
-    fn print(&self, header: &str) {
-        let mut v: Vec<u64>;
-        let l: usize;
-        
-        v = self.rects.values().map(|r| r.borrow().start).collect();
-        v.sort();
-        l = v.len();
+    /*
+     * When coalescing into buckets, we add the time in our current state
+     * (from our start to the specified end) to each bucket that it spans.
+     * Buckets are of the specified resolution, beginning at `origin`.
+     * Level 0 denotes the buckets that will become our rectangles; any
+     * higher level is a finer level of detail.
+     */
+    fn bucket(&mut self, level: usize, end: u64, nstates: u32, origin: u64,
+        resolution: u64)
+    {
+        let state = self.state.unwrap();
+        let mut start = self.start.unwrap();
 
-        for i in 0..l {
-            let me = self.rects.get(&v[i]).unwrap().borrow();
-            println!("{}: entity={}: [{}] {:?}: {:?}",
-                header, self.id, i, v[i], me);
+        if level >= self.buckets.len() {
+            self.buckets.resize(level + 1, vec![]);
         }
 
-        println!("{}: entity={}: last is {:?}", header, self.id, self.last);
-    }
+        let buckets = &mut self.buckets[level];
 
-    #[cfg(test)]
-    fn verify(&self) {
-        let mut v: Vec<u64>;
-        let l: usize;
-        
-        v = self.rects.values().map(|r| r.borrow().start).collect();
-        v.sort();
-        l = v.len();
-
-        for i in 0..l {
-            let me = self.rects.get(&v[i]).unwrap().borrow();
-            let mut weight = me.duration;
-
-            if i < l - 1 {
-                let next = self.rects.get(&v[i + 1]).unwrap().borrow();
-                assert_eq!(me.next, Some(next.start));
-                assert!(me.start < next.start);
-                weight += next.duration;
-            } else {
-                assert_eq!(me.next, None);
-                assert_eq!(self.last, Some(me.start));
-            }
+        while start < end {
+            let ndx = ((start - origin) / resolution) as usize;
+            let edge = cmp::min(origin + (ndx as u64 + 1) * resolution, end);
+            let duration = edge - start;
 
-            if i > 0 {
-                let prev = self.rects.get(&v[i - 1]).unwrap().borrow();
-                assert_eq!(me.prev, Some(prev.start));
-                assert!(me.start > prev.start);
-                weight += prev.duration;
-            } else {
-                assert_eq!(me.prev, None);
+            if ndx >= buckets.len() {
+                buckets.resize(ndx + 1, None);
             }
 
-            assert_eq!(me.weight, weight);
+            let bucket = buckets[ndx].get_or_insert_with(|| {
+                StatemapRect::new(start, 0, state, nstates)
+            });
 
-            if let Some(ref tags) = me.tags {
-                let duration = tags.iter().fold(0,
-                    |i, (_id, duration)| { i + duration });
+            bucket.duration += duration;
+            bucket.states[state as usize] += duration;
 
-                /*
-                 * This is technically a more vigorous assertion than we can
-                 * make:  we actually allow for partial tagging in that not
-                 * all states must by tagged all of the time.  For the moment,
-                 * though, we assert that if any states have been tagged, all
-                 * have been.
-                 */
-                assert_eq!(duration, me.duration);
+            if self.salient {
+                bucket.salient += duration;
             }
-        }
-    }
-
-    #[cfg(test)]
-    fn subsume_apply_and_verify(&mut self, victim: u64) ->
-        Vec<(u64, u64, Option<u64>)>
-    {
-        println!("=== Subsuming {}", victim);
-        self.print(&format!("Before subsuming {}", victim));
-        self.verify();
-
-        let tup = self.subsume(victim);
-        println!("Weight delta from subsuming {}: {:?}", victim, tup);
-
-        self.print(&format!("After subsuming {}, before applying", victim));
-        let updates = self.apply(tup);
-
-        self.print(&format!("After subsuming {}, after applying", victim));
-        self.verify();
-        updates
-    }
-}
-
-enum Ingest {
-    Success,
-    EndOfFile,
-}
-
-fn try_parse<'de, T>(content: &mut &'de str)
-    -> Result<Option<T>, serde_json::Error>
-where
-    T: serde::Deserialize<'de>
-{
-    let mut de = serde_json::Deserializer::from_str(*content).into_iter();
-    match de.next() {
-        Some(Ok(value)) => {
-            *content = &content[de.byte_offset()..];
-            Ok(Some(value))
-        }
-        Some(Err(err)) => Err(err),
-        None => Ok(None),
-    }
-}
-
-fn try_parse_raw<'de, T>(content: &mut &'de str)
-    -> Result<Option<(T, serde_json::Value)>, serde_json::Error>
-where
-    T: serde::Deserialize<'de>
-{
-    let mut de = serde_json::Deserializer::from_str(*content).into_iter();
-    let offset = de.byte_offset();
-
-    match de.next() {
-        Some(Ok(value)) => {
-            let v: serde_json::Value =
-                serde_json::from_str(&content[offset..de.byte_offset()])?;
 
-            *content = &content[de.byte_offset()..];
+            if let Some(id) = self.tag {
+                let tags = bucket.tags.get_or_insert_with(HashMap::new);
+                *tags.entry(id).or_insert(0) += duration;
+            }
 
-            Ok(Some((value, v)))
+            start = edge;
         }
-        Some(Err(err)) => Err(err),
-        None => Ok(None),
     }
-}
-
-fn line_number(mmap: &[u8], byte_offset: usize) -> usize {
-    let mut nls = mmap[..byte_offset].iter().filter(|&&b| b == b'\n').count();
 
     /*
-     * We report the line number of the first non-whitespace character after
-     * byte_offset.
+     * Double the resolution of our buckets at the specified level by
+     * folding each pair of buckets together.
      */
-    for b in mmap[byte_offset..].iter() {
-        if *b == b'\n' {
-            nls += 1;
+    fn rebucket(&mut self, level: usize) {
+        if level >= self.buckets.len() {
+            return;
         }
 
-        if !((*b as char).is_whitespace()) {
-            break;
-        }
+        fold_buckets(&mut self.buckets[level], |merged, bucket| {
+            match (merged.as_mut(), bucket) {
+                (Some(merged), Some(mut bucket)) => merged.absorb(&mut bucket),
+                (None, bucket) => *merged = bucket,
+                (_, None) => {}
+            }
+        });
     }
 
-    nls + 1
-}
-
-/*
- * The time value is written in the input as a JSON string containing a number.
- * Deserialize just the number here without allocating memory for a String.
- */
-fn datum_time_from_string<'de, D>(deserializer: D) -> Result<u64, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s: &str = serde::Deserialize::deserialize(deserializer)?;
-    match u64::from_str(s) {
-        Ok(time) => Ok(time),
-        Err(_) => Err(serde::de::Error::custom("illegal time value")),
-    }
-}
+    /*
+     * Convert our buckets into rectangles.  Adjacent buckets that consist
+     * of time in only one state (and with the same tag, if any) are
+     * represented with a single rectangle, as nothing is lost by doing so.
+     * We return the start and weight of each rectangle.
+     */
+    fn unbucket(&mut self) -> Vec<(u64, u64)> {
+        let rects = match self.buckets.get_mut(0) {
+            Some(buckets) => merge_buckets(buckets.drain(..)),
+            None => vec![]
+        };
 
-impl Statemap {
-    pub fn new(config: &Config) -> Self {
-        Statemap {
-            config: *config,
-            nrecs: 0,
-            nevents: 0,
-            entities: HashMap::new(),
-            states: Vec::new(),
-            byid: Vec::new(),
-            byweight: BTreeSet::new(),
-            metadata: None,
-            tags: HashMap::new(),
-            begin: 0,
-            end: 0,
-            last: 0,
-        }
-    }
+        let mut rval = vec![];
 
-    fn err<T>(&self, msg: &str) -> Result<T, Box<dyn Error>>  {
-        Err(Box::new(StatemapError::new(msg)))
-    }
+        for i in 0..rects.len() {
+            let mut rect = rects[i].clone();
 
-    fn entity_lookup(&mut self, name: &str) -> &mut StatemapEntity {
-        /*
-         * The lack of non-lexical lifetimes causes this code to be a bit
-         * gnarlier than it should really have to be.
-         */
-        if self.entities.contains_key(name) {
-            return match self.entities.get_mut(name) {
-                Some(entity) => { entity },
-                None => unreachable!()
-            };
-        }
+            rect.weight = rect.mass();
 
-        let entity = StatemapEntity::new(name, self.byid.len());
-        self.byid.push(name.to_string());
+            if i > 0 {
+                rect.prev = Some(rects[i - 1].start);
+                rect.weight += rects[i - 1].mass();
+            }
 
-        self.entities.insert(name.to_string(), entity);
-        self.entities.get_mut(name).unwrap()
-    }
+            if i < rects.len() - 1 {
+                rect.next = Some(rects[i + 1].start);
+                rect.weight += rects[i + 1].mass();
+            }
 
-    fn tag_lookup(&mut self, state: u32, tagr: &Option<String>)
-        -> Option<usize>
-    {
-        if self.config.notags {
-            return None;
+            rval.push((rect.start, rect.weight));
+            self.rects.insert(rect.start, RefCell::new(rect));
         }
 
-        match *tagr {
-            Some(ref tag) => {
-                let id;
-
-                match self.tags.get(&(state, tag.to_string())) {
-                    Some(( _value, idr)) => { return Some(*idr); },
-                    None => { id = self.tags.len(); }
-                }
-
-                let value = json!({ "state": state, "tag": tag.to_string() });
+        self.last = rects.last().map(|rect| rect.start);
 
-                self.tags.insert((state, tag.to_string()), (value, id));
-                Some(id)
-            },
-            None => None
-        }
+        rval
     }
 
     /*
-     * Takes a vector of updates to apply to our byweight tree as well as a
-     * template rectangle weight and applies the updates.
+     * Convert the buckets of each of our finer levels of detail into
+     * rectangles.  Unlike our (coalesced) rectangles, these are only
+     * rendered, and are kept simply in time order.
      */
-    fn apply(&mut self, updates: Vec<(u64, u64, Option<u64>)>,
-        rweight: &mut StatemapRectWeight)
-    {
-        for i in 0..updates.len() {
-            rweight.start = updates[i].0;
-            rweight.weight = updates[i].1;
+    fn unbucket_levels(&mut self) {
+        self.levels = self.buckets.iter_mut().skip(1)
+            .map(|buckets| merge_buckets(buckets.drain(..)))
+            .collect();
+        self.buckets.clear();
+    }
 
-            self.byweight.remove(rweight);
+    fn addto(&mut self, rect: u64, delta: u64) -> u64 {
+        let mut r = self.rects.get(&rect).unwrap().borrow_mut();
+        let old = r.weight;
 
-            match updates[i].2 {
-                Some(delta) => {
-                    rweight.weight += delta;
-                    self.byweight.insert(*rweight);
-                }
-                None => {}
-            }
-        }
+        r.weight += delta;
+        old
     }
 
-    /*
-     * Subsumes the rectangle of least weight, applies the deltas to the
-     * entity corresponding to that rectangle, and then applies the
-     * resulting rectangle weight updates.
-     */
-    fn trim(&mut self) {
-        let mut remove: StatemapRectWeight;
-        let updates;
+    fn subsume(&mut self, victim: u64)
+        -> ((Option<u64>, u64), (u64, u64), (u64, u64), (Option<u64>, u64))
+    {
+        let mut last = self.last;
+        let subsumed: u64;
+        let rval;
 
-        remove = *self.byweight.iter().next().unwrap();
-        self.byweight.remove(&remove);
-        
         /*
-         * We need a scope here to help the compiler out with respect to
-         * our use of entity.
+         * We return three weights that need to be adjusted: that of the
+         * rectangle to the left (post-subsume), that of the rectangle to
+         * the right (post-subsume) and that of the center rectangle.  Each
+         * of these adjustments is described as a start plus a weight to be
+         * added -- and all three are returned as a tuple that also includes
+         * the subsumed rectangle that needs to be removed.
+         */
+        let ldelta: (Option<u64>, u64);
+        let cdelta: (u64, u64);
+        let rdelta: (Option<u64>, u64);
+
+        /*
+         * We create a scope here to help out the borrow checker in terms of
+         * knowing that our immutable borrow of self.rects is being dropped
+         * before our mutable borrow of it, below.
          */
         {
-            let name = &self.byid[remove.entity];
-            let entity = self.entities.get_mut(name).unwrap();
+            let left: &RefCell<StatemapRect>;
+            let right: &RefCell<StatemapRect>;
 
-            if entity.rects.len() == 1 {
-                /*
-                 * If this entity only has one rectangle, than there is
-                 * nothing to subsume; we simply return.  (This weight has
-                 * already been removed, so we won't find it again until
-                 * another rectangle is added for this entity.)
-                 */
-                return;
-            }
+            /*
+             * We create a scope here to allow the borrow of the victim
+             * cell fall out of scope as we may need it to be mutable, below.
+             */
+            {
+                let vcell = self.rects.get(&victim).unwrap();
+                let v = vcell.borrow();
 
-            let deltas = entity.subsume(remove.start);
-            updates = entity.apply(deltas);
-        }
+                match (v.prev, v.next) {
+                    (None, None) => panic!("nothing to subsume"),
+                    (Some(prev), None) => {
+                        left = self.rects.get(&prev).unwrap();
+                        right = vcell;
 
-        self.apply(updates, &mut remove);
-    }
+                        let lref = left.borrow();
+                        ldelta = (lref.prev, v.mass());
+                        cdelta = (lref.start, 0);
+                        rdelta = (None, 0);
+                    }
+                    (None, Some(next)) => {
+                        left = vcell;
+                        right = self.rects.get(&next).unwrap();
 
-    #[must_use]
-    fn sort(&self, sortby: Option<usize>) -> Vec<usize>
-    {
-        let mut v: Vec<(u64, &String, usize)>;
+                        /*
+                         * We want the weight of the remaining (center)
+                         * rectangle to be the weight of our right rectangle;
+                         * to express this as a delta, we express it as the
+                         * difference between the two.
+                         */
+                        let rref = right.borrow();
+                        ldelta = (None, 0);
+                        cdelta = (v.start, rref.weight - v.weight);
+                        rdelta = (rref.next, v.mass());
+                    }
+                    (Some(prev), Some(next)) => {
+                        /*
+                         * We want whichever of our neighboring rectangles is
+                         * shorter (or rather, lighter) to subsume us.
+                         */
+                        let l = self.rects.get(&prev).unwrap();
+                        let r = self.rects.get(&next).unwrap();
 
-        let values = self.entities.values();
+                        let lref = l.borrow();
+                        let rref = r.borrow();
 
-        match sortby {
-            None => { v = values.map(|e| (0, &e.name, e.id)).collect(); },
-            Some(state) => {
-                v = values.map(|e| {
-                    let ttl = e.rects.values().fold(0, |i, r| {
-                        i + r.borrow().states[state]
-                    });
+                        if lref.mass() < rref.mass() {
+                            left = l;
+                            right = vcell;
 
-                    (ttl, &e.name, e.id)
-                }).collect();
+                            ldelta = (lref.prev, v.mass());
+                            cdelta = (lref.start, v.weight -
+                                (lref.mass() + v.mass()));
+                            rdelta = (Some(rref.start), lref.mass());
+                        } else {
+                            left = vcell;
+                            right = r;
+
+                            ldelta = (Some(lref.start), rref.mass());
+                            cdelta = (v.start, rref.weight -
+                                (rref.mass() + v.mass()));
+                            rdelta = (rref.next, v.mass());
+                        }
+                    }
+                }
             }
-        }
 
-        v.sort_by(|&a, &b| {
-            let result = b.0.cmp(&a.0);
+            let mut s = left.borrow_mut();
+            let mut v = right.borrow_mut();
 
-            if result == cmp::Ordering::Equal {
-                natord::compare(a.1, b.1)
-            } else {
-                result
-            }
-        });
+            s.next = v.next;
 
-        v.iter().map(|e| e.2).collect()
-    }
+            /*
+             * Set our subsumed next rectangle's previous to point back to us
+             * rather than the subsumed rectangle.
+             */
+            match s.next {
+                Some(next) => {
+                    self.rects.get(&next).unwrap()
+                        .borrow_mut().prev = Some(s.start);
+                }
+                None => {
+                    last = Some(s.start);
+                }
+            }
 
-    /*
-     * Return the total time spent across all entities and all rectangles
-     * in a particular state, for purposes of assigning a weight to the
-     * statemap itself.
-     */
-    fn weight(&self, state: usize) -> u64
-    {
-        self.entities.values().fold(0, |ttl, e| {
-            ttl + e.rects.values().fold(0, |i, r| {
-                i + r.borrow().states[state]
-            })
-        })
-    }
+            /*
+             * Add our duration, sum the value in each of the states, and
+             * fold in any tags.
+             */
+            s.absorb(&mut v);
 
-    #[cfg(test)]
-    fn verify(&self) {
-        /*
-         * First, verify each of the entities.
-         */
-        for entity in self.entities.values() {
-            entity.verify();
+            subsumed = v.start;
+            rval = (v.start, v.weight);
         }
 
         /*
-         * Verify that each rectangle in each entity can be found in our
-         * byweight set -- and that the weights match.
+         * Okay, we're done subsuming! We can remove the subsumed rectangle.
          */
-        for entity in self.entities.values() {
-            for cell in entity.rects.values() {
-                let rect = cell.borrow();
-
-                let rweight = StatemapRectWeight {
-                    entity: entity.id,
-                    weight: rect.weight,
-                    start: rect.start
-                };
+        self.rects.remove(&subsumed);
+        self.last = last;
 
-                assert!(self.byweight.contains(&rweight) ||
-                    entity.rects.len() == 1 ||
-                    Some(rect.start) == entity.last ||
-                    rect.next == entity.last);
-            }
-        }
+        (ldelta, cdelta, rval, rdelta)
+    }
 
-        let mut present = HashSet::new();
+    #[must_use]
+    fn apply(&mut self, deltas: ((Option<u64>, u64),
+        (u64, u64), (u64, u64), (Option<u64>, u64))) ->
+        Vec<(u64, u64, Option<u64>)>
+    {
+        let mut updates: Vec<(u64, u64, Option<u64>)> = vec![];
 
         /*
-         * Verify that each entity is valid that each entity/start tuple is
-         * present exactly once.
+         * Handle the left delta.
          */
-        for rweight in self.byweight.iter() {
-            let name = &self.byid[rweight.entity];
-            let tup = (rweight.entity, rweight.start);
-
-            assert!(self.entities.get(name).is_some());
-
-            let entity = self.entities.get(name).unwrap();
-
-            assert!(entity.rects.get(&rweight.start).is_some());
-            assert!(!present.contains(&tup));
-            present.insert(tup);
+        match (deltas.0).0 {
+            Some(rect) => {
+                let delta = (deltas.0).1;
+                updates.push((rect, self.addto(rect, delta), Some(delta)));
+            }
+            None => {}
         }
-    }
 
-    #[cfg(test)]
-    fn subsume_apply_and_verify(&mut self, what: &str, victim: u64) {
-        let id: usize;
-        let mut weight: Option<u64> = None;
-        let updates;
+        /*
+         * Handle the center delta.
+         */
+        let rect = (deltas.1).0;
+        let delta = (deltas.1).1;
+        updates.push((rect, self.addto(rect, delta), Some(delta)));
 
-    {
-        let entity = self.entity_lookup(what);
-            updates = entity.subsume_apply_and_verify(victim);
-            id = entity.id;
-        }
+        /*
+         * Handle the subsumed rectangle by pushing a delta update of None.
+         */
+        updates.push(((deltas.2).0, (deltas.2).1, None));
 
         /*
-         * Before we verify, remove the victim -- if it wasn't actually
-         * to be removed, we'll add it back when we apply the updates.
+         * And finally, the right delta.
          */
-        for rweight in self.byweight.iter() {
-            if rweight.entity == id && rweight.start == victim {
-                assert!(weight.is_none());
-                weight = Some(rweight.weight);
+        match (deltas.3).0 {
+            Some(rect) => {
+                let delta = (deltas.3).1;
+                updates.push((rect, self.addto(rect, delta), Some(delta)));
             }
+            None => {}
         }
 
-        assert!(weight.is_some());
+        updates
+    }
 
-        let mut rweight = StatemapRectWeight {
-            entity: id, weight: 0, start: 0
+    fn output_svg(&self, out: &mut dyn Write, render: &StatemapSVGRender,
+        y: u32, markers: &mut Vec<String>)
+        -> Result<Vec<String>, Box<dyn Error>>
+    {
+        let config = render.config;
+        let globals = render.globals;
+        let colors = render.colors;
+        let begin = render.begin;
+
+        let rect_width = |rect: &StatemapRect| -> f64 {
+            /*
+             * We add a fuzz factor to our width to assure it will always be
+             * nearly (but not quite!) half a pixel wider than it should be.
+             * This assures that none of the background (which is deliberately a
+             * bright color) comes through at the border of rectangles, without
+             * losing any accuracy (the next rectangle will tile over ours at
+             * an unadjusted offset).
+             */
+            ((rect.duration as f64 / globals.timeWidth as f64) *
+                globals.pixelWidth as f64) + 0.4 as f64
         };
 
-        self.apply(updates, &mut rweight);
-        self.print(&format!("After subsuming {} from {}", victim, what)); 
-        self.verify();
-    }
+        let background = |out: &mut dyn Write, x: f64, width: f64| {
+            if width > 0.0 {
+                writeln!(out, r##"<rect x="{}" y="{}" width="{}"
+                    height="{}" style="fill:{}" />"##, x, y, width,
+                    config.stripHeight, config.background)
+            } else {
+                Ok(())
+            }
+        };
 
-    #[cfg(test)]
-    fn print(&self, header: &str) {
-        println!("{}: by weight: {:?}", header, self.byweight);
+        let mut x: f64 = 0.0;
+        let mut map: Vec<i64>;
+        let mut data: Vec<String> = vec![];
 
-        for entity in self.entities.values() {
-            entity.print(header);
+        map = self.rects.values().map(|r| r.borrow().start as i64).collect();
+        map.sort();
+
+        if map.len() >= 1 && map[0] > begin {
+            background(out, 0.0, ((map[0] - begin) as f64 /
+                globals.timeWidth as f64) * globals.pixelWidth as f64)?;
         }
 
-        println!("");
-    }
+        writeln!(out, r##"<g id="{}{}-{}"><title>{} {}</title>"##,
+            globals.entityPrefix, render.id, escape(&self.name),
+            escape(render.locals.entityKind), escape(&self.name))?;
 
-    #[cfg(test)]
-    fn get_rects(&self, entity: &str) -> Vec<(u64, u64, Vec<u64>)> {
-        let mut rval: Vec<(u64, u64, Vec<u64>)>;
+        for i in 0..map.len() {
+            let rect = self.rects.get(&(map[i] as u64)).unwrap().borrow();
+            let w = rect_width(&rect);
+            let (mut datum, color) = rect_datum(&rect, colors);
 
-        let e = self.entities.get(entity);
+            x = ((map[i] - begin) as f64 /
+                globals.timeWidth as f64) * globals.pixelWidth as f64;
 
-        match e {
-            Some(entity) => {
-                rval = entity.rects.values().map(|r| {
-                    let rect = r.borrow();
+            datum.push_str("}");
+            data.push(datum);
 
-                    (rect.start, rect.duration, rect.states.clone())
-                }).collect();
+            writeln!(out, concat!(r##"<rect x="{}" y="{}" width="{}" "##,
+                r##"height="{}" onclick="mapclick(evt, {})" "##,
+                r##"style="fill:{}" />"##), x, y, w,
+                config.stripHeight, data.len() - 1, color)?;
 
-                rval.sort();
-            },
-            None => { rval = vec![]; }
+            /*
+             * If this blended rectangle contains time in a salient state,
+             * its color may well not reflect it; we mark it with a bar in
+             * the color of the salient state that it contains the most of.
+             * Because the viewer expects an entity to consist only of its
+             * rectangles, these markers are returned to be drawn after it.
+             */
+            if let Some(salient) = rect_salient(&rect, render.locals.states) {
+                markers.push(format!(concat!(r##"<rect x="{}" y="{}" "##,
+                    r##"width="{}" height="{}" class="statemap-salient" "##,
+                    r##"style="fill:{}" />"##), x, y, w.max(1.0),
+                    cmp::max(config.stripHeight / 4, 1), colors[salient]));
+            }
+
+            x += w;
         }
 
-        rval
+        writeln!(out, "</g>")?;
+
+        /*
+         * Finally, add a background rectangle that covers whatever remains
+         * of our width.
+         */
+        background(out, x, globals.pixelWidth as f64 - x)?;
+
+        Ok(data)
     }
 
     /*
-     * Ingest and advance `payload` past the metadata JSON object.
+     * Return the data for the specified finer level of detail.  These
+     * rectangles are drawn by the viewer rather than in the SVG, so each
+     * datum additionally carries its duration and color.
      */
-    fn ingest_metadata(&mut self, payload: &mut &str)
-        -> Result<(), Box<dyn Error>>
+    fn output_level(&self, level: usize, colors: &Vec<StatemapColor>)
+        -> Vec<String>
     {
-        let metadata: StatemapInputMetadata = match try_parse(payload)? {
-            None => return self.err("missing metadata payload"),
-            Some(metadata) => metadata,
-        };
+        self.level(level).iter().map(|rect| {
+            let (mut datum, color) = rect_datum(rect, colors);
+            datum.push_str(&format!(", d: {}, c: '{}' }}", rect.duration,
+                color));
+            datum
+        }).collect()
+    }
 
-        let nstates = metadata.states.len();
-        let mut states: Vec<Option<StatemapState>> = vec![None; nstates];
+    #[cfg(test)]
+    fn print(&self, header: &str) {
+        let mut v: Vec<u64>;
+        let l: usize;
+        
+        v = self.rects.values().map(|r| r.borrow().start).collect();
+        v.sort();
+        l = v.len();
 
-        if metadata.start.len() != 2 {
-            return self.err(concat!("\"start\" property must be a ",
-                "two element array"));
+        for i in 0..l {
+            let me = self.rects.get(&v[i]).unwrap().borrow();
+            println!("{}: entity={}: [{}] {:?}: {:?}",
+                header, self.id, i, v[i], me);
         }
 
-        for (key, value) in &metadata.states {
-            let ndx = value.value;
+        println!("{}: entity={}: last is {:?}", header, self.id, self.last);
+    }
 
-            if ndx >= nstates {
-                let errmsg = format!(concat!("state \"{}\" has value ({}) ",
-                    "that exceeds maximum allowed value ({})"),
-                    key, ndx, nstates - 1);
-                return self.err(&errmsg);
-            }
+    #[cfg(test)]
+    fn verify(&self) {
+        let mut v: Vec<u64>;
+        let l: usize;
+        
+        v = self.rects.values().map(|r| r.borrow().start).collect();
+        v.sort();
+        l = v.len();
 
-            if ndx < states.len() && states[ndx].is_some() {
-                let errmsg = format!(concat!("state \"{}\" has value ",
-                    "({}) that conflicts with state \"{}\""), key,
-                    ndx, states[ndx].as_ref().unwrap().name);
+        for i in 0..l {
+            let me = self.rects.get(&v[i]).unwrap().borrow();
+            let mut weight = me.mass();
 
-                return self.err(&errmsg);
+            if i < l - 1 {
+                let next = self.rects.get(&v[i + 1]).unwrap().borrow();
+                assert_eq!(me.next, Some(next.start));
+                assert!(me.start < next.start);
+                weight += next.mass();
+            } else {
+                assert_eq!(me.next, None);
+                assert_eq!(self.last, Some(me.start));
             }
 
-            states[ndx] = Some(StatemapState {
-                name: key.to_string(),
-                value: ndx,
-                color: match value.color {
-                    Some(ref str) => { Some(str.to_string()) },
-                    None => { None }
-                }
-            });
-        }
+            if i > 0 {
+                let prev = self.rects.get(&v[i - 1]).unwrap().borrow();
+                assert_eq!(me.prev, Some(prev.start));
+                assert!(me.start > prev.start);
+                weight += prev.mass();
+            } else {
+                assert_eq!(me.prev, None);
+            }
 
-        assert_eq!(self.states.len(), 0);
+            assert_eq!(me.weight, weight);
 
-        /*
-         * We have verified our states; now pull them into our array.
-         */
-        for _i in 0..nstates {
-            self.states.push(states.remove(0).unwrap());
+            if let Some(ref tags) = me.tags {
+                let duration = tags.iter().fold(0,
+                    |i, (_id, duration)| { i + duration });
+
+                /*
+                 * This is technically a more vigorous assertion than we can
+                 * make:  we actually allow for partial tagging in that not
+                 * all states must by tagged all of the time.  For the moment,
+                 * though, we assert that if any states have been tagged, all
+                 * have been.
+                 */
+                assert_eq!(duration, me.duration);
+            }
         }
+    }
 
-        self.metadata = Some(metadata);
+    #[cfg(test)]
+    fn subsume_apply_and_verify(&mut self, victim: u64) ->
+        Vec<(u64, u64, Option<u64>)>
+    {
+        println!("=== Subsuming {}", victim);
+        self.print(&format!("Before subsuming {}", victim));
+        self.verify();
 
-        Ok(())
+        let tup = self.subsume(victim);
+        println!("Weight delta from subsuming {}: {:?}", victim, tup);
+
+        self.print(&format!("After subsuming {}, before applying", victim));
+        let updates = self.apply(tup);
+
+        self.print(&format!("After subsuming {}, after applying", victim));
+        self.verify();
+        updates
     }
+}
 
-    fn ingest_end(&mut self) {
-        assert!(!self.config.abstime);
+enum Ingest {
+    Success,
+    EndOfFile,
+}
 
-        if self.config.end < 0 {
-            return;
+fn try_parse<'de, T>(content: &mut &'de str)
+    -> Result<Option<T>, serde_json::Error>
+where
+    T: serde::Deserialize<'de>
+{
+    let mut de = serde_json::Deserializer::from_str(*content).into_iter();
+    match de.next() {
+        Some(Ok(value)) => {
+            *content = &content[de.byte_offset()..];
+            Ok(Some(value))
         }
+        Some(Err(err)) => Err(err),
+        None => Ok(None),
+    }
+}
 
-        let mut end = self.last;
-        let begin = self.config.begin;
+fn try_parse_raw<'de, T>(content: &mut &'de str)
+    -> Result<Option<(T, serde_json::Value)>, serde_json::Error>
+where
+    T: serde::Deserialize<'de>
+{
+    let mut de = serde_json::Deserializer::from_str(*content).into_iter();
+    let offset = de.byte_offset();
 
-        /*
-         * If we've been given an ending time and it's less than the last
-         * rectangle for which we have state, we'll use that.
-         */
-        if self.config.end != 0 && self.config.end < end as i64 {
-            end = self.config.end as u64;
+    match de.next() {
+        Some(Ok(value)) => {
+            let v: serde_json::Value =
+                serde_json::from_str(&content[offset..de.byte_offset()])?;
+
+            *content = &content[de.byte_offset()..];
+
+            Ok(Some((value, v)))
         }
+        Some(Err(err)) => Err(err),
+        None => Ok(None),
+    }
+}
 
-        let nstates = self.states.len() as u32;
+/*
+ * Compressed input is detected by its magic number, and is transparently
+ * decompressed as it is ingested.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum StatemapCompression {
+    Gzip,
+    Zstd,
+    Xz,
+}
 
-        for entity in self.entities.values_mut() {
-            match entity.start {
-                Some(start) if start < end => {
-                    /*
-                     * If our start time is less than our begin time then
-                     * this entity must be in a single state for our entire
-                     * specified time -- and we move our start time up to our
-                     * begin time (and assert that we have no rectangles).
-                     */
-                    if begin != 0 && (start as i64) < begin {
-                        assert!(entity.rects.is_empty());
-                        entity.start = Some(begin as u64);
-                    }
+impl StatemapCompression {
+    const MAGIC: &'static [(&'static [u8], StatemapCompression)] = &[
+        (&[0x1f, 0x8b], StatemapCompression::Gzip),
+        (&[0x28, 0xb5, 0x2f, 0xfd], StatemapCompression::Zstd),
+        (&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00], StatemapCompression::Xz),
+    ];
 
-                    /*
-                     * We are adding a rectangle, but because we are now done
-                     * with ingestion, we are not updating the rectangle weight
-                     * tree and we are not going to subsume any rectangles; we
-                     * can safely ignore the return value.
-                     */
-                    entity.newrect(end, nstates);
+    /*
+     * The number of bytes needed to recognize any compressed input.
+     */
+    const HEADER: usize = 6;
 
-                    /*
-                     * Even though we expect no other ingestion, we set our
-                     * last to allow for state to be verified.
-                     */
-                    entity.last = entity.start;
-                },
-                _ => {}
+    fn detect(header: &[u8]) -> Option<StatemapCompression> {
+        for &(magic, compression) in StatemapCompression::MAGIC {
+            if header.starts_with(magic) {
+                return Some(compression);
             }
         }
 
-        let metadata = self.metadata.as_ref().unwrap();
-        let start = (metadata.start[0] * 1_000_000_000) + metadata.start[1];
-        self.begin = (self.config.begin + start as i64) as u64;
-        self.end = cmp::max(end, self.config.end as u64) + start;
+        None
     }
+}
+
+/*
+ * Input that isn't concatenated JSON may be a trace in a format that we know
+ * how to import; we detect this by the first character of the input that
+ * isn't whitespace -- and, if the input is an object, by the name of its
+ * first member.  Other input is taken to be a scheduler trace only if its
+ * first lines bear that out; anything else is left to fail as JSON.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum StatemapImport {
+    Sched,                                  // text of scheduler events
+    Chrome,                                 // Chrome trace events
+}
 
+impl StatemapImport {
     /*
-     * Ingest and advance `payload` past one JSON object datum.
+     * The number of bytes beyond which we won't look for the name of the
+     * first member of an object.
      */
-    fn ingest_datum(&mut self, payload: &mut &str)
-        -> Result<Ingest, Box<dyn Error>>
-    {
-        match try_parse::<StatemapInputDatum>(payload) {
-            Ok(None) => return Ok(Ingest::EndOfFile),
-            Ok(Some(datum)) => {
-                let time: u64 = datum.time;
-                let nstates: u32 = self.states.len() as u32;
-
-                self.last = time;
+    const HEADER: usize = 128;
 
-                /*
-                 * If the time of this datum is after our specified end time,
-                 * we have nothing further to do to process it.
-                 */
-                if self.config.end != 0 && time as i64 > self.config.end {
-                    return Ok(Ingest::Success);
-                }
-
-                if datum.state >= nstates {
-                    return self.err("illegal state value");
-                }
+    fn member(header: &[u8]) -> Option<&[u8]> {
+        let skip = |bytes: &[u8]| bytes.iter()
+            .position(|b| !b.is_ascii_whitespace());
 
-                let begin = self.config.begin;
-                let mut errmsg: Option<String> = None;
-                let mut insert: Option<StatemapRectWeight> = None;
-                let mut update: Option<(StatemapRectWeight, u64)> = None;
-                let tag = self.tag_lookup(datum.state, &datum.tag);
+        let rest = &header[skip(header)?..];
 
-                /*
-                 * We are going to do a lookup of our entity, but this will
-                 * cause us to lose our reference on self (mutable or
-                 * otherwise) -- which we need to fully record any error.  To
-                 * implement this absent non-lexical lifetimes, we put the
-                 * entity in a lexical scope implemented with "loop" so we
-                 * can break out of it on an error condition.
-                 */
-                loop {
-                    let name = &datum.entity;
-                    let entity = self.entity_lookup(name);
-
-                    match entity.start {
-                        Some(start) => {
-                            if time < start {
-                                errmsg = Some(format!(concat!("time {} is out",
-                                    " of order with respect to prior time {}"),
-                                    time, start));
-                                break;
-                            }
+        if !rest.starts_with(b"{") {
+            return None;
+        }
 
-                            if (time as i64) > begin {
-                                /*
-                                 * We can now create a new rectangle for this
-                                 * entity's past state.
-                                 */
-                                if begin > 0 && start < (begin as u64) {
-                                    entity.start = Some(begin as u64);
-                                }
-
-                                let rval = entity.newrect(time, nstates);
-                                entity.last = entity.start;
-
-                                match rval.0 {
-                                    Some(rect) => {
-                                        update = Some((StatemapRectWeight {
-                                            weight: rect.1,
-                                            start: rect.0,
-                                            entity: entity.id
-                                        }, rect.2));
-                                    }
-                                    None => {}
-                                }
-
-                                insert = Some(StatemapRectWeight {
-                                    weight: (rval.1).1,
-                                    start: (rval.1).0,
-                                    entity: entity.id
-                                });
-                            }
-                        }
-                        None => {}
-                    }
+        let rest = &rest[1..];
+        let rest = &rest[skip(rest)?..];
 
-                    entity.start = Some(time);
-                    entity.state = Some(datum.state);
-                    entity.tag = tag;
-                    break;
-                }
+        if !rest.starts_with(b"\"") {
+            return None;
+        }
 
-                if errmsg.is_some() {
-                    return self.err(&errmsg.unwrap());
-                }
+        let rest = &rest[1..];
+        Some(&rest[..rest.iter().position(|&b| b == b'"')?])
+    }
 
-                if update.is_some() {
-                    let mut rweight = update.unwrap().0;
-                    self.byweight.remove(&rweight);
-                    rweight.weight = update.unwrap().1;
-                    self.byweight.insert(rweight);
+    fn detect(header: &[u8]) -> Option<StatemapImport> {
+        match header.iter().find(|b| !b.is_ascii_whitespace()) {
+            None => None,
+            Some(&b'[') => Some(StatemapImport::Chrome),
+            Some(&b'{') => match StatemapImport::member(header) {
+                Some(member) if CHROME_KEYS.iter()
+                    .any(|key| key.as_bytes() == member) => {
+                    Some(StatemapImport::Chrome)
                 }
+                _ => None
+            },
+            Some(_) => match sched_detect(header, true) {
+                Some(true) => Some(StatemapImport::Sched),
+                _ => None,
+            },
+        }
+    }
 
-                if insert.is_some() {
-                    self.byweight.insert(insert.unwrap());
-                }
+    /*
+     * Returns true if more of the input is needed for detection.
+     */
+    fn partial(header: &[u8]) -> bool {
+        match header.iter().find(|b| !b.is_ascii_whitespace()) {
+            None => true,
+            Some(&b'{') => StatemapImport::member(header).is_none() &&
+                header.len() < StatemapImport::HEADER,
+            Some(&b'[') => false,
+            Some(_) => sched_detect(header, false).is_none(),
+        }
+    }
+}
+
+enum StatemapDecoder<R: Read> {
+    Plain(R),
+    Gzip(MultiGzDecoder<R>),
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<R>>),
+    Xz(XzDecoder<R>),
+}
 
-                return Ok(Ingest::Success);
+impl<R: Read> StatemapDecoder<R> {
+    fn new(reader: R, compression: Option<StatemapCompression>)
+        -> Result<Self, Box<dyn Error>>
+    {
+        Ok(match compression {
+            None => StatemapDecoder::Plain(reader),
+            Some(StatemapCompression::Gzip) => {
+                StatemapDecoder::Gzip(MultiGzDecoder::new(reader))
             }
-            Err(_) => {}
+            Some(StatemapCompression::Zstd) => {
+                StatemapDecoder::Zstd(zstd::stream::read::Decoder::new(reader)?)
+            }
+            Some(StatemapCompression::Xz) => {
+                StatemapDecoder::Xz(XzDecoder::new_multi_decoder(reader))
+            }
+        })
+    }
+}
+
+impl<R: Read> Read for StatemapDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            StatemapDecoder::Plain(ref mut r) => r.read(buf),
+            StatemapDecoder::Gzip(ref mut r) => r.read(buf),
+            StatemapDecoder::Zstd(ref mut r) => r.read(buf),
+            StatemapDecoder::Xz(ref mut r) => r.read(buf),
         }
+    }
+}
 
-        match try_parse::<StatemapInputDescription>(payload) {
-            Ok(None) => return Ok(Ingest::EndOfFile),
-            Ok(Some(datum)) => {
-                let entity = self.entity_lookup(&datum.entity);
-                entity.description = Some(datum.description.to_string());
+/*
+ * When ingesting from a stream (e.g., a pipe or standard input), we cannot
+ * simply map the input; we instead read it in chunks of this size.
+ */
+const STREAM_CHUNK: usize = 64 * 1024;
 
-                return Ok(Ingest::Success);
-            }
-            Err(_) => {}
+/*
+ * A buffered view of concatenated JSON payloads coming from an arbitrary
+ * reader.  Payloads are handed out as complete strings, allowing them to be
+ * parsed with the same machinery that we use for mapped files.
+ */
+struct StatemapStream<R: Read> {
+    reader: R,                              // underlying reader
+    buf: String,                            // buffered (valid UTF-8) input
+    partial: Vec<u8>,                       // trailing partial character
+    pos: usize,                             // offset of unconsumed input
+    lines: usize,                           // lines discarded from buf
+    eof: bool,                              // reader has been exhausted
+}
+
+impl<R: Read> StatemapStream<R> {
+    fn new(reader: R) -> Self {
+        StatemapStream {
+            reader: reader,
+            buf: String::new(),
+            partial: vec![],
+            pos: 0,
+            lines: 0,
+            eof: false,
         }
+    }
 
-        match try_parse::<StatemapInputEvent>(payload) {
-            Ok(None) => return Ok(Ingest::EndOfFile),
-            Ok(Some(_datum)) => {
-                /*
-                 * Right now, we don't do anything with events -- but the
-                 * intent is to be able to render these in the statemap, so
-                 * we also don't reject them.
-                 */
-                self.nevents += 1;
+    /*
+     * Discard any consumed input and read another chunk from our reader.
+     */
+    fn fill(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.pos > 0 {
+            self.lines += self.buf.as_bytes()[..self.pos].iter()
+                .filter(|&&b| b == b'\n').count();
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
 
-                return Ok(Ingest::Success);
+        let mut chunk = vec![0; STREAM_CHUNK];
+        let len = self.partial.len();
+        chunk[..len].copy_from_slice(&self.partial);
+
+        let nread = loop {
+            match self.reader.read(&mut chunk[len..]) {
+                Ok(nread) => break nread,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(Box::new(err)),
             }
-            Err(_) => {}
+        };
+
+        chunk.truncate(len + nread);
+        self.partial.clear();
+
+        if nread == 0 {
+            self.eof = true;
         }
 
-        match try_parse_raw::<StatemapInputTag>(payload) {
-            Ok(None) => return Ok(Ingest::EndOfFile),
-            Ok(Some((datum, value))) => {
-                if self.config.notags {
-                    return Ok(Ingest::Success);
+        /*
+         * Our chunk may end in the middle of a multi-byte character; we
+         * hang on to any such trailing bytes until our next read.
+         */
+        let valid = match str::from_utf8(&chunk) {
+            Ok(_) => chunk.len(),
+            Err(err) => {
+                if err.error_len().is_some() || self.eof {
+                    return Err(Box::new(err));
                 }
 
-                /*
-                 * We allow tags to be redefined, so we need to first lookup
-                 * our tag to see if it exists -- and if it does, we need
-                 * to use the existing ID.
-                 */
-                let id;
-
-                match self.tags.get(&(datum.state, datum.tag.to_string())) {
-                    Some((_value, idr)) => { id = *idr }
-                    None => { id = self.tags.len() }
-                };
+                err.valid_up_to()
+            }
+        };
 
-                self.tags.insert((datum.state, datum.tag), (value, id));
+        self.partial.extend_from_slice(&chunk[valid..]);
+        self.buf.push_str(str::from_utf8(&chunk[..valid])?);
 
-                return Ok(Ingest::Success);
+        Ok(())
+    }
 
+    /*
+     * Return the next complete JSON payload (including any whitespace that
+     * precedes it), or None if we have reached the end of our input.  If
+     * the next payload is malformed, it is returned as is for the caller to
+     * fail to parse.  Our position is not advanced; the caller is expected
+     * to do so via consume().
+     */
+    fn next(&mut self) -> Result<Option<&str>, Box<dyn Error>> {
+        let end = loop {
+            let contents = &self.buf[self.pos..];
+            let mut de = serde_json::Deserializer::from_str(contents)
+                .into_iter::<serde::de::IgnoredAny>();
+
+            match de.next() {
+                Some(Ok(_)) => break Some(de.byte_offset()),
+                Some(Err(ref err)) if err.is_eof() && !self.eof => {}
+                Some(Err(_)) => break Some(contents.len()),
+                None if self.eof => break None,
+                None => {}
             }
-            Err(_) => {}
-        }
 
-        self.err("unrecognized payload")
+            self.fill()?;
+        };
+
+        match end {
+            Some(end) => Ok(Some(&self.buf[self.pos..self.pos + end])),
+            None => Ok(None)
+        }
     }
 
-    pub fn ingest(&mut self, filename: &str) -> Result<(), Box<dyn Error>> {
-        let file = File::open(filename)?;
-        let mut nrecs = 0;
+    fn consume(&mut self, len: usize) {
+        self.pos += len;
+    }
 
-        /*
-         * Unsafe because Rust cannot enforce that the underlying data on
-         * filesystem is not mutated while our program contains a &[u8]
-         * reference to it. Mutating the file would result in undefined
-         * behavior.
-         */
-        let mmap = unsafe { MmapOptions::new().map(&file)? };
-        let mut contents = str::from_utf8(&mmap[..])?;
-        let len = contents.len();
+    /*
+     * Return the line number corresponding to the specified offset from
+     * our current position.
+     */
+    fn line_number(&self, offset: usize) -> usize {
+        self.lines + line_number(self.buf.as_bytes(), self.pos + offset)
+    }
+}
 
-        self.ingest_metadata(&mut contents)?;
+fn line_number(mmap: &[u8], byte_offset: usize) -> usize {
+    let mut nls = mmap[..byte_offset].iter().filter(|&&b| b == b'\n').count();
 
-        /*
-         * If our time was presented as absolute time, we will now convert it
-         * to be relative to our (now known) start time.
-         */
-        if self.config.abstime {
-            let metadata = self.metadata.as_ref().unwrap();
-            let start = (metadata.start[0] * 1_000_000_000 +
-                metadata.start[1]) as i64;
+    /*
+     * We report the line number of the first non-whitespace character after
+     * byte_offset.
+     */
+    for b in mmap[byte_offset..].iter() {
+        if *b == b'\n' {
+            nls += 1;
+        }
 
-            self.config.begin -= start;
-            self.config.end -= start;
-            self.config.abstime = false;
+        if !((*b as char).is_whitespace()) {
+            break;
         }
+    }
 
-        /*
-         * Now rip through our data pulling out concatenated JSON payloads.
-         */
-        loop {
-            match self.ingest_datum(&mut contents) {
-                Ok(Ingest::Success) => nrecs += 1,
-                Ok(Ingest::EndOfFile) => break,
-                Err(err) => {
-                    /*
-                     * Lazily compute the line number for our error message.
-                     */
-                    let remaining_len = contents.len();
-                    let byte_offset = len - remaining_len;
-                    let line = line_number(&mmap, byte_offset);
-                    let message =
-                        format!("illegal datum on line {}: {}", line, err);
-                    return self.err(&message);
-                }
-            }
+    nls + 1
+}
 
-            while self.byweight.len() >= self.config.maxrect as usize {
-                self.trim();
-            }
-        }
-        
-        self.ingest_end();
+/*
+ * The time value is written in the input as a JSON string containing a number.
+ * Deserialize just the number here without allocating memory for a String.
+ */
+fn datum_time_from_string<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s: &str = serde::Deserialize::deserialize(deserializer)?;
+    match u64::from_str(s) {
+        Ok(time) => Ok(time),
+        Err(_) => Err(serde::de::Error::custom("illegal time value")),
+    }
+}
 
-        eprintln!("{}: {} records processed, {} rectangles",
-            Path::new(filename).file_name().unwrap().to_string_lossy(),
-            nrecs, self.byweight.len());
-        Ok(())
+impl Statemap {
+    /**
+     * Create a new, empty statemap.  Data is added to it via `ingest` or
+     * `ingest_stream`.
+     */
+    pub fn new(config: &Config) -> Self {
+        Statemap {
+            config: *config,
+            nrecs: 0,
+            nevents: 0,
+            entities: HashMap::new(),
+            states: Vec::new(),
+            byid: Vec::new(),
+            byweight: BTreeSet::new(),
+            metadata: None,
+            tags: HashMap::new(),
+            events: HashMap::new(),
+            resolutions: Vec::new(),
+            utilization: None,
+            salient: Vec::new(),
+            begin: 0,
+            end: 0,
+            last: 0,
+        }
     }
 
-    pub fn timebounds(&self) -> (u64, u64) {
-        (self.begin, self.end)
+    fn err<T>(&self, msg: &str) -> Result<T, Box<dyn Error>>  {
+        Err(Box::new(StatemapError::new(msg)))
     }
 
-    fn output_defs(&self) {
+    fn entity_lookup(&mut self, name: &str) -> &mut StatemapEntity {
         /*
-         * Provide an "entities" member that has the descriptions for each
-         * entity, if they have one.  Yes, this is a little goofy -- it
-         * would make much more sense to have a "descriptions" member that
-         * consists of strings named by entity -- but we're doing this for
-         * the sake of compatibility with the legacy implementation, however
-         * dubious..
+         * The lack of non-lexical lifetimes causes this code to be a bit
+         * gnarlier than it should really have to be.
          */
-        println!("entities: {{");
-
-        let mut comma = "";
-
-        for entity in self.entities.values() {
-            let val = match entity.description {
-                Some(ref description) => {
-                    format!("description: \"{}\"", description)
-                }
-                _ => { "".to_string() }
+        if self.entities.contains_key(name) {
+            return match self.entities.get_mut(name) {
+                Some(entity) => { entity },
+                None => unreachable!()
             };
-
-            println!("    {} \"{}\": {{ {} }}", comma, entity.name, val);
-            comma = ",";
         }
 
-        println!("}}");
-
-        if self.tags.len() > 0 {
-            /*
-             * Pull our tags into a Vec so we can sort them and emit them in
-             * array order.
-             */
-            let mut tags: Vec<(usize, u32, &str)> = vec![];
+        let nstates = self.states.len() as u32;
+        let entity = StatemapEntity::new(name, self.byid.len(), nstates,
+            self.config.durations);
+        self.byid.push(name.to_string());
 
-            for ((state, tag), (_value, id)) in self.tags.iter() {
-                tags.push((*id, *state, tag));
-            }
+        self.entities.insert(name.to_string(), entity);
+        self.entities.get_mut(name).unwrap()
+    }
 
-            tags.sort_unstable();
+    fn tag_lookup(&mut self, state: u32, tagr: &Option<String>)
+        -> Option<usize>
+    {
+        if self.config.notags {
+            return None;
+        }
 
-            println!(", tags: [");
+        match *tagr {
+            Some(ref tag) => {
+                let id;
 
-            for i in 0..tags.len() {
-                let (value, id) =
-                    self.tags.get(&(tags[i].1, tags[i].2.to_string())).unwrap();
+                match self.tags.get(&(state, tag.to_string())) {
+                    Some(( _value, idr)) => { return Some(*idr); },
+                    None => { id = self.tags.len(); }
+                }
 
-                assert_eq!(i, *id);
-                println!("{}{}", serde_json::to_string_pretty(value).unwrap(),
-                    if i < tags.len() - 1 { "," } else { "" });
-            }
+                let value = json!({ "state": state, "tag": tag.to_string() });
 
-            println!("]");
+                self.tags.insert((state, tag.to_string()), (value, id));
+                Some(id)
+            },
+            None => None
         }
     }
 
-    fn output_svg(&self, id: usize, config: &StatemapSVGConfig,
-        globals: &StatemapSVGGlobals,
-        colors: &Vec<StatemapColor>) -> Result<(), Box<dyn Error>>
+    /*
+     * Takes a vector of updates to apply to our byweight tree as well as a
+     * template rectangle weight and applies the updates.
+     */
+    fn apply(&mut self, updates: Vec<(u64, u64, Option<u64>)>,
+        rweight: &mut StatemapRectWeight)
     {
-        let output_data = |data: &HashMap<&String, Vec<String>>| {
-            println!("\"data\": {{ ");
-            let mut comma = "";
-
-            for entity in data.keys() {
-                println!("{}\"{}\": [", comma, entity);
-
-                let datum = data.get(entity).unwrap();
+        for i in 0..updates.len() {
+            rweight.start = updates[i].0;
+            rweight.weight = updates[i].1;
 
-                if datum.len() > 0 {
-                    for i in 0..datum.len() - 1 {
-                        println!("{},", datum[i]);
-                    }
+            self.byweight.remove(rweight);
 
-                    println!("{}", datum[datum.len() - 1]);
+            match updates[i].2 {
+                Some(delta) => {
+                    rweight.weight += delta;
+                    self.byweight.insert(*rweight);
                 }
-
-                println!("]");
-                comma = ",";
+                None => {}
             }
+        }
+    }
 
-            println!(r##"}},"##);
-        };
-
-        let metadata = match self.metadata {
-            Some(ref metadata) => { metadata }
-            _ => { return self.err("metadata not found in data stream"); }
-        };
+    /*
+     * Subsumes the rectangle of least weight, applies the deltas to the
+     * entity corresponding to that rectangle, and then applies the
+     * resulting rectangle weight updates.
+     */
+    fn trim(&mut self) {
+        let mut remove: StatemapRectWeight;
+        let updates;
 
+        remove = *self.byweight.iter().next().unwrap();
+        self.byweight.remove(&remove);
+        
         /*
-         * Sort our entities, by whatever criteria has been specified.
+         * We need a scope here to help the compiler out with respect to
+         * our use of entity.
          */
+        {
+            let name = &self.byid[remove.entity];
+            let entity = self.entities.get_mut(name).unwrap();
+
+            if entity.rects.len() == 1 {
+                /*
+                 * If this entity only has one rectangle, than there is
+                 * nothing to subsume; we simply return.  (This weight has
+                 * already been removed, so we won't find it again until
+                 * another rectangle is added for this entity.)
+                 */
+                return;
+            }
+
+            let deltas = entity.subsume(remove.start);
+            updates = entity.apply(deltas);
+        }
+
+        self.apply(updates, &mut remove);
+    }
+
+    #[must_use]
+    fn sort(&self, sortby: Option<usize>) -> Vec<usize>
+    {
+        let mut v: Vec<(u64, &String, usize)>;
+
+        let values = self.entities.values();
+
+        match sortby {
+            None => { v = values.map(|e| (0, &e.name, e.id)).collect(); },
+            Some(state) => {
+                v = values.map(|e| {
+                    let ttl = e.rects.values().fold(0, |i, r| {
+                        i + r.borrow().states[state]
+                    });
+
+                    (ttl, &e.name, e.id)
+                }).collect();
+            }
+        }
+
+        v.sort_by(|&a, &b| {
+            let result = b.0.cmp(&a.0);
+
+            if result == cmp::Ordering::Equal {
+                natord::compare(a.1, b.1)
+            } else {
+                result
+            }
+        });
+
+        v.iter().map(|e| e.2).collect()
+    }
+
+    /*
+     * Return the total time spent across all entities and all rectangles
+     * in a particular state, for purposes of assigning a weight to the
+     * statemap itself.
+     */
+    /*
+     * Sort our entities, by whatever criteria has been specified.
+     */
+    fn sortby(&self, config: &StatemapSVGConfig)
+        -> Result<Vec<usize>, Box<dyn Error>>
+    {
+        let metadata = match self.metadata {
+            Some(ref metadata) => { metadata }
+            _ => { return self.err("metadata not found in data stream"); }
+        };
+
         let sort = match config.sortby {
             None => None,
             Some(ref sortby) => {
@@ -1678,1380 +2218,5773 @@ impl Statemap {
             }
         };
 
-        let entities = self.sort(sort);
-
-        println!(r##"<g id="statemap-{}" transform="matrix(1 0 0 1 0 0)">"##,
-            id);
+        Ok(self.sort(sort))
+    }
 
-        let mut y = 0;
-        let mut data = HashMap::new();
-        let mut title = metadata.title.clone();
+    /*
+     * The span of time (in nanoseconds) covered by this statemap when it
+     * is rendered.
+     */
+    fn time_width(&self) -> u64 {
+        let width = self.entities.values().fold(self.config.end,
+            |latest, e| {
+                match e.start {
+                    Some(start) => cmp::max(latest, start as i64),
+                    None => latest
+               }
+            }) - self.config.begin;
 
-        if let Some(ref host) = metadata.host {
-            title.push_str(&format!(" on {}", host));
-        }
+        assert!(width >= 0);
+        width as u64
+    }
 
-        let locals = StatemapSVGLocals {
-            offset: self.config.begin - globals.begin,
-            states: &self.states,
-            entityKind: match metadata.entityKind {
-                Some(ref kind) => { kind }
-                None => { "Entity" }
-            },
-            title: title,
-        };
+    fn weight(&self, state: usize) -> u64
+    {
+        self.entities.values().fold(0, |ttl, e| {
+            ttl + e.rects.values().fold(0, |i, r| {
+                i + r.borrow().states[state]
+            })
+        })
+    }
 
-        for e in entities {
-            let entity = self.entities.get(self.byid.get(e).unwrap()).unwrap();
-            data.insert(&entity.name, entity.output_svg(id,
-                self.config.begin, config, globals, &locals, &colors, y));
-            y += config.stripHeight;
+    #[cfg(test)]
+    fn verify(&self) {
+        /*
+         * First, verify each of the entities.
+         */
+        for entity in self.entities.values() {
+            entity.verify();
         }
 
-        println!("</g>");
-
         /*
-         * Finally, output our element in the global statemaps array.
+         * Verify that each rectangle in each entity can be found in our
+         * byweight set -- and that the weights match.
          */
-        println!("<defs>");
-        println!(r##"<script type="application/ecmascript"><![CDATA["##);
-
-        let str = serde_json::to_string_pretty(&locals).unwrap();
-
-        println!("g_statemaps[{}] = {{\n{},", id, &str[2..str.len() - 2]);
-
-        output_data(&data);
-        self.output_defs();
-
-        println!(r##"}} ]]></script></defs>"##);
+        for entity in self.entities.values() {
+            for cell in entity.rects.values() {
+                let rect = cell.borrow();
 
-        Ok(())
-    }
-}
+                let rweight = StatemapRectWeight {
+                    entity: entity.id,
+                    weight: rect.weight,
+                    start: rect.start
+                };
 
-impl<'a> StatemapSVG<'a> {
-    pub fn new(config: &'a StatemapSVGConfig) -> Self {
-        StatemapSVG {
-            config: config
+                assert!(self.byweight.contains(&rweight) ||
+                    entity.rects.len() == 1 ||
+                    Some(rect.start) == entity.last ||
+                    rect.next == entity.last);
+            }
         }
-    }
-
-    fn output_defs(&self, globals: &StatemapSVGGlobals)
-    {
-        println!("<defs>");
-
-        println!("<script type=\"application/ecmascript\"><![CDATA[");
-
-        println!("var globals = {{");
-        let str = serde_json::to_string_pretty(&self.config).unwrap();
-        println!("{},", &str[2..str.len() - 2]);
-
-        let str = serde_json::to_string_pretty(&globals).unwrap();
-        println!("{},", &str[2..str.len() - 2]);
-        println!("}}");
-
-        /*
-         * Now drop in our in-SVG code.
-         */
-        let lib = include_str!("statemap-svg.js");
 
-        println!("{}\n]]></script>", lib);
+        let mut present = HashSet::new();
 
         /*
-         * Next up: CSS.
+         * Verify that each entity is valid that each entity/start tuple is
+         * present exactly once.
          */
-        let css = include_str!("statemap-svg.css");
+        for rweight in self.byweight.iter() {
+            let name = &self.byid[rweight.entity];
+            let tup = (rweight.entity, rweight.start);
 
-        println!("<style type=\"text/css\"><![CDATA[\n{}\n]]></style>", css);
+            assert!(self.entities.get(name).is_some());
 
-        /*
-         * And now other definitions.
-         */
-        let defs = include_str!("statemap-svg.defs");
-        println!("{}", defs);
+            let entity = self.entities.get(name).unwrap();
 
-        println!("</defs>");
+            assert!(entity.rects.get(&rweight.start).is_some());
+            assert!(!present.contains(&tup));
+            present.insert(tup);
+        }
     }
 
-    fn title(&self, statemaps: &Vec<Statemap>) -> String
-    {
-        /*
-         * A little helper routine to generate an Oxford comma-separated
-         * list.
-         */
-        let oxford = |v: &Vec<String>| {
-            let map: HashSet<_> = v.iter().collect();
+    #[cfg(test)]
+    fn subsume_apply_and_verify(&mut self, what: &str, victim: u64) {
+        let id: usize;
+        let mut weight: Option<u64> = None;
+        let updates;
 
-            if map.len() == 1 {
-                v[0].clone()
-            } else if v.len() == 2 {
-                v.join(" and ")
-            } else {
-                (&v[0..v.len() - 1]).join(", ") + ", and " + &v[v.len() - 1]
-            }
-        };
+    {
+        let entity = self.entity_lookup(what);
+            updates = entity.subsume_apply_and_verify(victim);
+            id = entity.id;
+        }
 
         /*
-         * If we have "statemap" anywhere in the title of the first statemap,
-         * we assume it's a legacy title and just use that.
+         * Before we verify, remove the victim -- if it wasn't actually
+         * to be removed, we'll add it back when we apply the updates.
          */
-        let metadata = statemaps[0].metadata.as_ref().unwrap();
-
-        if metadata.title.find("tatemap").is_some() {
-            return metadata.title.clone();
+        for rweight in self.byweight.iter() {
+            if rweight.entity == id && rweight.start == victim {
+                assert!(weight.is_none());
+                weight = Some(rweight.weight);
+            }
         }
 
-        /*
-         * Gather our titles.
-         */
-        let titles = statemaps.iter()
-            .filter_map(|s| s.metadata.as_ref())
-            .map(|m| m.title.to_string())
-            .collect::<Vec<String>>();
+        assert!(weight.is_some());
 
-        let mut title = vec!["Statemap of".to_string(),
-            oxford(&titles), "activity".to_string()];
+        let mut rweight = StatemapRectWeight {
+            entity: id, weight: 0, start: 0
+        };
 
-        let hosts = statemaps.iter()
-            .filter_map(|s| s.metadata.as_ref())
-            .filter_map(|m| m.host.as_ref())
-            .map(|r| r.to_string())
-            .collect::<Vec<String>>();
+        self.apply(updates, &mut rweight);
+        self.print(&format!("After subsuming {} from {}", victim, what)); 
+        self.verify();
+    }
 
-        if hosts.len() > 0 {
-            title.push("on".to_string());
+    #[cfg(test)]
+    fn print(&self, header: &str) {
+        println!("{}: by weight: {:?}", header, self.byweight);
 
-            if hosts.iter().collect::<HashSet<_>>().len() > 5 {
-                title.push(format!("{} hosts", hosts.len()));
-            } else {
-                title.push(oxford(&hosts));
-            }
+        for entity in self.entities.values() {
+            entity.print(header);
         }
 
-        title.join(" ")
+        println!("");
     }
 
-    pub fn output(&self, statemaps: &Vec<Statemap>) ->
-        Result<(), Box<dyn Error>>
-    {
-        struct Props {
-            x: u32,
-            y: u32,
-            height: u32,
-            width: u32,
-            lheight: u32,
-            spacing: u32
-        };
+    #[cfg(test)]
+    fn get_rects(&self, entity: &str) -> Vec<(u64, u64, Vec<u64>)> {
+        let mut rval: Vec<(u64, u64, Vec<u64>)>;
 
-        let base = &statemaps[0];
+        let e = self.entities.get(entity);
 
-        let output_controls = |props: &Props| {
-            let width = props.width / 4;
-            let mut x = 0;
-            let y = 0;
+        match e {
+            Some(entity) => {
+                rval = entity.rects.values().map(|r| {
+                    let rect = r.borrow();
 
-            let icons = vec![
-                (include_str!("./icons/arrow-left-l.svg"), "panclick(50, 0)"),
-                (include_str!("./icons/zoom-in.svg"), "zoomclick(1.25)"),
-                (include_str!("./icons/zoom-out.svg"), "zoomclick(0.8)"),
-                (include_str!("./icons/arrow-right-l.svg"), "panclick(-50, 0)")
-            ];
+                    (rect.start, rect.duration, rect.states.clone())
+                }).collect();
 
-            println!(r##"<svg x="{}px" y="{}px" width="{}px" height="{}px">"##,
-                props.x, props.y, props.width, props.height);
+                rval.sort();
+            },
+            None => { rval = vec![]; }
+        }
 
-            for i in 0..icons.len() {
-                println!(r##"<svg x="{}px" y="{}px" width="{}px" height="{}px"
-                    onclick="{}"><rect x="0px" y="0px" width="{}px" 
-                    height="{}px" onclick="{}" class="button" />{}</svg>"##,
-                    x, y, width, width, icons[i].1,
-                    width, width, icons[i].1, icons[i].0);
-                x += width;
-            }
+        rval
+    }
 
-            println!("</svg>");
+    /*
+     * Ingest and advance `payload` past the metadata JSON object.
+     */
+    fn ingest_metadata(&mut self, payload: &mut &str)
+        -> Result<(), Box<dyn Error>>
+    {
+        let mut metadata: StatemapInputMetadata = match try_parse(payload)? {
+            None => return self.err("missing metadata payload"),
+            Some(metadata) => metadata,
         };
 
-        let output_legend = |statemap: &Statemap, id: usize,
-            props: &mut Props, colors: &Vec<StatemapColor>|
-        {
-            let x = props.x;
-            let mut y = props.y;
-            let height = props.lheight;
-            let width = props.width;
-
-            for state in 0..statemap.states.len() {
-                println!(concat!(r##"<rect x="{}" y="{}" width="{}" "##,
-                    r##"height="{}" id="statemap-legend-{}-{}" "##,
-                    r##"onclick="legendclick(evt, {}, {})" "##,
-                    r##"class="statemap-legend" style="fill:{}" />"##),
-                    x, y, width, height, id, state, id, state, colors[state]);
-                y += height + props.spacing;
+        let nstates = metadata.states.len();
+        let mut states: Vec<Option<StatemapState>> = vec![None; nstates];
 
-                println!(concat!(r##"<text x="{}" y="{}" "##,
-                    r##"class="statemap-legendlabel sansserif">{}</text>"##),
-                    x + (width / 2), y, statemap.states[state].name);
-                y += props.spacing;
-            }
+        if metadata.start.len() != 2 {
+            return self.err(concat!("\"start\" property must be a ",
+                "two element array"));
+        }
 
-            props.y = y;
+        /*
+         * If the clock of the host on which the data was gathered is known
+         * to be off (either in the metadata or in our configuration), we
+         * correct our start time -- before any absolute time in our
+         * configuration is made relative to it.  Either way, the start time
+         * (and its correction) must be representable in nanoseconds.
+         */
+        let start = metadata.start[0].checked_mul(1_000_000_000)
+            .and_then(|start| start.checked_add(metadata.start[1]))
+            .filter(|&start| start <= i64::MAX as u64);
+        let offset = metadata.clockOffset.unwrap_or(0)
+            .checked_add(self.config.offset);
+
+        let start = match (start, offset) {
+            (Some(start), Some(offset)) => (start as i64).checked_add(offset),
+            _ => None,
         };
 
-        let output_tagbox = || {
-            if !base.config.notags {
-                println!(r##"<g id="statemap-tagbox"></g>"##);
-                println!(r##"<g id="statemap-tagbox-select"></g>"##);
+        match start {
+            None => return self.err("illegal start time"),
+            Some(start) if start < 0 => {
+                return self.err("clock offset precedes the Epoch");
             }
-        };
+            Some(start) => {
+                metadata.start = vec![start as u64 / 1_000_000_000,
+                    start as u64 % 1_000_000_000];
+            }
+        }
 
-        let metadata = match base.metadata {
-            Some(ref metadata) => { metadata }
-            _ => { return base.err("metadata not found in data stream"); }
-        };
+        for (key, value) in &metadata.states {
+            let ndx = value.value;
 
-        #[allow(non_snake_case)]
-        let timeWidth = base.entities.values().fold(base.config.end,
-            |latest, e| {
-                match e.start {
-                    Some(start) => cmp::max(latest, start as i64),
-                    None => latest
-               }
-            }) - base.config.begin;
+            if ndx >= nstates {
+                let errmsg = format!(concat!("state \"{}\" has value ({}) ",
+                    "that exceeds maximum allowed value ({})"),
+                    key, ndx, nstates - 1);
+                return self.err(&errmsg);
+            }
 
-        assert!(timeWidth >= 0);
+            if ndx < states.len() && states[ndx].is_some() {
+                let errmsg = format!(concat!("state \"{}\" has value ",
+                    "({}) that conflicts with state \"{}\""), key,
+                    ndx, states[ndx].as_ref().unwrap().name);
 
-        let lmargin = self.config.legendWidth;
-        let tmargin = 60;
-        let rmargin = self.config.tagWidth;
-        let smargin = self.config.stripHeight;
-        let height: u32;
+                return self.err(&errmsg);
+            }
 
-        let stacked = true;
+            states[ndx] = Some(StatemapState {
+                name: key.to_string(),
+                value: ndx,
+                color: match value.color {
+                    Some(ref str) => { Some(str.to_string()) },
+                    None => { None }
+                },
+                salient: value.salient == Some(true) ||
+                    self.salient.contains(key),
+            });
+        }
 
-        if stacked || statemaps.len() == 1 {
-            let nentities = statemaps.iter().fold(0,
-                |total, statemap| { total + statemap.entities.len() });
+        assert_eq!(self.states.len(), 0);
 
-            height = nentities as u32 * self.config.stripHeight +
-                tmargin + ((statemaps.len() as u32) - 1) * smargin;
-        } else {
-            panic!("tabbed statemaps not yet supported");
+        /*
+         * We have verified our states; now pull them into our array.
+         */
+        for _i in 0..nstates {
+            self.states.push(states.remove(0).unwrap());
         }
 
-        let width = self.config.stripWidth + lmargin + rmargin;
+        self.metadata = Some(metadata);
 
-        let mut props = Props { x: 20, y: tmargin, height: 45,
-            width: lmargin, lheight: 15, spacing: 10 };
+        Ok(())
+    }
 
-        let mut sharedlegend = true;
-        let mut lheight = tmargin + props.height;
+    fn ingest_end(&mut self) {
+        assert!(!self.config.abstime);
+
+        if self.config.end < 0 {
+            return;
+        }
+
+        let mut end = self.last;
+        let begin = self.config.begin;
 
         /*
-         * We need to add in our legend height, but to determine this, we
-         * need to see to what degree we will be sharing legends.
+         * If we've been given an ending time and it's less than the last
+         * rectangle for which we have state, we'll use that.
          */
-        for i in 0..statemaps.len() {
-            if i == 0 || statemaps[i].states != statemaps[i - 1].states {
-                lheight += statemaps[i].states.len() as u32 *
-                    (props.lheight + (props.spacing * 2));
-
-                if i > 0 {
-                    lheight += props.spacing * 2;
-                    sharedlegend = false;
-                }
-            }
+        if self.config.end != 0 && self.config.end < end as i64 {
+            end = self.config.end as u64;
         }
 
-        let globals = StatemapSVGGlobals {
-            begin: base.config.begin,
-            end: base.config.end,
-            pixelWidth: self.config.stripWidth,
-            pixelHeight: height - tmargin,
-            totalHeight: cmp::max(height, lheight),
-            timeWidth: timeWidth as u64,
-            lmargin: lmargin,
-            tmargin: tmargin,
-            smargin: smargin,
-            entityPrefix: "statemap-entity-".to_string(),
-            states: &base.states,
-            start: &metadata.start,
-            entityKind: match metadata.entityKind {
-                Some(ref kind) => { kind }
-                None => { "Entity" }
-            }
+        let nstates = self.states.len() as u32;
+
+        let bucket = match self.config.coalesce {
+            StatemapCoalesce::Buckets => Some(self.ingest_resolution(0, end)),
+            StatemapCoalesce::Weight => None
         };
 
-        /*
-         * Make sure that all of our colors are valid.
-         */
-        let mut colors: Vec<Vec<StatemapColor>> = vec![];
+        let levels: Vec<(u64, u64)> = (1..self.config.levels as usize + 1)
+            .map(|level| self.ingest_resolution(level, end))
+            .collect();
 
-        for i in 0..statemaps.len() {
-            for j in 0..statemaps[i].states.len() {
-                colors.push(vec![]);
+        let mut newrects = vec![];
+        let mut spans = vec![];
 
-                match statemaps[i].states[j].color {
-                    Some(ref name) => {
-                        match StatemapColor::from_str(name) {
-                            Ok(color) => colors[i].push(color),
-                            Err(_err) => {
-                                return base.err(&format!(concat!("illegal ",
-                                    "color \"{}\" for state \"{}\""), name,
-                                    statemaps[i].states[j].name));
-                            }
-                        }
+        for entity in self.entities.values_mut() {
+            match entity.start {
+                Some(start) if start < end => {
+                    /*
+                     * If our start time is less than our begin time then
+                     * this entity must be in a single state for our entire
+                     * specified time -- and we move our start time up to our
+                     * begin time (and assert that we have no rectangles).
+                     */
+                    if begin != 0 && (start as i64) < begin {
+                        assert!(entity.rects.is_empty());
+                        entity.start = Some(begin as u64);
                     }
-                    None => colors[i].push(StatemapColor::random())
-                }
-            }
-        }
 
-        let sorted: Vec<usize>;
+                    entity.tally(end);
+                    spans.push((entity.start.unwrap(), entity.state.unwrap()));
 
-        match self.config.stacksortby {
-            None => { sorted = (0..statemaps.len()).collect(); }
-            Some(ref stacksortby) => {
-                /*
-                 * If we aren't all sharing a legend, this doesn't make sense.
-                 */
-                if !sharedlegend {
-                    return base.err("can only stack sort like statemaps");
-                }
+                    for (level, &(origin, resolution)) in
+                        levels.iter().enumerate() {
+                        entity.bucket(level + 1, end, nstates,
+                            origin, resolution);
+                    }
 
-                if !metadata.states.contains_key(stacksortby) {
-                    return base.err(&format!(concat!("unknown stack sorting ",
-                        "state \"{}\""), stacksortby));
-                }
+                    if let Some((origin, resolution)) = bucket {
+                        entity.bucket(0, end, nstates, origin, resolution);
+                        continue;
+                    }
+
+                    /*
+                     * We are adding a rectangle; while we will not subsume
+                     * any rectangles as part of ingestion, we update the
+                     * rectangle weight tree to allow this statemap to be
+                     * further coalesced with others (see coalesce()).
+                     */
+                    newrects.push((entity.id, entity.newrect(end, nstates)));
 
-                let sort = metadata.states.get(stacksortby).unwrap().value;
+                    /*
+                     * Even though we expect no other ingestion, we set our
+                     * last to allow for state to be verified.
+                     */
+                    entity.last = entity.start;
+                },
+                _ => {}
+            }
+        }
 
-                let mut weights = statemaps.iter()
-                    .map(|s| s.weight(sort))
-                    .enumerate()
-                    .collect::<Vec<(usize, _)>>();
+        for (id, rval) in newrects {
+            self.newrect_weights(id, rval);
+        }
 
-                weights.sort_by(|&(_, l), &(_, r)| r.cmp(&l));
+        for (start, state) in spans {
+            self.utilize(start, end, state);
+        }
 
-                sorted = weights.iter().map(|&(e, _)| e).collect();
+        /*
+         * If we have been coalescing into buckets, our buckets now become
+         * our rectangles.
+         */
+        if bucket.is_some() {
+            for entity in self.entities.values_mut() {
+                for (start, weight) in entity.unbucket() {
+                    self.byweight.insert(StatemapRectWeight {
+                        weight: weight,
+                        start: start,
+                        entity: entity.id
+                    });
+                }
             }
         }
 
-        println!(r##"<?xml version="1.0"?>
-            <!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN"
-                "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd">
-            <svg width="{}" height="{}"
-                xmlns="http://www.w3.org/2000/svg"
-                version="1.1"
-                onload="init(evt)">"##, width, globals.totalHeight);
+        for entity in self.entities.values_mut() {
+            entity.unbucket_levels();
+        }
 
-        self.output_defs(&globals);
+        let metadata = self.metadata.as_ref().unwrap();
+        let start = (metadata.start[0] * 1_000_000_000) + metadata.start[1];
+        self.begin = (self.config.begin + start as i64) as u64;
+        self.end = cmp::max(end, self.config.end as u64) + start;
+    }
 
-        let mut y = tmargin;
+    /*
+     * When coalescing into buckets, all entities share a bucket resolution.
+     * If we have been given a resolution, we use it; if we know the time
+     * span of the statemap, we use the resolution that will divide it into
+     * our target number of buckets.  Otherwise, we double our resolution
+     * as needed to keep the number of buckets at or below our target.
+     * Finer levels of detail are bucketed the same way, but each with
+     * LEVEL_FACTOR times the number of buckets of the level before it.
+     * Returns the origin and resolution of the buckets at the level.
+     */
+    fn ingest_resolution(&mut self, level: usize, time: u64) -> (u64, u64) {
+        let origin = cmp::max(self.config.begin, 0) as u64;
+        let scale = LEVEL_FACTOR.pow(level as u32);
+        let nbuckets = cmp::max(self.config.nbuckets, 1) * scale;
+        let end = self.config.end;
+
+        if level >= self.resolutions.len() {
+            self.resolutions.resize(level + 1, 0);
+        }
 
-        for i in 0..statemaps.len() {
-            let statemap = &statemaps[sorted[i]];
+        if self.resolutions[level] == 0 {
+            self.resolutions[level] = if self.config.resolution != 0 {
+                cmp::max(self.config.resolution / scale, 1)
+            } else if end > 0 && (end as u64) > origin {
+                cmp::max((end as u64 - origin).div_ceil(nbuckets), 1)
+            } else {
+                1
+            };
+        }
 
-            let height = statemap.entities.len() as u32 *
-                self.config.stripHeight;
+        if self.config.resolution == 0 && end <= 0 {
+            while time > origin + nbuckets * self.resolutions[level] {
+                self.resolutions[level] *= 2;
 
-            println!(r##"<svg x="{}px" y="{}px" width="{}px" height="{}px">"##,
-                lmargin, y, globals.pixelWidth, height);
+                for entity in self.entities.values_mut() {
+                    entity.rebucket(level);
+                }
 
-            /*
-             * First, we drop down a background rectangle as big as our SVG.
-             * This color will be changed dynamically to be a highlight color,
-             * and then rectangles can be made transparent to become
-             * highlighted.
-             */
-            println!(concat!(r##"<rect x="0px" y="0px" width="{}px" "##,
-                r##"height="{}px" fill="{}" id="statemap-{}-highlight" />"##),
-                globals.pixelWidth, height, self.config.background, i);
+                if level == 0 {
+                    if let Some(ref mut utilization) = self.utilization {
+                        utilization.rebucket();
+                    }
+                }
+            }
+        }
 
-            statemap.output_svg(i, &self.config, &globals, &colors[i])?;
+        (origin, self.resolutions[level])
+    }
 
-            println!("</svg>");
-
-            /*
-             * The border around this statemap.
-             */
-            println!(r##"<polygon class="statemap-border""##);
-            println!(r##"  points="{} {}, {} {}, {} {}, {} {}"/>"##,
-                lmargin, y, lmargin + globals.pixelWidth, y,
-                lmargin + globals.pixelWidth, y + height, lmargin, y + height);
-
-            y += height + smargin;
+    /*
+     * If we are recording the occupancy of each state, record that an entity
+     * was in the specified state from `start` to `end`.  Our record is
+     * created on demand, as our states are known by then; it shares the
+     * buckets of our coarsest level of detail (and is widened with them).
+     */
+    fn utilize(&mut self, start: u64, end: u64, state: u32) {
+        if !self.config.utilization {
+            return;
         }
 
-        println!(concat!(r##"<text x="{}" y="{}" "##,
-            r##"class="statemap-title sansserif">{}</text>"##),
-            lmargin + (globals.pixelWidth / 2), 16,
-            self.title(statemaps));
+        let (origin, resolution) = self.ingest_resolution(0, end);
+        let nstates = self.states.len();
 
-        println!(concat!(r##"<text x="{}" y="{}" class="statemap-timelabel"##,
-            r##" sansserif" id="statemap-timelabel"></text>"##),
-            lmargin + (globals.pixelWidth / 2), 34);
+        self.utilization.get_or_insert_with(|| {
+            StatemapUtilization::new(origin, resolution, nstates)
+        }).record(start, end, state as usize);
+    }
 
-        println!(r##"<line x1="{}" y1="{}" x2="{}" y2="{}""##,
-            lmargin + 10, 40, lmargin + globals.pixelWidth - 10, 40);
-        println!(r##"class="statemap-timeline" />"##);
+    /*
+     * Ingest a single state transition.  This is used both for data that
+     * has been parsed from JSON and for data presented via `transition`.
+     */
+    fn ingest_state(&mut self, datum: StatemapInputDatum)
+        -> Result<Ingest, Box<dyn Error>>
+    {
+        let time: u64 = datum.time;
+        let nstates: u32 = self.states.len() as u32;
 
-        props.width -= (2 * props.x) + 10;
+        self.last = time;
 
-        output_controls(&props);
+        /*
+         * If the time of this datum is after our specified end time,
+         * we have nothing further to do to process it.
+         */
+        if self.config.end != 0 && time as i64 > self.config.end {
+            return Ok(Ingest::Success);
+        }
 
-        props.y += props.height;
+        if datum.state >= nstates {
+            return self.err("illegal state value");
+        }
 
-        for i in 0..statemaps.len() {
-            if i == 0 || statemaps[i].states != statemaps[i - 1].states {
-                output_legend(&statemaps[i], i, &mut props, &colors[i]);
-            }
+        /*
+         * If we have been given a resolution, our buckets don't widen as
+         * time goes on -- so we must be sure that there won't be too many
+         * of them before we create them.
+         */
+        let resolution = self.config.resolution;
+        let origin = cmp::max(self.config.begin, 0) as u64;
 
-            if !sharedlegend {
-                props.y += props.spacing * 2;
-            }
+        if resolution != 0 && time.saturating_sub(origin) / resolution >
+            MAX_BUCKETS {
+            return self.err(&format!(concat!("resolution of {}ns requires ",
+                "more than {} buckets"), resolution, MAX_BUCKETS));
         }
 
-        output_tagbox();
+        let bucket = match self.config.coalesce {
+            StatemapCoalesce::Buckets => Some(self.ingest_resolution(0, time)),
+            StatemapCoalesce::Weight => None
+        };
 
-        println!("</svg>");
+        let levels: Vec<(u64, u64)> = (1..self.config.levels as usize + 1)
+            .map(|level| self.ingest_resolution(level, time))
+            .collect();
 
-        Ok(())
-    }
-}
+        let begin = self.config.begin;
+        let salient = self.states[datum.state as usize].salient;
+        let mut errmsg: Option<String> = None;
+        let mut newrect = None;
+        let mut span = None;
+        let tag = self.tag_lookup(datum.state, &datum.tag);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::env;
-    use std::process;
-    use std::fs;
-    use std::io::Write;
+        /*
+         * We are going to do a lookup of our entity, but this will
+         * cause us to lose our reference on self (mutable or
+         * otherwise) -- which we need to fully record any error.  To
+         * implement this absent non-lexical lifetimes, we put the
+         * entity in a lexical scope implemented with "loop" so we
+         * can break out of it on an error condition.
+         */
+        loop {
+            let name = &datum.entity;
+            let entity = self.entity_lookup(name);
 
-    fn metadata(config: Option<&Config>, mut metadata: &str) -> Statemap {
-        let mut statemap;
+            match entity.start {
+                Some(start) => {
+                    if time < start {
+                        errmsg = Some(format!(concat!("time {} is out",
+                            " of order with respect to prior time {}"),
+                            time, start));
+                        break;
+                    }
 
-        match config {
-            Some(config) => { statemap = Statemap::new(&config); },
-            None => {
-                let config: Config = Default::default();
-                statemap = Statemap::new(&config);
+                    if (time as i64) > begin {
+                        /*
+                         * We can now create a new rectangle for this
+                         * entity's past state.
+                         */
+                        if begin > 0 && start < (begin as u64) {
+                            entity.start = Some(begin as u64);
+                        }
+
+                        entity.tally(time);
+                        span = Some((entity.start.unwrap(),
+                            entity.state.unwrap()));
+
+                        /*
+                         * Any finer levels of detail are always bucketed.
+                         */
+                        for (level, &(origin, resolution)) in
+                            levels.iter().enumerate() {
+                            entity.bucket(level + 1, time, nstates,
+                                origin, resolution);
+                        }
+
+                        /*
+                         * If we are coalescing into buckets, we add our
+                         * time to our buckets rather than creating a
+                         * rectangle.
+                         */
+                        if let Some((origin, resolution)) = bucket {
+                            entity.bucket(0, time, nstates, origin,
+                                resolution);
+                        } else {
+                            let rval = entity.newrect(time, nstates);
+                            entity.last = entity.start;
+                            newrect = Some((entity.id, rval));
+                        }
+                    }
+                }
+                None => {}
             }
+
+            entity.visit(time, datum.state, begin);
+            entity.start = Some(time);
+            entity.state = Some(datum.state);
+            entity.salient = salient;
+            entity.tag = tag;
+            break;
         }
 
-        match statemap.ingest_metadata(&mut metadata) {
-            Err(err) => { panic!("metadata incorrectly failed: {:?}", err); }
-            Ok(_) => { statemap }
+        if errmsg.is_some() {
+            return self.err(&errmsg.unwrap());
         }
-    }
 
-    fn minimal(config: Option<&Config>) -> Statemap {
-        metadata(config, r##"{
-            "start": [ 0, 0 ],
-            "title": "Foo",
-            "states": {
-                "zero": {"value": 0 },
-                "one": {"value": 1 }
-            }
-        }"##)
+        if let Some((id, rval)) = newrect {
+            self.newrect_weights(id, rval);
+        }
+
+        if let Some((start, state)) = span {
+            self.utilize(start, time, state);
+        }
+
+        Ok(Ingest::Success)
     }
 
-    fn data(config: Option<&Config>, data: Vec<&str>) -> Statemap {
-        let mut statemap = minimal(config);
+    /*
+     * Update our rectangle weight tree to reflect a new rectangle for the
+     * specified entity, given the return value from the entity's newrect().
+     */
+    fn newrect_weights(&mut self, entity: usize,
+        rval: (Option<(u64, u64, u64)>, (u64, u64)))
+    {
+        if let Some(rect) = rval.0 {
+            let mut rweight = StatemapRectWeight {
+                weight: rect.1,
+                start: rect.0,
+                entity: entity
+            };
 
-        for mut datum in data {
-            match statemap.ingest_datum(&mut datum) {
-                Err(err) => { panic!("data incorrectly failed: {:?}", err); }
-                Ok(_) => {}
-            }
+            self.byweight.remove(&rweight);
+            rweight.weight = rect.2;
+            self.byweight.insert(rweight);
         }
 
-        statemap.ingest_end();
-        statemap
+        self.byweight.insert(StatemapRectWeight {
+            weight: (rval.1).1,
+            start: (rval.1).0,
+            entity: entity
+        });
     }
 
-    fn bad_metadata(mut metadata: &str, expected: &str) {
-        let config: Config = Default::default();
-        let mut statemap = Statemap::new(&config);
+    fn ingest_description(&mut self, datum: StatemapInputDescription)
+        -> Result<Ingest, Box<dyn Error>>
+    {
+        let entity = self.entity_lookup(&datum.entity);
+        entity.description = Some(datum.description.to_string());
 
-        match statemap.ingest_metadata(&mut metadata) {
-            Err(err) => {
-                let errmsg = format!("{}", err);
+        Ok(Ingest::Success)
+    }
 
-                if errmsg.find(expected).is_none() {
-                    panic!("error ('{}') did not contain '{}' as expected",
-                        errmsg, expected);
-                }
-            },
-            Ok(_) => { panic!("bad metadata succeeded!"); }
+    fn ingest_event(&mut self, datum: StatemapInputEvent)
+        -> Result<Ingest, Box<dyn Error>>
+    {
+        let time = datum.time;
+
+        self.nevents += 1;
+        self.last = cmp::max(self.last, time);
+
+        /*
+         * As with state data, events outside of our specified
+         * time range are dropped.
+         */
+        if self.config.end != 0 && time as i64 > self.config.end {
+            return Ok(Ingest::Success);
         }
-    }
 
-    fn bad_datum(operand: Option<Statemap>, mut datum: &str, expected: &str) {
-        let mut statemap = match operand {
-            Some(statemap) => statemap,
-            None => {
-                metadata(None, r##"{
-                    "start": [ 0, 0 ],
-                    "title": "Foo",
-                    "states": {
-                        "zero": {"value": 0 },
-                        "one": {"value": 1 }
-                    }
-                }"##)
-            }
-        };
+        if (time as i64) < self.config.begin {
+            return Ok(Ingest::Success);
+        }
 
-        match statemap.ingest_datum(&mut datum) {
-            Err(err) => {
-                let errmsg = format!("{}", err);
+        let event = match self.events.get(&datum.event) {
+            Some(id) => *id,
+            None => self.events.len()
+        };
 
-                if errmsg.find(expected).is_none() {
-                    panic!("error ('{}') did not contain '{}' as expected",
-                        errmsg, expected);
-                }
-            },
-            Ok(_) => { panic!("bad datum succeeded!"); }
+        if event == self.events.len() {
+            self.events.insert(datum.event, event);
         }
+
+        let entity = self.entity_lookup(&datum.entity);
+
+        entity.events.push(StatemapEvent {
+            time: time,
+            event: event,
+            target: datum.target,
+        });
+
+        Ok(Ingest::Success)
     }
 
-    fn statemap_ingest(statemap: &mut Statemap, raw: &str)
-        -> Result<(), Box<Error>>
+    fn ingest_tag(&mut self, datum: StatemapInputTag, value: Value)
+        -> Result<Ingest, Box<dyn Error>>
     {
-        let mut path = env::temp_dir();
-        path.push(format!("statemap.test.{}.{:p}", process::id(), statemap));
+        if self.config.notags {
+            return Ok(Ingest::Success);
+        }
 
-        let filename = path.to_str().unwrap();
-        let mut file = File::create(filename)?;
-        file.write_all(raw.as_bytes())?;
+        /*
+         * We allow tags to be redefined, so we need to first lookup
+         * our tag to see if it exists -- and if it does, we need
+         * to use the existing ID.
+         */
+        let id;
 
-        let result = statemap.ingest(filename);
+        match self.tags.get(&(datum.state, datum.tag.to_string())) {
+            Some((_value, idr)) => { id = *idr }
+            None => { id = self.tags.len() }
+        };
 
-        fs::remove_file(filename)?;
+        self.tags.insert((datum.state, datum.tag), (value, id));
 
-        result
+        Ok(Ingest::Success)
     }
 
-    fn bad_statemap(raw: &str, expected: &str) {
-        let config: Config = Default::default();
-        let mut statemap = Statemap::new(&config);
+    /*
+     * Ingest and advance `payload` past one JSON object datum.
+     */
+    fn ingest_datum(&mut self, payload: &mut &str)
+        -> Result<Ingest, Box<dyn Error>>
+    {
+        match try_parse::<StatemapInputDatum>(payload) {
+            Ok(None) => return Ok(Ingest::EndOfFile),
+            Ok(Some(datum)) => return self.ingest_state(datum),
+            Err(_) => {}
+        }
 
-        match statemap_ingest(&mut statemap, raw) {
-            Err(err) => {
-                let errmsg = format!("{}\n", err);
+        match try_parse::<StatemapInputDescription>(payload) {
+            Ok(None) => return Ok(Ingest::EndOfFile),
+            Ok(Some(datum)) => return self.ingest_description(datum),
+            Err(_) => {}
+        }
 
-                if errmsg.find(expected).is_none() {
-                    panic!("error ('{}') did not contain '{}' as expected",
-                        errmsg, expected);
-                }
-            },
-            Ok(_) => { panic!("bad statemap succeeded!"); }
+        match try_parse::<StatemapInputEvent>(payload) {
+            Ok(None) => return Ok(Ingest::EndOfFile),
+            Ok(Some(datum)) => return self.ingest_event(datum),
+            Err(_) => {}
         }
-    }
 
-    macro_rules! bad_statemap {
-        ($what:expr) => ({
-            bad_statemap(include_str!(concat!("../tst/tst.", $what, ".in")),
-                include_str!(concat!("../tst/tst.", $what, ".err")));
-        });
+        match try_parse_raw::<StatemapInputTag>(payload) {
+            Ok(None) => return Ok(Ingest::EndOfFile),
+            Ok(Some((datum, value))) => return self.ingest_tag(datum, value),
+            Err(_) => {}
+        }
+
+        self.err("unrecognized payload")
     }
 
-    fn good_statemap(config: &Config, raw: &str) -> Statemap {
-        let mut statemap = Statemap::new(&config);
+    /*
+     * If our time was presented as absolute time, we convert it to be
+     * relative to our (now known) start time.
+     */
+    fn ingest_abstime(&mut self) {
+        if self.config.abstime {
+            let metadata = self.metadata.as_ref().unwrap();
+            let start = (metadata.start[0] * 1_000_000_000 +
+                metadata.start[1]) as i64;
 
-        match statemap_ingest(&mut statemap, raw) {
-            Err(err) => {
-                panic!("statemap failed: {}", err);
-            },
-            Ok(_) => { statemap }
+            self.config.begin -= start;
+            self.config.end -= start;
+            self.config.abstime = false;
         }
     }
 
-    macro_rules! good_statemap {
-        ($what:expr) => ({
-            let mut config: Config = Default::default();
-            config.notags = false;
-
-            good_statemap(&config,
-                include_str!(concat!("../tst/tst.", $what, ".in")))
-        });
-
-        ($what:expr, $conf:expr) => ({
-            good_statemap($conf,
-                include_str!(concat!("../tst/tst.", $what, ".in")))
-        });
+    fn ingest_trim(&mut self) {
+        while self.byweight.len() >= self.config.maxrect as usize {
+            self.trim();
+        }
     }
 
-    #[test]
-    fn good_minimal() {
-        metadata(None, r##"{
-            "start": [ 0, 0 ],
-            "title": "Foo",
-            "states": {
-                "zero": {"value": 0 }
-            }
-        }"##);
+    fn ingest_done(&mut self, nrecs: u64) {
+        self.ingest_end();
+        self.nrecs = nrecs;
     }
 
-    #[test]
-    fn bad_title_missing() {
-        bad_metadata(r##"{
-            "start": [ 0, 0 ],
-            "states": {
-                "zero": {"value": 0 }
-            }
-        }"##, "missing field `title`");
-    }
+    /**
+     * Ingest the specified file.  Regular files are mapped; anything else
+     * (including standard input, denoted by a filename of "-") is read as
+     * a stream.
+     */
+    pub fn ingest(&mut self, filename: &str) -> Result<(), Box<dyn Error>> {
+        if filename == "-" {
+            let stdin = io::stdin();
+            let locked = stdin.lock();
+            return self.ingest_stream(locked);
+        }
 
-    #[test]
-    fn bad_start_missing() {
-        bad_metadata(r##"{
-            "title": "Foo",
-            "states": {
-                "zero": {"value": 0 }
-            }
-        }"##, "missing field `start`");
-    }
+        let file = File::open(filename)?;
 
-    #[test]
-    fn bad_start_badval() {
-        bad_metadata(r##"{
-            "start": [ -1, 0 ],
-            "title": "Foo",
-            "states": {
-                "zero": {"value": 0 }
-            }
-        }"##, "invalid value: integer `-1`");
-    }
+        if !file.metadata()?.is_file() {
+            return self.ingest_stream(file);
+        }
 
-    #[test]
-    fn bad_start_tooshort() {
-        bad_metadata(r##"{
-            "start": [ 0 ],
-            "title": "Foo",
-            "states": {
-                "zero": {"value": 0 }
-            }
-        }"##, "\"start\" property must be a two element array");
-    }
+        let mut nrecs = 0;
 
-    #[test]
-    fn bad_start_toolong() {
-        bad_metadata(r##"{
-            "start": [ 0, 0, 3 ],
-            "title": "Foo",
-            "states": {
-                "zero": {"value": 0 }
+        /*
+         * Unsafe because Rust cannot enforce that the underlying data on
+         * filesystem is not mutated while our program contains a &[u8]
+         * reference to it. Mutating the file would result in undefined
+         * behavior.
+         */
+        let mmap = unsafe { MmapOptions::new().map(&file)? };
+
+        /*
+         * If our file is compressed, we can't use it directly; we stream
+         * it through the appropriate decompressor instead.
+         */
+        if StatemapCompression::detect(&mmap[..]).is_some() {
+            return self.ingest_stream(&mmap[..]);
+        }
+
+        if let Some(import) = StatemapImport::detect(&mmap[..]) {
+            return self.import(&mmap[..], import);
+        }
+
+        let mut contents = str::from_utf8(&mmap[..])?;
+        let len = contents.len();
+
+        self.ingest_metadata(&mut contents)?;
+        self.ingest_abstime();
+
+        /*
+         * Now rip through our data pulling out concatenated JSON payloads.
+         */
+        loop {
+            match self.ingest_datum(&mut contents) {
+                Ok(Ingest::Success) => nrecs += 1,
+                Ok(Ingest::EndOfFile) => break,
+                Err(err) => {
+                    /*
+                     * Lazily compute the line number for our error message.
+                     */
+                    let remaining_len = contents.len();
+                    let byte_offset = len - remaining_len;
+                    let line = line_number(&mmap, byte_offset);
+                    let message =
+                        format!("illegal datum on line {}: {}", line, err);
+                    return self.err(&message);
+                }
             }
-        }"##, "\"start\" property must be a two element array");
-    }
 
-    #[test]
-    fn bad_states_missing() {
-        bad_metadata(r##"{
-            "start": [ 0, 0 ],
-            "title": "Foo"
-        }"##, "missing field `states`");
-    }
+            self.ingest_trim();
+        }
 
-    #[test]
-    fn bad_states_badmap() {
-        bad_metadata(r##"{
-            "start": [ 0, 0 ],
-            "title": "Foo",
-            "states": 123
-        }"##, "expected a map");
+        self.ingest_done(nrecs);
+        Ok(())
     }
 
-    #[test]
-    fn bad_states_value_missing() {
-        bad_metadata(r##"{
-            "start": [ 0, 0 ],
-            "title": "Foo",
-            "states": {
-                "zero": {}
+    /**
+     * Ingest concatenated JSON from an arbitrary reader.  This is slower
+     * than ingesting a mapped file, but allows for data to be read from
+     * pipes and the like.  If the data is compressed (with gzip, zstd or
+     * xz), it will be decompressed.
+     */
+    pub fn ingest_stream<R: Read>(&mut self, mut reader: R)
+        -> Result<(), Box<dyn Error>>
+    {
+        let mut header = [0; StatemapCompression::HEADER];
+        let mut len = 0;
+
+        /*
+         * Read enough of our input to determine if it's compressed, and
+         * then put it back in front of the rest of the stream.
+         */
+        while len < header.len() {
+            match reader.read(&mut header[len..]) {
+                Ok(0) => break,
+                Ok(nread) => len += nread,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(Box::new(err)),
             }
-        }"##, "missing field `value`");
-    }
+        }
 
-    #[test]
-    fn bad_states_value_bad() {
-        bad_metadata(r##"{
-            "start": [ 0, 0 ],
-            "title": "Foo",
-            "states": {
-                "zero": {"value": -1 }
+        let compression = StatemapCompression::detect(&header[..len]);
+        let mut decoder =
+            StatemapDecoder::new((&header[..len]).chain(reader), compression)?;
+
+        /*
+         * Similarly, read through any leading whitespace (and the name of
+         * the first member of any object) to determine if our input is a
+         * trace to be imported.
+         */
+        let mut lead = vec![];
+
+        loop {
+            let mut byte = [0; 1];
+
+            match decoder.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) => {
+                    lead.push(byte[0]);
+
+                    if !StatemapImport::partial(&lead) {
+                        break;
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(Box::new(err)),
             }
-        }"##, "invalid value: integer `-1`");
-    }
+        }
 
-    #[test]
-    fn bad_states_value_skipped1() {
-        bad_metadata(r##"{
-            "start": [ 0, 0 ],
-            "title": "Foo",
-            "states": {
-                "zero": {"value": 0 },
-                "one": {"value": 2 }
+        let decoder = (&lead[..]).chain(decoder);
+
+        if let Some(import) = StatemapImport::detect(&lead) {
+            return self.import(BufReader::new(decoder), import);
+        }
+
+        let mut stream = StatemapStream::new(decoder);
+        let mut nrecs = 0;
+
+        let consumed = {
+            let mut payload = match stream.next()? {
+                Some(payload) => payload,
+                None => ""
+            };
+
+            let len = payload.len();
+            self.ingest_metadata(&mut payload)?;
+            len - payload.len()
+        };
+
+        stream.consume(consumed);
+
+        self.ingest_abstime();
+
+        loop {
+            let consumed;
+
+            /*
+             * As with a mapped file, we lazily compute the line number for
+             * any error -- but we must do so relative to our stream.
+             */
+            match stream.next()? {
+                None => break,
+                Some(payload) => {
+                    let mut contents = payload;
+
+                    match self.ingest_datum(&mut contents) {
+                        Ok(Ingest::Success) => nrecs += 1,
+                        Ok(Ingest::EndOfFile) => {},
+                        Err(err) => {
+                            let offset = payload.len() - contents.len();
+                            let line = stream.line_number(offset);
+                            let message = format!(
+                                "illegal datum on line {}: {}", line, err);
+                            return self.err(&message);
+                        }
+                    }
+
+                    consumed = payload.len();
+                }
             }
-        }"##, "state \"one\" has value (2) that exceeds maximum");
+
+            stream.consume(consumed);
+            self.ingest_trim();
+        }
+
+        self.ingest_done(nrecs);
+        Ok(())
     }
 
-    #[test]
-    fn bad_states_value_toohigh() {
-        bad_metadata(r##"{
-            "start": [ 0, 0 ],
-            "title": "Foo",
-            "states": {
-                "zero": {"value": 1 },
-                "one": {"value": 2 }
-            }
-        }"##, "state \"one\" has value (2) that exceeds maximum");
+    /*
+     * Import a trace in a format other than concatenated JSON.
+     */
+    fn import<R: BufRead>(&mut self, reader: R, import: StatemapImport)
+        -> Result<(), Box<dyn Error>>
+    {
+        match import {
+            StatemapImport::Sched => self.ingest_sched(reader),
+            StatemapImport::Chrome => self.ingest_chrome(reader),
+        }
     }
 
-    #[test]
-    fn bad_states_value_duplicate() {
-        bad_metadata(r##"{
-            "start": [ 0, 0 ],
-            "title": "Foo",
-            "states": {
-                "zero": {"value": 1 },
-                "one": {"value": 1 }
-            }
-        }"##, "has value (1) that conflicts");
+    /*
+     * When a statemap is constructed programmatically, we create our
+     * metadata on demand.
+     */
+    fn builder_metadata(&mut self) -> &mut StatemapInputMetadata {
+        if self.metadata.is_none() {
+            self.metadata = Some(StatemapInputMetadata {
+                start: vec![0, 0],
+                title: "".to_string(),
+                host: None,
+                entityKind: None,
+                clockOffset: None,
+                states: HashMap::new(),
+            });
+        }
+
+        self.metadata.as_mut().unwrap()
     }
 
-    #[test]
-    fn bad_line_basic() {
-        bad_statemap!("bad_line_basic");
+    /*
+     * Called before any data is added via the builder interface.
+     */
+    fn builder_data(&mut self) {
+        self.builder_metadata();
+        self.ingest_abstime();
     }
 
-    #[test]
-    fn bad_line_whitespace() {
-        bad_statemap!("bad_line_whitespace");
+    fn builder_check(&self, what: &str) -> Result<(), Box<dyn Error>> {
+        if self.nrecs != 0 || !self.entities.is_empty() {
+            return self.err(&format!(
+                "cannot set {} after data has been added", what));
+        }
+
+        Ok(())
     }
 
-    #[test]
-    fn bad_line_newline() {
-        bad_statemap!("bad_line_newline");
+    /**
+     * Set the title of a statemap that is being constructed
+     * programmatically.
+     */
+    pub fn set_title(&mut self, title: &str) {
+        self.builder_metadata().title = title.to_string();
     }
 
-    #[test]
-    fn basic() {
-        let statemap = metadata(None, r##"{
-            "start": [ 1528417173, 255882937 ],
-            "title": "Foo",
-            "host": "HA8S7MRD2",
-            "entityKind": "Process",
-            "states": {
-                "on-cpu": {"value": 0, "color": "#2e9107" },
-                "off-cpu-waiting": {"value": 1, "color": "#f9f9f9" },
-                "off-cpu-semop": {"value": 2, "color": "#FF5733" },
-                "off-cpu-blocked": {"value": 3, "color": "#C70039" },
-                "off-cpu-zfs-read": {"value": 4, "color": "#FFC300" },
-                "off-cpu-zfs-write": {"value": 5, "color": "#338AFF" },
-                "off-cpu-zil-commit": {"value": 6, "color": "#66FFCC" },
-                "off-cpu-tx-delay": {"value": 7, "color": "#e1ff00" },
-                "off-cpu-dead": {"value": 8, "color": "#E0E0E0" }
-            }
-        }"##);
-        assert_eq!(statemap.states.len(), 9);
-        assert_eq!(statemap.states[0].name, "on-cpu");
-        assert_eq!(statemap.states[0].color, Some("#2e9107".to_string()));
-        assert_eq!(statemap.states[1].name, "off-cpu-waiting");
-        assert_eq!(statemap.states[1].color, Some("#f9f9f9".to_string()));
-        assert_eq!(statemap.states[8].name, "off-cpu-dead");
+    /**
+     * Set the host on which the statemap's data was gathered.
+     */
+    pub fn set_host(&mut self, host: &str) {
+        self.builder_metadata().host = Some(host.to_string());
     }
 
-    #[test]
-    fn basic_datum() {
-        let mut _statemap = data(None, vec![
-            r##"{ "time": "156683", "entity": "foo", "state": 0 }"##
-        ]);
+    /**
+     * Set the kind of entity (e.g., "CPU", "Thread") in the statemap.
+     */
+    pub fn set_entity_kind(&mut self, kind: &str) {
+        self.builder_metadata().entityKind = Some(kind.to_string());
     }
 
-    #[test]
-    fn basic_description() {
-        let mut statemap = data(None, vec![
-            r##"{ "time": "156683", "entity": "foo", "state": 0 }"##,
-            r##"{ "entity": "foo", "description": "This is a foo!" }"##
-        ]);
+    /**
+     * Set the start time of the statemap, in nanoseconds since the Epoch;
+     * all times presented to `transition` and `event` are relative to it.
+     * This must be set before any data is added.
+     */
+    pub fn set_start(&mut self, start: u64) -> Result<(), Box<dyn Error>> {
+        self.builder_check("start time")?;
 
-        assert_eq!(statemap.entity_lookup("foo").description,
-            Some("This is a foo!".to_string()));
+        self.builder_metadata().start =
+            vec![start / 1_000_000_000, start % 1_000_000_000];
+
+        Ok(())
     }
 
-    #[test]
-    fn bad_datum_badtime() {
-        bad_datum(None, r##"
-            { "time": 156683, "entity": "foo", "state": 0 }
-        "##, "unrecognized payload");
-    }
+    /**
+     * Add a state with an optional color, returning its value.  States
+     * are assigned values in the order in which they are added, and must
+     * all be added before any data.
+     */
+    pub fn add_state(&mut self, name: &str, color: Option<&str>)
+        -> Result<u32, Box<dyn Error>>
+    {
+        self.builder_check("states")?;
 
-    #[test]
-    fn bad_datum_badtime_float() {
-        bad_datum(None, r##"
-            { "time": "156683.12", "entity": "foo", "state": 0 }
-        "##, "unrecognized payload");
-    }
+        if self.states.iter().any(|state| state.name == name) {
+            return self.err(&format!("state \"{}\" is already defined", name));
+        }
 
-    #[test]
-    fn bad_datum_nostate() {
-        bad_datum(None, r##"
-            { "time": "156683", "entity": "foo" }
-        "##, "unrecognized payload");
-    }
+        if let Some(color) = color {
+            StatemapColor::from_str(color)?;
+        }
 
-    #[test]
-    fn bad_datum_badstate() {
-        bad_datum(None, r##"
-            { "time": "156683", "entity": "foo", "state": 200 }
-        "##, "illegal state value");
-    }
+        let value = self.states.len();
 
-    #[test]
-    fn bad_datum_backwards() {
-        let statemap = data(None, vec![
-            r##"{ "time": "156683", "entity": "foo", "state": 0 }"##
-        ]);
+        self.builder_metadata().states.insert(name.to_string(),
+            StatemapInputState {
+                color: color.map(|c| c.to_string()),
+                value: value,
+                salient: None,
+            });
 
-        bad_datum(Some(statemap), r##"
-            { "time": "156682", "entity": "foo", "state": 1 }
-        "##, "out of order with respect to prior time");
+        let salient = self.salient.iter().any(|s| s == name);
+
+        self.states.push(StatemapState {
+            name: name.to_string(),
+            value: value,
+            color: color.map(|c| c.to_string()),
+            salient: salient,
+        });
+
+        Ok(value as u32)
     }
 
-    #[test]
-    fn basic_data() {
-        let statemap = data(None, vec![
-            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
-        ]);
+    /**
+     * Designate the named state as salient.  Salient states are weighted
+     * heavily when coalescing, and rectangles that have been coalesced will
+     * be marked if they contain time in a salient state.  This overrides
+     * the "salient" property in the metadata, and must be specified before
+     * any data is ingested or added; it is not an error if the statemap has
+     * no such state.
+     */
+    pub fn set_salient(&mut self, state: &str) -> Result<(), Box<dyn Error>> {
+        self.builder_check("salient states")?;
 
-        statemap.verify();
+        for s in self.states.iter_mut() {
+            if s.name == state {
+                s.salient = true;
+            }
+        }
+
+        self.salient.push(state.to_string());
+        Ok(())
     }
 
-    #[test]
-    fn subsume() {
-        let mut statemap = data(None, vec![
-            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
-        ]);
+    /**
+     * Provide a description for the specified entity.
+     */
+    pub fn describe_entity(&mut self, entity: &str, description: &str) {
+        self.builder_data();
 
-        statemap.print("Initial load");
-        statemap.verify();
-        statemap.subsume_apply_and_verify("foo", 100000);
-        statemap.subsume_apply_and_verify("foo", 300000);
-        statemap.subsume_apply_and_verify("foo", 100000);
+        self.nrecs += 1;
+        self.entity_lookup(entity).description = Some(description.to_string());
     }
 
-    #[test]
-    fn subsume_right() {
-        let mut statemap = data(None, vec![
-            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
-        ]);
+    /**
+     * Indicate that `entity` has entered `state` (with an optional tag) at
+     * the specified time, in nanoseconds relative to the start time.
+     * Transitions for a given entity must be presented in time order.
+     */
+    pub fn transition(&mut self, entity: &str, time: u64, state: u32,
+        tag: Option<&str>) -> Result<(), Box<dyn Error>>
+    {
+        self.builder_data();
 
-        statemap.print("Initial load");
-        statemap.verify();
+        self.ingest_state(StatemapInputDatum {
+            time: time,
+            entity: entity.to_string(),
+            state: state,
+            tag: tag.map(|t| t.to_string()),
+        })?;
 
-        statemap.subsume_apply_and_verify("foo", 500000);
-        statemap.subsume_apply_and_verify("foo", 400000);
-        statemap.subsume_apply_and_verify("foo", 300000);
-        statemap.subsume_apply_and_verify("foo", 200000);
+        self.nrecs += 1;
+        self.ingest_trim();
+
+        Ok(())
     }
 
-    #[test]
-    fn subsume_middle() {
-        let mut statemap = data(None, vec![
-            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
-        ]);
+    /**
+     * Define a tag for the specified state.  The fields must be a JSON
+     * object; they are presented when a tagged state is selected.
+     */
+    pub fn define_tag(&mut self, state: u32, tag: &str, fields: Value)
+        -> Result<(), Box<dyn Error>>
+    {
+        let mut value = match fields {
+            Value::Object(map) => Value::Object(map),
+            Value::Null => json!({}),
+            _ => { return self.err("tag fields must be an object"); }
+        };
 
-        statemap.print("Initial load");
-        statemap.verify();
-        statemap.subsume_apply_and_verify("foo", 300000);
-        statemap.subsume_apply_and_verify("foo", 300000);
-        statemap.subsume_apply_and_verify("foo", 200000);
+        value["state"] = json!(state);
+        value["tag"] = json!(tag);
+
+        self.builder_data();
+        self.ingest_tag(StatemapInputTag {
+            state: state,
+            tag: tag.to_string(),
+        }, value)?;
+
+        self.nrecs += 1;
+        Ok(())
     }
 
-    #[test]
-    fn subsume_tagged() {
-        let mut statemap = data(None, vec![
-            r##"{ "time": "100", "entity": "foo", "state": 0, "tag": "a" }"##,
-            r##"{ "time": "200", "entity": "foo", "state": 1, "tag": "b" }"##,
-            r##"{ "time": "300", "entity": "foo", "state": 0, "tag": "c" }"##,
-            r##"{ "time": "400", "entity": "foo", "state": 1, "tag": "b" }"##,
-            r##"{ "time": "500", "entity": "foo", "state": 0, "tag": "a" }"##,
-            r##"{ "time": "600", "entity": "foo", "state": 1, "tag": "b" }"##
-        ]);
+    /**
+     * Record an event of the specified type on `entity`, with an optional
+     * target entity.
+     */
+    pub fn event(&mut self, entity: &str, time: u64, event: &str,
+        target: Option<&str>)
+    {
+        self.builder_data();
 
-        statemap.print("Initial load");
-        statemap.verify();
-        statemap.subsume_apply_and_verify("foo", 100);
-        statemap.subsume_apply_and_verify("foo", 300);
-        statemap.subsume_apply_and_verify("foo", 100);
+        /*
+         * Events cannot fail to be ingested.
+         */
+        let _ = self.ingest_event(StatemapInputEvent {
+            time: time,
+            entity: entity.to_string(),
+            event: event.to_string(),
+            target: target.map(|t| t.to_string()),
+        });
+
+        self.nrecs += 1;
     }
 
-    #[test]
-    fn trim() {
-        let mut statemap = data(None, vec![
-            r##"{ "time": "0", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "100", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "101", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "104", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "106", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "206", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "207", "entity": "foo", "state": 0 }"##
-        ]);
+    /**
+     * Complete the construction of a statemap.  This must be called after
+     * all data has been added and before the statemap is rendered.
+     */
+    pub fn finish(&mut self) {
+        self.builder_data();
+        self.ingest_end();
+    }
 
-        statemap.print("Initial");
+    /**
+     * Coalesce the specified (ingested) statemaps such that they have no
+     * more than `maxrect` rectangles in total.  Rectangles are trimmed by
+     * weight across all of the statemaps, so this should only be used on
+     * statemaps that share time bounds (as stacked statemaps do).  Each
+     * entity retains at least one rectangle, so the total may exceed
+     * `maxrect` if there are more entities than that.
+     */
+    pub fn coalesce(statemaps: &mut [Statemap], maxrect: u64)
+        -> Result<(), Box<dyn Error>>
+    {
+        if statemaps.iter().any(|s| s.resolution().is_some()) {
+            return Err(Box::new(StatemapError::new(
+                "cannot further coalesce statemaps coalesced into buckets")));
+        }
 
-        statemap.trim();
-        statemap.verify();
-        statemap.print("After first trim");
+        let mut nrects: u64 = statemaps.iter()
+            .map(|s| s.byweight.len() as u64)
+            .sum();
 
-        statemap.trim();
-        statemap.verify();
-        statemap.print("After second trim");
+        /*
+         * Each trim removes exactly one rectangle from the weight tree of
+         * the statemap that it trims.
+         */
+        while nrects > maxrect {
+            let victim = statemaps.iter().enumerate()
+                .filter_map(|(i, s)| s.byweight.iter().next()
+                    .map(|rweight| (rweight.weight, i)))
+                .min();
+
+            match victim {
+                Some((_, i)) => statemaps[i].trim(),
+                None => break
+            }
 
-        statemap.trim();
-        statemap.verify();
-        statemap.print("After third trim");
+            nrects -= 1;
+        }
+
+        Ok(())
     }
 
-    #[test]
-    fn trim_insert() {
-        let mut statemap = data(None, vec![
-            r##"{ "time": "0", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "100", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "101", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "104", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "106", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "206", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "207", "entity": "foo", "state": 0 }"##
-        ]);
+    /**
+     * Return the beginning and end of the statemap, as nanoseconds since
+     * the Epoch.
+     */
+    pub fn timebounds(&self) -> (u64, u64) {
+        (self.begin, self.end)
+    }
 
-        statemap.print("Initial");
+    /**
+     * The number of records ingested.
+     */
+    pub fn nrecs(&self) -> u64 {
+        self.nrecs
+    }
 
-        statemap.trim();
-        statemap.verify();
-        statemap.print("After first trim");
+    /**
+     * The number of rectangles that are being tracked for coalescing.
+     */
+    pub fn nrects(&self) -> usize {
+        self.byweight.len()
+    }
 
-        let mut datum = r##"{ "time": "210", "entity": "foo", "state": 1 }"##;
+    /**
+     * If coalescing into buckets, the width of each bucket in nanoseconds.
+     */
+    pub fn resolution(&self) -> Option<u64> {
+        match self.config.coalesce {
+            StatemapCoalesce::Buckets => match self.resolutions.first() {
+                Some(&resolution) if resolution != 0 => Some(resolution),
+                _ => None
+            },
+            _ => None
+        }
+    }
 
-        assert!(statemap.ingest_datum(&mut datum).is_ok());
-        statemap.verify();
-        statemap.print("After insert");
+    /**
+     * The width of each bucket in nanoseconds for each finer level of
+     * detail, from coarsest to finest (see `StatemapEntity::level`).
+     */
+    pub fn levels(&self) -> &[u64] {
+        if self.resolutions.len() > 1 {
+            &self.resolutions[1..]
+        } else {
+            &[]
+        }
+    }
 
-        statemap.trim();
-        statemap.verify();
-        statemap.print("After second trim");
+    /**
+     * If recording the occupancy of each state, return it.
+     */
+    pub fn utilization(&self) -> Option<&StatemapUtilization> {
+        self.utilization.as_ref()
     }
 
-    #[test]
-    fn trim_multient() {
-        let mut statemap = data(None, vec![
-            r##"{ "time": "0", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "1000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "1010", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "1040", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "1060", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "2060", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "2070", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "0", "entity": "bar", "state": 0 }"##,
-            r##"{ "time": "10", "entity": "bar", "state": 1 }"##,
-        ]);
+    pub fn title(&self) -> Option<&str> {
+        self.metadata.as_ref().map(|m| m.title.as_str())
+    }
 
-        statemap.print("Initial");
+    pub fn host(&self) -> Option<&str> {
+        match self.metadata {
+            Some(ref metadata) => metadata.host.as_ref().map(|h| h.as_str()),
+            None => None
+        }
+    }
 
-        statemap.trim();
-        statemap.verify();
-        statemap.print("After trim");
+    /**
+     * Return the valid states, indexed by value.
+     */
+    pub fn states(&self) -> &[StatemapState] {
+        &self.states
     }
 
-    #[test]
-    fn data_begin_time() {
-        let mut config: Config = Default::default();
-        config.begin = 200000;
+    /**
+     * Return our entities, sorted by name.
+     */
+    pub fn entities(&self) -> Vec<&StatemapEntity> {
+        self.sort(None).iter()
+            .map(|&e| self.entities.get(&self.byid[e]).unwrap())
+            .collect()
+    }
 
-        let statemap = data(Some(&config), vec![
-            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
-        ]);
+    pub fn entity(&self, name: &str) -> Option<&StatemapEntity> {
+        self.entities.get(name)
+    }
 
-        statemap.verify();
-        statemap.print("Begin at 200000");
+    /**
+     * Return the time of the first event of the specified type, in
+     * nanoseconds relative to the start time, or None if there is no such
+     * event.
+     */
+    pub fn event_time(&self, event: &str) -> Option<u64> {
+        let id = *self.events.get(event)?;
+
+        self.entities.values()
+            .flat_map(|e| e.events.iter())
+            .filter(|e| e.event == id)
+            .map(|e| e.time)
+            .min()
+    }
 
-        let rects = statemap.get_rects("foo");
-        assert_eq!(rects.len(), 4);
-        assert_eq!(rects[0].0, 200000);
-        assert_eq!(rects[0].1, 300000 - 200000);
+    /**
+     * Return our tag definitions, indexed by tag identifier.
+     */
+    pub fn tags(&self) -> Vec<&Value> {
+        let mut tags: Vec<(usize, &Value)> = self.tags.values()
+            .map(|&(ref value, id)| (id, value))
+            .collect();
+
+        tags.sort_by_key(|&(id, _)| id);
+        tags.into_iter().map(|(_, value)| value).collect()
     }
 
-    #[test]
-    fn data_begin_time_later() {
+    /**
+     * Return the transitions of all entities from one state to another,
+     * sorted by the state transitioned from and then by the state
+     * transitioned to.
+     */
+    pub fn transitions(&self) -> Vec<StatemapTransition> {
+        let mut transitions: HashMap<(usize, usize), StatemapTransition> =
+            HashMap::new();
+
+        for entity in self.entities.values() {
+            for t in entity.transitions() {
+                let total = transitions.entry((t.from, t.to))
+                    .or_insert(StatemapTransition { count: 0, dwell: 0, ..t });
+
+                total.count += t.count;
+                total.dwell += t.dwell;
+            }
+        }
+
+        let mut transitions: Vec<StatemapTransition> =
+            transitions.values().cloned().collect();
+
+        transitions.sort_by_key(|t| (t.from, t.to));
+        transitions
+    }
+
+    /**
+     * Return the distribution of the durations of visits to each state by
+     * all entities, indexed by state value.  This is empty unless
+     * durations are being recorded (see `Config::durations`).
+     */
+    pub fn durations(&self) -> Vec<StatemapDistribution> {
+        let mut durations = match self.config.durations {
+            Some(durations) => {
+                vec![StatemapDistribution::new(durations); self.states.len()]
+            }
+            None => { return vec![]; }
+        };
+
+        for entity in self.entities.values() {
+            for (dist, other) in durations.iter_mut().zip(&entity.durations) {
+                dist.merge(other);
+            }
+        }
+
+        durations
+    }
+
+    /*
+     * Return the names of our event types, in identifier order.
+     */
+    fn event_types(&self) -> Vec<&String> {
+        let mut types: Vec<(usize, &String)> = self.events.iter()
+            .map(|(name, id)| (*id, name))
+            .collect();
+
+        types.sort_unstable();
+        types.into_iter().map(|(_, name)| name).collect()
+    }
+
+    /*
+     * Determine if any of our events have a target that is an entity in
+     * this statemap (and will therefore be drawn as an arrow).
+     */
+    fn has_arrows(&self) -> bool {
+        self.entities.values().any(|e| e.events.iter().any(|event| {
+            match event.target {
+                Some(ref target) => self.entities.contains_key(target),
+                None => false
+            }
+        }))
+    }
+
+    /*
+     * Determine if this statemap can share a legend with another.
+     */
+    fn legend_shared(&self, other: &Statemap) -> bool {
+        self.states == other.states &&
+            self.event_types() == other.event_types()
+    }
+
+    fn output_defs(&self, out: &mut dyn Write, events: &Vec<String>)
+        -> Result<(), Box<dyn Error>>
+    {
+        /*
+         * Provide an "entities" member that has the descriptions for each
+         * entity, if they have one.  Yes, this is a little goofy -- it
+         * would make much more sense to have a "descriptions" member that
+         * consists of strings named by entity -- but we're doing this for
+         * the sake of compatibility with the legacy implementation, however
+         * dubious..
+         */
+        writeln!(out, "entities: {{")?;
+
+        let mut comma = "";
+
+        for entity in self.entities.values() {
+            let val = match entity.description {
+                Some(ref description) => {
+                    format!("description: \"{}\"", description)
+                }
+                _ => { "".to_string() }
+            };
+
+            writeln!(out, "    {} \"{}\": {{ {} }}", comma, entity.name, val)?;
+            comma = ",";
+        }
+
+        writeln!(out, "}}")?;
+
+        if self.tags.len() > 0 {
+            /*
+             * Pull our tags into a Vec so we can sort them and emit them in
+             * array order.
+             */
+            let mut tags: Vec<(usize, u32, &str)> = vec![];
+
+            for ((state, tag), (_value, id)) in self.tags.iter() {
+                tags.push((*id, *state, tag));
+            }
+
+            tags.sort_unstable();
+
+            writeln!(out, ", tags: [")?;
+
+            for i in 0..tags.len() {
+                let (value, id) =
+                    self.tags.get(&(tags[i].1, tags[i].2.to_string())).unwrap();
+
+                assert_eq!(i, *id);
+                writeln!(out, "{}{}",
+                    serde_json::to_string_pretty(value).unwrap(),
+                    if i < tags.len() - 1 { "," } else { "" })?;
+            }
+
+            writeln!(out, "]")?;
+        }
+
+        if events.len() > 0 {
+            writeln!(out, ", eventTypes: {}",
+                serde_json::to_string(&self.event_types()).unwrap())?;
+            writeln!(out, ", events: [")?;
+
+            for i in 0..events.len() {
+                writeln!(out, "{}{}", events[i],
+                    if i < events.len() - 1 { "," } else { "" })?;
+            }
+
+            writeln!(out, "]")?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Events are drawn as glyphs outside of our transformed group:  they
+     * should not be stretched as the statemap is zoomed, so the viewer
+     * instead updates their positions as the transform changes.  We return
+     * the data for each glyph, in the order that they were emitted.
+     */
+    fn output_events(&self, out: &mut dyn Write, id: usize,
+        strips: &Vec<(usize, u32)>, config: &StatemapSVGConfig,
+        globals: &StatemapSVGGlobals) -> Result<Vec<String>, Box<dyn Error>>
+    {
+        let size = cmp::min(config.stripHeight / 2, 5);
+        let mut data: Vec<String> = vec![];
+
+        let event_x = |event: &StatemapEvent| -> f64 {
+            ((event.time as i64 - self.config.begin) as f64 /
+                globals.timeWidth as f64) * globals.pixelWidth as f64
+        };
+
+        let ys: HashMap<&str, u32> = strips.iter()
+            .map(|&(e, y)| (self.byid[e].as_str(), y))
+            .collect();
+
+        /*
+         * Any event that has an entity in this statemap as its target is
+         * drawn as an arrow from the middle of the source strip to the
+         * edge of the target strip.  These are drawn first, so the event
+         * glyphs sit atop them.
+         */
+        writeln!(out, r##"<g id="statemap-{}-arrows">"##, id)?;
+
+        for &(e, y) in strips {
+            let entity = self.entities.get(self.byid.get(e).unwrap()).unwrap();
+
+            for event in entity.events.iter() {
+                let target = match event.target {
+                    Some(ref target) => match ys.get(target.as_str()) {
+                        Some(target) => *target,
+                        None => continue
+                    },
+                    None => continue
+                };
+
+                if target == y {
+                    continue;
+                }
+
+                let y1 = y + (config.stripHeight / 2);
+                let y2 = if target > y { target } else {
+                    target + config.stripHeight
+                };
+
+                writeln!(out, concat!(
+                    r##"<line transform="translate({},0)" "##,
+                    r##"x1="0" y1="{}" x2="0" y2="{}" "##,
+                    r##"class="statemap-arrow" />"##),
+                    event_x(event), y1, y2)?;
+            }
+        }
+
+        writeln!(out, "</g>")?;
+
+        writeln!(out, r##"<g id="statemap-{}-events">"##, id)?;
+
+        for &(e, y) in strips {
+            let entity = self.entities.get(self.byid.get(e).unwrap()).unwrap();
+            let mut events: Vec<&StatemapEvent> = entity.events.iter().collect();
+
+            events.sort_by_key(|event| event.time);
+
+            for event in events {
+                let x = event_x(event);
+
+                data.push(event_datum(entity, event));
+
+                writeln!(out, concat!(
+                    r##"<path transform="translate({},{})" "##,
+                    r##"d="M0 -{} L{} 0 L0 {} L-{} 0 Z" "##,
+                    r##"onclick="eventclick(evt, {}, {})" "##,
+                    r##"class="statemap-event" style="fill:{}" />"##),
+                    x, y + (config.stripHeight / 2), size, size, size, size,
+                    id, data.len() - 1, event_color(event.event))?;
+            }
+        }
+
+        writeln!(out, "</g>")?;
+
+        Ok(data)
+    }
+
+    /*
+     * Return the members of our element in the viewer's statemaps array
+     * that describe the statemap itself.
+     */
+    fn locals(&self, globals: &StatemapSVGGlobals)
+        -> Result<StatemapSVGLocals<'_>, Box<dyn Error>>
+    {
+        let metadata = match self.metadata {
+            Some(ref metadata) => { metadata }
+            _ => { return self.err("metadata not found in data stream"); }
+        };
+
+        let mut title = metadata.title.clone();
+
+        if let Some(ref host) = metadata.host {
+            title.push_str(&format!(" on {}", host));
+        }
+
+        Ok(StatemapSVGLocals {
+            offset: self.config.begin - globals.begin,
+            states: &self.states,
+            entityKind: match metadata.entityKind {
+                Some(ref kind) => { kind }
+                None => { "Entity" }
+            },
+            title: title,
+        })
+    }
+
+    /*
+     * If we are recording the durations of visits, output a summary of them
+     * for each state, along with the number of visits in each power of two
+     * for the viewer to draw as a histogram.
+     */
+    fn output_durations(&self, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        let durations = self.durations();
+
+        if durations.is_empty() {
+            return Ok(());
+        }
+
+        let data: Vec<Value> = durations.iter().map(|dist| {
+            let mut datum = distribution_datum(dist);
+            datum["octaves"] = json!(dist.octaves());
+            datum
+        }).collect();
+
+        writeln!(out, "\"durations\": {},", serde_json::to_string(&data)?)?;
+
+        Ok(())
+    }
+
+    /*
+     * If we have finer levels of detail, output each of them for the viewer
+     * to draw as the statemap is zoomed.
+     */
+    fn output_levels(&self, out: &mut dyn Write, colors: &Vec<StatemapColor>)
+        -> Result<(), Box<dyn Error>>
+    {
+        let levels = self.levels();
+
+        if levels.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(out, "\"levels\": [")?;
+
+        for (level, resolution) in levels.iter().enumerate() {
+            let data: HashMap<&String, Vec<String>> = self.entities
+                .values()
+                .map(|e| (&e.name, e.output_level(level, colors)))
+                .collect();
+
+            write!(out, "{}{{ \"resolution\": {}, \"data\": ",
+                if level > 0 { "," } else { "" }, resolution)?;
+            output_data(out, &data)?;
+            writeln!(out, " }}")?;
+        }
+
+        writeln!(out, "],")?;
+
+        Ok(())
+    }
+
+    /*
+     * Output our utilization band:  a stacked area chart of the number of
+     * entities in each state over time, with a path for each state (in
+     * order of value).  The band is as high as all of our entities, and
+     * clicking on a state's path selects the state.
+     */
+    fn output_utilization(&self, out: &mut dyn Write, id: usize,
+        config: &StatemapSVGConfig, globals: &StatemapSVGGlobals,
+        colors: &Vec<StatemapColor>) -> Result<(), Box<dyn Error>>
+    {
+        let height = (globals.utilizationHeight - UTILIZATION_MARGIN) as f64;
+        let scale = height / cmp::max(self.entities.len(), 1) as f64;
+
+        let occupancy = match self.utilization {
+            Some(ref utilization) => utilization.occupancy(),
+            None => vec![]
+        };
+
+        let x = |time: u64| -> f64 {
+            ((time as i64 - self.config.begin) as f64 /
+                globals.timeWidth as f64) * globals.pixelWidth as f64
+        };
+
+        writeln!(out, r##"<g id="statemap-{}-utilization">"##, id)?;
+
+        writeln!(out, concat!(r##"<rect x="0" y="0" width="{}" "##,
+            r##"height="{}" style="fill:{}" />"##), globals.pixelWidth,
+            globals.utilizationHeight, config.background)?;
+
+        /*
+         * Each edge of an area steps from bucket to bucket; we only need
+         * the points at which it changes height.
+         */
+        let edge = |points: Vec<(f64, f64)>| -> Vec<String> {
+            points.iter().enumerate().filter(|&(i, &(_, y))| {
+                i == 0 || i == points.len() - 1 ||
+                    points[i - 1].1 != y || points[i + 1].1 != y
+            }).map(|(_, &(x, y))| format!("{:.2} {:.2}", x, y)).collect()
+        };
+
+        let mut base = vec![0.0; occupancy.len()];
+
+        for state in 0..self.states.len() {
+            if occupancy.iter().all(|(_, _, o)| o[state] == 0.0) {
+                continue;
+            }
+
+            let mut top = vec![];
+            let mut bottom = vec![];
+
+            for (i, &(start, end, ref o)) in occupancy.iter().enumerate() {
+                let y = height - (base[i] * scale);
+
+                base[i] += o[state];
+
+                let y1 = height - (base[i] * scale);
+
+                top.push((x(start), y1));
+                top.push((x(end), y1));
+                bottom.push((x(start), y));
+                bottom.push((x(end), y));
+            }
+
+            bottom.reverse();
+
+            let mut points = edge(top);
+            points.extend(edge(bottom));
+
+            writeln!(out, concat!(r##"<path d="M{} Z" "##,
+                r##"class="statemap-utilization" style="fill:{}" "##,
+                r##"onclick="utilizationclick(evt, {}, {})">"##,
+                r##"<title>{}</title></path>"##), points.join(" L"),
+                colors[state], id, state, escape(&self.states[state].name))?;
+        }
+
+        writeln!(out, "</g>")?;
+
+        Ok(())
+    }
+
+    fn output_svg(&self, out: &mut dyn Write, id: usize,
+        config: &StatemapSVGConfig, globals: &StatemapSVGGlobals,
+        colors: &Vec<StatemapColor>) -> Result<(), Box<dyn Error>>
+    {
+        let locals = self.locals(globals)?;
+        let entities = self.sortby(config)?;
+
+        writeln!(out,
+            r##"<g id="statemap-{}" transform="matrix(1 0 0 1 0 0)">"##, id)?;
+
+        if globals.utilizationHeight > 0 {
+            self.output_utilization(out, id, config, globals, colors)?;
+        }
+
+        let mut y = globals.utilizationHeight;
+        let mut data = HashMap::new();
+
+        let mut strips: Vec<(usize, u32)> = vec![];
+        let mut markers: Vec<String> = vec![];
+
+        let render = StatemapSVGRender {
+            id: id,
+            begin: self.config.begin,
+            config: config,
+            globals: globals,
+            locals: &locals,
+            colors: colors,
+        };
+
+        for e in entities {
+            let entity = self.entities.get(self.byid.get(e).unwrap()).unwrap();
+            data.insert(&entity.name,
+                entity.output_svg(out, &render, y, &mut markers)?);
+            strips.push((e, y));
+            y += config.stripHeight;
+        }
+
+        if markers.len() > 0 {
+            writeln!(out, r##"<g id="statemap-{}-salient">"##, id)?;
+
+            for marker in markers {
+                writeln!(out, "{}", marker)?;
+            }
+
+            writeln!(out, "</g>")?;
+        }
+
+        writeln!(out, "</g>")?;
+
+        let events = self.output_events(out, id, &strips, config, globals)?;
+
+        /*
+         * Finally, output our element in the global statemaps array.
+         */
+        writeln!(out, "<defs>")?;
+        writeln!(out, r##"<script type="application/ecmascript"><![CDATA["##)?;
+
+        let str = serde_json::to_string_pretty(&locals).unwrap();
+
+        writeln!(out, "g_statemaps[{}] = {{\n{},", id,
+            &str[2..str.len() - 2])?;
+
+        write!(out, "\"data\": ")?;
+        output_data(out, &data)?;
+        writeln!(out, ",")?;
+
+        self.output_levels(out, colors)?;
+        self.output_durations(out)?;
+        self.output_defs(out, &events)?;
+
+        writeln!(out, r##"}} ]]></script></defs>"##)?;
+
+        Ok(())
+    }
+}
+
+impl<'a> StatemapSVG<'a> {
+    /**
+     * Create a renderer of (possibly stacked) statemaps as SVG.
+     */
+    pub fn new(config: &'a StatemapSVGConfig) -> Self {
+        StatemapSVG {
+            config: config
+        }
+    }
+
+    fn output_defs(&self, out: &mut dyn Write, globals: &StatemapSVGGlobals)
+        -> Result<(), Box<dyn Error>>
+    {
+        writeln!(out, "<defs>")?;
+
+        writeln!(out, "<script type=\"application/ecmascript\"><![CDATA[")?;
+
+        writeln!(out, "var globals = {{")?;
+        let str = serde_json::to_string_pretty(&self.config).unwrap();
+        writeln!(out, "{},", &str[2..str.len() - 2])?;
+
+        let str = serde_json::to_string_pretty(&globals).unwrap();
+        writeln!(out, "{},", &str[2..str.len() - 2])?;
+        writeln!(out, "}}")?;
+
+        /*
+         * Now drop in our in-SVG code.
+         */
+        let lib = include_str!("statemap-svg.js");
+
+        writeln!(out, "{}\n]]></script>", lib)?;
+
+        /*
+         * Next up: CSS.
+         */
+        let css = include_str!("statemap-svg.css");
+
+        writeln!(out, "<style type=\"text/css\"><![CDATA[\n{}\n]]></style>",
+            css)?;
+
+        /*
+         * And now other definitions.
+         */
+        let defs = include_str!("statemap-svg.defs");
+        writeln!(out, "{}", defs)?;
+
+        writeln!(out, "</defs>")?;
+
+        Ok(())
+    }
+
+    /*
+     * Make sure that all of our colors are valid, returning the color of
+     * each state of each statemap.
+     */
+    fn colors(&self, statemaps: &Vec<Statemap>)
+        -> Result<Vec<Vec<StatemapColor>>, Box<dyn Error>>
+    {
+        let mut colors: Vec<Vec<StatemapColor>> = vec![];
+
+        for i in 0..statemaps.len() {
+            colors.push(vec![]);
+
+            for j in 0..statemaps[i].states.len() {
+                match statemaps[i].states[j].color {
+                    Some(ref name) => {
+                        match StatemapColor::from_str(name) {
+                            Ok(color) => colors[i].push(color),
+                            Err(_err) => {
+                                return statemaps[0].err(&format!(concat!(
+                                    "illegal color \"{}\" for state \"{}\""),
+                                    name, statemaps[i].states[j].name));
+                            }
+                        }
+                    }
+                    None => colors[i].push(StatemapColor::random())
+                }
+            }
+        }
+
+        Ok(colors)
+    }
+
+    /*
+     * Return the order in which our statemaps should be stacked.
+     */
+    fn stacksort(&self, statemaps: &Vec<Statemap>, sharedlegend: bool)
+        -> Result<Vec<usize>, Box<dyn Error>>
+    {
+        let base = &statemaps[0];
+
+        let stacksortby = match self.config.stacksortby {
+            None => { return Ok((0..statemaps.len()).collect()); }
+            Some(ref stacksortby) => stacksortby
+        };
+
+        /*
+         * If we aren't all sharing a legend, this doesn't make sense.
+         */
+        if !sharedlegend {
+            return base.err("can only stack sort like statemaps");
+        }
+
+        let metadata = match base.metadata {
+            Some(ref metadata) => { metadata }
+            _ => { return base.err("metadata not found in data stream"); }
+        };
+
+        if !metadata.states.contains_key(stacksortby) {
+            return base.err(&format!(concat!("unknown stack sorting ",
+                "state \"{}\""), stacksortby));
+        }
+
+        let sort = metadata.states.get(stacksortby).unwrap().value;
+
+        let mut weights = statemaps.iter()
+            .map(|s| s.weight(sort))
+            .enumerate()
+            .collect::<Vec<(usize, _)>>();
+
+        weights.sort_by(|&(_, l), &(_, r)| r.cmp(&l));
+
+        Ok(weights.iter().map(|&(e, _)| e).collect())
+    }
+
+    fn title(&self, statemaps: &Vec<Statemap>) -> String
+    {
+        /*
+         * A little helper routine to generate an Oxford comma-separated
+         * list.
+         */
+        let oxford = |v: &Vec<String>| {
+            let map: HashSet<_> = v.iter().collect();
+
+            if map.len() == 1 {
+                v[0].clone()
+            } else if v.len() == 2 {
+                v.join(" and ")
+            } else {
+                (&v[0..v.len() - 1]).join(", ") + ", and " + &v[v.len() - 1]
+            }
+        };
+
+        /*
+         * If we have "statemap" anywhere in the title of the first statemap,
+         * we assume it's a legacy title and just use that.
+         */
+        let metadata = statemaps[0].metadata.as_ref().unwrap();
+
+        if metadata.title.find("tatemap").is_some() {
+            return metadata.title.clone();
+        }
+
+        /*
+         * Gather our titles.
+         */
+        let titles = statemaps.iter()
+            .filter_map(|s| s.metadata.as_ref())
+            .map(|m| m.title.to_string())
+            .collect::<Vec<String>>();
+
+        let mut title = vec!["Statemap of".to_string(),
+            oxford(&titles), "activity".to_string()];
+
+        let hosts = statemaps.iter()
+            .filter_map(|s| s.metadata.as_ref())
+            .filter_map(|m| m.host.as_ref())
+            .map(|r| r.to_string())
+            .collect::<Vec<String>>();
+
+        if hosts.len() > 0 {
+            title.push("on".to_string());
+
+            if hosts.iter().collect::<HashSet<_>>().len() > 5 {
+                title.push(format!("{} hosts", hosts.len()));
+            } else {
+                title.push(oxford(&hosts));
+            }
+        }
+
+        title.join(" ")
+    }
+
+    /**
+     * Render the specified statemaps as a single SVG to `out`.  The first
+     * statemap dictates the time bounds of the rendering.
+     */
+    pub fn output<W: Write>(&self, statemaps: &Vec<Statemap>, out: &mut W)
+        -> Result<(), Box<dyn Error>>
+    {
+        self.output_svg(statemaps, out)
+    }
+
+    fn output_svg(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        struct Props {
+            x: u32,
+            y: u32,
+            height: u32,
+            width: u32,
+            lheight: u32,
+            spacing: u32
+        };
+
+        let base = &statemaps[0];
+
+        let output_controls = |out: &mut dyn Write, props: &Props|
+            -> io::Result<()>
+        {
+            let width = props.width / 4;
+            let mut x = 0;
+            let y = 0;
+
+            let icons = vec![
+                (include_str!("./icons/arrow-left-l.svg"), "panclick(50, 0)"),
+                (include_str!("./icons/zoom-in.svg"), "zoomclick(1.25)"),
+                (include_str!("./icons/zoom-out.svg"), "zoomclick(0.8)"),
+                (include_str!("./icons/arrow-right-l.svg"), "panclick(-50, 0)")
+            ];
+
+            writeln!(out,
+                r##"<svg x="{}px" y="{}px" width="{}px" height="{}px">"##,
+                props.x, props.y, props.width, props.height)?;
+
+            for i in 0..icons.len() {
+                writeln!(out,
+                    r##"<svg x="{}px" y="{}px" width="{}px" height="{}px"
+                    onclick="{}"><rect x="0px" y="0px" width="{}px" 
+                    height="{}px" onclick="{}" class="button" />{}</svg>"##,
+                    x, y, width, width, icons[i].1,
+                    width, width, icons[i].1, icons[i].0)?;
+                x += width;
+            }
+
+            writeln!(out, "</svg>")
+        };
+
+        let output_legend = |out: &mut dyn Write, statemap: &Statemap,
+            id: usize, props: &mut Props, colors: &Vec<StatemapColor>|
+            -> io::Result<()>
+        {
+            let x = props.x;
+            let mut y = props.y;
+            let height = props.lheight;
+            let width = props.width;
+
+            for state in 0..statemap.states.len() {
+                writeln!(out, concat!(r##"<rect x="{}" y="{}" width="{}" "##,
+                    r##"height="{}" id="statemap-legend-{}-{}" "##,
+                    r##"onclick="legendclick(evt, {}, {})" "##,
+                    r##"class="statemap-legend" style="fill:{}" />"##),
+                    x, y, width, height, id, state, id, state, colors[state])?;
+                y += height + props.spacing;
+
+                writeln!(out, concat!(r##"<text x="{}" y="{}" "##,
+                    r##"class="statemap-legendlabel sansserif">{}</text>"##),
+                    x + (width / 2), y, escape(&statemap.states[state].name))?;
+                y += props.spacing;
+            }
+
+            /*
+             * If we have events, list each event type (along with its
+             * glyph) below our states.
+             */
+            let events = statemap.event_types();
+
+            if events.len() > 0 {
+                y += props.spacing;
+            }
+
+            for event in 0..events.len() {
+                let cy = y + (height / 2);
+
+                writeln!(out, concat!(
+                    r##"<path transform="translate({},{})" "##,
+                    r##"d="M0 -5 L5 0 L0 5 L-5 0 Z" "##,
+                    r##"id="statemap-event-legend-{}-{}" "##,
+                    r##"class="statemap-event" style="fill:{}" />"##),
+                    x + 5, cy, id, event, event_color(event))?;
+
+                writeln!(out, concat!(r##"<text x="{}" y="{}" "##,
+                    r##"class="statemap-eventlabel sansserif">{}</text>"##),
+                    x + 15, cy + 3, escape(events[event]))?;
+                y += height;
+            }
+
+            /*
+             * If any events are drawn as arrows, add a toggle for them.
+             */
+            if statemap.has_arrows() {
+                let cy = y + (height / 2);
+
+                writeln!(out, concat!(r##"<line x1="{}" y1="{}" x2="{}" "##,
+                    r##"y2="{}" class="statemap-arrow" />"##),
+                    x, cy, x + 8, cy)?;
+
+                writeln!(out, concat!(r##"<text x="{}" y="{}" "##,
+                    r##"id="statemap-arrows-legend-{}" "##,
+                    r##"onclick="arrowsclick(evt)" "##,
+                    r##"class="statemap-eventlabel sansserif">"##,
+                    r##"hide arrows</text>"##), x + 15, cy + 3, id)?;
+                y += height;
+            }
+
+            props.y = y;
+
+            Ok(())
+        };
+
+        /*
+         * Our tagbox is also used to display the details of any event that
+         * is clicked on (and the distribution of the durations of visits to
+         * a state, if recorded), so we need it if we have events -- even if
+         * we aren't displaying tags.
+         */
+        let hasevents = statemaps.iter().any(|s| s.events.len() > 0);
+        let hasdurations = base.config.durations.is_some();
+
+        let output_tagbox = |out: &mut dyn Write| -> io::Result<()> {
+            if !base.config.notags || hasevents || hasdurations {
+                writeln!(out, r##"<g id="statemap-tagbox"></g>"##)?;
+                writeln!(out, r##"<g id="statemap-tagbox-select"></g>"##)?;
+            }
+
+            Ok(())
+        };
+
+        let metadata = match base.metadata {
+            Some(ref metadata) => { metadata }
+            _ => { return base.err("metadata not found in data stream"); }
+        };
+
+        #[allow(non_snake_case)]
+        let timeWidth = base.time_width();
+
+        let lmargin = self.config.legendWidth;
+        let tmargin = 60;
+        let rmargin = self.config.tagWidth;
+        let smargin = self.config.stripHeight;
+        let height: u32;
+
+        let tabbed = self.config.layout == StatemapLayout::Tabbed &&
+            statemaps.len() > 1;
+
+        /*
+         * If our statemaps are tabbed, only one is visible at a time (with
+         * a row of tabs to select it below them), so we need only be as
+         * tall as the tallest of them.
+         */
+        let tabmargin = 10;
+        let tabheight = 20;
+
+        /*
+         * If we are recording the occupancy of each state, each statemap
+         * has a band showing it above its entities.
+         */
+        let uheight = if base.config.utilization {
+            UTILIZATION_HEIGHT
+        } else {
+            0
+        };
+
+        if !tabbed {
+            let nentities = statemaps.iter().fold(0,
+                |total, statemap| { total + statemap.entities.len() });
+
+            height = nentities as u32 * self.config.stripHeight +
+                tmargin + ((statemaps.len() as u32) - 1) * smargin +
+                statemaps.len() as u32 * uheight;
+        } else {
+            let nentities = statemaps.iter().fold(0,
+                |max, statemap| { cmp::max(max, statemap.entities.len()) });
+
+            height = nentities as u32 * self.config.stripHeight + tmargin +
+                uheight;
+        }
+
+        let width = self.config.stripWidth + lmargin + rmargin;
+
+        let mut props = Props { x: 20, y: tmargin, height: 45,
+            width: lmargin, lheight: 15, spacing: 10 };
+
+        let mut sharedlegend = true;
+        let mut lheight = tmargin + props.height;
+        let mut tallest = 0;
+
+        let legend_height = |statemap: &Statemap| -> u32 {
+            let nevents = statemap.events.len() as u32;
+
+            let mut height = statemap.states.len() as u32 *
+                (props.lheight + (props.spacing * 2));
+
+            if nevents > 0 {
+                height += props.spacing + nevents * props.lheight;
+            }
+
+            if statemap.has_arrows() {
+                height += props.lheight;
+            }
+
+            height
+        };
+
+        /*
+         * We need to add in our legend height, but to determine this, we
+         * need to see to what degree we will be sharing legends.  (If our
+         * statemaps are tabbed, each tab has its own legend.)
+         */
+        for i in 0..statemaps.len() {
+            if i == 0 || !statemaps[i].legend_shared(&statemaps[i - 1]) {
+                lheight += legend_height(&statemaps[i]);
+
+                if i > 0 {
+                    lheight += props.spacing * 2;
+                    sharedlegend = false;
+                }
+            }
+
+            tallest = cmp::max(tallest, legend_height(&statemaps[i]));
+        }
+
+        if tabbed {
+            lheight = tmargin + props.height + tallest;
+        }
+
+        let globals = StatemapSVGGlobals {
+            begin: base.config.begin,
+            end: base.config.end,
+            pixelWidth: self.config.stripWidth,
+            pixelHeight: height - tmargin,
+            totalHeight: cmp::max(height +
+                if tabbed { tabmargin + tabheight } else { 0 }, lheight),
+            utilizationHeight: uheight,
+            timeWidth: timeWidth,
+            lmargin: lmargin,
+            tmargin: tmargin,
+            smargin: smargin,
+            entityPrefix: "statemap-entity-".to_string(),
+            states: &base.states,
+            start: &metadata.start,
+            entityKind: match metadata.entityKind {
+                Some(ref kind) => { kind }
+                None => { "Entity" }
+            }
+        };
+
+        let colors = self.colors(statemaps)?;
+        let sorted = self.stacksort(statemaps, sharedlegend)?;
+
+        writeln!(out, r##"<?xml version="1.0"?>
+            <!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN"
+                "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd">
+            <svg width="{}" height="{}"
+                xmlns="http://www.w3.org/2000/svg"
+                version="1.1"
+                onload="init(evt)">"##, width, globals.totalHeight)?;
+
+        self.output_defs(out, &globals)?;
+
+        let mut y = tmargin;
+
+        /*
+         * When tabbed, each statemap (and, below, its legend) is in a group
+         * that is displayed only when its tab is selected.
+         */
+        let display = |i: usize| if i == 0 { "inline" } else { "none" };
+
+        for i in 0..statemaps.len() {
+            let statemap = &statemaps[sorted[i]];
+
+            let height = statemap.entities.len() as u32 *
+                self.config.stripHeight + uheight;
+
+            if tabbed {
+                writeln!(out, r##"<g id="statemap-tab-{}" display="{}">"##,
+                    i, display(i))?;
+            }
+
+            writeln!(out,
+                r##"<svg x="{}px" y="{}px" width="{}px" height="{}px">"##,
+                lmargin, y, globals.pixelWidth, height)?;
+
+            /*
+             * First, we drop down a background rectangle as big as our SVG.
+             * This color will be changed dynamically to be a highlight color,
+             * and then rectangles can be made transparent to become
+             * highlighted.
+             */
+            writeln!(out, concat!(r##"<rect x="0px" y="0px" width="{}px" "##,
+                r##"height="{}px" fill="{}" id="statemap-{}-highlight" />"##),
+                globals.pixelWidth, height, self.config.background, i)?;
+
+            statemap.output_svg(out, i, &self.config, &globals, &colors[i])?;
+
+            writeln!(out, "</svg>")?;
+
+            /*
+             * The border around this statemap.
+             */
+            writeln!(out, r##"<polygon class="statemap-border""##)?;
+            writeln!(out, r##"  points="{} {}, {} {}, {} {}, {} {}"/>"##,
+                lmargin, y, lmargin + globals.pixelWidth, y,
+                lmargin + globals.pixelWidth, y + height,
+                lmargin, y + height)?;
+
+            if tabbed {
+                writeln!(out, "</g>")?;
+            } else {
+                y += height + smargin;
+            }
+        }
+
+        /*
+         * If we are tabbed, we have a row of tabs below our statemaps, each
+         * bearing the title of its statemap.
+         */
+        if tabbed {
+            let twidth = globals.pixelWidth / statemaps.len() as u32;
+            let y = tmargin + globals.pixelHeight + tabmargin;
+
+            for i in 0..statemaps.len() {
+                let locals = statemaps[sorted[i]].locals(&globals)?;
+
+                writeln!(out, concat!(r##"<svg x="{}px" y="{}px" "##,
+                    r##"width="{}px" height="{}px" "##,
+                    r##"onclick="tabclick(evt, {})">"##),
+                    lmargin + i as u32 * twidth, y, twidth, tabheight, i)?;
+
+                writeln!(out, concat!(r##"<rect x="0px" y="0px" "##,
+                    r##"width="{}px" height="{}px" "##,
+                    r##"id="statemap-tab-{}-button" class="statemap-tab{}" "##,
+                    r##"/>"##), twidth, tabheight, i,
+                    if i == 0 { " statemap-tab-selected" } else { "" })?;
+
+                writeln!(out, concat!(r##"<text x="{}" y="{}" "##,
+                    r##"class="statemap-tablabel sansserif">{}</text>"##),
+                    twidth / 2, (tabheight / 2) + 4, escape(&locals.title))?;
+
+                writeln!(out, "</svg>")?;
+            }
+        }
+
+        writeln!(out, concat!(r##"<text x="{}" y="{}" "##,
+            r##"class="statemap-title sansserif">{}</text>"##),
+            lmargin + (globals.pixelWidth / 2), 16,
+            escape(&self.title(statemaps)))?;
+
+        writeln!(out, concat!(r##"<text x="{}" y="{}" "##,
+            r##"class="statemap-timelabel sansserif" "##,
+            r##"id="statemap-timelabel"></text>"##),
+            lmargin + (globals.pixelWidth / 2), 34)?;
+
+        writeln!(out, r##"<line x1="{}" y1="{}" x2="{}" y2="{}""##,
+            lmargin + 10, 40, lmargin + globals.pixelWidth - 10, 40)?;
+        writeln!(out, r##"class="statemap-timeline" />"##)?;
+
+        props.width -= (2 * props.x) + 10;
+
+        output_controls(out, &props)?;
+
+        props.y += props.height;
+
+        for i in 0..statemaps.len() {
+            if tabbed {
+                let y = props.y;
+
+                writeln!(out,
+                    r##"<g id="statemap-tab-{}-legend" display="{}">"##,
+                    i, display(i))?;
+                output_legend(out, &statemaps[sorted[i]], i, &mut props,
+                    &colors[i])?;
+                writeln!(out, "</g>")?;
+
+                props.y = y;
+                continue;
+            }
+
+            if i == 0 || !statemaps[i].legend_shared(&statemaps[i - 1]) {
+                output_legend(out, &statemaps[i], i, &mut props, &colors[i])?;
+            }
+
+            if !sharedlegend {
+                props.y += props.spacing * 2;
+            }
+        }
+
+        output_tagbox(out)?;
+
+        writeln!(out, "</svg>")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::fs;
+    use std::io::Write;
+    use std::io::Read;
+
+    fn metadata(config: Option<&Config>, mut metadata: &str) -> Statemap {
+        let mut statemap;
+
+        match config {
+            Some(config) => { statemap = Statemap::new(&config); },
+            None => {
+                let config: Config = Default::default();
+                statemap = Statemap::new(&config);
+            }
+        }
+
+        match statemap.ingest_metadata(&mut metadata) {
+            Err(err) => { panic!("metadata incorrectly failed: {:?}", err); }
+            Ok(_) => { statemap }
+        }
+    }
+
+    fn minimal(config: Option<&Config>) -> Statemap {
+        metadata(config, r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0 },
+                "one": {"value": 1 }
+            }
+        }"##)
+    }
+
+    fn data(config: Option<&Config>, data: Vec<&str>) -> Statemap {
+        let mut statemap = minimal(config);
+
+        for mut datum in data {
+            match statemap.ingest_datum(&mut datum) {
+                Err(err) => { panic!("data incorrectly failed: {:?}", err); }
+                Ok(_) => {}
+            }
+        }
+
+        statemap.ingest_end();
+        statemap
+    }
+
+    fn bad_metadata(mut metadata: &str, expected: &str) {
+        let config: Config = Default::default();
+        let mut statemap = Statemap::new(&config);
+
+        match statemap.ingest_metadata(&mut metadata) {
+            Err(err) => {
+                let errmsg = format!("{}", err);
+
+                if errmsg.find(expected).is_none() {
+                    panic!("error ('{}') did not contain '{}' as expected",
+                        errmsg, expected);
+                }
+            },
+            Ok(_) => { panic!("bad metadata succeeded!"); }
+        }
+    }
+
+    fn bad_datum(operand: Option<Statemap>, mut datum: &str, expected: &str) {
+        let mut statemap = match operand {
+            Some(statemap) => statemap,
+            None => {
+                metadata(None, r##"{
+                    "start": [ 0, 0 ],
+                    "title": "Foo",
+                    "states": {
+                        "zero": {"value": 0 },
+                        "one": {"value": 1 }
+                    }
+                }"##)
+            }
+        };
+
+        match statemap.ingest_datum(&mut datum) {
+            Err(err) => {
+                let errmsg = format!("{}", err);
+
+                if errmsg.find(expected).is_none() {
+                    panic!("error ('{}') did not contain '{}' as expected",
+                        errmsg, expected);
+                }
+            },
+            Ok(_) => { panic!("bad datum succeeded!"); }
+        }
+    }
+
+    fn statemap_ingest(statemap: &mut Statemap, raw: &str)
+        -> Result<(), Box<Error>>
+    {
+        statemap_ingest_bytes(statemap, raw.as_bytes())
+    }
+
+    fn statemap_ingest_bytes(statemap: &mut Statemap, raw: &[u8])
+        -> Result<(), Box<Error>>
+    {
+        let mut path = env::temp_dir();
+        path.push(format!("statemap.test.{}.{:p}", process::id(), statemap));
+
+        let filename = path.to_str().unwrap();
+        let mut file = File::create(filename)?;
+        file.write_all(raw)?;
+
+        let result = statemap.ingest(filename);
+
+        fs::remove_file(filename)?;
+
+        result
+    }
+
+    /*
+     * A reader that returns at most a handful of bytes at a time, assuring
+     * that payloads (and characters) will straddle reads.
+     */
+    struct Trickle<'a> {
+        data: &'a [u8],
+        size: usize,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = cmp::min(cmp::min(self.size, buf.len()), self.data.len());
+
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn statemap_ingest_stream(statemap: &mut Statemap, raw: &str, size: usize)
+        -> Result<(), Box<dyn Error>>
+    {
+        let trickle = Trickle { data: raw.as_bytes(), size: size };
+        statemap.ingest_stream(trickle)
+    }
+
+    fn bad_statemap(raw: &str, expected: &str) {
+        let config: Config = Default::default();
+        let mut statemap = Statemap::new(&config);
+
+        match statemap_ingest(&mut statemap, raw) {
+            Err(err) => {
+                let errmsg = format!("{}\n", err);
+
+                if errmsg.find(expected).is_none() {
+                    panic!("error ('{}') did not contain '{}' as expected",
+                        errmsg, expected);
+                }
+            },
+            Ok(_) => { panic!("bad statemap succeeded!"); }
+        }
+
+        for size in vec![1, 7, STREAM_CHUNK] {
+            let mut statemap = Statemap::new(&config);
+
+            match statemap_ingest_stream(&mut statemap, raw, size) {
+                Err(err) => {
+                    let errmsg = format!("{}\n", err);
+
+                    if errmsg.find(expected).is_none() {
+                        panic!(concat!("stream error ('{}') did not ",
+                            "contain '{}' as expected"), errmsg, expected);
+                    }
+                },
+                Ok(_) => { panic!("bad statemap stream succeeded!"); }
+            }
+        }
+    }
+
+    macro_rules! bad_statemap {
+        ($what:expr) => ({
+            bad_statemap(include_str!(concat!("../tst/tst.", $what, ".in")),
+                include_str!(concat!("../tst/tst.", $what, ".err")));
+        });
+    }
+
+    fn good_statemap(config: &Config, raw: &str) -> Statemap {
+        let mut statemap = Statemap::new(&config);
+
+        match statemap_ingest(&mut statemap, raw) {
+            Err(err) => {
+                panic!("statemap failed: {}", err);
+            },
+            Ok(_) => { statemap }
+        }
+    }
+
+    macro_rules! good_statemap {
+        ($what:expr) => ({
+            let mut config: Config = Default::default();
+            config.notags = false;
+
+            good_statemap(&config,
+                include_str!(concat!("../tst/tst.", $what, ".in")))
+        });
+
+        ($what:expr, $conf:expr) => ({
+            good_statemap($conf,
+                include_str!(concat!("../tst/tst.", $what, ".in")))
+        });
+    }
+
+    #[test]
+    fn good_minimal() {
+        metadata(None, r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##);
+    }
+
+    #[test]
+    fn bad_title_missing() {
+        bad_metadata(r##"{
+            "start": [ 0, 0 ],
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##, "missing field `title`");
+    }
+
+    #[test]
+    fn bad_start_missing() {
+        bad_metadata(r##"{
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##, "missing field `start`");
+    }
+
+    #[test]
+    fn bad_start_badval() {
+        bad_metadata(r##"{
+            "start": [ -1, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##, "invalid value: integer `-1`");
+    }
+
+    #[test]
+    fn bad_start_tooshort() {
+        bad_metadata(r##"{
+            "start": [ 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##, "\"start\" property must be a two element array");
+    }
+
+    #[test]
+    fn bad_start_toolong() {
+        bad_metadata(r##"{
+            "start": [ 0, 0, 3 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##, "\"start\" property must be a two element array");
+    }
+
+    #[test]
+    fn bad_states_missing() {
+        bad_metadata(r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo"
+        }"##, "missing field `states`");
+    }
+
+    #[test]
+    fn bad_states_badmap() {
+        bad_metadata(r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": 123
+        }"##, "expected a map");
+    }
+
+    #[test]
+    fn bad_states_value_missing() {
+        bad_metadata(r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {}
+            }
+        }"##, "missing field `value`");
+    }
+
+    #[test]
+    fn bad_states_value_bad() {
+        bad_metadata(r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": -1 }
+            }
+        }"##, "invalid value: integer `-1`");
+    }
+
+    #[test]
+    fn bad_states_value_skipped1() {
+        bad_metadata(r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0 },
+                "one": {"value": 2 }
+            }
+        }"##, "state \"one\" has value (2) that exceeds maximum");
+    }
+
+    #[test]
+    fn bad_states_value_toohigh() {
+        bad_metadata(r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 1 },
+                "one": {"value": 2 }
+            }
+        }"##, "state \"one\" has value (2) that exceeds maximum");
+    }
+
+    #[test]
+    fn bad_states_value_duplicate() {
+        bad_metadata(r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 1 },
+                "one": {"value": 1 }
+            }
+        }"##, "has value (1) that conflicts");
+    }
+
+    #[test]
+    fn bad_line_basic() {
+        bad_statemap!("bad_line_basic");
+    }
+
+    #[test]
+    fn bad_line_whitespace() {
+        bad_statemap!("bad_line_whitespace");
+    }
+
+    #[test]
+    fn bad_line_newline() {
+        bad_statemap!("bad_line_newline");
+    }
+
+    #[test]
+    fn basic() {
+        let statemap = metadata(None, r##"{
+            "start": [ 1528417173, 255882937 ],
+            "title": "Foo",
+            "host": "HA8S7MRD2",
+            "entityKind": "Process",
+            "states": {
+                "on-cpu": {"value": 0, "color": "#2e9107" },
+                "off-cpu-waiting": {"value": 1, "color": "#f9f9f9" },
+                "off-cpu-semop": {"value": 2, "color": "#FF5733" },
+                "off-cpu-blocked": {"value": 3, "color": "#C70039" },
+                "off-cpu-zfs-read": {"value": 4, "color": "#FFC300" },
+                "off-cpu-zfs-write": {"value": 5, "color": "#338AFF" },
+                "off-cpu-zil-commit": {"value": 6, "color": "#66FFCC" },
+                "off-cpu-tx-delay": {"value": 7, "color": "#e1ff00" },
+                "off-cpu-dead": {"value": 8, "color": "#E0E0E0" }
+            }
+        }"##);
+        assert_eq!(statemap.states.len(), 9);
+        assert_eq!(statemap.states[0].name, "on-cpu");
+        assert_eq!(statemap.states[0].color, Some("#2e9107".to_string()));
+        assert_eq!(statemap.states[1].name, "off-cpu-waiting");
+        assert_eq!(statemap.states[1].color, Some("#f9f9f9".to_string()));
+        assert_eq!(statemap.states[8].name, "off-cpu-dead");
+    }
+
+    #[test]
+    fn basic_datum() {
+        let mut _statemap = data(None, vec![
+            r##"{ "time": "156683", "entity": "foo", "state": 0 }"##
+        ]);
+    }
+
+    #[test]
+    fn basic_description() {
+        let mut statemap = data(None, vec![
+            r##"{ "time": "156683", "entity": "foo", "state": 0 }"##,
+            r##"{ "entity": "foo", "description": "This is a foo!" }"##
+        ]);
+
+        assert_eq!(statemap.entity_lookup("foo").description,
+            Some("This is a foo!".to_string()));
+    }
+
+    #[test]
+    fn bad_datum_badtime() {
+        bad_datum(None, r##"
+            { "time": 156683, "entity": "foo", "state": 0 }
+        "##, "unrecognized payload");
+    }
+
+    #[test]
+    fn bad_datum_badtime_float() {
+        bad_datum(None, r##"
+            { "time": "156683.12", "entity": "foo", "state": 0 }
+        "##, "unrecognized payload");
+    }
+
+    #[test]
+    fn bad_datum_nostate() {
+        bad_datum(None, r##"
+            { "time": "156683", "entity": "foo" }
+        "##, "unrecognized payload");
+    }
+
+    #[test]
+    fn bad_datum_badstate() {
+        bad_datum(None, r##"
+            { "time": "156683", "entity": "foo", "state": 200 }
+        "##, "illegal state value");
+    }
+
+    #[test]
+    fn bad_datum_backwards() {
+        let statemap = data(None, vec![
+            r##"{ "time": "156683", "entity": "foo", "state": 0 }"##
+        ]);
+
+        bad_datum(Some(statemap), r##"
+            { "time": "156682", "entity": "foo", "state": 1 }
+        "##, "out of order with respect to prior time");
+    }
+
+    #[test]
+    fn bad_datum_resolution() {
+        let mut config: Config = Default::default();
+        config.coalesce = StatemapCoalesce::Buckets;
+        config.resolution = 1;
+
+        let statemap = data(Some(&config), vec![
+            r##"{ "time": "1000000", "entity": "foo", "state": 0 }"##
+        ]);
+
+        bad_datum(Some(statemap), r##"
+            { "time": "43200000000000", "entity": "foo", "state": 1 }
+        "##, "resolution of 1ns requires more than 1000000 buckets");
+    }
+
+    #[test]
+    fn basic_data() {
+        let statemap = data(None, vec![
+            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
+        ]);
+
+        statemap.verify();
+    }
+
+    #[test]
+    fn subsume() {
+        let mut statemap = data(None, vec![
+            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
+        ]);
+
+        statemap.print("Initial load");
+        statemap.verify();
+        statemap.subsume_apply_and_verify("foo", 100000);
+        statemap.subsume_apply_and_verify("foo", 300000);
+        statemap.subsume_apply_and_verify("foo", 100000);
+    }
+
+    #[test]
+    fn subsume_right() {
+        let mut statemap = data(None, vec![
+            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
+        ]);
+
+        statemap.print("Initial load");
+        statemap.verify();
+
+        statemap.subsume_apply_and_verify("foo", 500000);
+        statemap.subsume_apply_and_verify("foo", 400000);
+        statemap.subsume_apply_and_verify("foo", 300000);
+        statemap.subsume_apply_and_verify("foo", 200000);
+    }
+
+    #[test]
+    fn subsume_middle() {
+        let mut statemap = data(None, vec![
+            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
+        ]);
+
+        statemap.print("Initial load");
+        statemap.verify();
+        statemap.subsume_apply_and_verify("foo", 300000);
+        statemap.subsume_apply_and_verify("foo", 300000);
+        statemap.subsume_apply_and_verify("foo", 200000);
+    }
+
+    #[test]
+    fn subsume_tagged() {
+        let mut statemap = data(None, vec![
+            r##"{ "time": "100", "entity": "foo", "state": 0, "tag": "a" }"##,
+            r##"{ "time": "200", "entity": "foo", "state": 1, "tag": "b" }"##,
+            r##"{ "time": "300", "entity": "foo", "state": 0, "tag": "c" }"##,
+            r##"{ "time": "400", "entity": "foo", "state": 1, "tag": "b" }"##,
+            r##"{ "time": "500", "entity": "foo", "state": 0, "tag": "a" }"##,
+            r##"{ "time": "600", "entity": "foo", "state": 1, "tag": "b" }"##
+        ]);
+
+        statemap.print("Initial load");
+        statemap.verify();
+        statemap.subsume_apply_and_verify("foo", 100);
+        statemap.subsume_apply_and_verify("foo", 300);
+        statemap.subsume_apply_and_verify("foo", 100);
+    }
+
+    #[test]
+    fn trim() {
+        let mut statemap = data(None, vec![
+            r##"{ "time": "0", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "100", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "101", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "104", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "106", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "206", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "207", "entity": "foo", "state": 0 }"##
+        ]);
+
+        statemap.print("Initial");
+
+        statemap.trim();
+        statemap.verify();
+        statemap.print("After first trim");
+
+        statemap.trim();
+        statemap.verify();
+        statemap.print("After second trim");
+
+        statemap.trim();
+        statemap.verify();
+        statemap.print("After third trim");
+    }
+
+    #[test]
+    fn trim_insert() {
+        let mut statemap = data(None, vec![
+            r##"{ "time": "0", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "100", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "101", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "104", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "106", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "206", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "207", "entity": "foo", "state": 0 }"##
+        ]);
+
+        statemap.print("Initial");
+
+        statemap.trim();
+        statemap.verify();
+        statemap.print("After first trim");
+
+        let mut datum = r##"{ "time": "210", "entity": "foo", "state": 1 }"##;
+
+        assert!(statemap.ingest_datum(&mut datum).is_ok());
+        statemap.verify();
+        statemap.print("After insert");
+
+        statemap.trim();
+        statemap.verify();
+        statemap.print("After second trim");
+    }
+
+    #[test]
+    fn trim_multient() {
+        let mut statemap = data(None, vec![
+            r##"{ "time": "0", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "1000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "1010", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "1040", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "1060", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "2060", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "2070", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "0", "entity": "bar", "state": 0 }"##,
+            r##"{ "time": "10", "entity": "bar", "state": 1 }"##,
+        ]);
+
+        statemap.print("Initial");
+
+        statemap.trim();
+        statemap.verify();
+        statemap.print("After trim");
+    }
+
+    #[test]
+    fn data_begin_time() {
+        let mut config: Config = Default::default();
+        config.begin = 200000;
+
+        let statemap = data(Some(&config), vec![
+            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
+        ]);
+
+        statemap.verify();
+        statemap.print("Begin at 200000");
+
+        let rects = statemap.get_rects("foo");
+        assert_eq!(rects.len(), 4);
+        assert_eq!(rects[0].0, 200000);
+        assert_eq!(rects[0].1, 300000 - 200000);
+    }
+
+    #[test]
+    fn data_begin_time_later() {
+        let mut config: Config = Default::default();
+        config.begin = 200001;
+
+        let statemap = data(Some(&config), vec![
+            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
+        ]);
+
+        statemap.verify();
+        statemap.print("Begin at 200001");
+
+        let rects = statemap.get_rects("foo");
+        assert_eq!(rects.len(), 4);
+        assert_eq!(rects[0].0, 200001);
+        assert_eq!(rects[0].1, 300000 - 200001);
+        assert_eq!((rects[0].2)[0], 0);
+        assert_eq!((rects[0].2)[1], 300000 - 200001);
+    }
+
+    #[test]
+    fn data_begin_end_time() {
+        let mut config: Config = Default::default();
+        config.begin = 250000;
+        config.end = 310000;
+
+        let statemap = data(Some(&config), vec![
+            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
+        ]);
+
+        statemap.verify();
+        statemap.print("Begin at 250000, end at 310000");
+
+        let rects = statemap.get_rects("foo");
+        assert_eq!(rects.len(), 2);
+        assert_eq!(rects[0].0, 250000);
+        assert_eq!(rects[0].1, 250000 - 200000);
+        assert_eq!((rects[0].2)[0], 0);
+        assert_eq!((rects[0].2)[1], 250000 - 200000);
+
+        assert_eq!(rects[1].0, 300000);
+        assert_eq!(rects[1].1, 310000 - 300000);
+        assert_eq!((rects[1].2)[0], 310000 - 300000);
+        assert_eq!((rects[1].2)[1], 0);
+    }
+
+    #[test]
+    fn data_wrapped_time() {
+        let mut config: Config = Default::default();
+        config.begin = 250000;
+        config.end = 260000;
+
+        let statemap = data(Some(&config), vec![
+            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
+            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
+            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
+        ]);
+
+        statemap.verify();
+        statemap.print("Begin at 250000, end at 260000");
+
+        let rects = statemap.get_rects("foo");
+        assert_eq!(rects.len(), 1);
+        assert_eq!(rects[0].0, 250000);
+        assert_eq!(rects[0].1, 260000 - 250000);
+        assert_eq!((rects[0].2)[0], 0);
+        assert_eq!((rects[0].2)[1], 260000 - 250000);
+    }
+
+    #[test]
+    fn color_named() {
+        let colors = vec![
+            ("aliceblue", (240, 248, 255)),
+            ("antiquewhite", (250, 235, 215)),
+            ("aqua", (0, 255, 255)),
+            ("aquamarine", (127, 255, 212)),
+            ("azure", (240, 255, 255)),
+            ("beige", (245, 245, 220)),
+            ("bisque", (255, 228, 196)),
+            ("black", (0, 0, 0)),
+            ("blanchedalmond", (255, 235, 205)),
+            ("blue", (0, 0, 255)),
+            ("blueviolet", (138, 43, 226)),
+            ("brown", (165, 42, 42)),
+            ("burlywood", (222, 184, 135)),
+            ("cadetblue", (95, 158, 160)),
+            ("chartreuse", (127, 255, 0)),
+            ("chocolate", (210, 105, 30)),
+            ("coral", (255, 127, 80)),
+            ("cornflowerblue", (100, 149, 237)),
+            ("cornsilk", (255, 248, 220)),
+            ("crimson", (220, 20, 60)),
+            ("cyan", (0, 255, 255)),
+            ("darkblue", (0, 0, 139)),
+            ("darkcyan", (0, 139, 139)),
+            ("darkgoldenrod", (184, 134, 11)),
+            ("darkgray", (169, 169, 169)),
+            ("darkgreen", (0, 100, 0)),
+            ("darkgrey", (169, 169, 169)),
+            ("darkkhaki", (189, 183, 107)),
+            ("darkmagenta", (139, 0, 139)),
+            ("darkolivegreen", (85, 107, 47)),
+            ("darkorange", (255, 140, 0)),
+            ("darkorchid", (153, 50, 204)),
+            ("darkred", (139, 0, 0)),
+            ("darksalmon", (233, 150, 122)),
+            ("darkseagreen", (143, 188, 143)),
+            ("darkslateblue", (72, 61, 139)),
+            ("darkslategray", (47, 79, 79)),
+            ("darkslategrey", (47, 79, 79)),
+            ("darkturquoise", (0, 206, 209)),
+            ("darkviolet", (148, 0, 211)),
+            ("deeppink", (255, 20, 147)),
+            ("deepskyblue", (0, 191, 255)),
+            ("dimgray", (105, 105, 105)),
+            ("dimgrey", (105, 105, 105)),
+            ("dodgerblue", (30, 144, 255)),
+            ("firebrick", (178, 34, 34)),
+            ("floralwhite", (255, 250, 240)),
+            ("forestgreen", (34, 139, 34)),
+            ("fuchsia", (255, 0, 255)),
+            ("gainsboro", (220, 220, 220)),
+            ("ghostwhite", (248, 248, 255)),
+            ("gold", (255, 215, 0)),
+            ("goldenrod", (218, 165, 32)),
+            ("gray", (128, 128, 128)),
+            ("green", (0, 128, 0)),
+            ("greenyellow", (173, 255, 47)),
+            ("grey", (128, 128, 128)),
+            ("honeydew", (240, 255, 240)),
+            ("hotpink", (255, 105, 180)),
+            ("indianred", (205, 92, 92)),
+            ("indigo", (75, 0, 130)),
+            ("ivory", (255, 255, 240)),
+            ("khaki", (240, 230, 140)),
+            ("lavender", (230, 230, 250)),
+            ("lavenderblush", (255, 240, 245)),
+            ("lawngreen", (124, 252, 0)),
+            ("lemonchiffon", (255, 250, 205)),
+            ("lightblue", (173, 216, 230)),
+            ("lightcoral", (240, 128, 128)),
+            ("lightcyan", (224, 255, 255)),
+            ("lightgoldenrodyellow", (250, 250, 210)),
+            ("lightgray", (211, 211, 211)),
+            ("lightgreen", (144, 238, 144)),
+            ("lightgrey", (211, 211, 211)),
+            ("lightpink", (255, 182, 193)),
+            ("lightsalmon", (255, 160, 122)),
+            ("lightseagreen", (32, 178, 170)),
+            ("lightskyblue", (135, 206, 250)),
+            ("lightslategray", (119, 136, 153)),
+            ("lightslategrey", (119, 136, 153)),
+            ("lightsteelblue", (176, 196, 222)),
+            ("lightyellow", (255, 255, 224)),
+            ("lime", (0, 255, 0)),
+            ("limegreen", (50, 205, 50)),
+            ("linen", (250, 240, 230)),
+            ("magenta", (255, 0, 255)),
+            ("maroon", (128, 0, 0)),
+            ("mediumaquamarine", (102, 205, 170)),
+            ("mediumblue", (0, 0, 205)),
+            ("mediumorchid", (186, 85, 211)),
+            ("mediumpurple", (147, 112, 219)),
+            ("mediumseagreen", (60, 179, 113)),
+            ("mediumslateblue", (123, 104, 238)),
+            ("mediumspringgreen", (0, 250, 154)),
+            ("mediumturquoise", (72, 209, 204)),
+            ("mediumvioletred", (199, 21, 133)),
+            ("midnightblue", (25, 25, 112)),
+            ("mintcream", (245, 255, 250)),
+            ("mistyrose", (255, 228, 225)),
+            ("moccasin", (255, 228, 181)),
+            ("navajowhite", (255, 222, 173)),
+            ("navy", (0, 0, 128)),
+            ("oldlace", (253, 245, 230)),
+            ("olive", (128, 128, 0)),
+            ("olivedrab", (107, 142, 35)),
+            ("orange", (255, 165, 0)),
+            ("orangered", (255, 69, 0)),
+            ("orchid", (218, 112, 214)),
+            ("palegoldenrod", (238, 232, 170)),
+            ("palegreen", (152, 251, 152)),
+            ("paleturquoise", (175, 238, 238)),
+            ("palevioletred", (219, 112, 147)),
+            ("papayawhip", (255, 239, 213)),
+            ("peachpuff", (255, 218, 185)),
+            ("peru", (205, 133, 63)),
+            ("pink", (255, 192, 203)),
+            ("plum", (221, 160, 221)),
+            ("powderblue", (176, 224, 230)),
+            ("purple", (128, 0, 128)),
+            ("rebeccapurple", (102, 51, 153)),
+            ("red", (255, 0, 0)),
+            ("rosybrown", (188, 143, 143)),
+            ("royalblue", (65, 105, 225)),
+            ("saddlebrown", (139, 69, 19)),
+            ("salmon", (250, 128, 114)),
+            ("sandybrown", (244, 164, 96)),
+            ("seagreen", (46, 139, 87)),
+            ("seashell", (255, 245, 238)),
+            ("sienna", (160, 82, 45)),
+            ("silver", (192, 192, 192)),
+            ("skyblue", (135, 206, 235)),
+            ("slateblue", (106, 90, 205)),
+            ("slategray", (112, 128, 144)),
+            ("slategrey", (112, 128, 144)),
+            ("snow", (255, 250, 250)),
+            ("springgreen", (0, 255, 127)),
+            ("steelblue", (70, 130, 180)),
+            ("tan", (210, 180, 140)),
+            ("teal", (0, 128, 128)),
+            ("thistle", (216, 191, 216)),
+            ("tomato", (255, 99, 71)),
+            ("turquoise", (64, 224, 208)),
+            ("violet", (238, 130, 238)),
+            ("wheat", (245, 222, 179)),
+            ("white", (255, 255, 255)),
+            ("whitesmoke", (245, 245, 245)),
+            ("yellow", (255, 255, 0)),
+            ("yellowgreen", (154, 205, 50)),
+        ];
+
+        let notcolors = vec!["nixon", "yellowgreenybeeny", "#1234567",
+            "1234567", "$123456", "#123456#" ];
+
+        for i in 0..notcolors.len() {
+            match StatemapColor::from_str(notcolors[i]) {
+                Ok(color) => {
+                    panic!("lookup of {} succeeded with {:?}!",
+                        notcolors[i], color);
+                },
+                Err(err) => {
+                    println!("lookup of {} failed with {}", notcolors[i], err);
+                }
+            }
+        }
+
+        for i in 0..colors.len() {
+            match StatemapColor::from_str(colors[i].0) {
+                Ok(color) => {
+                    let out = format!("rgb({}, {}, {})",
+                        (colors[i].1).0, (colors[i].1).1, (colors[i].1).2);
+                    assert_eq!(out, color.to_string());
+                },
+                Err(err) => {
+                    panic!("lookup of {} failed with {}!", colors[i].0, err);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn color_mix() {
+        let red = StatemapColor::from_str("red").unwrap();
+        let white = StatemapColor::from_str("white").unwrap();
+
+        let tests = vec![
+            (0.0, "rgb(255, 0, 0)"),
+            (0.25, "rgb(255, 137, 137)"),
+            (0.5, "rgb(255, 188, 188)"),
+            (0.75, "rgb(255, 225, 225)"),
+            (1.0, "rgb(255, 255, 255)"),
+        ];
+
+        for i in 0..tests.len() {
+            assert_eq!(red._mix(&white, tests[i].0).to_string(), tests[i].1);
+            assert_eq!(red._mix(&white, tests[i].0).to_string(),
+                white._mix(&red, 1.0 - tests[i].0).to_string());
+        }
+    }
+
+    #[test]
+    fn color_mix_linear() {
+        let color = StatemapColor::from_str("#2e9107").unwrap();
+        let other = StatemapColor::from_str("#f9f9f9").unwrap();
+        let ratio = 351500 as f64 / 840108 as f64;
+        let mix = color._mix(&other, ratio as f32);
+
+        println!("color={}, other={}, mix={}", color, other, mix);
+    }
+
+    #[test]
+    fn color_mix_nonlinear() {
+        let color = StatemapColor::from_str("#2e9107").unwrap();
+        let other = StatemapColor::from_str("#f9f9f9").unwrap();
+        let ratio = 351500 as f64 / 840108 as f64;
+        let mix = color.mix_nonlinear(&other, ratio as f32);
+
+        println!("color={}, other={}, mix={}", color, other, mix);
+    }
+
+    #[test]
+    fn tag_basic() {
+        let statemap = good_statemap!("tag_basic");
+        println!("{:?}", statemap.tags);
+        statemap.verify();
+    }
+
+    #[test]
+    fn tag_redefined() {
+        let statemap = good_statemap!("tag_redefined");
+        println!("{:?}", statemap.tags);
+        statemap.verify();
+    }
+
+    #[test]
+    fn timebounds() {
+        let statemap = good_statemap!("io");
+        let _timebounds = statemap.timebounds();
+
+        let mut config: Config = Default::default();
+        config.begin = 100000;
+
+        let bounded = good_statemap!("io", &config);
+        println!("{:?}", bounded.timebounds());
+
+        config.begin = -100000;
+        config.end = 10000;
+
+        let bounded = good_statemap!("io", &config);
+        println!("{:?}", bounded.timebounds());
+
+        statemap.verify();
+    }
+
+    #[test]
+    fn accessors() {
+        let statemap = good_statemap!("events");
+
+        assert_eq!(statemap.title(), Some("Threads"));
+        assert_eq!(statemap.host(), Some("thumper"));
+        assert_eq!(statemap.nrecs(), 17);
+
+        let states: Vec<&str> = statemap.states().iter()
+            .map(|s| s.name()).collect();
+        assert_eq!(states, vec!["on-cpu", "off-cpu-waiting", "off-cpu-futex"]);
+        assert_eq!(statemap.states()[0].color(), Some("#2e9107"));
+
+        let entities: Vec<&str> = statemap.entities().iter()
+            .map(|e| e.name()).collect();
+        assert_eq!(entities, vec!["1", "2", "3"]);
+
+        let rects = statemap.entity("2").unwrap().rects();
+        assert_eq!(rects.len(), 3);
+        assert_eq!(rects[0].start(), 0);
+
+        for i in 1..rects.len() {
+            assert_eq!(rects[i].start(),
+                rects[i - 1].start() + rects[i - 1].duration());
+        }
+
+        assert_eq!(rects[1].start(), 1100);
+        assert_eq!(rects[1].duration(), 900);
+        assert_eq!(rects[1].states(), &[900, 0, 0]);
+        assert!(rects[1].tags().is_empty());
+        assert!(statemap.entity("4").is_none());
+    }
+
+    #[test]
+    fn accessors_tags() {
+        let statemap = good_statemap!("tag_basic");
+        let tags = statemap.tags();
+
+        for entity in statemap.entities() {
+            for rect in entity.rects() {
+                for (id, duration) in rect.tags() {
+                    assert!(id < tags.len());
+                    assert!(duration <= rect.duration());
+                }
+            }
+        }
+    }
+
+    /*
+     * Construct the equivalent of tst.events.in via the builder interface.
+     */
+    fn builder_events(config: &Config) -> Statemap {
+        let mut statemap = Statemap::new(config);
+
+        statemap.set_title("Threads");
+        statemap.set_host("thumper");
+        statemap.set_entity_kind("Thread");
+        statemap.set_start(1579579142 * 1_000_000_000).unwrap();
+
+        let on = statemap.add_state("on-cpu", Some("#2e9107")).unwrap();
+        let waiting =
+            statemap.add_state("off-cpu-waiting", Some("#f9f9f9")).unwrap();
+        let futex =
+            statemap.add_state("off-cpu-futex", Some("#f0f0f0")).unwrap();
+
+        statemap.transition("1", 0, on, None).unwrap();
+        statemap.transition("2", 0, futex, None).unwrap();
+        statemap.transition("3", 0, waiting, None).unwrap();
+        statemap.transition("1", 1000, waiting, None).unwrap();
+        statemap.event("1", 1000, "wakeup", Some("2"));
+        statemap.transition("2", 1100, on, None).unwrap();
+        statemap.event("2", 1500, "signal", None);
+        statemap.event("2", 2000, "wakeup", Some("3"));
+        statemap.transition("2", 2000, futex, None).unwrap();
+        statemap.transition("3", 2050, on, None).unwrap();
+        statemap.event("3", 2500, "wakeup", Some("1"));
+        statemap.transition("3", 2500, waiting, None).unwrap();
+        statemap.transition("1", 2600, on, None).unwrap();
+        statemap.event("1", 3000, "signal", None);
+        statemap.transition("1", 3000, waiting, None).unwrap();
+        statemap.transition("2", 3000, waiting, None).unwrap();
+        statemap.transition("3", 3000, waiting, None).unwrap();
+        statemap.finish();
+
+        statemap
+    }
+
+    fn builder_compare(config: &Config) {
+        let statemap = good_statemap!("events", config);
+        let built = builder_events(config);
+
+        assert_eq!(built.title(), statemap.title());
+        assert_eq!(built.host(), statemap.host());
+        assert_eq!(built.nrecs(), statemap.nrecs());
+        assert_eq!(built.nrects(), statemap.nrects());
+        assert_eq!(built.timebounds(), statemap.timebounds());
+        assert_eq!(built.states(), statemap.states());
+        assert_eq!(built.event_types(), statemap.event_types());
+
+        for entity in statemap.entities() {
+            let other = built.entity(entity.name()).unwrap();
+            let rects = entity.rects();
+            let orects = other.rects();
+
+            assert_eq!(rects.len(), orects.len());
+
+            for (rect, orect) in rects.iter().zip(orects.iter()) {
+                assert_eq!(rect.start(), orect.start());
+                assert_eq!(rect.duration(), orect.duration());
+                assert_eq!(rect.states(), orect.states());
+            }
+        }
+
+        built.verify();
+    }
+
+    #[test]
+    fn builder() {
+        builder_compare(&Default::default());
+    }
+
+    #[test]
+    fn builder_coalesced() {
+        let mut config: Config = Default::default();
+        config.maxrect = 3;
+        builder_compare(&config);
+    }
+
+    #[test]
+    fn builder_bounded() {
+        let mut config: Config = Default::default();
+        config.begin = 1200;
+        config.end = 2800;
+        builder_compare(&config);
+    }
+
+    #[test]
+    fn builder_abstime() {
+        let mut config: Config = Default::default();
+        config.abstime = true;
+        config.begin = 1579579142 * 1_000_000_000 + 1200;
+        config.end = 1579579142 * 1_000_000_000 + 2800;
+        builder_compare(&config);
+    }
+
+    #[test]
+    fn builder_tags() {
+        let mut statemap = Statemap::new(&Default::default());
+        let state = statemap.add_state("on-cpu", None).unwrap();
+
+        statemap.define_tag(state, "cafe", json!({ "pid": 123 })).unwrap();
+        statemap.transition("1", 0, state, Some("cafe")).unwrap();
+        statemap.transition("1", 100, state, Some("dead")).unwrap();
+        statemap.transition("1", 300, state, None).unwrap();
+        statemap.finish();
+
+        let tags = statemap.tags();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0]["pid"], 123);
+        assert_eq!(tags[0]["tag"], "cafe");
+        assert_eq!(tags[1]["tag"], "dead");
+
+        let rects = statemap.entity("1").unwrap().rects();
+        assert_eq!(rects.len(), 2);
+        assert_eq!(rects[0].tags(), vec![(0, 100)]);
+        assert_eq!(rects[1].tags(), vec![(1, 200)]);
+
+        match statemap.define_tag(state, "beef", json!(123)) {
+            Err(err) => assert_eq!(err.to_string(),
+                "tag fields must be an object"),
+            Ok(_) => panic!("non-object tag fields incorrectly succeeded")
+        }
+    }
+
+    #[test]
+    fn builder_errors() {
+        let mut statemap = Statemap::new(&Default::default());
+
+        let on = statemap.add_state("on-cpu", None).unwrap();
+        let off = statemap.add_state("off-cpu", Some("blue")).unwrap();
+        assert_eq!((on, off), (0, 1));
+
+        let expect = |result: Result<(), Box<dyn Error>>, expected: &str| {
+            match result {
+                Err(err) => assert_eq!(err.to_string(), expected),
+                Ok(_) => panic!("builder incorrectly succeeded")
+            }
+        };
+
+        expect(statemap.add_state("on-cpu", None).map(|_| ()),
+            "state \"on-cpu\" is already defined");
+        expect(statemap.add_state("waiting", Some("bleu")).map(|_| ()),
+            "\"bleu\" is not a valid color");
+
+        statemap.transition("1", 100, on, None).unwrap();
+
+        expect(statemap.transition("1", 50, off, None),
+            "time 50 is out of order with respect to prior time 100");
+        expect(statemap.transition("1", 200, 2, None),
+            "illegal state value");
+        expect(statemap.add_state("waiting", None).map(|_| ()),
+            "cannot set states after data has been added");
+        expect(statemap.set_start(1),
+            "cannot set start time after data has been added");
+    }
+
+    fn stacked(what: &str, n: usize, config: &Config) -> Vec<Statemap> {
+        let raw = match what {
+            "io" => include_str!("../tst/tst.io.in"),
+            "tag_basic" => include_str!("../tst/tst.tag_basic.in"),
+            _ => panic!("unknown data")
+        };
+
+        let mut config = *config;
+        let mut statemaps = vec![];
+
+        for _i in 0..n {
+            let statemap = good_statemap(&config, raw);
+
+            config.abstime = true;
+            config.begin = statemap.timebounds().0 as i64;
+            config.end = statemap.timebounds().1 as i64;
+            statemaps.push(statemap);
+        }
+
+        statemaps
+    }
+
+    #[test]
+    fn coalesce_global() {
+        let mut statemaps = stacked("io", 2, &Default::default());
+        let expected = totals(&statemaps[0]);
+
+        for statemap in &statemaps {
+            let nrects = statemap.entities.values()
+                .fold(0, |n, e| n + e.rects.len());
+            assert_eq!(statemap.nrects(), nrects);
+        }
+
+        Statemap::coalesce(&mut statemaps, 60).unwrap();
+
+        /*
+         * Our statemaps are identical, so they should each bear half of
+         * our budget.
+         */
+        for statemap in &statemaps {
+            assert_eq!(statemap.nrects(), 30);
+            assert_eq!(totals(statemap), expected);
+            statemap.verify();
+        }
+
+        Statemap::coalesce(&mut statemaps, 0).unwrap();
+
+        for statemap in &statemaps {
+            assert_eq!(statemap.nrects(), 0);
+            assert_eq!(totals(statemap), expected);
+        }
+    }
+
+    #[test]
+    fn coalesce_global_uneven() {
+        let mut config: Config = Default::default();
+        config.maxrect = 100;
+
+        let mut statemaps = stacked("tag_basic", 1, &config);
+        statemaps.extend(stacked("io", 1, &config));
+
+        let before: Vec<usize> = statemaps.iter().map(|s| s.nrects()).collect();
+        Statemap::coalesce(&mut statemaps, 100).unwrap();
+        let after: Vec<usize> = statemaps.iter().map(|s| s.nrects()).collect();
+
+        assert!(after[0] + after[1] <= 100);
+        assert!(after[0] < before[0] && after[1] < before[1]);
+    }
+
+    #[test]
+    fn coalesce_global_buckets() {
+        let mut statemaps = stacked("io", 2, &buckets(100, 0));
+
+        match Statemap::coalesce(&mut statemaps, 10) {
+            Err(err) => assert_eq!(err.to_string(), concat!("cannot ",
+                "further coalesce statemaps coalesced into buckets")),
+            Ok(_) => panic!("coalescing buckets incorrectly succeeded")
+        }
+    }
+
+    #[test]
+    fn events() {
+        let statemap = good_statemap!("events");
+
+        assert_eq!(statemap.nevents, 5);
+        assert_eq!(statemap.event_types(), vec!["wakeup", "signal"]);
+
+        let entity = statemap.entities.get("1").unwrap();
+        assert_eq!(entity.events.len(), 2);
+        assert_eq!(entity.events[0].time, 1000);
+        assert_eq!(entity.events[0].event, 0);
+        assert_eq!(entity.events[0].target, Some("2".to_string()));
+        assert_eq!(entity.events[1].time, 3000);
+        assert_eq!(entity.events[1].event, 1);
+        assert_eq!(entity.events[1].target, None);
+
+        assert!(statemap.has_arrows());
+        statemap.verify();
+    }
+
+    #[test]
+    fn events_bounded() {
+        let mut config: Config = Default::default();
+        config.begin = 1200;
+        config.end = 2800;
+
+        let statemap = good_statemap!("events", &config);
+
+        assert_eq!(statemap.nevents, 5);
+
+        let nevents = statemap.entities.values()
+            .fold(0, |n, e| n + e.events.len());
+        assert_eq!(nevents, 3);
+        assert!(statemap.entities.get("1").unwrap().events.is_empty());
+        assert!(statemap.has_arrows());
+    }
+
+    #[test]
+    fn events_time() {
+        let statemap = good_statemap!("events");
+
+        assert_eq!(statemap.event_time("wakeup"), Some(1000));
+        assert_eq!(statemap.event_time("signal"), Some(1500));
+        assert_eq!(statemap.event_time("preempt"), None);
+    }
+
+    #[test]
+    fn events_aligned() {
+        /*
+         * A statemap aligned to a later event in another may begin before
+         * its own start.
+         */
+        let mut config: Config = Default::default();
+        config.begin = -1000;
+        config.end = 2000;
+
+        let statemap = good_statemap!("events", &config);
+        let (begin, end) = statemap.timebounds();
+
+        assert_eq!(begin, 1579579142_000_000_000 - 1000);
+        assert_eq!(end, 1579579142_000_000_000 + 2000);
+
+        let rects = statemap.entity("1").unwrap().rects();
+        assert_eq!(rects[0].start(), 0);
+        assert_eq!(rects.last().unwrap().start() +
+            rects.last().unwrap().duration(), 2000);
+        statemap.verify();
+
+        let output = svg(&vec![good_statemap!("events"), statemap],
+            &Default::default());
+        assert!(output.contains(r##""offset": -1000"##));
+    }
+
+    #[test]
+    fn clock_offset() {
+        let mut config: Config = Default::default();
+        config.offset = -1_500_000_000;
+
+        let statemap = good_statemap!("events", &config);
+        let (begin, _end) = statemap.timebounds();
+        assert_eq!(begin, 1579579140_500_000_000);
+
+        let statemap = metadata(None, r##"{
+            "start": [ 1, 200 ],
+            "title": "Foo",
+            "clockOffset": 1000,
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##);
+
+        assert_eq!(statemap.metadata.unwrap().start, vec![1, 1200]);
+
+        /*
+         * An offset in our configuration is applied in addition to any
+         * offset in the metadata.
+         */
+        config.offset = -300;
+
+        let statemap = metadata(Some(&config), r##"{
+            "start": [ 1, 200 ],
+            "title": "Foo",
+            "clockOffset": 1000,
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##);
+
+        assert_eq!(statemap.metadata.unwrap().start, vec![1, 900]);
+    }
+
+    #[test]
+    fn clock_offset_abstime() {
+        /*
+         * Absolute times in our configuration are relative to the corrected
+         * start time.
+         */
+        let mut config: Config = Default::default();
+        config.abstime = true;
+        config.begin = 1579579142_000_000_000;
+        config.end = 1579579142_000_002_000;
+        config.offset = 1000;
+
+        let statemap = good_statemap!("events", &config);
+        let (begin, end) = statemap.timebounds();
+
+        assert_eq!(begin, 1579579142_000_000_000);
+        assert_eq!(end, 1579579142_000_002_000);
+
+        let rects = statemap.entity("1").unwrap().rects();
+        assert_eq!(rects[0].start(), 0);
+        statemap.verify();
+
+        let output = svg(&vec![good_statemap!("events"), statemap],
+            &Default::default());
+        assert!(output.contains(r##""offset": -1000"##));
+    }
+
+    #[test]
+    fn bad_clock_offset() {
+        bad_metadata(r##"{
+            "start": [ 1, 0 ],
+            "title": "Foo",
+            "clockOffset": -2000000000,
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##, "clock offset precedes the Epoch");
+
+        bad_metadata(r##"{
+            "start": [ 18446744073709551615, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##, "illegal start time");
+
+        bad_metadata(r##"{
+            "start": [ 9223372036, 0 ],
+            "title": "Foo",
+            "clockOffset": 9223372036854775807,
+            "states": {
+                "zero": {"value": 0 }
+            }
+        }"##, "illegal start time");
+    }
+
+    #[test]
+    fn bad_event_time() {
+        bad_datum(None, r##"
+            { "time": "12ms", "entity": "foo", "event": "bar" }
+        "##, "unrecognized payload");
+    }
+
+    #[test]
+    fn stream() {
+        let raw = include_str!("../tst/tst.io.in");
+        let config: Config = Default::default();
+        let statemap = good_statemap!("io");
+
+        for size in vec![1, 13, STREAM_CHUNK] {
+            let mut streamed = Statemap::new(&config);
+
+            if let Err(err) = statemap_ingest_stream(&mut streamed, raw, size) {
+                panic!("stream of size {} failed: {}", size, err);
+            }
+
+            streamed.verify();
+            assert_eq!(streamed.timebounds(), statemap.timebounds());
+
+            for state in 0..statemap.states.len() {
+                assert_eq!(streamed.weight(state), statemap.weight(state));
+            }
+        }
+    }
+
+    #[test]
+    fn stream_multibyte() {
+        let raw = r##"{
+            "start": [ 0, 0 ],
+            "title": "Föö",
+            "states": {
+                "zero": {"value": 0 },
+                "one": {"value": 1 }
+            }
+        }
+        { "time": "100", "entity": "föö", "state": 0 }
+        { "entity": "föö", "description": "Ünïcödé" }
+        { "time": "200", "entity": "föö", "state": 1 }
+        "##;
+
+        let config: Config = Default::default();
+        let mut statemap = Statemap::new(&config);
+
+        if let Err(err) = statemap_ingest_stream(&mut statemap, raw, 1) {
+            panic!("multibyte stream failed: {}", err);
+        }
+
+        assert_eq!(statemap.entity_lookup("föö").description,
+            Some("Ünïcödé".to_string()));
+    }
+
+    #[test]
+    fn stream_empty() {
+        let config: Config = Default::default();
+        let mut statemap = Statemap::new(&config);
+
+        match statemap_ingest_stream(&mut statemap, "  \n", 1) {
+            Err(err) => {
+                assert!(format!("{}", err).find("missing metadata").is_some());
+            }
+            Ok(_) => { panic!("empty stream succeeded!"); }
+        }
+    }
+
+    fn compress(raw: &str, compression: StatemapCompression) -> Vec<u8> {
+        let mut compressed = vec![];
+
+        match compression {
+            StatemapCompression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(
+                    &mut compressed, flate2::Compression::default());
+                encoder.write_all(raw.as_bytes()).unwrap();
+                encoder.finish().unwrap();
+            }
+            StatemapCompression::Zstd => {
+                compressed = zstd::stream::encode_all(raw.as_bytes(), 0)
+                    .unwrap();
+            }
+            StatemapCompression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(
+                    &mut compressed, 6);
+                encoder.write_all(raw.as_bytes()).unwrap();
+                encoder.finish().unwrap();
+            }
+        }
+
+        assert_eq!(StatemapCompression::detect(&compressed),
+            Some(compression));
+
+        compressed
+    }
+
+    #[test]
+    fn compressed() {
+        let raw = include_str!("../tst/tst.io.in");
+        let config: Config = Default::default();
+        let statemap = good_statemap!("io");
+
+        for compression in vec![StatemapCompression::Gzip,
+            StatemapCompression::Zstd, StatemapCompression::Xz] {
+            let compressed = compress(raw, compression);
+            let mut mapped = Statemap::new(&config);
+            let mut streamed = Statemap::new(&config);
+
+            if let Err(err) = statemap_ingest_bytes(&mut mapped, &compressed) {
+                panic!("{:?} file failed: {}", compression, err);
+            }
+
+            let trickle = Trickle { data: &compressed, size: 5 };
+
+            if let Err(err) = streamed.ingest_stream(trickle) {
+                panic!("{:?} stream failed: {}", compression, err);
+            }
+
+            for state in 0..statemap.states.len() {
+                assert_eq!(mapped.weight(state), statemap.weight(state));
+                assert_eq!(streamed.weight(state), statemap.weight(state));
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_bad_line() {
+        let raw = include_str!("../tst/tst.bad_line_newline.in");
+        let expected = include_str!("../tst/tst.bad_line_newline.err");
+        let config: Config = Default::default();
+
+        for compression in vec![StatemapCompression::Gzip,
+            StatemapCompression::Zstd, StatemapCompression::Xz] {
+            let compressed = compress(raw, compression);
+            let mut statemap = Statemap::new(&config);
+
+            match statemap_ingest_bytes(&mut statemap, &compressed) {
+                Err(err) => {
+                    assert_eq!(format!("{}\n", err), expected);
+                }
+                Ok(_) => { panic!("bad {:?} succeeded!", compression); }
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_truncated() {
+        let raw = include_str!("../tst/tst.io.in");
+        let compressed = compress(raw, StatemapCompression::Gzip);
+        let config: Config = Default::default();
+        let mut statemap = Statemap::new(&config);
+
+        let truncated = &compressed[..compressed.len() / 2];
+        assert!(statemap_ingest_bytes(&mut statemap, truncated).is_err());
+    }
+
+    #[test]
+    fn weight() {
+        let statemap = good_statemap!("io");
+
+        assert_eq!(statemap.weight(0), 737063);
+        assert_eq!(statemap.weight(1), 2290450);
+        assert_eq!(statemap.weight(2), 934399);
+        assert_eq!(statemap.weight(3), 1082403);
+    }
+
+    fn svg(statemaps: &Vec<Statemap>, config: &StatemapSVGConfig) -> String {
+        let mut out: Vec<u8> = vec![];
+
+        match StatemapSVG::new(config).output(statemaps, &mut out) {
+            Err(err) => { panic!("output incorrectly failed: {:?}", err); }
+            Ok(_) => {}
+        }
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn svg_basic() {
+        let statemaps = vec![good_statemap!("events")];
+        let output = svg(&statemaps, &Default::default());
+
+        assert!(output.starts_with("<?xml"));
+        assert!(output.trim_end().ends_with("</svg>"));
+        assert!(output.contains("Statemap of Threads activity on thumper"));
+        assert!(output.contains("g_statemaps[0] = {"));
+        assert!(!output.contains("g_statemaps[1]"));
+
+        for entity in &["1", "2", "3"] {
+            assert!(output.contains(&format!(
+                r##"<g id="statemap-entity-0-{}"><title>Thread {}</title>"##,
+                entity, entity)));
+        }
+
+        assert_eq!(output.matches("onclick=\"eventclick(").count(), 5);
+        assert_eq!(output.matches("class=\"statemap-arrow\"").count(), 4);
+        assert!(output.contains("hide arrows"));
+    }
+
+    #[test]
+    fn svg_stacked() {
+        let mut config: Config = Default::default();
+        let first = good_statemap!("events", &config);
+
+        config.abstime = true;
+        config.begin = first.timebounds().0 as i64;
+        config.end = first.timebounds().1 as i64;
+
+        let second = good_statemap!("events", &config);
+        let statemaps = vec![first, second];
+        let output = svg(&statemaps, &Default::default());
+
+        assert!(output.contains("g_statemaps[1] = {"));
+        assert!(output.contains("statemap-entity-1-3"));
+
+        /*
+         * Our statemaps are alike, so they should share a legend.
+         */
+        assert!(output.contains("statemap-legend-0-0"));
+        assert!(!output.contains("statemap-legend-1-0"));
+    }
+
+    #[test]
+    fn svg_tabbed() {
+        let statemaps = vec![good_statemap!("events"), good_statemap!("io")];
+        let mut config: StatemapSVGConfig = Default::default();
+        config.layout = StatemapLayout::Tabbed;
+
+        let output = svg(&statemaps, &config);
+
+        /*
+         * Each statemap is in its own tab -- with its own legend -- and
+         * only the first is displayed.
+         */
+        assert!(output.contains(r##"id="statemap-tab-0" display="inline""##));
+        assert!(output.contains(r##"id="statemap-tab-1" display="none""##));
+        assert!(output.contains("statemap-tab-1-legend"));
+        assert!(output.contains("statemap-legend-0-0"));
+        assert!(output.contains("statemap-legend-1-0"));
+        assert!(output.contains("tabclick(evt, 1)"));
+
+        /*
+         * Both statemaps begin at the top margin, and we are only as tall
+         * as the taller of them.
+         */
+        assert_eq!(output.matches(r##"<svg x="138px" y="60px""##).count(), 2);
+        assert!(output.contains(r##""pixelHeight": 80,"##));
+
+        /*
+         * A single statemap has no tabs.
+         */
+        let output = svg(&vec![good_statemap!("events")], &config);
+        assert!(!output.contains("statemap-tab-0"));
+    }
+
+    #[test]
+    fn svg_utilization() {
+        let output = svg(&vec![good_statemap!("events")], &Default::default());
+        assert!(!output.contains("statemap-0-utilization"));
+
+        let mut config: Config = Default::default();
+        config.utilization = true;
+
+        let output = svg(&vec![good_statemap!("events", &config)],
+            &Default::default());
+
+        /*
+         * Our band is above our entities, and has a path for each state
+         * that is occupied.
+         */
+        assert!(output.contains(r##"<g id="statemap-0-utilization">"##));
+        assert!(output.contains(r##""utilizationHeight": 44,"##));
+        assert!(output.contains("utilizationclick(evt, 0, 2)"));
+        assert_eq!(output.matches("utilizationclick(evt, 0,").count(), 3);
+        assert!(output.contains(concat!(r##"<svg x="138px" y="60px" "##,
+            r##"width="862px" height="74px">"##)));
+    }
+
+    #[test]
+    fn svg_builder() {
+        let statemaps = vec![builder_events(&Default::default())];
+        let output = svg(&statemaps, &Default::default());
+
+        assert!(output.contains("Statemap of Threads activity on thumper"));
+        assert!(output.contains(r##"id="statemap-entity-0-2""##));
+    }
+
+    #[test]
+    fn svg_sortby_bad() {
+        let statemaps = vec![good_statemap!("events")];
+        let mut config: StatemapSVGConfig = Default::default();
+        let mut out: Vec<u8> = vec![];
+
+        config.sortby = Some("on-disk".to_string());
+
+        match StatemapSVG::new(&config).output(&statemaps, &mut out) {
+            Err(err) => assert_eq!(err.to_string(),
+                "cannot sort by state \"on-disk\": no such state"),
+            Ok(_) => panic!("bad sortby incorrectly succeeded")
+        }
+    }
+
+    #[test]
+    fn svg_write_error() {
+        /*
+         * A writer that fails after a fixed number of bytes, to assure
+         * that we propagate errors rather than panic.
+         */
+        struct Full { left: usize }
+
+        impl Write for Full {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.left == 0 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "full"));
+                }
+
+                let len = cmp::min(self.left, buf.len());
+                self.left -= len;
+                Ok(len)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let statemaps = vec![good_statemap!("events")];
+        let config: StatemapSVGConfig = Default::default();
+        let len = svg(&statemaps, &config).len();
+
+        for left in &[0, 100, len / 2, len - 1] {
+            let mut out = Full { left: *left };
+
+            match StatemapSVG::new(&config).output(&statemaps, &mut out) {
+                Err(err) => assert_eq!(err.to_string(), "full"),
+                Ok(_) => panic!("output to full writer succeeded")
+            }
+        }
+    }
+
+    fn html(statemaps: &Vec<Statemap>, config: &StatemapSVGConfig) -> String {
+        let mut out: Vec<u8> = vec![];
+
+        match StatemapHTML::new(config).output(statemaps, &mut out) {
+            Err(err) => { panic!("output incorrectly failed: {:?}", err); }
+            Ok(_) => {}
+        }
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn html_basic() {
+        let statemaps = vec![good_statemap!("events")];
+        let output = html(&statemaps, &Default::default());
+
+        assert!(output.starts_with("<!DOCTYPE html>"));
+        assert!(output.trim_end().ends_with("</html>"));
+        assert!(output.contains(
+            "<title>Statemap of Threads activity on thumper</title>"));
+        assert!(output.contains(r##"<canvas id="statemap-canvas">"##));
+        assert!(output.contains("g_statemaps[0] = {"));
+        assert!(!output.contains("g_statemaps[1]"));
+        assert!(output.contains(r##""order": ["1","2","3"],"##));
+
+        /*
+         * Our rectangles should be drawn by the viewer rather than appear
+         * as elements -- and each should carry its duration and color.
+         */
+        assert!(!output.contains("<rect"));
+        assert!(output.contains(r##"{ "t": 0, "s": 0, d: 1000, "##));
+        assert_eq!(output.matches(r##""n":"##).count(), 5);
+    }
+
+    #[test]
+    fn html_stacked() {
         let mut config: Config = Default::default();
-        config.begin = 200001;
+        let first = good_statemap!("events", &config);
 
-        let statemap = data(Some(&config), vec![
-            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
-        ]);
+        config.abstime = true;
+        config.begin = first.timebounds().0 as i64;
+        config.end = first.timebounds().1 as i64;
+
+        let second = good_statemap!("events", &config);
+        let statemaps = vec![first, second, good_statemap!("io")];
+        let output = html(&statemaps, &Default::default());
+
+        assert!(output.contains("g_statemaps[2] = {"));
+
+        /*
+         * Our first two statemaps are alike and should share a legend.
+         */
+        assert_eq!(output.matches(r##""legend": 0,"##).count(), 2);
+        assert_eq!(output.matches(r##""legend": 2,"##).count(), 1);
+    }
+
+    #[test]
+    fn html_escaped() {
+        let statemaps = vec![good_statemap!("events")];
+        let mut config: StatemapSVGConfig = Default::default();
+
+        config.background = "</script>".to_string();
+
+        let output = html(&statemaps, &config);
+
+        assert!(output.contains(r##""background": "<\/script>","##));
+        assert_eq!(output.matches("</script>").count(),
+            output.matches("<script>").count());
+    }
+
+    fn json(statemaps: &Vec<Statemap>) -> Value {
+        let mut out: Vec<u8> = vec![];
+
+        match StatemapJSON::new().output(statemaps, &mut out) {
+            Err(err) => { panic!("output incorrectly failed: {:?}", err); }
+            Ok(_) => {}
+        }
+
+        serde_json::from_slice(&out).unwrap()
+    }
+
+    #[test]
+    fn json_basic() {
+        let mut statemap = good_statemap!("events");
+        statemap.describe_entity("2", "the \"second\" thread");
+
+        let statemaps = vec![statemap];
+        let output = json(&statemaps);
+        let doc = &output["statemaps"][0];
+
+        assert_eq!(output["statemaps"].as_array().unwrap().len(), 1);
+        assert_eq!(doc["metadata"]["title"], "Threads");
+        assert_eq!(doc["metadata"]["host"], "thumper");
+        assert_eq!(doc["metadata"]["entityKind"], "Thread");
+        assert_eq!(doc["metadata"]["start"], 1579579142000000000u64);
+        assert_eq!(doc["metadata"]["resolution"], Value::Null);
+        assert_eq!(doc["states"][1]["name"], "off-cpu-waiting");
+        assert_eq!(doc["states"][0]["color"], "#2e9107");
+        assert!(doc["tags"].as_array().unwrap().is_empty());
+
+        let entities = doc["entities"].as_array().unwrap();
+        let names: Vec<&str> = entities.iter()
+            .map(|e| e["name"].as_str().unwrap()).collect();
+        assert_eq!(names, vec!["1", "2", "3"]);
+        assert_eq!(entities[0]["description"], Value::Null);
+        assert_eq!(entities[1]["description"], "the \"second\" thread");
+        assert!(entities[0].get("levels").is_none());
+
+        /*
+         * Our rectangles should be exactly those of the statemap.
+         */
+        for entity in entities {
+            let rects = entity["rects"].as_array().unwrap();
+            let name = entity["name"].as_str().unwrap();
+            let expected = statemaps[0].entity(name).unwrap().rects();
+
+            assert_eq!(rects.len(), expected.len());
+
+            for (rect, expected) in rects.iter().zip(&expected) {
+                assert_eq!(rect["start"], expected.start());
+                assert_eq!(rect["duration"], expected.duration());
+                assert_eq!(rect["states"], json!(expected.states()));
+            }
+        }
+
+        assert_eq!(entities[1]["rects"][1],
+            json!({ "start": 1100, "duration": 900,
+            "states": [ 900, 0, 0 ], "tags": [] }));
+    }
+
+    #[test]
+    fn json_tags() {
+        let statemaps = vec![good_statemap!("tag_basic"), good_statemap!("io")];
+        let output = json(&statemaps);
+        let doc = &output["statemaps"][0];
+        let tags = doc["tags"].as_array().unwrap();
+
+        assert_eq!(output["statemaps"].as_array().unwrap().len(), 2);
+        assert_eq!(tags.len(), statemaps[0].tags().len());
+        assert_eq!(tags[0], *statemaps[0].tags()[0]);
+
+        let mut tagged = 0;
+
+        for entity in doc["entities"].as_array().unwrap() {
+            for rect in entity["rects"].as_array().unwrap() {
+                let duration = rect["duration"].as_u64().unwrap();
+                let states = rect["states"].as_array().unwrap();
+
+                assert_eq!(states.iter()
+                    .fold(0, |t, s| t + s.as_u64().unwrap()), duration);
+
+                for tag in rect["tags"].as_array().unwrap() {
+                    assert!(tag[0].as_u64().unwrap() < tags.len() as u64);
+                    assert!(tag[1].as_u64().unwrap() <= duration);
+                    tagged += 1;
+                }
+            }
+        }
+
+        assert!(tagged > 0);
+    }
+
+    #[test]
+    fn json_levels() {
+        let mut config: Config = Default::default();
+        config.coalesce = StatemapCoalesce::Buckets;
+        config.nbuckets = 10;
+        config.levels = 2;
+
+        let statemaps = vec![good_statemap!("io", &config)];
+        let output = json(&statemaps);
+        let doc = &output["statemaps"][0];
+        let levels = statemaps[0].levels();
+
+        assert_eq!(doc["metadata"]["resolution"],
+            statemaps[0].resolution().unwrap());
+        assert_eq!(doc["metadata"]["levels"], json!(levels));
+
+        for entity in doc["entities"].as_array().unwrap() {
+            let name = entity["name"].as_str().unwrap();
+            let expected = statemaps[0].entity(name).unwrap();
+
+            assert_eq!(entity["levels"].as_array().unwrap().len(),
+                levels.len());
+
+            for i in 0..levels.len() {
+                assert_eq!(entity["levels"][i].as_array().unwrap().len(),
+                    expected.level(i).len());
+            }
+        }
+    }
+
+    fn stats(statemaps: &Vec<Statemap>, format: StatemapStatsFormat)
+        -> String
+    {
+        let mut out: Vec<u8> = vec![];
+
+        match StatemapStats::new(format).output(statemaps, &mut out) {
+            Err(err) => { panic!("output incorrectly failed: {:?}", err); }
+            Ok(_) => {}
+        }
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn stats_totals() {
+        let statemap = good_statemap!("events");
+
+        assert_eq!(statemap.entity("1").unwrap().totals(), &[1400, 1600, 0]);
+        assert_eq!(statemap.entity("2").unwrap().totals(), &[900, 0, 2100]);
+
+        /*
+         * Our totals should be unaffected by coalescing -- and should match
+         * the time in the rectangles regardless of the time bounds.
+         */
+        let mut config: Config = Default::default();
+        config.maxrect = 3;
+
+        let bounds: &[(i64, i64)] = &[(0, 0), (100000, 0), (0, 2000000),
+            (1000000, 1500000)];
+
+        for &(begin, end) in bounds {
+            config.begin = begin;
+            config.end = end;
+
+            let statemap = good_statemap!("tag_basic", &config);
+            let mut total = 0;
+
+            for entity in statemap.entities() {
+                let mut expected = vec![0; statemap.states().len()];
+
+                for rect in entity.rects() {
+                    for (e, t) in expected.iter_mut().zip(rect.states()) {
+                        *e += t;
+                    }
+                }
+
+                assert_eq!(entity.totals(), &expected[..]);
+                total += expected.iter().sum::<u64>();
+            }
+
+            if end != 0 {
+                assert!(total <= (end - begin) as u64 *
+                    statemap.entities().len() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn stats_formats() {
+        let statemaps = vec![good_statemap!("events"), good_statemap!("io")];
+
+        let output = stats(&statemaps, StatemapStatsFormat::Text);
+        assert!(output.starts_with("Threads on thumper\n\n"));
+        assert!(output.contains(
+            "2       off-cpu-futex    0.002100   70.00%\n"));
+        assert!(output.contains(
+            "all     off-cpu-waiting  0.004150   46.11%\n"));
+        assert!(!output.contains("2       off-cpu-waiting"));
+
+        let output = stats(&statemaps, StatemapStatsFormat::CSV);
+        assert!(output.starts_with("statemap,entity,state,time,percent\n"));
+        assert!(output.contains("\n0,2,off-cpu-waiting,0,0.0000\n"));
+        assert!(output.contains("\n0,,on-cpu,2750,30.5556\n"));
+        assert!(output.contains("\n1,"));
+
+        let output = stats(&statemaps, StatemapStatsFormat::JSON);
+        let doc: Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(doc["statemaps"].as_array().unwrap().len(), 2);
+        assert_eq!(doc["statemaps"][0]["states"][2], "off-cpu-futex");
+        assert_eq!(doc["statemaps"][0]["entities"][1]["name"], "2");
+        assert_eq!(doc["statemaps"][0]["entities"][1]["time"],
+            json!([900, 0, 2100]));
+        assert_eq!(doc["statemaps"][0]["aggregate"]["time"],
+            json!([2750, 4150, 2100]));
+        assert_eq!(doc["statemaps"][0]["aggregate"]["percent"][2].as_f64()
+            .unwrap().round(), 23.0);
+    }
+
+    #[test]
+    fn durations_basic() {
+        let statemap = good_statemap!("events");
+
+        assert!(statemap.entity("1").unwrap().durations().is_empty());
+        assert!(statemap.durations().is_empty());
+
+        let mut config: Config = Default::default();
+        config.durations = Some(StatemapDurations::Exact);
+
+        let statemap = good_statemap!("events", &config);
+        let durations = statemap.entity("1").unwrap().durations();
+
+        /*
+         * Thread 1's final visit (to off-cpu-waiting) has not ended, and
+         * thread 3's visit to off-cpu-waiting continues across a datum for
+         * the same state.
+         */
+        assert_eq!(durations.len(), 3);
+        assert_eq!(durations[0].count(), 2);
+        assert_eq!(durations[0].min(), 400);
+        assert_eq!(durations[0].max(), 1000);
+        assert_eq!(durations[0].mean(), 700.0);
+        assert_eq!(durations[1].count(), 1);
+        assert_eq!(durations[1].percentiles(&[50.0]), vec![1600]);
+        assert_eq!(durations[2].count(), 0);
+
+        let durations = statemap.entity("3").unwrap().durations();
+        assert_eq!(durations[1].count(), 1);
+        assert_eq!(durations[1].max(), 2050);
+
+        let durations = statemap.durations();
+        assert_eq!(durations[0].count(), 4);
+        assert_eq!(durations[0].percentiles(&[0.0, 50.0, 75.0, 100.0]),
+            vec![400, 450, 900, 1000]);
+
+        /*
+         * Only visits that begin and end within our time bounds should be
+         * recorded.
+         */
+        config.begin = 500;
+        config.end = 2800;
+
+        let statemap = good_statemap!("events", &config);
+        let durations = statemap.entity("1").unwrap().durations();
+        assert_eq!(durations[0].count(), 0);
+        assert_eq!(durations[1].count(), 1);
+        assert_eq!(statemap.durations()[0].count(), 2);
+    }
+
+    #[test]
+    fn utilization_basic() {
+        assert!(good_statemap!("events").utilization().is_none());
+
+        let mut config: Config = Default::default();
+        config.utilization = true;
+        config.nbuckets = 3;
+        config.end = 3000;
+
+        let statemap = good_statemap!("events", &config);
+        let utilization = statemap.utilization().unwrap();
+        assert_eq!(utilization.resolution(), 1000);
+
+        let expected: Vec<(u64, u64, Vec<f64>)> = vec![
+            (0, 1000, vec![1.0, 1.0, 1.0]),
+            (1000, 2000, vec![0.9, 2.0, 0.1]),
+            (2000, 3000, vec![0.85, 1.15, 1.0]),
+        ];
+
+        assert_eq!(utilization.occupancy(), expected);
+    }
+
+    #[test]
+    fn utilization_adaptive() {
+        /*
+         * If our end isn't known, our buckets should widen as needed --
+         * but the time in each state should remain that of our entities.
+         */
+        let mut config: Config = Default::default();
+        config.utilization = true;
+        config.nbuckets = 3;
+
+        let statemap = good_statemap!("io", &config);
+        let utilization = statemap.utilization().unwrap();
+        let occupancy = utilization.occupancy();
+        let (begin, end) = statemap.timebounds();
+
+        assert!(utilization.resolution().is_power_of_two());
+        assert!(end - begin <= 3 * utilization.resolution());
+        assert!(occupancy.len() <= 3);
+
+        for state in 0..statemap.states().len() {
+            let total = statemap.entities().iter()
+                .fold(0, |total, e| total + e.totals()[state]);
+
+            let recorded = occupancy.iter()
+                .fold(0.0, |t, &(start, end, ref o)| {
+                    t + o[state] * (end - start) as f64
+                });
+
+            assert!((recorded - total as f64).abs() < 1.0);
+        }
+
+        for &(_, _, ref o) in &occupancy {
+            assert!(o.iter().sum::<f64>() <= statemap.entities().len() as f64);
+        }
+
+        /*
+         * When coalescing into buckets, our occupancy should share the
+         * buckets of our entities.
+         */
+        config.coalesce = StatemapCoalesce::Buckets;
+
+        let statemap = good_statemap!("io", &config);
+        assert_eq!(Some(statemap.utilization().unwrap().resolution()),
+            statemap.resolution());
+    }
+
+    #[test]
+    fn durations_histogram() {
+        let mut exact = StatemapDistribution::new(StatemapDurations::Exact);
+        let mut hist = StatemapDistribution::new(StatemapDurations::Histogram);
+        let mut durations: Vec<u64> = vec![0, 1, 255, 256, 257];
+
+        for i in 0..10000u64 {
+            durations.push((i * i * 7919) % 10_000_000_000);
+        }
+
+        for &duration in &durations {
+            exact.record(duration);
+            hist.record(duration);
+        }
+
+        let percentiles = [0.0, 1.0, 10.0, 50.0, 90.0, 99.0, 99.9, 100.0];
+        let expected = exact.percentiles(&percentiles);
+        let actual = hist.percentiles(&percentiles);
+
+        assert_eq!(hist.count(), durations.len() as u64);
+        assert_eq!(hist.min(), exact.min());
+        assert_eq!(hist.max(), exact.max());
+        assert_eq!(hist.mean(), exact.mean());
+
+        for (&e, &a) in expected.iter().zip(&actual) {
+            assert!(a >= e && a - e <= e / 100, "{} vs. {}", a, e);
+        }
+
+        assert_eq!(exact.octaves(), hist.octaves());
+        assert_eq!(exact.octaves().iter().sum::<u64>(), exact.count());
+        assert_eq!(exact.octaves().len(), 34);
+
+        /*
+         * Merging an exact distribution into a histogram (or vice versa)
+         * should result in a histogram.
+         */
+        let mut merged = exact.clone();
+        merged.merge(&hist);
+        hist.merge(&exact);
+
+        assert_eq!(merged.count(), 2 * exact.count());
+        assert_eq!(merged.percentiles(&percentiles),
+            hist.percentiles(&percentiles));
+        assert_eq!(merged.percentiles(&percentiles), actual);
+    }
+
+    #[test]
+    fn durations_output() {
+        let mut config: Config = Default::default();
+        config.durations = Some(StatemapDurations::Histogram);
+
+        let statemaps = vec![good_statemap!("events", &config)];
+
+        let output = stats(&statemaps, StatemapStatsFormat::Text);
+        assert!(output.contains(concat!("THREAD  STATE            COUNT",
+            "     MEAN      MIN      P50      P90      P99      MAX\n")));
+        assert!(output.contains(concat!("all     on-cpu               4",
+            "    688ns    400ns    451ns  1.000us  1.000us  1.000us\n")));
+
+        let output = stats(&statemaps, StatemapStatsFormat::JSON);
+        let doc: Value = serde_json::from_str(&output).unwrap();
+        let doc = &doc["statemaps"][0];
+
+        assert_eq!(doc["entities"][1]["durations"][2]["count"], 2);
+        assert_eq!(doc["entities"][1]["durations"][2]["p90"], 1100);
+        assert_eq!(doc["aggregate"]["durations"][0]["p50"], 451);
+
+        let output = svg(&statemaps, &Default::default());
+        assert!(output.contains(r##""durations": [{"count":4,"##));
+        assert!(output.contains(r##""octaves":[0,0,0,0,0,0,0,0,2,2]"##));
+        assert!(output.contains(r##"<g id="statemap-tagbox"></g>"##));
+    }
+
+    fn transitions(statemaps: &Vec<Statemap>,
+        format: StatemapTransitionsFormat) -> String
+    {
+        let mut out: Vec<u8> = vec![];
+
+        match StatemapTransitions::new(format).output(statemaps, &mut out) {
+            Err(err) => { panic!("output incorrectly failed: {:?}", err); }
+            Ok(_) => {}
+        }
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn transitions_basic() {
+        let statemap = good_statemap!("events");
+        let transitions = statemap.entity("1").unwrap().transitions();
+
+        /*
+         * Thread 1 goes from on-cpu to off-cpu-waiting twice (after 1000ns
+         * and then 400ns), and back once (after 1600ns).
+         */
+        assert_eq!(transitions.len(), 2);
+        assert_eq!((transitions[0].from(), transitions[0].to()), (0, 1));
+        assert_eq!(transitions[0].count(), 2);
+        assert_eq!(transitions[0].dwell(), 1400);
+        assert_eq!(transitions[0].mean_dwell(), 700.0);
+        assert_eq!((transitions[1].from(), transitions[1].to()), (1, 0));
+        assert_eq!(transitions[1].count(), 1);
+        assert_eq!(transitions[1].dwell(), 1600);
+
+        /*
+         * Thread 3's datum for the state it is already in is not a
+         * transition.
+         */
+        let transitions = statemap.entity("3").unwrap().transitions();
+        assert_eq!(transitions.len(), 2);
+        assert_eq!(transitions.iter().map(|t| t.count()).sum::<u64>(), 2);
+
+        let aggregate = statemap.transitions();
+        assert_eq!(aggregate.len(), 5);
+        assert_eq!((aggregate[0].from(), aggregate[0].to()), (0, 1));
+        assert_eq!(aggregate[0].count(), 3);
+        assert_eq!(aggregate[0].dwell(), 1850);
+
+        /*
+         * Transitions before our begin time are not counted.
+         */
+        let mut config: Config = Default::default();
+        config.begin = 2100;
+
+        let statemap = good_statemap!("events", &config);
+        let aggregate = statemap.transitions();
+        assert_eq!(aggregate.iter().map(|t| t.count()).sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn transitions_formats() {
+        let statemaps = vec![good_statemap!("events")];
+
+        let output = transitions(&statemaps,
+            StatemapTransitionsFormat::Text);
+        assert!(output.starts_with("Threads on thumper: all entities\n\n"));
+        assert!(output.contains(
+            "on-cpu                -                3              1\n"));
+        assert!(output.contains(
+            "off-cpu-waiting  1.825us                -              -\n"));
+        assert!(output.contains("Threads on thumper: Thread 2\n"));
+
+        let output = transitions(&statemaps,
+            StatemapTransitionsFormat::JSON);
+        let doc: Value = serde_json::from_str(&output).unwrap();
+        let doc = &doc["statemaps"][0];
+
+        assert_eq!(doc["aggregate"]["count"],
+            json!([[0, 3, 1], [2, 0, 0], [1, 1, 0]]));
+        assert_eq!(doc["aggregate"]["dwell"][1], json!([1825.0, null, null]));
+        assert_eq!(doc["entities"][0]["name"], "1");
+        assert_eq!(doc["entities"][0]["count"][0], json!([0, 2, 0]));
+
+        let output = transitions(&statemaps, StatemapTransitionsFormat::DOT);
+        assert!(output.starts_with("digraph statemap {\n"));
+        assert!(output.contains(
+            r##""on-cpu" [label="on-cpu", fillcolor="#2e9107"];"##));
+        assert!(output.contains(concat!(r##""on-cpu" -> "off-cpu-waiting" "##,
+            r##"[label="3 (617ns)", weight=3, penwidth=5.00];"##)));
+        assert!(!output.contains("subgraph"));
+
+        let statemaps = vec![good_statemap!("events"), good_statemap!("io")];
+        let output = transitions(&statemaps, StatemapTransitionsFormat::DOT);
+        assert!(output.contains("    subgraph cluster_1 {\n"));
+        assert!(output.contains(r##""0:on-cpu" -> "0:off-cpu-waiting""##));
+    }
+
+    #[test]
+    fn diff_basic() {
+        let before = good_statemap!("events");
+        let after = good_statemap!("diff");
+        let diff = StatemapDiff::new(&before, &after).unwrap();
+
+        /*
+         * States are matched by name, with states found only in the second
+         * statemap following those of the first.
+         */
+        let states: Vec<&str> = diff.states().iter()
+            .map(|s| s.name()).collect();
+        assert_eq!(states,
+            vec!["on-cpu", "off-cpu-waiting", "off-cpu-futex", "off-cpu-io"]);
+
+        let deltas = diff.deltas();
+        assert_eq!(deltas.len(), 5);
+
+        assert_eq!(deltas[0].entity(), Some("1"));
+        assert_eq!(deltas[0].before(), &[1400, 1600, 0, 0]);
+        assert_eq!(deltas[0].after(), &[2500, 1000, 0, 0]);
+        assert_eq!(deltas[0].delta(1), -600);
+
+        assert_eq!(deltas[1].before(), &[900, 0, 2100, 0]);
+        assert_eq!(deltas[1].after(), &[1400, 1000, 0, 1100]);
+
+        /*
+         * Entities found in only one statemap spent no time in the other.
+         */
+        assert_eq!(deltas[2].entity(), Some("3"));
+        assert_eq!(deltas[2].after(), &[0, 0, 0, 0]);
+        assert_eq!(deltas[3].entity(), Some("4"));
+        assert_eq!(deltas[3].before(), &[0, 0, 0, 0]);
+
+        assert_eq!(deltas[4].entity(), None);
+        assert_eq!(deltas[4].before(), &[2750, 4150, 2100, 0]);
+        assert_eq!(deltas[4].after(), &[3900, 5500, 0, 1100]);
+    }
+
+    #[test]
+    fn diff_combined() {
+        let before = good_statemap!("events");
+        let after = good_statemap!("diff");
+        let diff = StatemapDiff::new(&before, &after).unwrap();
+        let combined = diff.combined().unwrap();
+
+        let names: Vec<&str> = combined.entities().iter()
+            .map(|e| e.name()).collect();
+        assert_eq!(names,
+            vec!["1 (A)", "1 (B)", "2 (A)", "2 (B)", "3 (A)", "4 (B)"]);
+
+        /*
+         * The combined statemap is relative to the start of the first, and
+         * spans the longer of the two.
+         */
+        let (begin, end) = before.timebounds();
+        assert_eq!(combined.timebounds(), (begin, end + 500));
+        assert_eq!(combined.states().len(), 4);
+
+        let rects = combined.entity("2 (B)").unwrap().rects();
+        assert_eq!(rects[0].states(), &[0, 0, 0, 1100]);
+        assert_eq!(rects[1].states(), &[1400, 0, 0, 0]);
+
+        assert_eq!(combined.entity("1 (A)").unwrap().totals(),
+            &[1400, 1600, 0, 0]);
+
+        /*
+         * Event types are shared, and targets are renamed.
+         */
+        assert_eq!(combined.event_types(),
+            vec!["wakeup", "signal", "preempt"]);
+
+        let events = &combined.entity("1 (B)").unwrap().events;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, 2);
+        assert_eq!(events[1].event, 0);
+        assert_eq!(events[1].target, Some("2 (B)".to_string()));
+
+        let output = svg(&vec![combined], &Default::default());
+        assert!(output.contains("Threads (A) vs. Threads (B) on thumper"));
+
+        /*
+         * Tags defined in both statemaps are defined once.
+         */
+        let tagged = good_statemap!("tag_basic");
+        let combined = StatemapDiff::new(&tagged, &tagged).unwrap()
+            .combined().unwrap();
+
+        assert_eq!(combined.tags(), tagged.tags());
+    }
+
+    fn diff(before: &Statemap, after: &Statemap, format: StatemapDiffFormat)
+        -> String
+    {
+        let mut out: Vec<u8> = vec![];
+        let diff = StatemapDiff::new(before, after).unwrap();
+
+        match diff.output(format, &mut out) {
+            Err(err) => { panic!("output incorrectly failed: {:?}", err); }
+            Ok(_) => {}
+        }
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn diff_formats() {
+        let before = good_statemap!("events");
+        let after = good_statemap!("diff");
+
+        let output = diff(&before, &after, StatemapDiffFormat::Text);
+        assert!(output.starts_with(
+            "A: Threads on thumper\nB: Threads on thumper\n\n"));
+        assert!(output.contains(concat!("1       off-cpu-waiting  ",
+            "0.001600  0.001000  -0.000600   -37.50%\n")));
+        assert!(output.contains(concat!("2       off-cpu-io       ",
+            "0.000000  0.001100  +0.001100         -\n")));
+        assert!(!output.contains("1       off-cpu-futex"));
+
+        let output = diff(&before, &after, StatemapDiffFormat::CSV);
+        assert!(output.starts_with("entity,state,a,b,delta\n"));
+        assert!(output.contains("\n3,on-cpu,450,0,-450\n"));
+        assert!(output.contains("\n,off-cpu-futex,2100,0,-2100\n"));
+
+        let output = diff(&before, &after, StatemapDiffFormat::JSON);
+        let doc: Value = serde_json::from_str(&output).unwrap();
+
+        assert_eq!(doc["b"]["end"].as_u64().unwrap(),
+            after.timebounds().1);
+        assert_eq!(doc["states"][3], "off-cpu-io");
+        assert_eq!(doc["entities"][3]["name"], "4");
+        assert_eq!(doc["entities"][3]["delta"], json!([0, 3500, 0, 0]));
+        assert_eq!(doc["aggregate"]["delta"],
+            json!([1150, 1350, -2100, 1100]));
+
+        let mut out: Vec<u8> = vec![];
+        let config: StatemapSVGConfig = Default::default();
+
+        StatemapDiff::new(&before, &after).unwrap()
+            .output_delta(&config, &mut out).unwrap();
+
+        let output = String::from_utf8(out).unwrap();
+        assert!(output.contains("Threads: B relative to A"));
+        assert!(output.contains(r##"dominant-baseline="middle">4</text>"##));
+        assert!(output.contains("off-cpu-io</text>"));
+        assert!(output.ends_with("</svg>\n"));
+    }
+
+    /*
+     * Render the specified statemaps as PNG, returning the decoded image's
+     * dimensions and RGB pixels.
+     */
+    fn png(statemaps: &Vec<Statemap>, config: &StatemapSVGConfig,
+        pngconf: &StatemapPNGConfig) -> (u32, u32, Vec<u8>)
+    {
+        let mut out: Vec<u8> = vec![];
+
+        match StatemapPNG::new(config, pngconf).output(statemaps, &mut out) {
+            Err(err) => { panic!("output incorrectly failed: {:?}", err); }
+            Ok(_) => {}
+        }
+
+        let decoder = png::Decoder::new(&out[..]);
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(info.bit_depth, png::BitDepth::Eight);
+        pixels.truncate(info.buffer_size());
+
+        (info.width, info.height, pixels)
+    }
+
+    fn pixel(image: &(u32, u32, Vec<u8>), x: u32, y: u32) -> (u8, u8, u8) {
+        let offs = ((y * image.0 + x) * 3) as usize;
+        (image.2[offs], image.2[offs + 1], image.2[offs + 2])
+    }
+
+    #[test]
+    fn png_basic() {
+        let statemaps = vec![good_statemap!("events")];
+        let config: StatemapSVGConfig = Default::default();
+        let image = png(&statemaps, &config, &Default::default());
+
+        /*
+         * By default, our dimensions are those of the SVG -- and with only
+         * three entities, our height is dictated by our legend.
+         */
+        assert_eq!(image.0, config.legendWidth + config.stripWidth + 20);
+        assert_eq!(image.1, 60 + (3 * (15 + 20)));
+        assert_eq!(pixel(&image, 0, 0), (255, 255, 255));
+
+        /*
+         * The start of each strip should be in one of our states' colors.
+         */
+        let colors: Vec<(u8, u8, u8)> = StatemapSVG::new(&config)
+            .colors(&statemaps).unwrap()[0].iter()
+            .map(|color| color.rgb()).collect();
+
+        for i in 0..3 {
+            let y = 60 + (i * config.stripHeight) + config.stripHeight / 2;
+            let p = pixel(&image, config.legendWidth + 1, y);
+            assert!(colors.contains(&p), "unexpected color {:?}", p);
+        }
+
+        /*
+         * And our legend should be in the colors of our states.
+         */
+        for (i, color) in colors.iter().enumerate() {
+            assert_eq!(pixel(&image, 25, 60 + (i as u32 * 35) + 5), *color);
+        }
+    }
+
+    #[test]
+    fn png_dimensions() {
+        let statemaps = vec![good_statemap!("events"), good_statemap!("io")];
+        let config: StatemapSVGConfig = Default::default();
+        let pngconf = StatemapPNGConfig { width: 640, height: 480 };
+        let image = png(&statemaps, &config, &pngconf);
+
+        assert_eq!((image.0, image.1), (640, 480));
+        assert_eq!(image.2.len(), 640 * 480 * 3);
+
+        /*
+         * Just beyond the right edge of our strips should be white.
+         */
+        assert_eq!(pixel(&image, 640 - 19, 70), (255, 255, 255));
+        assert_ne!(pixel(&image, 640 - 22, 70), (255, 255, 255));
+
+        /*
+         * A legend too narrow for any label is allowed.
+         */
+        let mut config: StatemapSVGConfig = Default::default();
+        config.legendWidth = 2;
 
-        statemap.verify();
-        statemap.print("Begin at 200001");
+        let image = png(&statemaps, &config, &Default::default());
+        assert_eq!(image.2.len(), (image.0 * image.1 * 3) as usize);
+    }
 
-        let rects = statemap.get_rects("foo");
-        assert_eq!(rects.len(), 4);
-        assert_eq!(rects[0].0, 200001);
-        assert_eq!(rects[0].1, 300000 - 200001);
-        assert_eq!((rects[0].2)[0], 0);
-        assert_eq!((rects[0].2)[1], 300000 - 200001);
+    #[test]
+    fn png_too_small() {
+        let statemaps = vec![good_statemap!("events")];
+        let config: StatemapSVGConfig = Default::default();
+
+        let check = |pngconf: StatemapPNGConfig, expected: &str| {
+            let mut out: Vec<u8> = vec![];
+
+            match StatemapPNG::new(&config, &pngconf)
+                .output(&statemaps, &mut out) {
+                Err(err) => assert_eq!(err.to_string(), expected),
+                Ok(_) => panic!("small PNG incorrectly succeeded")
+            }
+        };
+
+        check(StatemapPNGConfig { width: 158, height: 0 },
+            "width must exceed 158 pixels");
+        check(StatemapPNGConfig { width: 0, height: 72 },
+            "height must be at least 73 pixels");
     }
 
     #[test]
-    fn data_begin_end_time() {
+    fn png_too_large() {
+        let statemaps = vec![good_statemap!("events")];
+        let config: StatemapSVGConfig = Default::default();
+
+        for &(width, height) in &[(50_000, 50_000), (u32::MAX, u32::MAX)] {
+            let pngconf = StatemapPNGConfig { width: width, height: height };
+            let mut out: Vec<u8> = vec![];
+
+            match StatemapPNG::new(&config, &pngconf)
+                .output(&statemaps, &mut out) {
+                Err(err) => assert_eq!(err.to_string(), format!(
+                    "{}x{} pixels exceeds maximum PNG size of 100000000 pixels",
+                    width, height)),
+                Ok(_) => panic!("large PNG incorrectly succeeded")
+            }
+        }
+    }
+
+    /*
+     * Return the total time spent in each state by each entity.
+     */
+    fn totals(statemap: &Statemap) -> HashMap<String, Vec<u64>> {
+        let mut totals = HashMap::new();
+
+        for entity in statemap.entities() {
+            let mut total = vec![0; statemap.states().len()];
+
+            for rect in entity.rects() {
+                assert_eq!(rect.states().iter().sum::<u64>(), rect.duration());
+
+                for i in 0..total.len() {
+                    total[i] += rect.states()[i];
+                }
+            }
+
+            totals.insert(entity.name().to_string(), total);
+        }
+
+        totals
+    }
+
+    fn buckets(nbuckets: u64, resolution: u64) -> Config {
         let mut config: Config = Default::default();
-        config.begin = 250000;
-        config.end = 310000;
+        config.coalesce = StatemapCoalesce::Buckets;
+        config.nbuckets = nbuckets;
+        config.resolution = resolution;
+        config
+    }
 
-        let statemap = data(Some(&config), vec![
-            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
-        ]);
+    #[test]
+    fn buckets_resolution() {
+        let statemap = good_statemap!("events", &buckets(100, 1000));
+        assert_eq!(statemap.resolution(), Some(1000));
+
+        let rects = statemap.entity("1").unwrap().rects();
+        let expected: Vec<(u64, u64, Vec<u64>)> = vec![
+            (0, 1000, vec![1000, 0, 0]),
+            (1000, 1000, vec![0, 1000, 0]),
+            (2000, 1000, vec![400, 600, 0]),
+        ];
+
+        assert_eq!(rects.iter().map(|r| (r.start(), r.duration(),
+            r.states().to_vec())).collect::<Vec<_>>(), expected);
 
+        /*
+         * Adjacent buckets that are entirely in the same state should be
+         * combined.
+         */
+        let rects = statemap.entity("3").unwrap().rects();
+        let expected: Vec<(u64, u64, Vec<u64>)> = vec![
+            (0, 2000, vec![0, 2000, 0]),
+            (2000, 1000, vec![450, 550, 0]),
+        ];
+
+        assert_eq!(rects.iter().map(|r| (r.start(), r.duration(),
+            r.states().to_vec())).collect::<Vec<_>>(), expected);
+
+        assert_eq!(statemap.nrects(), 8);
         statemap.verify();
-        statemap.print("Begin at 250000, end at 310000");
+    }
 
-        let rects = statemap.get_rects("foo");
-        assert_eq!(rects.len(), 2);
-        assert_eq!(rects[0].0, 250000);
-        assert_eq!(rects[0].1, 250000 - 200000);
-        assert_eq!((rects[0].2)[0], 0);
-        assert_eq!((rects[0].2)[1], 250000 - 200000);
+    #[test]
+    fn buckets_adaptive() {
+        for nbuckets in &[1, 2, 3, 10, 862] {
+            let statemap = good_statemap!("io", &buckets(*nbuckets, 0));
+            let resolution = statemap.resolution().unwrap();
+            let (begin, end) = statemap.timebounds();
+            let span = end - begin;
+
+            assert!(resolution.is_power_of_two());
+            assert!(span <= nbuckets * resolution);
+            assert!(resolution == 1 || span > nbuckets * resolution / 2);
+
+            for entity in statemap.entities() {
+                let rects = entity.rects();
+
+                for i in 1..rects.len() {
+                    assert_eq!(rects[i].start(),
+                        rects[i - 1].start() + rects[i - 1].duration());
+
+                    if rects[i].uniform().is_none() {
+                        assert_eq!(rects[i].start() % resolution, 0);
+                    }
+                }
+            }
 
-        assert_eq!(rects[1].0, 300000);
-        assert_eq!(rects[1].1, 310000 - 300000);
-        assert_eq!((rects[1].2)[0], 310000 - 300000);
-        assert_eq!((rects[1].2)[1], 0);
+            assert_eq!(totals(&statemap), totals(&good_statemap!("io")));
+            statemap.verify();
+        }
     }
 
     #[test]
-    fn data_wrapped_time() {
-        let mut config: Config = Default::default();
-        config.begin = 250000;
-        config.end = 260000;
+    fn buckets_bounded() {
+        let mut config = buckets(10, 0);
+        config.begin = 500;
+        config.end = 2500;
+
+        let statemap = good_statemap!("events", &config);
+        assert_eq!(statemap.resolution(), Some(200));
+
+        for entity in statemap.entities() {
+            for rect in entity.rects() {
+                assert_eq!((rect.start() - 500) % 200, 0);
+                assert!(rect.start() >= 500);
+                assert!(rect.start() + rect.duration() <= 2500);
+            }
+        }
 
-        let statemap = data(Some(&config), vec![
-            r##"{ "time": "100000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "200000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "300000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "400000", "entity": "foo", "state": 1 }"##,
-            r##"{ "time": "500000", "entity": "foo", "state": 0 }"##,
-            r##"{ "time": "600000", "entity": "foo", "state": 1 }"##
-        ]);
+        let mut config: Config = Default::default();
+        config.begin = 500;
+        config.end = 2500;
 
+        let weighted = good_statemap!("events", &config);
+        assert_eq!(totals(&statemap), totals(&weighted));
         statemap.verify();
-        statemap.print("Begin at 250000, end at 260000");
-
-        let rects = statemap.get_rects("foo");
-        assert_eq!(rects.len(), 1);
-        assert_eq!(rects[0].0, 250000);
-        assert_eq!(rects[0].1, 260000 - 250000);
-        assert_eq!((rects[0].2)[0], 0);
-        assert_eq!((rects[0].2)[1], 260000 - 250000);
     }
 
     #[test]
-    fn color_named() {
-        let colors = vec![
-            ("aliceblue", (240, 248, 255)),
-            ("antiquewhite", (250, 235, 215)),
-            ("aqua", (0, 255, 255)),
-            ("aquamarine", (127, 255, 212)),
-            ("azure", (240, 255, 255)),
-            ("beige", (245, 245, 220)),
-            ("bisque", (255, 228, 196)),
-            ("black", (0, 0, 0)),
-            ("blanchedalmond", (255, 235, 205)),
-            ("blue", (0, 0, 255)),
-            ("blueviolet", (138, 43, 226)),
-            ("brown", (165, 42, 42)),
-            ("burlywood", (222, 184, 135)),
-            ("cadetblue", (95, 158, 160)),
-            ("chartreuse", (127, 255, 0)),
-            ("chocolate", (210, 105, 30)),
-            ("coral", (255, 127, 80)),
-            ("cornflowerblue", (100, 149, 237)),
-            ("cornsilk", (255, 248, 220)),
-            ("crimson", (220, 20, 60)),
-            ("cyan", (0, 255, 255)),
-            ("darkblue", (0, 0, 139)),
-            ("darkcyan", (0, 139, 139)),
-            ("darkgoldenrod", (184, 134, 11)),
-            ("darkgray", (169, 169, 169)),
-            ("darkgreen", (0, 100, 0)),
-            ("darkgrey", (169, 169, 169)),
-            ("darkkhaki", (189, 183, 107)),
-            ("darkmagenta", (139, 0, 139)),
-            ("darkolivegreen", (85, 107, 47)),
-            ("darkorange", (255, 140, 0)),
-            ("darkorchid", (153, 50, 204)),
-            ("darkred", (139, 0, 0)),
-            ("darksalmon", (233, 150, 122)),
-            ("darkseagreen", (143, 188, 143)),
-            ("darkslateblue", (72, 61, 139)),
-            ("darkslategray", (47, 79, 79)),
-            ("darkslategrey", (47, 79, 79)),
-            ("darkturquoise", (0, 206, 209)),
-            ("darkviolet", (148, 0, 211)),
-            ("deeppink", (255, 20, 147)),
-            ("deepskyblue", (0, 191, 255)),
-            ("dimgray", (105, 105, 105)),
-            ("dimgrey", (105, 105, 105)),
-            ("dodgerblue", (30, 144, 255)),
-            ("firebrick", (178, 34, 34)),
-            ("floralwhite", (255, 250, 240)),
-            ("forestgreen", (34, 139, 34)),
-            ("fuchsia", (255, 0, 255)),
-            ("gainsboro", (220, 220, 220)),
-            ("ghostwhite", (248, 248, 255)),
-            ("gold", (255, 215, 0)),
-            ("goldenrod", (218, 165, 32)),
-            ("gray", (128, 128, 128)),
-            ("green", (0, 128, 0)),
-            ("greenyellow", (173, 255, 47)),
-            ("grey", (128, 128, 128)),
-            ("honeydew", (240, 255, 240)),
-            ("hotpink", (255, 105, 180)),
-            ("indianred", (205, 92, 92)),
-            ("indigo", (75, 0, 130)),
-            ("ivory", (255, 255, 240)),
-            ("khaki", (240, 230, 140)),
-            ("lavender", (230, 230, 250)),
-            ("lavenderblush", (255, 240, 245)),
-            ("lawngreen", (124, 252, 0)),
-            ("lemonchiffon", (255, 250, 205)),
-            ("lightblue", (173, 216, 230)),
-            ("lightcoral", (240, 128, 128)),
-            ("lightcyan", (224, 255, 255)),
-            ("lightgoldenrodyellow", (250, 250, 210)),
-            ("lightgray", (211, 211, 211)),
-            ("lightgreen", (144, 238, 144)),
-            ("lightgrey", (211, 211, 211)),
-            ("lightpink", (255, 182, 193)),
-            ("lightsalmon", (255, 160, 122)),
-            ("lightseagreen", (32, 178, 170)),
-            ("lightskyblue", (135, 206, 250)),
-            ("lightslategray", (119, 136, 153)),
-            ("lightslategrey", (119, 136, 153)),
-            ("lightsteelblue", (176, 196, 222)),
-            ("lightyellow", (255, 255, 224)),
-            ("lime", (0, 255, 0)),
-            ("limegreen", (50, 205, 50)),
-            ("linen", (250, 240, 230)),
-            ("magenta", (255, 0, 255)),
-            ("maroon", (128, 0, 0)),
-            ("mediumaquamarine", (102, 205, 170)),
-            ("mediumblue", (0, 0, 205)),
-            ("mediumorchid", (186, 85, 211)),
-            ("mediumpurple", (147, 112, 219)),
-            ("mediumseagreen", (60, 179, 113)),
-            ("mediumslateblue", (123, 104, 238)),
-            ("mediumspringgreen", (0, 250, 154)),
-            ("mediumturquoise", (72, 209, 204)),
-            ("mediumvioletred", (199, 21, 133)),
-            ("midnightblue", (25, 25, 112)),
-            ("mintcream", (245, 255, 250)),
-            ("mistyrose", (255, 228, 225)),
-            ("moccasin", (255, 228, 181)),
-            ("navajowhite", (255, 222, 173)),
-            ("navy", (0, 0, 128)),
-            ("oldlace", (253, 245, 230)),
-            ("olive", (128, 128, 0)),
-            ("olivedrab", (107, 142, 35)),
-            ("orange", (255, 165, 0)),
-            ("orangered", (255, 69, 0)),
-            ("orchid", (218, 112, 214)),
-            ("palegoldenrod", (238, 232, 170)),
-            ("palegreen", (152, 251, 152)),
-            ("paleturquoise", (175, 238, 238)),
-            ("palevioletred", (219, 112, 147)),
-            ("papayawhip", (255, 239, 213)),
-            ("peachpuff", (255, 218, 185)),
-            ("peru", (205, 133, 63)),
-            ("pink", (255, 192, 203)),
-            ("plum", (221, 160, 221)),
-            ("powderblue", (176, 224, 230)),
-            ("purple", (128, 0, 128)),
-            ("rebeccapurple", (102, 51, 153)),
-            ("red", (255, 0, 0)),
-            ("rosybrown", (188, 143, 143)),
-            ("royalblue", (65, 105, 225)),
-            ("saddlebrown", (139, 69, 19)),
-            ("salmon", (250, 128, 114)),
-            ("sandybrown", (244, 164, 96)),
-            ("seagreen", (46, 139, 87)),
-            ("seashell", (255, 245, 238)),
-            ("sienna", (160, 82, 45)),
-            ("silver", (192, 192, 192)),
-            ("skyblue", (135, 206, 235)),
-            ("slateblue", (106, 90, 205)),
-            ("slategray", (112, 128, 144)),
-            ("slategrey", (112, 128, 144)),
-            ("snow", (255, 250, 250)),
-            ("springgreen", (0, 255, 127)),
-            ("steelblue", (70, 130, 180)),
-            ("tan", (210, 180, 140)),
-            ("teal", (0, 128, 128)),
-            ("thistle", (216, 191, 216)),
-            ("tomato", (255, 99, 71)),
-            ("turquoise", (64, 224, 208)),
-            ("violet", (238, 130, 238)),
-            ("wheat", (245, 222, 179)),
-            ("white", (255, 255, 255)),
-            ("whitesmoke", (245, 245, 245)),
-            ("yellow", (255, 255, 0)),
-            ("yellowgreen", (154, 205, 50)),
-        ];
+    fn buckets_tags() {
+        let statemap = good_statemap!("tag_basic", &buckets(50, 0));
+        let tagged = good_statemap!("tag_basic");
+
+        for entity in statemap.entities() {
+            let mut total = HashMap::new();
+
+            for rect in entity.rects() {
+                for (id, duration) in rect.tags() {
+                    assert!(duration <= rect.duration());
+                    *total.entry(id).or_insert(0) += duration;
+                }
+            }
+
+            let mut expected = HashMap::new();
+
+            for rect in tagged.entity(entity.name()).unwrap().rects() {
+                for (id, duration) in rect.tags() {
+                    *expected.entry(id).or_insert(0) += duration;
+                }
+            }
+
+            assert_eq!(total, expected);
+        }
+
+        /*
+         * Not all states in this data are tagged, so buckets will in general
+         * be only partially tagged -- and we therefore cannot verify() it.
+         */
+    }
 
-        let notcolors = vec!["nixon", "yellowgreenybeeny", "#1234567",
-            "1234567", "$123456", "#123456#" ];
+    #[test]
+    fn buckets_builder() {
+        builder_compare(&buckets(7, 0));
+        builder_compare(&buckets(100, 250));
+    }
 
-        for i in 0..notcolors.len() {
-            match StatemapColor::from_str(notcolors[i]) {
-                Ok(color) => {
-                    panic!("lookup of {} succeeded with {:?}!",
-                        notcolors[i], color);
-                },
-                Err(err) => {
-                    println!("lookup of {} failed with {}", notcolors[i], err);
+    /*
+     * Construct a statemap with an entity that spends most of its time in
+     * a single state, punctuated by brief visits to other states.
+     */
+    fn salient_statemap(salient: Option<&str>, maxrect: u64) -> Statemap {
+        let mut config: Config = Default::default();
+        config.maxrect = maxrect;
+
+        let mut statemap = Statemap::new(&config);
+
+        if let Some(state) = salient {
+            statemap.set_salient(state).unwrap();
+        }
+
+        let idle = statemap.add_state("idle", None).unwrap();
+        let error = statemap.add_state("error", None).unwrap();
+        let busy = statemap.add_state("busy", None).unwrap();
+
+        for i in 0..20 {
+            let state = if i == 13 { error } else { busy };
+
+            statemap.transition("foo", i * 1000, idle, None).unwrap();
+            statemap.transition("foo", i * 1000 + 500, state, None).unwrap();
+            statemap.transition("foo", i * 1000 + 505, idle, None).unwrap();
+        }
+
+        statemap.transition("foo", 20000, idle, None).unwrap();
+        statemap.finish();
+        statemap
+    }
+
+    #[test]
+    fn salient_metadata() {
+        let statemap = metadata(None, r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0 },
+                "one": {"value": 1, "salient": true },
+                "two": {"value": 2, "salient": false }
+            }
+        }"##);
+
+        let salient: Vec<bool> = statemap.states().iter()
+            .map(|s| s.salient()).collect();
+        assert_eq!(salient, vec![false, true, false]);
+
+        bad_metadata(r##"{
+            "start": [ 0, 0 ],
+            "title": "Foo",
+            "states": {
+                "zero": {"value": 0, "salient": "yes" }
+            }
+        }"##, "invalid type: string \"yes\", expected a boolean");
+    }
+
+    #[test]
+    fn salient_coalesce() {
+        let pure = |statemap: &Statemap| {
+            statemap.entity("foo").unwrap().rects().iter()
+                .any(|r| r.states()[1] == r.duration())
+        };
+
+        /*
+         * Without coalescing, our error state is in its own rectangle --
+         * but when we coalesce, it is lost unless it is salient.
+         */
+        assert!(pure(&salient_statemap(None, 25000)));
+        assert!(!pure(&salient_statemap(None, 10)));
+
+        let statemap = salient_statemap(Some("error"), 10);
+        assert!(pure(&statemap));
+        assert!(statemap.states()[1].salient());
+        assert!(statemap.nrects() <= 10);
+        statemap.verify();
+
+        /*
+         * A salient state that does not exist is not an error.
+         */
+        let statemap = salient_statemap(Some("stall"), 10);
+        assert!(!pure(&statemap));
+        assert!(statemap.states().iter().all(|s| !s.salient()));
+    }
+
+    #[test]
+    fn salient_override() {
+        let raw = include_str!("../tst/tst.io.in");
+        let mut config: Config = Default::default();
+        config.maxrect = 20;
+
+        let mut statemap = Statemap::new(&config);
+        statemap.set_salient("3 I/Os").unwrap();
+        statemap_ingest_bytes(&mut statemap, raw.as_bytes()).unwrap();
+
+        assert!(statemap.states()[3].salient());
+        assert_eq!(statemap.states().iter().filter(|s| s.salient()).count(), 1);
+        statemap.verify();
+
+        match statemap.set_salient("2 I/Os") {
+            Err(err) => assert_eq!(err.to_string(),
+                "cannot set salient states after data has been added"),
+            Ok(_) => panic!("late salient state incorrectly succeeded")
+        }
+    }
+
+    #[test]
+    fn salient_svg() {
+        let mut config: Config = Default::default();
+        config.maxrect = 20;
+
+        let statemaps = vec![good_statemap!("io", &config)];
+        let output = svg(&statemaps, &Default::default());
+        assert!(!output.contains("statemap-0-salient"));
+
+        let raw = include_str!("../tst/tst.io.in");
+        let mut statemap = Statemap::new(&config);
+        statemap.set_salient("3 I/Os").unwrap();
+        statemap_ingest_bytes(&mut statemap, raw.as_bytes()).unwrap();
+
+        let color = StatemapColor::from_str("#fed976").unwrap().to_string();
+        let statemaps = vec![statemap];
+        let output = svg(&statemaps, &Default::default());
+
+        assert!(output.contains(r##"<g id="statemap-0-salient">"##));
+
+        let markers: Vec<&str> = output.lines()
+            .filter(|l| l.contains(r##"class="statemap-salient""##))
+            .collect();
+
+        assert!(markers.len() > 0);
+
+        for marker in markers {
+            assert!(marker.ends_with(&format!("style=\"fill:{}\" />", color)));
+        }
+    }
+
+    fn level_totals(statemap: &Statemap, level: usize)
+        -> HashMap<String, Vec<u64>>
+    {
+        let mut totals = HashMap::new();
+
+        for entity in statemap.entities() {
+            let rects = entity.level(level);
+            let mut total = vec![0; statemap.states().len()];
+
+            for i in 0..rects.len() {
+                assert_eq!(rects[i].states().iter().sum::<u64>(),
+                    rects[i].duration());
+
+                if i > 0 {
+                    assert_eq!(rects[i].start(),
+                        rects[i - 1].start() + rects[i - 1].duration());
+                }
+
+                for j in 0..total.len() {
+                    total[j] += rects[i].states()[j];
                 }
             }
+
+            totals.insert(entity.name().to_string(), total);
         }
 
-        for i in 0..colors.len() {
-            match StatemapColor::from_str(colors[i].0) {
-                Ok(color) => {
-                    let out = format!("rgb({}, {}, {})",
-                        (colors[i].1).0, (colors[i].1).1, (colors[i].1).2);
-                    assert_eq!(out, color.to_string());
-                },
-                Err(err) => {
-                    panic!("lookup of {} failed with {}!", colors[i].0, err);
+        totals
+    }
+
+    #[test]
+    fn levels_none() {
+        let statemap = good_statemap!("io");
+        assert_eq!(statemap.levels().len(), 0);
+
+        for entity in statemap.entities() {
+            assert_eq!(entity.level(0).len(), 0);
+        }
+
+        let statemaps = vec![statemap];
+        let output = svg(&statemaps, &Default::default());
+        assert!(!output.contains("\"levels\""));
+    }
+
+    #[test]
+    fn levels_adaptive() {
+        let mut config: Config = Default::default();
+        config.maxrect = 10;
+        config.nbuckets = 10;
+        config.levels = 3;
+
+        let statemap = good_statemap!("io", &config);
+        let levels = statemap.levels();
+        let (begin, end) = statemap.timebounds();
+        let span = end - begin;
+        let mut nbuckets = config.nbuckets;
+
+        assert_eq!(levels.len(), 3);
+        assert_eq!(statemap.resolution(), None);
+
+        for level in 0..levels.len() {
+            nbuckets *= LEVEL_FACTOR;
+
+            assert!(levels[level].is_power_of_two());
+            assert!(span <= nbuckets * levels[level]);
+            assert!(level == 0 || levels[level] <= levels[level - 1]);
+
+            for entity in statemap.entities() {
+                for rect in entity.level(level) {
+                    if rect.uniform().is_none() {
+                        let end = rect.start() + rect.duration() - 1;
+                        assert_eq!(rect.start() / levels[level],
+                            end / levels[level]);
+                    }
                 }
             }
+
+            assert_eq!(level_totals(&statemap, level), totals(&statemap));
         }
+
+        /*
+         * Our levels of detail should have no effect on our rectangles.
+         */
+        config.levels = 0;
+        let coarse = good_statemap!("io", &config);
+        assert_eq!(statemap.nrects(), coarse.nrects());
+        assert_eq!(totals(&statemap), totals(&coarse));
+        statemap.verify();
     }
 
     #[test]
-    fn color_mix() {
-        let red = StatemapColor::from_str("red").unwrap();
-        let white = StatemapColor::from_str("white").unwrap();
+    fn levels_buckets() {
+        let mut config = buckets(100, 1000);
+        config.levels = 2;
 
-        let tests = vec![
-            (0.0, "rgb(255, 0, 0)"),
-            (0.25, "rgb(255, 137, 137)"),
-            (0.5, "rgb(255, 188, 188)"),
-            (0.75, "rgb(255, 225, 225)"),
-            (1.0, "rgb(255, 255, 255)"),
-        ];
+        let statemap = good_statemap!("events", &config);
+        assert_eq!(statemap.resolution(), Some(1000));
+        assert_eq!(statemap.levels(), &[250, 62]);
 
-        for i in 0..tests.len() {
-            assert_eq!(red._mix(&white, tests[i].0).to_string(), tests[i].1);
-            assert_eq!(red._mix(&white, tests[i].0).to_string(),
-                white._mix(&red, 1.0 - tests[i].0).to_string());
+        for level in 0..2 {
+            assert_eq!(level_totals(&statemap, level), totals(&statemap));
+        }
+
+        let mut config = buckets(10, 0);
+        config.begin = 500;
+        config.end = 2500;
+        config.levels = 1;
+
+        let statemap = good_statemap!("events", &config);
+        assert_eq!(statemap.levels(), &[50]);
+
+        for entity in statemap.entities() {
+            for rect in entity.level(0) {
+                assert_eq!((rect.start() - 500) % 50, 0);
+                assert!(rect.start() + rect.duration() <= 2500);
+            }
         }
+
+        assert_eq!(level_totals(&statemap, 0), totals(&statemap));
+        statemap.verify();
     }
 
     #[test]
-    fn color_mix_linear() {
-        let color = StatemapColor::from_str("#2e9107").unwrap();
-        let other = StatemapColor::from_str("#f9f9f9").unwrap();
-        let ratio = 351500 as f64 / 840108 as f64;
-        let mix = color._mix(&other, ratio as f32);
+    fn levels_svg() {
+        let mut config: Config = Default::default();
+        config.maxrect = 10;
+        config.levels = 2;
 
-        println!("color={}, other={}, mix={}", color, other, mix);
+        let statemaps = vec![good_statemap!("io", &config)];
+        let levels = statemaps[0].levels().to_vec();
+        let output = svg(&statemaps, &Default::default());
+
+        assert!(output.contains("\"levels\": ["));
+
+        for resolution in levels {
+            assert!(output.contains(&format!("{{ \"resolution\": {}, ",
+                resolution)));
+        }
+
+        /*
+         * Each datum at a finer level of detail should carry its duration
+         * and color.
+         */
+        let ndata = output.lines().filter(|l| l.contains(", d: ")).count();
+        let nrects: usize = statemaps[0].entities().iter()
+            .map(|e| e.level(0).len() + e.level(1).len()).sum();
+
+        assert_eq!(ndata, nrects);
+        assert!(output.lines().filter(|l| l.contains(", d: "))
+            .all(|l| l.contains(", c: 'rgb(")));
+    }
+
+    fn sched(what: &str, entity: StatemapSchedEntity) -> Statemap {
+        let mut config: Config = Default::default();
+        config.sched = entity;
+
+        let raw = match what {
+            "ftrace" => include_str!("../tst/tst.ftrace.in"),
+            "perf" => include_str!("../tst/tst.perf.in"),
+            _ => include_str!("../tst/tst.ftrace_report.in"),
+        };
+
+        good_statemap(&config, raw)
     }
 
     #[test]
-    fn color_mix_nonlinear() {
-        let color = StatemapColor::from_str("#2e9107").unwrap();
-        let other = StatemapColor::from_str("#f9f9f9").unwrap();
-        let ratio = 351500 as f64 / 840108 as f64;
-        let mix = color.mix_nonlinear(&other, ratio as f32);
+    fn sched_cpu() {
+        let statemap = sched("ftrace", StatemapSchedEntity::CPU);
+        let states: Vec<&str> = statemap.states().iter()
+            .map(|s| s.name()).collect();
+
+        assert_eq!(states, vec!["idle", "running"]);
+        assert_eq!(statemap.metadata.as_ref().unwrap().start, vec![0, 0]);
+        assert_eq!(statemap.title(),
+            Some("CPU activity (trace time 100.000000s)"));
+        assert_eq!(statemap.entity("0").unwrap().totals(),
+            &[200_000, 1_000_000]);
+        assert_eq!(statemap.entity("1").unwrap().totals(),
+            &[400_000, 700_000]);
+        assert_eq!(statemap.entity("1").unwrap().description(), Some("CPU 1"));
+        statemap.verify();
 
-        println!("color={}, other={}, mix={}", color, other, mix);
+        /*
+         * The running state is tagged with the thread on CPU.
+         */
+        let tags = statemap.tags();
+        assert_eq!(tags.len(), 3);
+        assert!(tags.iter().any(|t| t["tag"] == "2001" && t["state"] == 1 &&
+            t["comm"] == "Web Content" && t["pid"] == 2001));
+
+        let tagged: u64 = statemap.entity("0").unwrap().rects().iter()
+            .flat_map(|r| r.tags()).map(|(_, d)| d).sum();
+        assert_eq!(tagged, 1_000_000);
     }
 
     #[test]
-    fn tag_basic() {
-        let statemap = good_statemap!("tag_basic");
-        println!("{:?}", statemap.tags);
+    fn sched_thread() {
+        let statemap = sched("ftrace", StatemapSchedEntity::Thread);
+        let states: Vec<&str> = statemap.states().iter()
+            .map(|s| s.name()).collect();
+
+        assert_eq!(states,
+            vec!["running", "runnable", "sleeping", "blocked-io", "dead"]);
+
+        let names: Vec<&str> = statemap.entities().iter()
+            .map(|e| e.name()).collect();
+        assert_eq!(names, vec!["56", "1234", "2001"]);
+
+        let entity = statemap.entity("1234").unwrap();
+        assert_eq!(entity.description(), Some("bash"));
+        assert_eq!(entity.totals(), &[800_000, 300_000, 100_000, 0, 0]);
+
+        let entity = statemap.entity("2001").unwrap();
+        assert_eq!(entity.description(), Some("Web Content"));
+        assert_eq!(entity.totals(), &[700_000, 100_000, 0, 200_000, 100_000]);
+
+        /*
+         * A wakeup is an event on the waking thread, unless it was the
+         * idle thread.
+         */
+        assert_eq!(statemap.entity("1234").unwrap().events.len(), 1);
+        assert_eq!(statemap.entity("2001").unwrap().events.len(), 1);
+        assert_eq!(statemap.entity("56").unwrap().totals(),
+            &[200_000, 100_000, 700_000, 0, 0]);
         statemap.verify();
     }
 
     #[test]
-    fn tag_redefined() {
-        let statemap = good_statemap!("tag_redefined");
-        println!("{:?}", statemap.tags);
+    fn sched_report() {
+        /*
+         * The output of trace-cmd report is equivalent to the raw trace,
+         * whether mapped or streamed.
+         */
+        let entities = [StatemapSchedEntity::CPU, StatemapSchedEntity::Thread];
+
+        for &entity in &entities {
+            let expected = totals(&sched("ftrace", entity));
+            assert_eq!(totals(&sched("report", entity)), expected);
+
+            for size in vec![1, 7, STREAM_CHUNK] {
+                let mut config: Config = Default::default();
+                config.sched = entity;
+
+                let mut statemap = Statemap::new(&config);
+                statemap_ingest_stream(&mut statemap,
+                    include_str!("../tst/tst.ftrace_report.in"),
+                    size).unwrap();
+
+                assert_eq!(totals(&statemap), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn sched_perf() {
+        let statemap = sched("perf", StatemapSchedEntity::CPU);
+        let expected = totals(&sched("ftrace", StatemapSchedEntity::CPU));
+
+        assert_eq!(totals(&statemap), expected);
+
+        /*
+         * The thread group of each thread is known, and is included in its
+         * tag; its exit is an event on its CPU.
+         */
+        let tags = statemap.tags();
+        assert_eq!(tags.len(), 3);
+        assert!(tags.iter().any(|t| t["tag"] == "2001" && t["tgid"] == 2000 &&
+            t["comm"] == "Web Content" && t["pid"] == 2001));
+
+        let events = &statemap.entity("1").unwrap().events;
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[2].time, 1_050_000);
+        assert_eq!(events[2].target, None);
+
+        let statemap = sched("perf", StatemapSchedEntity::Thread);
+        let entity = statemap.entity("1234").unwrap();
+
+        assert_eq!(entity.description(), Some("bash"));
+        assert_eq!(entity.totals(), &[800_000, 300_000, 100_000, 0, 0]);
+
+        /*
+         * A thread is dead once it has exited.
+         */
+        let entity = statemap.entity("2001").unwrap();
+        assert_eq!(entity.description(), Some("Web Content (process 2000)"));
+        assert_eq!(entity.totals(),
+            &[650_000, 100_000, 0, 200_000, 150_000]);
         statemap.verify();
     }
 
     #[test]
-    fn timebounds() {
-        let statemap = good_statemap!("io");
-        let _timebounds = statemap.timebounds();
+    fn bad_sched() {
+        bad_statemap("# tracer: nop\n#\n", "no scheduler events found");
 
-        let mut config: Config = Default::default();
-        config.begin = 100000;
+        /*
+         * Input that isn't a scheduler trace remains an error as JSON.
+         */
+        bad_statemap("This is not a statemap.\n",
+            "expected value at line 1 column 1");
+        bad_statemap(&"Nor [0] is this: sched_switch\n".repeat(40),
+            "expected value at line 1 column 1");
+
+        bad_statemap(concat!(
+            "  bash-1234 [000] 100.000200: sched_switch: bash:1234 [120] S ",
+            "==> swapper/0:0 [120]\n",
+            "  bash-1234 [001] 100.000100: sched_switch: bash:1234 [120] S ",
+            "==> swapper/0:0 [120]\n"),
+            "illegal event on line 2: events are not in time order");
+
+        bad_statemap(
+            "  bash-1234 [000] 100.000200: sched_switch: bash:1234 [120]\n",
+            "illegal event on line 1: malformed sched_switch event");
+
+        bad_statemap(concat!(
+            "  bash 1234 [000] 100.000200: sched:sched_process_exit: ",
+            "bash 1234\n"),
+            "illegal event on line 1: malformed sched_process_exit event");
+    }
 
-        let bounded = good_statemap!("io", &config);
-        println!("{:?}", bounded.timebounds());
+    #[test]
+    fn chrome_basic() {
+        let statemap = good_statemap!("chrome");
+        let states: Vec<&str> = statemap.states().iter()
+            .map(|s| s.name()).collect();
 
-        config.begin = -100000;
-        config.end = 10000;
+        /*
+         * Span names are states in descending order of time spent in them.
+         */
+        assert_eq!(states, vec!["idle", "query", "compress", "gc", "request",
+            "parse", "sweep", "flush"]);
+        assert_eq!(statemap.title(), Some("server (trace time 0.001000s)"));
+        assert_eq!(statemap.metadata.as_ref().unwrap().start, vec![0, 0]);
+
+        let entity = statemap.entity("1/1").unwrap();
+        assert_eq!(entity.description(), Some("main (server)"));
+        assert_eq!(entity.totals(),
+            &[0, 50_000, 0, 0, 30_000, 20_000, 0, 0]);
+        assert_eq!(entity.events.len(), 1);
+        assert_eq!(entity.events[0].time, 45_000);
 
-        let bounded = good_statemap!("io", &config);
-        println!("{:?}", bounded.timebounds());
+        /*
+         * Complete events that begin at the same time nest by duration.
+         */
+        let entity = statemap.entity("1/2").unwrap();
+        assert_eq!(entity.description(), Some("worker (server)"));
+        assert_eq!(entity.totals(),
+            &[5_000, 0, 30_000, 30_000, 0, 0, 10_000, 5_000]);
+
+        let tags = statemap.tags();
+        assert_eq!(tags.len(), 3);
+        assert!(tags.iter().any(|t| t["state"] == 4 && t["url"] == "/index"));
+        assert!(tags.iter().any(|t| t["state"] == 7 && t["bytes"] == 4096));
+        statemap.verify();
+    }
+
+    #[test]
+    fn chrome_other() {
+        let mut config: Config = Default::default();
+        config.maxstates = 4;
+
+        let statemap = good_statemap!("chrome", &config);
+        let states: Vec<&str> = statemap.states().iter()
+            .map(|s| s.name()).collect();
 
+        assert_eq!(states,
+            vec!["idle", "query", "compress", "gc", "request", "other"]);
+        assert_eq!(statemap.entity("1/2").unwrap().totals(),
+            &[5_000, 0, 30_000, 30_000, 0, 15_000]);
+
+        /*
+         * A span in the other state is tagged with its name.
+         */
+        let tags = statemap.tags();
+        assert_eq!(tags.len(), 5);
+        assert!(tags.iter().any(|t| t["state"] == 5 && t["name"] == "sweep"));
+        assert!(tags.iter().any(|t| t["state"] == 5 && t["name"] == "flush" &&
+            t["bytes"] == 4096));
         statemap.verify();
+
+        /*
+         * Spans named for our own states are imported as those states.
+         */
+        let raw = concat!("[",
+            "{ \"name\": \"idle\", \"ph\": \"X\", \"ts\": 0, \"dur\": 1, ",
+            "\"pid\": 1, \"tid\": 1 },",
+            "{ \"name\": \"other\", \"ph\": \"X\", \"ts\": 1, \"dur\": 2, ",
+            "\"pid\": 1, \"tid\": 1 },",
+            "{ \"name\": \"a\", \"ph\": \"X\", \"ts\": 3, \"dur\": 4, ",
+            "\"pid\": 1, \"tid\": 1 }]");
+
+        let statemap = good_statemap(&Default::default(), raw);
+        let states: Vec<&str> = statemap.states().iter()
+            .map(|s| s.name()).collect();
+
+        assert_eq!(states, vec!["idle", "a", "other"]);
+        assert_eq!(statemap.entity("1/1").unwrap().totals(),
+            &[1_000, 4_000, 2_000]);
+        assert!(statemap.tags().iter()
+            .any(|t| t["state"] == 2 && t["name"] == "other"));
     }
 
     #[test]
-    fn weight() {
-        let statemap = good_statemap!("io");
+    fn chrome_array() {
+        /*
+         * An array of events may be unterminated; thread IDs may be
+         * strings.
+         */
+        let raw = concat!("[\n",
+            "{ \"name\": \"a\", \"ph\": \"B\", \"ts\": 1.5, \"pid\": 7, ",
+            "\"tid\": \"t\" },\n",
+            "{ \"name\": \"b\", \"ph\": \"X\", \"ts\": 2, \"dur\": 0.25, ",
+            "\"pid\": 7, \"tid\": \"t\" },\n",
+            "{ \"ph\": \"E\", \"ts\": 3, \"pid\": 7, \"tid\": \"t\" },\n");
 
-        assert_eq!(statemap.weight(0), 737063);
-        assert_eq!(statemap.weight(1), 2290450);
-        assert_eq!(statemap.weight(2), 934399);
-        assert_eq!(statemap.weight(3), 1082403);
+        let config: Config = Default::default();
+        let statemap = good_statemap(&config, raw);
+        let entity = statemap.entity("7/t").unwrap();
+
+        assert_eq!(statemap.title(),
+            Some("Trace events (trace time 0.000001s)"));
+        assert_eq!(entity.totals(), &[0, 1250, 250]);
+        assert_eq!(entity.description(), None);
+
+        for size in vec![1, 7, STREAM_CHUNK] {
+            let mut statemap = Statemap::new(&config);
+            statemap_ingest_stream(&mut statemap, raw, size).unwrap();
+            assert_eq!(totals(&statemap), totals(&good_statemap(&config, raw)));
+        }
+
+        /*
+         * A trace may begin with members other than its events.
+         */
+        let raw = concat!("{ \"displayTimeUnit\": \"ns\", \"traceEvents\": [",
+            "{ \"name\": \"a\", \"ph\": \"X\", \"ts\": 0, \"dur\": 1, ",
+            "\"pid\": 1, \"tid\": 1 }] }");
+
+        for size in vec![1, 7, STREAM_CHUNK] {
+            let mut statemap = Statemap::new(&config);
+            statemap_ingest_stream(&mut statemap, raw, size).unwrap();
+            assert_eq!(statemap.entity("1/1").unwrap().totals(), &[0, 1000]);
+        }
+    }
+
+    #[test]
+    fn chrome_svg_escape() {
+        /*
+         * Span and instant names are arbitrary, and must be escaped.
+         */
+        let raw = concat!("[",
+            "{ \"name\": \"std::vector<int> & co\", \"ph\": \"X\", ",
+            "\"ts\": 0, \"dur\": 2, \"pid\": 1, \"tid\": \"<t>\" },",
+            "{ \"name\": \"a<b>\", \"ph\": \"i\", \"ts\": 1, ",
+            "\"pid\": 1, \"tid\": \"<t>\" }]");
+
+        let mut config: Config = Default::default();
+        config.utilization = true;
+
+        let statemap = good_statemap(&config, raw);
+        let output = svg(&vec![statemap], &Default::default());
+
+        assert!(output.contains(">std::vector&lt;int&gt; &amp; co</text>"));
+        assert!(output.contains(
+            "<title>std::vector&lt;int&gt; &amp; co</title>"));
+        assert!(output.contains(">a&lt;b&gt;</text>"));
+        assert!(output.contains("<title>Thread 1/&lt;t&gt;</title>"));
+        assert!(!output.contains(">std::vector<int>"));
+    }
+
+    #[test]
+    fn bad_chrome() {
+        bad_statemap("[ { \"name\": \"a\", \"ts\": 0 } ]", "illegal trace");
+        bad_statemap("{ \"traceEvents\": 3 }", "illegal trace");
+        bad_statemap("[ { \"name\": \"a\", \"ph\": \"C\", \"ts\": 0 } ]",
+            "no trace events found");
+        bad_statemap("[ { \"name\": \"a\", \"ph\": \"B\" } ]",
+            "event 0 has missing or illegal timestamp");
+        bad_statemap(
+            "[ { \"name\": \"a\", \"ph\": \"X\", \"ts\": 1, \"dur\": -1 } ]",
+            "event 0 has illegal duration");
     }
 }
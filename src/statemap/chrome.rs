@@ -0,0 +1,449 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * Importing of traces in the Chrome Trace Event Format (as written by
+ * Chrome, Perfetto and many application tracers).  Each thread becomes an
+ * entity, and the name of the innermost span (a duration event, either as
+ * a begin/end pair or as a complete event) that a thread is in becomes its
+ * state; time outside of any span is idle.  Because span names are
+ * unbounded, only the names that account for the most time become states,
+ * with any others becoming a single "other" state.  The arguments of a span
+ * become the fields of a tag; instant events become statemap events, and
+ * thread and process names become descriptions.  Other events are ignored.
+ */
+
+use std::io::BufRead;
+use std::error::Error;
+use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::cmp;
+
+use super::serde_json;
+use super::serde_json::Value;
+use super::Statemap;
+use super::trace_title;
+
+#[derive(Deserialize, Debug)]
+struct ChromeEvent {
+    name: Option<String>,                   // name of event
+    ph: String,                             // phase (i.e., type) of event
+    ts: Option<f64>,                        // time, in microseconds
+    dur: Option<f64>,                       // duration of complete event
+    pid: Option<Value>,                     // process ID
+    tid: Option<Value>,                     // thread ID
+    args: Option<Value>,                    // arguments, if any
+}
+
+/*
+ * A trace is either an array of events or an object with an array of
+ * events as its traceEvents member.
+ */
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ChromeTrace {
+    Events(Vec<ChromeEvent>),
+    Object {
+        #[serde(rename = "traceEvents")]
+        events: Vec<ChromeEvent>,
+    },
+}
+
+/*
+ * The members of a top-level object that identify it as a trace rather than
+ * as statemap metadata.
+ */
+pub(super) const CHROME_KEYS: &[&str] = &[
+    "traceEvents", "displayTimeUnit", "systemTraceEvents", "otherData",
+    "stackFrames", "samples", "metadata",
+];
+
+/*
+ * Our own states.  These names can't also be states for spans:  a span
+ * named "idle" is imported as idle, and one named "other" as other.
+ */
+const IDLE: &str = "idle";
+const IDLE_COLOR: &str = "#e0e0e0";
+const OTHER: &str = "other";
+const OTHER_COLOR: &str = "#808080";
+
+/*
+ * The beginning or end of a span on a thread.  Edges are sorted by time,
+ * and then by rank:  at a given time, complete events end before anything
+ * else happens, and begin after anything else happens.  Within a rank,
+ * complete events are ordered to nest properly, and begin/end events remain
+ * in the order in which they appear in the trace.
+ */
+#[derive(Debug)]
+struct ChromeEdge {
+    time: u64,                              // time of edge
+    rank: u8,                               // rank of edge at its time
+    order: u64,                             // order of edge within rank
+    span: Option<usize>,                    // span, if not an end event
+    begin: bool,                            // edge begins span
+}
+
+#[derive(Debug)]
+struct ChromeSpan {
+    name: String,                           // name of span
+    args: Option<Value>,                    // arguments of span, if any
+    complete: bool,                         // span is a complete event
+}
+
+#[derive(Default, Debug)]
+struct ChromeThread {
+    pid: String,                            // process of thread
+    edges: Vec<ChromeEdge>,                 // edges of spans on thread
+    instants: Vec<(u64, String)>,           // instant events on thread
+}
+
+fn chrome_id(id: &Option<Value>) -> String {
+    match *id {
+        Some(Value::String(ref id)) => id.to_string(),
+        Some(ref id) => id.to_string(),
+        None => "0".to_string(),
+    }
+}
+
+fn chrome_time(time: f64) -> Option<u64> {
+    if time.is_nan() || time < 0.0 {
+        None
+    } else {
+        Some((time * 1000.0).round() as u64)
+    }
+}
+
+fn chrome_name(args: &Option<Value>) -> Option<String> {
+    match *args {
+        Some(ref args) => args["name"].as_str().map(|n| n.to_string()),
+        None => None,
+    }
+}
+
+/*
+ * Walk the edges of a thread, calling the specified function with each
+ * distinct time at which the thread's innermost span may have changed, and
+ * with that span (if any).
+ */
+fn chrome_walk<F>(edges: &[ChromeEdge], spans: &[ChromeSpan], mut func: F)
+    where F: FnMut(u64, Option<usize>)
+{
+    let mut stack: Vec<usize> = vec![];
+    let mut ndx = 0;
+
+    while ndx < edges.len() {
+        let time = edges[ndx].time;
+
+        while ndx < edges.len() && edges[ndx].time == time {
+            let edge = &edges[ndx];
+            ndx += 1;
+
+            /*
+             * A complete event ends its own span; an end event ends the
+             * innermost span that was begun by a begin event.
+             */
+            let found = match (edge.begin, edge.span) {
+                (true, Some(span)) => {
+                    stack.push(span);
+                    continue;
+                }
+                (_, Some(span)) => stack.iter().rposition(|&s| s == span),
+                (_, None) => stack.iter().rposition(|&s| !spans[s].complete),
+            };
+
+            if let Some(found) = found {
+                stack.remove(found);
+            }
+        }
+
+        func(time, stack.last().cloned());
+    }
+}
+
+impl Statemap {
+    /*
+     * Import a trace in the Chrome Trace Event Format.  As with scheduler
+     * traces, timestamps aren't relative to the Epoch, so the statemap
+     * starts at zero (plus any clock offset in our configuration), with the
+     * time of the first event in the trace noted in its title.
+     */
+    pub(super) fn ingest_chrome<R: BufRead>(&mut self, mut reader: R)
+        -> Result<(), Box<dyn Error>>
+    {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents)?;
+
+        /*
+         * An array of events is allowed to be unterminated (and to have a
+         * trailing comma), as it is when a tracer exits abruptly.
+         */
+        let trimmed = contents.trim_end();
+
+        if trimmed.starts_with('[') && !trimmed.ends_with(']') {
+            contents = format!("{}]", trimmed.trim_end_matches(','));
+        }
+
+        let events = match serde_json::from_str::<ChromeTrace>(&contents) {
+            Ok(ChromeTrace::Events(events)) => events,
+            Ok(ChromeTrace::Object { events }) => events,
+            Err(err) => {
+                return self.err(&format!("illegal trace: {}", err));
+            }
+        };
+
+        let mut threads: BTreeMap<String, ChromeThread> = BTreeMap::new();
+        let mut spans: Vec<ChromeSpan> = vec![];
+        let mut tnames: HashMap<String, String> = HashMap::new();
+        let mut pnames: BTreeMap<String, String> = BTreeMap::new();
+        let mut origin: Option<u64> = None;
+
+        for (seq, event) in events.into_iter().enumerate() {
+            let pid = chrome_id(&event.pid);
+            let entity = format!("{}/{}", pid, chrome_id(&event.tid));
+            let name = event.name.unwrap_or_default();
+
+            if event.ph == "M" {
+                match name.as_str() {
+                    "thread_name" => {
+                        if let Some(tname) = chrome_name(&event.args) {
+                            tnames.insert(entity, tname);
+                        }
+                    }
+                    "process_name" => {
+                        if let Some(pname) = chrome_name(&event.args) {
+                            pnames.insert(pid, pname);
+                        }
+                    }
+                    _ => {}
+                }
+
+                continue;
+            }
+
+            let kind = event.ph.as_str();
+
+            if !["B", "E", "X", "i", "I"].contains(&kind) {
+                continue;
+            }
+
+            let time = match event.ts.and_then(chrome_time) {
+                Some(time) => time,
+                None => {
+                    return self.err(&format!(concat!("event {} has ",
+                        "missing or illegal timestamp"), seq));
+                }
+            };
+
+            origin = Some(origin.map_or(time, |o| cmp::min(o, time)));
+
+            let thread = threads.entry(entity).or_default();
+            let span = spans.len();
+
+            thread.pid = pid;
+
+            match kind {
+                "B" | "X" => {
+                    spans.push(ChromeSpan {
+                        name: name,
+                        args: event.args,
+                        complete: kind == "X",
+                    });
+                }
+                "E" => {}
+                _ => {
+                    thread.instants.push((time, name));
+                    continue;
+                }
+            }
+
+            if kind != "X" {
+                thread.edges.push(ChromeEdge {
+                    time: time,
+                    rank: 1,
+                    order: seq as u64,
+                    span: if kind == "B" { Some(span) } else { None },
+                    begin: kind == "B",
+                });
+
+                continue;
+            }
+
+            let end = match event.dur.map_or(Some(0), chrome_time) {
+                Some(dur) => time + dur,
+                None => {
+                    return self.err(&format!(
+                        "event {} has illegal duration", seq));
+                }
+            };
+
+            thread.edges.push(ChromeEdge {
+                time: time,
+                rank: 2,
+                order: u64::MAX - end,
+                span: Some(span),
+                begin: true,
+            });
+
+            thread.edges.push(ChromeEdge {
+                time: end,
+                rank: 0,
+                order: u64::MAX - time,
+                span: Some(span),
+                begin: false,
+            });
+        }
+
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return self.err("no trace events found"),
+        };
+
+        for thread in threads.values_mut() {
+            thread.edges.sort_by_key(|e| (e.time, e.rank, e.order));
+            thread.instants.sort_by_key(|&(time, _)| time);
+        }
+
+        /*
+         * Determine the time that each thread spends in each span name.
+         */
+        let end = threads.values()
+            .filter_map(|t| t.edges.last().map(|e| e.time))
+            .max().unwrap_or(origin);
+
+        let mut totals: HashMap<&str, u64> = HashMap::new();
+
+        for thread in threads.values() {
+            let mut last: Option<(u64, usize)> = None;
+
+            chrome_walk(&thread.edges, &spans, |time, span| {
+                if let Some((start, span)) = last {
+                    *totals.entry(&spans[span].name).or_insert(0) +=
+                        time - start;
+                }
+
+                last = span.map(|span| (time, span));
+            });
+
+            if let Some((start, span)) = last {
+                *totals.entry(&spans[span].name).or_insert(0) += end - start;
+            }
+        }
+
+        /*
+         * The names that account for the most time become states.
+         */
+        let others = totals.contains_key(OTHER);
+        let mut names: Vec<(&str, u64)> = totals.into_iter()
+            .filter(|&(name, _)| name != IDLE && name != OTHER)
+            .collect();
+        names.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        let max = self.config.maxstates as usize;
+
+        let title = match pnames.len() {
+            1 => pnames.values().next().unwrap().to_string(),
+            _ => "Trace events".to_string(),
+        };
+
+        if self.config.offset < 0 {
+            return self.err("clock offset precedes the Epoch");
+        }
+
+        self.set_title(&trace_title(&title, origin));
+        self.set_entity_kind("Thread");
+        self.set_start(self.config.offset as u64)?;
+
+        let idle = self.add_state(IDLE, Some(IDLE_COLOR))?;
+        let mut states: HashMap<String, u32> = HashMap::new();
+
+        for &(name, _) in names.iter().take(max) {
+            states.insert(name.to_string(), self.add_state(name, None)?);
+        }
+
+        let other = if names.len() > max || others {
+            Some(self.add_state(OTHER, Some(OTHER_COLOR))?)
+        } else {
+            None
+        };
+
+        /*
+         * Now walk each thread again, transitioning to the state of its
+         * innermost span -- and tagging the state with the span's arguments
+         * (and, if it has been bucketed into the other state, its name).
+         */
+        let mut tags: HashMap<(u32, String), String> = HashMap::new();
+
+        for (entity, thread) in threads.iter() {
+            let mut transitions: Vec<(u64, u32, Option<Value>)> = vec![];
+
+            chrome_walk(&thread.edges, &spans, |time, span| {
+                let span = match span {
+                    Some(span) => &spans[span],
+                    None => {
+                        transitions.push((time - origin, idle, None));
+                        return;
+                    }
+                };
+
+                let mut fields = match span.args {
+                    Some(Value::Object(ref args)) => args.clone(),
+                    _ => serde_json::Map::new(),
+                };
+
+                let state = match states.get(&span.name) {
+                    Some(&state) => state,
+                    None if span.name == IDLE => idle,
+                    None => {
+                        fields.insert("name".to_string(), json!(span.name));
+                        other.unwrap()
+                    }
+                };
+
+                transitions.push((time - origin, state, match fields.len() {
+                    0 => None,
+                    _ => Some(Value::Object(fields)),
+                }));
+            });
+
+            /*
+             * Spans with identical arguments (in the same state) share a
+             * tag.
+             */
+            for (time, state, fields) in transitions {
+                let tag = match fields {
+                    Some(fields) => {
+                        let key = (state, fields.to_string());
+
+                        if !tags.contains_key(&key) {
+                            let tag = tags.len().to_string();
+                            self.define_tag(state, &tag, fields)?;
+                            tags.insert(key.clone(), tag);
+                        }
+
+                        Some(tags[&key].clone())
+                    }
+                    None => None
+                };
+
+                self.transition(entity, time, state, tag.as_deref())?;
+            }
+
+            for &(time, ref name) in &thread.instants {
+                self.event(entity, time - origin, name, None);
+            }
+
+            if let Some(tname) = tnames.get(entity) {
+                let description = match pnames.get(&thread.pid) {
+                    Some(pname) => format!("{} ({})", tname, pname),
+                    None => tname.to_string(),
+                };
+
+                self.describe_entity(entity, &description);
+            }
+        }
+
+        self.finish();
+        Ok(())
+    }
+}
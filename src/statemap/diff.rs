@@ -0,0 +1,786 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * Comparison of two statemaps of the same workload -- typically captured
+ * before and after some change.  Entities are matched by name and states are
+ * matched by name, and both statemaps are aligned to relative time:  each is
+ * taken relative to its own start, so captures taken at different times can
+ * be compared directly.  The comparison can be reported as the change in
+ * the time that each entity spent in each state; rendered as a single
+ * statemap in which each entity of the first statemap is followed by its
+ * counterpart from the second; or rendered as a strip for each entity that
+ * shows which state gained (or lost) the most time over the course of it.
+ */
+
+use std::io::Write;
+use std::error::Error;
+use std::cell::RefCell;
+use std::str::FromStr;
+use std::cmp;
+
+use super::natord;
+use super::serde_json;
+use super::serde_json::Value;
+use super::{Statemap, StatemapEntity, StatemapEvent, StatemapRect};
+use super::{StatemapState, StatemapColor, StatemapSVGConfig};
+use super::{csv_field, duration_text, escape};
+
+/**
+ * The format of a report of the differences between two statemaps.
+ */
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum StatemapDiffFormat {
+    Text,                                   // human-readable table
+    CSV,                                    // one row per entity and state
+    JSON,                                   // JSON document
+}
+
+/*
+ * The labels of the two statemaps being compared.  These are appended to
+ * entity names in the combined statemap; they must sort in this order.
+ */
+const LABELS: [&str; 2] = ["A", "B"];
+
+/*
+ * The layout of the delta strips:  the margin to the left of the strips
+ * (for entity names), above them (for the title) and below them (for the
+ * time axis), and the width of each legend entry.
+ */
+const DELTA_LMARGIN: u32 = 120;
+const DELTA_TMARGIN: u32 = 50;
+const DELTA_BMARGIN: u32 = 40;
+const DELTA_LEGEND: u32 = 15;
+
+/**
+ * The time that an entity spent in each state in each of two statemaps.
+ * Times are indexed by the value of the state in the comparison (see
+ * `StatemapDiff::states`); an entity that is absent from a statemap spent
+ * no time in any state in it.
+ */
+#[derive(Clone,Debug)]
+pub struct StatemapDelta {
+    entity: String,                         // name of entity
+    before: Vec<u64>,                       // time in each state in A
+    after: Vec<u64>,                        // time in each state in B
+}
+
+impl StatemapDelta {
+    /**
+     * The name of the entity, or None for the aggregate of all entities.
+     */
+    pub fn entity(&self) -> Option<&str> {
+        if self.entity.is_empty() { None } else { Some(&self.entity) }
+    }
+
+    /**
+     * The time (in nanoseconds) spent in each state in the first statemap.
+     */
+    pub fn before(&self) -> &[u64] {
+        &self.before
+    }
+
+    /**
+     * The time (in nanoseconds) spent in each state in the second statemap.
+     */
+    pub fn after(&self) -> &[u64] {
+        &self.after
+    }
+
+    /**
+     * The change in the time (in nanoseconds) spent in the specified state.
+     */
+    pub fn delta(&self, state: usize) -> i64 {
+        self.after[state] as i64 - self.before[state] as i64
+    }
+}
+
+/**
+ * A comparison of two statemaps.
+ */
+pub struct StatemapDiff<'a> {
+    statemaps: [&'a Statemap; 2],           // statemaps being compared
+    states: Vec<StatemapState>,             // states in either statemap
+    maps: [Vec<usize>; 2],                  // statemap state to our state
+    names: Vec<String>,                     // entities in either statemap
+}
+
+/*
+ * Return the start time of a statemap, in nanoseconds since the Epoch.
+ */
+fn start(statemap: &Statemap) -> u64 {
+    let metadata = statemap.metadata.as_ref().unwrap();
+    metadata.start[0] * 1_000_000_000 + metadata.start[1]
+}
+
+/*
+ * Return the time bounds of a statemap relative to its start.
+ */
+fn relbounds(statemap: &Statemap) -> (u64, u64) {
+    let (begin, end) = statemap.timebounds();
+    let start = start(statemap);
+
+    (begin.saturating_sub(start), end.saturating_sub(start))
+}
+
+/*
+ * Return the title of a statemap, including its host (if any).
+ */
+fn title(statemap: &Statemap) -> String {
+    let metadata = statemap.metadata.as_ref().unwrap();
+    let mut title = metadata.title.clone();
+
+    if let Some(ref host) = metadata.host {
+        title.push_str(&format!(" on {}", host));
+    }
+
+    title
+}
+
+/*
+ * Express a time in nanoseconds as milliseconds, with an explicit sign if
+ * it is a change in time.
+ */
+fn ms(time: i64, signed: bool) -> String {
+    let sign = if time < 0 { "-" } else if signed { "+" } else { "" };
+    let abs = time.unsigned_abs();
+
+    format!("{}{}.{:06}", sign, abs / 1_000_000, abs % 1_000_000)
+}
+
+/*
+ * Return a copy of a rectangle with its states (and tags) renumbered.
+ */
+fn remap_rect(rect: &StatemapRect, map: &[usize], tags: &[usize],
+    nstates: usize) -> StatemapRect
+{
+    let mut states = vec![0; nstates];
+
+    for (state, &time) in rect.states.iter().enumerate() {
+        states[map[state]] += time;
+    }
+
+    StatemapRect {
+        states: states,
+        tags: rect.tags.as_ref().map(|t| {
+            t.iter().map(|(&tag, &time)| (tags[tag], time)).collect()
+        }),
+        ..rect.clone()
+    }
+}
+
+impl<'a> StatemapDiff<'a> {
+    /**
+     * Create a comparison of two (ingested) statemaps.  The statemaps must
+     * have at least one state in common.
+     */
+    pub fn new(before: &'a Statemap, after: &'a Statemap)
+        -> Result<Self, Box<dyn Error>>
+    {
+        let statemaps = [before, after];
+
+        for statemap in &statemaps {
+            if statemap.metadata.is_none() {
+                return statemap.err("metadata not found in data stream");
+            }
+        }
+
+        let mut states: Vec<StatemapState> = vec![];
+        let mut maps = [vec![], vec![]];
+
+        for (statemap, map) in statemaps.iter().zip(maps.iter_mut()) {
+            for state in &statemap.states {
+                let value = match states.iter()
+                    .position(|s| s.name == state.name) {
+                    Some(value) => value,
+                    None => {
+                        states.push(StatemapState {
+                            value: states.len(),
+                            salient: false,
+                            ..state.clone()
+                        });
+
+                        states.len() - 1
+                    }
+                };
+
+                if states[value].color.is_none() {
+                    states[value].color = state.color.clone();
+                }
+
+                states[value].salient |= state.salient;
+                map.push(value);
+            }
+        }
+
+        if !maps[1].iter().any(|&value| value < before.states.len()) {
+            return before.err("statemaps have no states in common");
+        }
+
+        let mut names: Vec<String> = before.entities.keys()
+            .chain(after.entities.keys().filter(|name| {
+                !before.entities.contains_key(*name)
+            }))
+            .cloned()
+            .collect();
+
+        names.sort_by(|a, b| natord::compare(a, b));
+
+        Ok(StatemapDiff {
+            statemaps: statemaps,
+            states: states,
+            maps: maps,
+            names: names,
+        })
+    }
+
+    /**
+     * Return the states of the comparison, indexed by value:  the states of
+     * the first statemap, followed by any states found only in the second.
+     */
+    pub fn states(&self) -> &[StatemapState] {
+        &self.states
+    }
+
+    /*
+     * Return the exact time that the named entity spent in each of our
+     * states in the specified statemap.
+     */
+    fn totals(&self, which: usize, name: &str) -> Vec<u64> {
+        let mut totals = vec![0; self.states.len()];
+
+        if let Some(entity) = self.statemaps[which].entities.get(name) {
+            for (state, &total) in entity.totals.iter().enumerate() {
+                totals[self.maps[which][state]] += total;
+            }
+        }
+
+        totals
+    }
+
+    /**
+     * Return the time that each entity spent in each state in both
+     * statemaps, sorted by entity name and followed by the aggregate of all
+     * entities.
+     */
+    pub fn deltas(&self) -> Vec<StatemapDelta> {
+        let nstates = self.states.len();
+        let mut deltas: Vec<StatemapDelta> = self.names.iter()
+            .map(|name| StatemapDelta {
+                entity: name.clone(),
+                before: self.totals(0, name),
+                after: self.totals(1, name),
+            }).collect();
+
+        let mut aggregate = StatemapDelta {
+            entity: "".to_string(),
+            before: vec![0; nstates],
+            after: vec![0; nstates],
+        };
+
+        for delta in &deltas {
+            for state in 0..nstates {
+                aggregate.before[state] += delta.before[state];
+                aggregate.after[state] += delta.after[state];
+            }
+        }
+
+        deltas.push(aggregate);
+        deltas
+    }
+
+    /**
+     * Report the change in the time spent in each state to `out`.
+     */
+    pub fn output<W: Write>(&self, format: StatemapDiffFormat, out: &mut W)
+        -> Result<(), Box<dyn Error>>
+    {
+        match format {
+            StatemapDiffFormat::Text => self.output_text(out),
+            StatemapDiffFormat::CSV => self.output_csv(out),
+            StatemapDiffFormat::JSON => self.output_json(out),
+        }
+    }
+
+    /*
+     * Our text output identifies each statemap, followed by a table with a
+     * row for each state that each entity spent time in (in either
+     * statemap), followed by a row for each state in aggregate.  Times are
+     * in milliseconds; the change is also expressed as a percentage of the
+     * time in the first statemap, where there is any.
+     */
+    fn output_text(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let metadata = self.statemaps[0].metadata.as_ref().unwrap();
+
+        let kind = match metadata.entityKind {
+            Some(ref kind) => kind.to_uppercase(),
+            None => "ENTITY".to_string()
+        };
+
+        let header = [LABELS[0].to_string() + "(ms)",
+            LABELS[1].to_string() + "(ms)", "DELTA(ms)".to_string(),
+            "CHANGE".to_string()];
+
+        let deltas = self.deltas();
+        let mut rows: Vec<(&str, &str, Vec<String>)> = vec![];
+
+        for delta in &deltas {
+            let name = delta.entity().unwrap_or("all");
+
+            for (value, state) in self.states.iter().enumerate() {
+                let (before, after) = (delta.before[value], delta.after[value]);
+
+                if before == 0 && after == 0 {
+                    continue;
+                }
+
+                let change = if before == 0 {
+                    "-".to_string()
+                } else {
+                    format!("{:+.2}%",
+                        (delta.delta(value) as f64 * 100.0) / before as f64)
+                };
+
+                rows.push((name, &state.name, vec![ms(before as i64, false),
+                    ms(after as i64, false), ms(delta.delta(value), true),
+                    change]));
+            }
+        }
+
+        let ewidth = rows.iter()
+            .fold(kind.len(), |w, r| cmp::max(w, r.0.len()));
+        let swidth = rows.iter()
+            .fold("STATE".len(), |w, r| cmp::max(w, r.1.len()));
+        let widths: Vec<usize> = header.iter().enumerate()
+            .map(|(i, h)| rows.iter()
+                .fold(h.len(), |w, r| cmp::max(w, r.2[i].len())))
+            .collect();
+
+        for (label, statemap) in LABELS.iter().zip(&self.statemaps) {
+            writeln!(out, "{}: {}", label, title(statemap))?;
+        }
+
+        writeln!(out)?;
+
+        let line = |out: &mut dyn Write, name: &str, state: &str,
+            fields: &[String]| -> Result<(), Box<dyn Error>> {
+            write!(out, "{:<ew$}  {:<sw$}", name, state,
+                ew = ewidth, sw = swidth)?;
+
+            for (field, width) in fields.iter().zip(&widths) {
+                write!(out, "  {:>w$}", field, w = width)?;
+            }
+
+            writeln!(out)?;
+            Ok(())
+        };
+
+        line(out, &kind, "STATE", &header)?;
+
+        for (name, state, fields) in &rows {
+            line(out, name, state, fields)?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Our CSV output has a row for every state for every entity, with times
+     * in nanoseconds.  The rows for the aggregate have an empty entity.
+     */
+    fn output_csv(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        writeln!(out, "entity,state,a,b,delta")?;
+
+        for delta in self.deltas() {
+            for (value, state) in self.states.iter().enumerate() {
+                writeln!(out, "{},{},{},{},{}",
+                    csv_field(delta.entity().unwrap_or("")),
+                    csv_field(&state.name),
+                    delta.before[value], delta.after[value],
+                    delta.delta(value))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Our JSON output has the metadata of each statemap, the states of the
+     * comparison, and the time in each state in each statemap (along with
+     * the change) for each entity and in aggregate, in nanoseconds.
+     */
+    fn output_json(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let nstates = self.states.len();
+
+        let metadata: Vec<Value> = self.statemaps.iter().map(|statemap| {
+            let metadata = statemap.metadata.as_ref().unwrap();
+            let (begin, end) = statemap.timebounds();
+
+            json!({
+                "title": metadata.title,
+                "host": metadata.host,
+                "entityKind": metadata.entityKind,
+                "begin": begin,
+                "end": end,
+            })
+        }).collect();
+
+        let datum = |delta: &StatemapDelta| -> Value {
+            json!({
+                "a": delta.before,
+                "b": delta.after,
+                "delta": (0..nstates).map(|s| delta.delta(s))
+                    .collect::<Vec<i64>>(),
+            })
+        };
+
+        let mut deltas = self.deltas();
+        let aggregate = datum(&deltas.pop().unwrap());
+
+        let entities: Vec<Value> = deltas.iter().map(|delta| {
+            let mut doc = datum(delta);
+            doc["name"] = json!(delta.entity);
+            doc
+        }).collect();
+
+        let states: Vec<&str> = self.states.iter()
+            .map(|s| s.name.as_str())
+            .collect();
+
+        serde_json::to_writer_pretty(&mut *out, &json!({
+            "a": metadata[0],
+            "b": metadata[1],
+            "states": states,
+            "entities": entities,
+            "aggregate": aggregate,
+        }))?;
+
+        writeln!(out)?;
+
+        Ok(())
+    }
+
+    /**
+     * Return a single statemap that combines both statemaps, in which each
+     * entity is named with the label of its statemap ("A" or "B") such that
+     * each entity of the first statemap is followed by its counterpart from
+     * the second when sorted by name.  The combined statemap has the states
+     * of the comparison, and spans the time of both statemaps relative to
+     * the start of the first; it can be rendered like any other statemap.
+     */
+    pub fn combined(&self) -> Result<Statemap, Box<dyn Error>> {
+        let [before, after] = self.statemaps;
+        let nstates = self.states.len();
+
+        let mut config = before.config;
+        config.begin = cmp::min(before.config.begin, after.config.begin);
+        config.end = cmp::max(relbounds(before).1,
+            relbounds(after).1) as i64;
+        config.durations = None;
+
+        let mut combined = Statemap::new(&config);
+        let metadata = before.metadata.as_ref().unwrap();
+
+        combined.set_title(&format!("{} ({}) vs. {} ({})",
+            before.title().unwrap(), LABELS[0],
+            after.title().unwrap(), LABELS[1]));
+
+        if let Some(ref host) = metadata.host {
+            combined.set_host(host);
+        }
+
+        if let Some(ref kind) = metadata.entityKind {
+            combined.set_entity_kind(kind);
+        }
+
+        combined.set_start(start(before))?;
+
+        for state in &self.states {
+            let value = combined.add_state(&state.name,
+                state.color.as_deref())? as usize;
+            combined.states[value].salient = state.salient;
+        }
+
+        for (which, statemap) in self.statemaps.iter().enumerate() {
+            let map = &self.maps[which];
+            let label = |name: &str| format!("{} ({})", name, LABELS[which]);
+
+            /*
+             * Tags and event types are renumbered in the combined statemap.
+             */
+            let mut tags = vec![0; statemap.tags.len()];
+            let mut defs: Vec<(&(u32, String), usize, &Value)> =
+                statemap.tags.iter()
+                .map(|(key, &(ref value, id))| (key, id, value))
+                .collect();
+
+            defs.sort_by_key(|&(_, id, _)| id);
+
+            for (&(state, ref tag), id, value) in defs {
+                let state = map[state as usize] as u32;
+                let mut value = value.clone();
+                value["state"] = json!(state);
+
+                /*
+                 * A tag defined in both statemaps takes its definition
+                 * from the first.
+                 */
+                let next = combined.tags.len();
+                tags[id] = combined.tags.entry((state, tag.clone()))
+                    .or_insert((value, next)).1;
+            }
+
+            let events: Vec<usize> = statemap.event_types().into_iter()
+                .map(|name| {
+                    let next = combined.events.len();
+                    *combined.events.entry(name.clone()).or_insert(next)
+                }).collect();
+
+            let mut names: Vec<&String> = statemap.entities.keys().collect();
+            names.sort_by(|a, b| natord::compare(a, b));
+
+            for name in names {
+                let source: &StatemapEntity = &statemap.entities[name];
+                let entity = combined.entity_lookup(&label(name));
+
+                entity.description = source.description.clone();
+
+                entity.rects = source.rects.iter()
+                    .map(|(&start, rect)| (start, RefCell::new(remap_rect(
+                        &rect.borrow(), map, &tags, nstates))))
+                    .collect();
+
+                if before.resolutions == after.resolutions {
+                    entity.levels = source.levels.iter()
+                        .map(|level| level.iter()
+                            .map(|r| remap_rect(r, map, &tags, nstates))
+                            .collect())
+                        .collect();
+                }
+
+                entity.events = source.events.iter()
+                    .map(|event| StatemapEvent {
+                        time: event.time,
+                        event: events[event.event],
+                        target: event.target.as_ref().map(|t| label(t)),
+                    }).collect();
+
+                for (state, &total) in source.totals.iter().enumerate() {
+                    entity.totals[map[state]] += total;
+                }
+
+                entity.transitions = source.transitions.iter()
+                    .map(|(&(from, to), &transition)| ((map[from as usize]
+                        as u32, map[to as usize] as u32), transition))
+                    .collect();
+            }
+
+            combined.nrecs += statemap.nrecs;
+            combined.nevents += statemap.nevents;
+        }
+
+        if before.resolutions == after.resolutions {
+            combined.resolutions = before.resolutions.clone();
+        } else if let Some(&resolution) = before.resolutions.first() {
+            combined.resolutions = vec![resolution];
+        }
+
+        combined.begin = (start(before) as i64 + config.begin) as u64;
+        combined.end = start(before) + config.end as u64;
+
+        Ok(combined)
+    }
+
+    /*
+     * Return the time that the named entity spent in each of our states in
+     * each of the specified buckets of time of the specified statemap.
+     * Rectangles that span buckets (or that have been coalesced) are
+     * apportioned to each bucket by their overlap with it.
+     */
+    fn buckets(&self, which: usize, name: &str, origin: u64, width: f64,
+        nbuckets: usize) -> Vec<Vec<f64>>
+    {
+        let mut buckets = vec![vec![0.0; self.states.len()]; nbuckets];
+
+        let entity = match self.statemaps[which].entities.get(name) {
+            Some(entity) => entity,
+            None => { return buckets; }
+        };
+
+        for rect in entity.rects.values() {
+            let rect = rect.borrow();
+
+            if rect.duration == 0 {
+                continue;
+            }
+
+            let rstart = rect.start as f64 - origin as f64;
+            let rend = rstart + rect.duration as f64;
+            let first = (rstart / width).max(0.0) as usize;
+            let last = cmp::min((rend / width).ceil() as usize, nbuckets);
+
+            for (b, bucket) in buckets.iter_mut()
+                .enumerate().take(last).skip(first) {
+                let bstart = b as f64 * width;
+                let overlap = rend.min(bstart + width) - rstart.max(bstart);
+
+                if overlap <= 0.0 {
+                    continue;
+                }
+
+                let fraction = overlap / rect.duration as f64;
+
+                for (state, &time) in rect.states.iter().enumerate() {
+                    bucket[self.maps[which][state]] += time as f64 * fraction;
+                }
+            }
+        }
+
+        buckets
+    }
+
+    /**
+     * Render the comparison as a static SVG with a strip for each entity,
+     * divided into a bucket of time for each pixel.  The top half of each
+     * bucket is in the color of the state that gained the most time in the
+     * second statemap relative to the first, and the bottom half is in the
+     * color of the state that lost the most; each is more opaque the
+     * greater the change.
+     */
+    pub fn output_delta<W: Write>(&self, config: &StatemapSVGConfig,
+        out: &mut W) -> Result<(), Box<dyn Error>>
+    {
+        let mut colors: Vec<StatemapColor> = vec![];
+
+        for state in &self.states {
+            colors.push(match state.color {
+                Some(ref name) => match StatemapColor::from_str(name) {
+                    Ok(color) => color,
+                    Err(_) => {
+                        return self.statemaps[0].err(&format!(
+                            "illegal color \"{}\" for state \"{}\"",
+                            name, state.name));
+                    }
+                },
+                None => StatemapColor::random()
+            });
+        }
+
+        let bounds = [relbounds(self.statemaps[0]),
+            relbounds(self.statemaps[1])];
+        let origin = cmp::min(bounds[0].0, bounds[1].0);
+        let span = cmp::max(bounds[0].1, bounds[1].1).saturating_sub(origin);
+
+        let nbuckets = config.stripWidth as usize;
+        let bwidth = cmp::max(span, 1) as f64 / nbuckets as f64;
+        let height = config.stripHeight;
+        let half = height as f64 / 2.0;
+
+        let legend = DELTA_LMARGIN + config.stripWidth + 20;
+        let strips = self.names.len() as u32 * height;
+        let total = DELTA_TMARGIN + cmp::max(strips,
+            self.states.len() as u32 * (DELTA_LEGEND + 5)) + DELTA_BMARGIN;
+
+        writeln!(out, r##"<?xml version="1.0"?>"##)?;
+        writeln!(out, concat!(r##"<svg width="{}" height="{}" "##,
+            r##"xmlns="http://www.w3.org/2000/svg" version="1.1" "##,
+            r##"font-family="Helvetica, Arial, sans-serif" "##,
+            r##"font-size="11">"##), legend + config.legendWidth, total)?;
+
+        writeln!(out, concat!(r##"<text x="{}" y="20" font-size="14" "##,
+            r##"text-anchor="middle">{}: {} relative to {}</text>"##),
+            DELTA_LMARGIN + config.stripWidth / 2,
+            escape(self.statemaps[1].title().unwrap()), LABELS[1], LABELS[0])?;
+        writeln!(out, concat!(r##"<text x="{}" y="38" "##,
+            r##"text-anchor="middle">top: state that gained the most "##,
+            r##"time; bottom: state that lost the most time</text>"##),
+            DELTA_LMARGIN + config.stripWidth / 2)?;
+
+        for (i, name) in self.names.iter().enumerate() {
+            let y = DELTA_TMARGIN + i as u32 * height;
+            let before = self.buckets(0, name, origin, bwidth, nbuckets);
+            let after = self.buckets(1, name, origin, bwidth, nbuckets);
+
+            writeln!(out, concat!(r##"<text x="{}" y="{}" "##,
+                r##"text-anchor="end" dominant-baseline="middle">{}</text>"##),
+                DELTA_LMARGIN - 5, y as f64 + half, escape(name))?;
+            writeln!(out, concat!(r##"<rect x="{}" y="{}" width="{}" "##,
+                r##"height="{}" style="fill:{}" />"##), DELTA_LMARGIN, y,
+                config.stripWidth, height, config.background)?;
+
+            /*
+             * For each half of the strip, determine the state and opacity
+             * of each bucket, and then draw runs of like buckets as a single
+             * rectangle.
+             */
+            for &(gained, offset) in &[(true, 0.0), (false, half)] {
+                let mut runs: Vec<(usize, usize, usize, u32)> = vec![];
+
+                for (b, (old, new)) in before.iter().zip(&after).enumerate() {
+                    let mut best: Option<(usize, f64)> = None;
+
+                    for state in 0..self.states.len() {
+                        let delta = if gained {
+                            new[state] - old[state]
+                        } else {
+                            old[state] - new[state]
+                        };
+
+                        if delta > 0.0 &&
+                            best.map_or(true, |(_, most)| delta > most) {
+                            best = Some((state, delta));
+                        }
+                    }
+
+                    let (state, delta) = match best {
+                        Some(best) => best,
+                        None => { continue; }
+                    };
+
+                    let opacity = ((delta / bwidth).min(1.0) * 10.0).ceil()
+                        as u32;
+
+                    match runs.last_mut() {
+                        Some(run) if run.1 == b && run.2 == state &&
+                            run.3 == opacity => { run.1 = b + 1; }
+                        _ => runs.push((b, b + 1, state, opacity))
+                    }
+                }
+
+                for (first, last, state, opacity) in runs {
+                    writeln!(out, concat!(r##"<rect x="{}" y="{}" "##,
+                        r##"width="{}" height="{}" style="fill:{}" "##,
+                        r##"fill-opacity="{}" />"##),
+                        DELTA_LMARGIN as usize + first, y as f64 + offset,
+                        last - first, half, colors[state],
+                        opacity as f64 / 10.0)?;
+                }
+            }
+        }
+
+        let axis = DELTA_TMARGIN + strips + 15;
+
+        writeln!(out, r##"<text x="{}" y="{}">0</text>"##,
+            DELTA_LMARGIN, axis)?;
+        writeln!(out, r##"<text x="{}" y="{}" text-anchor="end">{}</text>"##,
+            DELTA_LMARGIN + config.stripWidth, axis,
+            duration_text(span as f64))?;
+
+        for (i, (state, color)) in self.states.iter().zip(&colors).enumerate() {
+            let y = DELTA_TMARGIN + i as u32 * (DELTA_LEGEND + 5);
+
+            writeln!(out, concat!(r##"<rect x="{}" y="{}" width="{}" "##,
+                r##"height="{}" style="fill:{}" />"##), legend, y,
+                DELTA_LEGEND, DELTA_LEGEND, color)?;
+            writeln!(out, concat!(r##"<text x="{}" y="{}" "##,
+                r##"dominant-baseline="middle">{}</text>"##),
+                legend + DELTA_LEGEND + 5, y + DELTA_LEGEND / 2,
+                escape(&state.name))?;
+        }
+
+        writeln!(out, "</svg>")?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * Distributions of the durations of visits to a state.  A visit begins
+ * when an entity enters a state and ends when it enters a different one; if
+ * so configured, the duration of each visit is recorded as it is ingested
+ * (and therefore before it is coalesced).  Durations can be recorded
+ * exactly, or -- to bound the memory needed for a large statemap -- in a
+ * histogram in the manner of an HDR histogram:  there is a bucket for each
+ * power of two, each of which is divided into linear sub-buckets such that
+ * any recorded duration is within 1% of its bucket.
+ */
+
+use std::collections::BTreeMap;
+
+/**
+ * The manner in which the durations of visits to each state are recorded.
+ */
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum StatemapDurations {
+    Exact,                                  // every duration is kept
+    Histogram,                              // durations are bucketed
+}
+
+/*
+ * The number of bits of each duration that determine its bucket:  durations
+ * less than 2^SUB_BITS each have their own bucket, and each power of two
+ * above that is divided into 2^(SUB_BITS - 1) buckets.
+ */
+const SUB_BITS: u32 = 8;
+
+/**
+ * The distribution of the durations (in nanoseconds) of visits to a state.
+ */
+#[derive(Clone,Debug)]
+pub struct StatemapDistribution {
+    count: u64,                             // number of durations
+    sum: u64,                               // sum of durations
+    min: u64,                               // minimum duration
+    max: u64,                               // maximum duration
+    exact: Option<Vec<u64>>,                // durations, if exact
+    buckets: BTreeMap<usize, u64>,          // counts by bucket, if not
+}
+
+/*
+ * Return the bucket for the specified duration.
+ */
+fn bucket(duration: u64) -> usize {
+    if duration < (1 << SUB_BITS) {
+        return duration as usize;
+    }
+
+    let shift = 64 - duration.leading_zeros() - SUB_BITS;
+    let sub = duration >> shift;
+
+    ((shift as usize) << (SUB_BITS - 1)) + sub as usize
+}
+
+/*
+ * Return the lowest and highest duration that fall into the specified
+ * bucket.
+ */
+fn bucket_bounds(bucket: usize) -> (u64, u64) {
+    if bucket < (1 << SUB_BITS) {
+        return (bucket as u64, bucket as u64);
+    }
+
+    let shift = (bucket >> (SUB_BITS - 1)) - 1;
+    let sub = (bucket - (shift << (SUB_BITS - 1))) as u64;
+
+    (sub << shift, ((sub + 1) << shift).wrapping_sub(1))
+}
+
+/*
+ * Return the power of two that a duration falls into (with durations of
+ * zero falling into the first).
+ */
+fn octave(duration: u64) -> usize {
+    63 - duration.max(1).leading_zeros() as usize
+}
+
+impl StatemapDistribution {
+    /**
+     * Create an empty distribution, recorded in the specified manner.
+     */
+    pub fn new(durations: StatemapDurations) -> Self {
+        StatemapDistribution {
+            count: 0,
+            sum: 0,
+            min: 0,
+            max: 0,
+            exact: match durations {
+                StatemapDurations::Exact => Some(vec![]),
+                StatemapDurations::Histogram => None
+            },
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    /**
+     * Record a duration.
+     */
+    pub fn record(&mut self, duration: u64) {
+        if self.count == 0 || duration < self.min {
+            self.min = duration;
+        }
+
+        if duration > self.max {
+            self.max = duration;
+        }
+
+        self.count += 1;
+        self.sum += duration;
+
+        match self.exact {
+            Some(ref mut exact) => exact.push(duration),
+            None => *self.buckets.entry(bucket(duration)).or_insert(0) += 1
+        }
+    }
+
+    /**
+     * Add the durations in another distribution to this one.  If either
+     * is a histogram, the result is a histogram.
+     */
+    pub fn merge(&mut self, other: &StatemapDistribution) {
+        if other.count == 0 {
+            return;
+        }
+
+        if self.count == 0 || other.min < self.min {
+            self.min = other.min;
+        }
+
+        if other.max > self.max {
+            self.max = other.max;
+        }
+
+        self.count += other.count;
+        self.sum += other.sum;
+
+        if let (&mut Some(ref mut exact), Some(durations)) =
+            (&mut self.exact, &other.exact) {
+            exact.extend(durations);
+            return;
+        }
+
+        /*
+         * At least one of us is a histogram; if we aren't, we become one.
+         */
+        if let Some(exact) = self.exact.take() {
+            for duration in exact {
+                *self.buckets.entry(bucket(duration)).or_insert(0) += 1;
+            }
+        }
+
+        match other.exact {
+            Some(ref durations) => {
+                for &duration in durations {
+                    *self.buckets.entry(bucket(duration)).or_insert(0) += 1;
+                }
+            }
+            None => {
+                for (&b, &count) in &other.buckets {
+                    *self.buckets.entry(b).or_insert(0) += count;
+                }
+            }
+        }
+    }
+
+    /**
+     * The number of durations recorded.
+     */
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /**
+     * The shortest duration recorded (or 0 if none has been).
+     */
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    /**
+     * The longest duration recorded (or 0 if none has been).
+     */
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /**
+     * The mean of the durations recorded (or 0 if none has been).
+     */
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /**
+     * Return the specified percentiles (each from 0 to 100) of the
+     * durations recorded, using the nearest-rank method.  If recorded in a
+     * histogram, each is the highest duration in the bucket of the
+     * duration of that rank, and is therefore within 1% of it.
+     */
+    pub fn percentiles(&self, percentiles: &[f64]) -> Vec<u64> {
+        let rank = |p: f64| -> u64 {
+            let rank = ((p / 100.0) * self.count as f64).ceil() as u64;
+            rank.max(1).min(self.count)
+        };
+
+        if self.count == 0 {
+            return vec![0; percentiles.len()];
+        }
+
+        if let Some(ref exact) = self.exact {
+            let mut sorted = exact.clone();
+            sorted.sort_unstable();
+
+            return percentiles.iter()
+                .map(|&p| sorted[rank(p) as usize - 1])
+                .collect();
+        }
+
+        percentiles.iter().map(|&p| {
+            let rank = rank(p);
+            let mut seen = 0;
+
+            for (&b, &count) in &self.buckets {
+                seen += count;
+
+                if seen >= rank {
+                    return bucket_bounds(b).1.max(self.min).min(self.max);
+                }
+            }
+
+            self.max
+        }).collect()
+    }
+
+    /**
+     * Return the number of durations that fall into each power of two,
+     * from 1 nanosecond up to the power of two of the longest duration.
+     * (Durations of zero are counted with those of 1 nanosecond.)
+     */
+    pub fn octaves(&self) -> Vec<u64> {
+        let mut octaves = vec![];
+
+        if self.count == 0 {
+            return octaves;
+        }
+
+        octaves.resize(octave(self.max) + 1, 0);
+
+        match self.exact {
+            Some(ref exact) => {
+                for &duration in exact {
+                    octaves[octave(duration)] += 1;
+                }
+            }
+            None => {
+                for (&b, &count) in &self.buckets {
+                    octaves[octave(bucket_bounds(b).0)] += count;
+                }
+            }
+        }
+
+        octaves
+    }
+}
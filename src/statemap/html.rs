@@ -0,0 +1,301 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * Rendering of statemaps as a self-contained HTML document.  Where the SVG
+ * has an element for every rectangle -- which can bring a browser to its
+ * knees for a statemap with hundreds of thousands of them -- the HTML
+ * instead embeds the data for each statemap and leaves the drawing of it to
+ * the canvas-based viewer in statemap-html.js, which draws only what is
+ * visible.
+ */
+
+use std::io::Write;
+use std::error::Error;
+use std::collections::HashMap;
+use std::cmp;
+
+use super::serde_json;
+use super::{Statemap, StatemapSVG, StatemapSVGConfig, StatemapSVGGlobals};
+use super::{StatemapEntity, StatemapColor, StatemapState};
+use super::{rect_datum, rect_salient, output_data, event_datum};
+use super::{escape, EVENT_COLORS};
+
+/**
+ * A renderer of (possibly stacked) statemaps as HTML.
+ */
+pub struct StatemapHTML<'a> {
+    config: &'a StatemapSVGConfig,
+}
+
+/*
+ * Our data is embedded in script elements, which end at the first "</" --
+ * regardless of whether it appears in a string.  Outside of a string, "</"
+ * is not valid ECMAScript, so we can safely escape it everywhere.
+ */
+fn script(out: &mut dyn Write, script: &[u8]) -> Result<(), Box<dyn Error>> {
+    let script = String::from_utf8_lossy(script);
+
+    writeln!(out, "<script>\n{}\n</script>", script.replace("</", "<\\/"))?;
+    Ok(())
+}
+
+/*
+ * Return the data for an entity.  As the viewer draws every rectangle, each
+ * datum carries its duration and color -- as well as the color of the mark
+ * for any salient state.
+ */
+fn entity_data(entity: &StatemapEntity, states: &Vec<StatemapState>,
+    colors: &Vec<StatemapColor>) -> Vec<String>
+{
+    entity.rects().iter().map(|rect| {
+        let (mut datum, color) = rect_datum(rect, colors);
+        datum.push_str(&format!(", d: {}, c: '{}'", rect.duration, color));
+
+        if let Some(salient) = rect_salient(rect, states) {
+            datum.push_str(&format!(", m: '{}'", colors[salient]));
+        }
+
+        datum.push_str(" }");
+        datum
+    }).collect()
+}
+
+impl<'a> StatemapHTML<'a> {
+    /**
+     * Create a renderer of (possibly stacked) statemaps as HTML.
+     */
+    pub fn new(config: &'a StatemapSVGConfig) -> Self {
+        StatemapHTML {
+            config: config,
+        }
+    }
+
+    /**
+     * Render the specified statemaps as a single HTML document to `out`.
+     * As with `StatemapSVG::output`, the first statemap dictates the time
+     * bounds of the rendering.
+     */
+    pub fn output<W: Write>(&self, statemaps: &Vec<Statemap>, out: &mut W)
+        -> Result<(), Box<dyn Error>>
+    {
+        self.output_html(statemaps, out)
+    }
+
+    /*
+     * Output our element in the viewer's statemaps array.  This consists
+     * of the same members as the SVG has, along with the legend to use,
+     * the colors of our states and the order of our entities.
+     */
+    fn output_statemap(&self, out: &mut dyn Write, statemap: &Statemap,
+        id: usize, legend: usize, globals: &StatemapSVGGlobals,
+        colors: &Vec<StatemapColor>) -> Result<(), Box<dyn Error>>
+    {
+        let locals = statemap.locals(globals)?;
+        let order: Vec<&String> = statemap.sortby(self.config)?.iter()
+            .map(|&e| &statemap.byid[e])
+            .collect();
+
+        let str = serde_json::to_string_pretty(&locals).unwrap();
+        writeln!(out, "g_statemaps[{}] = {{\n{},", id, &str[2..str.len() - 2])?;
+
+        let names: Vec<String> = colors.iter().map(|c| c.to_string()).collect();
+
+        writeln!(out, "\"legend\": {},", legend)?;
+        writeln!(out, "\"colors\": {},", serde_json::to_string(&names)?)?;
+        writeln!(out, "\"order\": {},", serde_json::to_string(&order)?)?;
+
+        let data: HashMap<&String, Vec<String>> = statemap.entities.values()
+            .map(|e| (&e.name, entity_data(e, &statemap.states, colors)))
+            .collect();
+
+        write!(out, "\"data\": ")?;
+        output_data(out, &data)?;
+        writeln!(out, ",")?;
+
+        statemap.output_levels(out, colors)?;
+
+        /*
+         * Our events are in the same order as they are in the SVG:  by
+         * entity, and then by time.
+         */
+        let mut events = vec![];
+
+        for name in &order {
+            let entity = statemap.entities.get(*name).unwrap();
+            let mut sorted: Vec<_> = entity.events.iter().collect();
+
+            sorted.sort_by_key(|event| event.time);
+            events.extend(sorted.iter().map(|e| event_datum(entity, e)));
+        }
+
+        statemap.output_defs(out, &events)?;
+        writeln!(out, "}};")?;
+
+        Ok(())
+    }
+
+    fn output_html(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        let svg = StatemapSVG::new(self.config);
+        let base = &statemaps[0];
+
+        let metadata = match base.metadata {
+            Some(ref metadata) => { metadata }
+            _ => { return base.err("metadata not found in data stream"); }
+        };
+
+        /*
+         * Our layout is that of the SVG, with the same margins and legend.
+         */
+        let lmargin = self.config.legendWidth;
+        let tmargin = 60;
+        let rmargin = self.config.tagWidth;
+        let smargin = self.config.stripHeight;
+
+        let nentities = statemaps.iter()
+            .fold(0, |total, s| total + s.entities.len()) as u32;
+        let height = nentities * self.config.stripHeight + tmargin +
+            (statemaps.len() as u32 - 1) * smargin;
+
+        let (x, lheight, spacing) = (20, 15, 10);
+        let mut legend = vec![];
+        let mut legendheight = tmargin + 45;
+
+        for i in 0..statemaps.len() {
+            if i == 0 || !statemaps[i].legend_shared(&statemaps[i - 1]) {
+                let nevents = statemaps[i].event_types().len() as u32;
+
+                legendheight += statemaps[i].states.len() as u32 *
+                    (lheight + (spacing * 2));
+
+                if nevents > 0 {
+                    legendheight += spacing + nevents * lheight;
+                }
+
+                if statemaps[i].has_arrows() {
+                    legendheight += lheight;
+                }
+
+                if i > 0 {
+                    legendheight += spacing * 2;
+                }
+
+                legend.push(i);
+            } else {
+                let shared = legend[i - 1];
+                legend.push(shared);
+            }
+        }
+
+        let sharedlegend = legend.iter().all(|&l| l == 0);
+        let colors = svg.colors(statemaps)?;
+        let sorted = svg.stacksort(statemaps, sharedlegend)?;
+        let title = svg.title(statemaps);
+
+        let globals = StatemapSVGGlobals {
+            begin: base.config.begin,
+            end: base.config.end,
+            pixelWidth: self.config.stripWidth,
+            pixelHeight: height - tmargin,
+            totalHeight: cmp::max(height, legendheight),
+            utilizationHeight: 0,
+            timeWidth: base.time_width(),
+            lmargin: lmargin,
+            tmargin: tmargin,
+            smargin: smargin,
+            entityPrefix: "statemap-entity-".to_string(),
+            states: &base.states,
+            start: &metadata.start,
+            entityKind: match metadata.entityKind {
+                Some(ref kind) => { kind }
+                None => { "Entity" }
+            }
+        };
+
+        let width = lmargin + self.config.stripWidth + rmargin;
+
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+        writeln!(out, "<title>{}</title>", escape(&title))?;
+        writeln!(out, "<style>\n{}\n</style>",
+            include_str!("../statemap-html.css"))?;
+        writeln!(out, "</head>\n<body onload=\"init()\">")?;
+
+        writeln!(out, concat!(r##"<div id="statemap" "##,
+            r##"style="width: {}px; height: {}px">"##),
+            width, globals.totalHeight)?;
+        writeln!(out, r##"<canvas id="statemap-canvas"></canvas>"##)?;
+
+        /*
+         * Our controls are as they are in the SVG.
+         */
+        let icons = vec![
+            (include_str!("../icons/arrow-left-l.svg"), "panclick(50, 0)"),
+            (include_str!("../icons/zoom-in.svg"), "zoomclick(1.25)"),
+            (include_str!("../icons/zoom-out.svg"), "zoomclick(0.8)"),
+            (include_str!("../icons/arrow-right-l.svg"), "panclick(-50, 0)")
+        ];
+
+        let cwidth = lmargin - (2 * x) - 10;
+
+        writeln!(out, concat!(r##"<div id="statemap-controls" "##,
+            r##"style="left: {}px; top: {}px; width: {}px; height: {}px">"##),
+            x, tmargin, cwidth, cwidth / icons.len() as u32)?;
+
+        for (icon, click) in icons {
+            writeln!(out, concat!(r##"<div class="statemap-button" "##,
+                r##"onclick="{}">{}</div>"##), click, icon)?;
+        }
+
+        writeln!(out, "</div>")?;
+        writeln!(out, r##"<div id="statemap-legend"></div>"##)?;
+
+        writeln!(out, concat!(r##"<div id="statemap-tagbox-container" "##,
+            r##"style="left: {}px; top: {}px; width: {}px">"##),
+            lmargin + self.config.stripWidth + x, tmargin - 12,
+            rmargin - (2 * x))?;
+        writeln!(out, r##"<div id="statemap-tagbox"></div>"##)?;
+        writeln!(out, r##"<div id="statemap-tagbox-select"></div>"##)?;
+        writeln!(out, "</div>\n</div>")?;
+
+        /*
+         * Our globals are as they are in the SVG, with the addition of our
+         * title and the colors of our events.
+         */
+        let mut buf: Vec<u8> = vec![];
+
+        writeln!(buf, "var globals = {{")?;
+        let str = serde_json::to_string_pretty(&self.config).unwrap();
+        writeln!(buf, "{},", &str[2..str.len() - 2])?;
+
+        let str = serde_json::to_string_pretty(&globals).unwrap();
+        writeln!(buf, "{},", &str[2..str.len() - 2])?;
+        writeln!(buf, "\"title\": {},", serde_json::to_string(&title)?)?;
+        writeln!(buf, "\"eventColors\": {}",
+            serde_json::to_string(EVENT_COLORS)?)?;
+        writeln!(buf, "}}")?;
+
+        script(out, &buf)?;
+        script(out, include_str!("../statemap-html.js").as_bytes())?;
+
+        /*
+         * And finally, our statemaps, in the order in which they are to be
+         * stacked.
+         */
+        for (id, &i) in sorted.iter().enumerate() {
+            let mut buf: Vec<u8> = vec![];
+            let legend = sorted.iter().position(|&s| s == legend[i]).unwrap();
+
+            self.output_statemap(&mut buf, &statemaps[i], id, legend,
+                &globals, &colors[i])?;
+            script(out, &buf)?;
+        }
+
+        writeln!(out, "</body>\n</html>")?;
+
+        Ok(())
+    }
+}
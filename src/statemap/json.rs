@@ -0,0 +1,201 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * Export of (possibly stacked) statemaps as a JSON document, allowing other
+ * tools to consume the coalesced rectangles without scraping the SVG.  The
+ * document is an object with a single "statemaps" member:  an array that has
+ * an element for each statemap, in the order in which they were specified.
+ * Each element has the following members:
+ *
+ *   metadata   The title, host and entity kind of the statemap; its start
+ *              (the time from which rectangle start times are offset) and
+ *              its begin and end -- all in nanoseconds since the Epoch --
+ *              along with the width of each bucket in nanoseconds (or null
+ *              if not coalescing into buckets) and of each finer level of
+ *              detail.
+ *
+ *   states     The valid states, indexed by state value.
+ *
+ *   tags       The definitions of any tags, indexed by tag identifier.
+ *
+ *   entities   The entities, sorted by name, each with its name, its
+ *              description (or null), its rectangles and (if there are
+ *              finer levels of detail) its rectangles at each level.
+ *
+ * Each rectangle has its start and duration (in nanoseconds), the time spent
+ * in each state (indexed by state value) and the time spent with each tag,
+ * as pairs of tag identifier and time.  The fraction of the rectangle spent
+ * in a state or with a tag is its time divided by the rectangle's duration.
+ * To keep the document readable (and diffable), each rectangle is on its own
+ * line.
+ */
+
+use std::io::Write;
+use std::error::Error;
+
+use super::serde_json;
+use super::serde_json::Value;
+use super::{Statemap, StatemapEntity, StatemapRect, StatemapState};
+
+/**
+ * A renderer of (possibly stacked) statemaps as JSON.
+ */
+#[derive(Default)]
+pub struct StatemapJSON {}
+
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+struct StatemapJSONMetadata<'a> {
+    title: Option<&'a str>,
+    host: Option<&'a str>,
+    entityKind: Option<&'a str>,
+    start: u64,
+    begin: u64,
+    end: u64,
+    resolution: Option<u64>,
+    levels: &'a [u64],
+}
+
+#[derive(Serialize)]
+struct StatemapJSONRect<'a> {
+    start: u64,
+    duration: u64,
+    states: &'a [u64],
+    tags: Vec<(usize, u64)>,
+}
+
+/*
+ * Output an array of rectangles, one to a line.
+ */
+fn output_rects(out: &mut dyn Write, rects: &[StatemapRect], indent: &str)
+    -> Result<(), Box<dyn Error>>
+{
+    writeln!(out, "[")?;
+
+    for (i, rect) in rects.iter().enumerate() {
+        let datum = StatemapJSONRect {
+            start: rect.start(),
+            duration: rect.duration(),
+            states: rect.states(),
+            tags: rect.tags(),
+        };
+
+        writeln!(out, "{} {}{}", indent, serde_json::to_string(&datum)?,
+            if i < rects.len() - 1 { "," } else { "" })?;
+    }
+
+    write!(out, "{}]", indent)?;
+    Ok(())
+}
+
+impl StatemapJSON {
+    /**
+     * Create a renderer of (possibly stacked) statemaps as JSON.
+     */
+    pub fn new() -> Self {
+        StatemapJSON {}
+    }
+
+    /**
+     * Render the specified statemaps as a single JSON document to `out`.
+     */
+    pub fn output<W: Write>(&self, statemaps: &Vec<Statemap>, out: &mut W)
+        -> Result<(), Box<dyn Error>>
+    {
+        self.output_json(statemaps, out)
+    }
+
+    fn output_entity(&self, out: &mut dyn Write, statemap: &Statemap,
+        entity: &StatemapEntity) -> Result<(), Box<dyn Error>>
+    {
+        writeln!(out, "    {{")?;
+        writeln!(out, "     \"name\": {},",
+            serde_json::to_string(entity.name())?)?;
+        writeln!(out, "     \"description\": {},",
+            serde_json::to_string(&entity.description())?)?;
+        write!(out, "     \"rects\": ")?;
+        output_rects(out, &entity.rects(), "     ")?;
+
+        let levels = statemap.levels();
+
+        if !levels.is_empty() {
+            writeln!(out, ",\n     \"levels\": [")?;
+
+            for i in 0..levels.len() {
+                write!(out, "      ")?;
+                output_rects(out, entity.level(i), "      ")?;
+                writeln!(out, "{}",
+                    if i < levels.len() - 1 { "," } else { "" })?;
+            }
+
+            write!(out, "     ]")?;
+        }
+
+        write!(out, "\n    }}")?;
+        Ok(())
+    }
+
+    fn output_statemap(&self, out: &mut dyn Write, statemap: &Statemap)
+        -> Result<(), Box<dyn Error>>
+    {
+        let metadata = match statemap.metadata {
+            Some(ref metadata) => { metadata }
+            _ => { return statemap.err("metadata not found in data stream"); }
+        };
+
+        let (begin, end) = statemap.timebounds();
+
+        let header = StatemapJSONMetadata {
+            title: statemap.title(),
+            host: statemap.host(),
+            entityKind: metadata.entityKind.as_deref(),
+            start: metadata.start[0] * 1_000_000_000 + metadata.start[1],
+            begin: begin,
+            end: end,
+            resolution: statemap.resolution(),
+            levels: statemap.levels(),
+        };
+
+        let states: &[StatemapState] = statemap.states();
+        let tags: Vec<&Value> = statemap.tags();
+
+        writeln!(out, "  {{")?;
+        writeln!(out, "   \"metadata\": {},",
+            serde_json::to_string(&header)?)?;
+        writeln!(out, "   \"states\": {},", serde_json::to_string(states)?)?;
+        writeln!(out, "   \"tags\": {},", serde_json::to_string(&tags)?)?;
+        writeln!(out, "   \"entities\": [")?;
+
+        let entities = statemap.entities();
+
+        for (i, entity) in entities.iter().enumerate() {
+            self.output_entity(out, statemap, entity)?;
+            writeln!(out, "{}", if i < entities.len() - 1 { "," } else { "" })?;
+        }
+
+        writeln!(out, "   ]")?;
+        write!(out, "  }}")?;
+
+        Ok(())
+    }
+
+    fn output_json(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        writeln!(out, "{{")?;
+        writeln!(out, " \"statemaps\": [")?;
+
+        for (i, statemap) in statemaps.iter().enumerate() {
+            self.output_statemap(out, statemap)?;
+            writeln!(out, "{}",
+                if i < statemaps.len() - 1 { "," } else { "" })?;
+        }
+
+        writeln!(out, " ]")?;
+        writeln!(out, "}}")?;
+
+        Ok(())
+    }
+}
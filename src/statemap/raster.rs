@@ -0,0 +1,637 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * A pure-Rust rasterizer for statemaps.  This renders the same layout as
+ * StatemapSVG -- strips, legend, title and a time axis -- but without any
+ * of its interactive elements, allowing statemaps to be rendered as PNG
+ * for contexts in which an SVG with embedded ECMAScript is unwelcome.
+ */
+
+use std::io::Write;
+use std::error::Error;
+use std::cmp;
+
+use super::png;
+use super::{Statemap, StatemapSVG, StatemapSVGConfig, StatemapColor};
+use super::{rect_color, rect_salient};
+
+/**
+ * The dimensions of a statemap rendered as PNG.  The layout is otherwise
+ * dictated by the `StatemapSVGConfig`; a width or height of 0 denotes that
+ * the dimension should be derived from it, just as it would be for SVG.
+ */
+#[derive(Copy,Clone,Debug,Default)]
+pub struct StatemapPNGConfig {
+    pub width: u32,                         // total width, in pixels
+    pub height: u32,                        // total height, in pixels
+}
+
+/**
+ * A renderer of (possibly stacked) statemaps as PNG.
+ */
+pub struct StatemapPNG<'a> {
+    config: &'a StatemapSVGConfig,
+    png: &'a StatemapPNGConfig,
+}
+
+type Rgb = [u8; 3];
+
+const BLACK: Rgb = [0, 0, 0];
+const WHITE: Rgb = [255, 255, 255];
+
+/*
+ * The largest raster (in pixels) that we are willing to allocate.
+ */
+const MAX_PIXELS: usize = 100_000_000;
+
+/*
+ * Our font is a fixed-width 5x7 bitmap font, with each glyph occupying a
+ * 6x8 cell.  Each row of a glyph is a byte, with the leftmost pixel in the
+ * most significant of the low five bits.
+ */
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const CELL_WIDTH: u32 = 6;
+
+const FONT: [[u8; 7]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04], // '!'
+    [0x0a, 0x0a, 0x0a, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x0a, 0x0a, 0x1f, 0x0a, 0x1f, 0x0a, 0x0a], // '#'
+    [0x04, 0x0f, 0x14, 0x0e, 0x05, 0x1e, 0x04], // '$'
+    [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03], // '%'
+    [0x0c, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0d], // '&'
+    [0x0c, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00], // '\''
+    [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02], // '('
+    [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08], // ')'
+    [0x00, 0x04, 0x15, 0x0e, 0x15, 0x04, 0x00], // '*'
+    [0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08], // ','
+    [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c], // '.'
+    [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00], // '/'
+    [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e], // '0'
+    [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e], // '1'
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f], // '2'
+    [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e], // '3'
+    [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02], // '4'
+    [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e], // '5'
+    [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e], // '6'
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08], // '7'
+    [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e], // '8'
+    [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c], // '9'
+    [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00], // ':'
+    [0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x04, 0x08], // ';'
+    [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02], // '<'
+    [0x00, 0x00, 0x1f, 0x00, 0x1f, 0x00, 0x00], // '='
+    [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08], // '>'
+    [0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // '?'
+    [0x0e, 0x11, 0x01, 0x0d, 0x15, 0x15, 0x0e], // '@'
+    [0x0e, 0x11, 0x11, 0x11, 0x1f, 0x11, 0x11], // 'A'
+    [0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e], // 'B'
+    [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e], // 'C'
+    [0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c], // 'D'
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f], // 'E'
+    [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10], // 'F'
+    [0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f], // 'G'
+    [0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11], // 'H'
+    [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // 'I'
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c], // 'J'
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11], // 'K'
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f], // 'L'
+    [0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11], // 'M'
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11], // 'N'
+    [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // 'O'
+    [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10], // 'P'
+    [0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d], // 'Q'
+    [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11], // 'R'
+    [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e], // 'S'
+    [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // 'T'
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // 'U'
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04], // 'V'
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a], // 'W'
+    [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11], // 'X'
+    [0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04], // 'Y'
+    [0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f], // 'Z'
+    [0x0e, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0e], // '['
+    [0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00], // '\\'
+    [0x0e, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0e], // ']'
+    [0x04, 0x0a, 0x11, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f], // '_'
+    [0x08, 0x04, 0x02, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x0e, 0x01, 0x0f, 0x11, 0x0f], // 'a'
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x1e], // 'b'
+    [0x00, 0x00, 0x0e, 0x10, 0x10, 0x11, 0x0e], // 'c'
+    [0x01, 0x01, 0x0d, 0x13, 0x11, 0x11, 0x0f], // 'd'
+    [0x00, 0x00, 0x0e, 0x11, 0x1f, 0x10, 0x0e], // 'e'
+    [0x06, 0x09, 0x08, 0x1c, 0x08, 0x08, 0x08], // 'f'
+    [0x00, 0x0f, 0x11, 0x11, 0x0f, 0x01, 0x0e], // 'g'
+    [0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x11], // 'h'
+    [0x04, 0x00, 0x0c, 0x04, 0x04, 0x04, 0x0e], // 'i'
+    [0x02, 0x00, 0x06, 0x02, 0x02, 0x12, 0x0c], // 'j'
+    [0x10, 0x10, 0x12, 0x14, 0x18, 0x14, 0x12], // 'k'
+    [0x0c, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // 'l'
+    [0x00, 0x00, 0x1a, 0x15, 0x15, 0x11, 0x11], // 'm'
+    [0x00, 0x00, 0x16, 0x19, 0x11, 0x11, 0x11], // 'n'
+    [0x00, 0x00, 0x0e, 0x11, 0x11, 0x11, 0x0e], // 'o'
+    [0x00, 0x00, 0x1e, 0x11, 0x1e, 0x10, 0x10], // 'p'
+    [0x00, 0x00, 0x0d, 0x13, 0x0f, 0x01, 0x01], // 'q'
+    [0x00, 0x00, 0x16, 0x19, 0x10, 0x10, 0x10], // 'r'
+    [0x00, 0x00, 0x0e, 0x10, 0x0e, 0x01, 0x1e], // 's'
+    [0x08, 0x08, 0x1c, 0x08, 0x08, 0x09, 0x06], // 't'
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x13, 0x0d], // 'u'
+    [0x00, 0x00, 0x11, 0x11, 0x11, 0x0a, 0x04], // 'v'
+    [0x00, 0x00, 0x11, 0x11, 0x15, 0x15, 0x0a], // 'w'
+    [0x00, 0x00, 0x11, 0x0a, 0x04, 0x0a, 0x11], // 'x'
+    [0x00, 0x00, 0x11, 0x11, 0x0f, 0x01, 0x0e], // 'y'
+    [0x00, 0x00, 0x1f, 0x02, 0x04, 0x08, 0x1f], // 'z'
+    [0x02, 0x04, 0x04, 0x08, 0x04, 0x04, 0x02], // '{'
+    [0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // '|'
+    [0x08, 0x04, 0x04, 0x02, 0x04, 0x04, 0x08], // '}'
+    [0x00, 0x00, 0x08, 0x15, 0x02, 0x00, 0x00], // '~'
+];
+
+/*
+ * Our time labels are denominated in microseconds, so we have a glyph for
+ * the micro sign as well.
+ */
+const MICRO: [u8; 7] = [0x00, 0x00, 0x11, 0x11, 0x13, 0x1a, 0x10];
+const UNKNOWN: char = '?';
+
+fn glyph(c: char) -> &'static [u8; 7] {
+    match c {
+        ' ' ..= '~' => &FONT[c as usize - ' ' as usize],
+        '\u{3bc}' | '\u{b5}' => &MICRO,
+        _ => glyph(UNKNOWN)
+    }
+}
+
+/*
+ * A simple raster of RGB pixels.  All drawing is done with coverage-based
+ * antialiasing, so coordinates need not be integral.
+ */
+struct Raster {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Raster {
+    /*
+     * Create a raster, returning None if it would exceed MAX_PIXELS.
+     */
+    fn new(width: u32, height: u32, background: Rgb) -> Option<Self> {
+        let npixels = (width as usize).checked_mul(height as usize)
+            .filter(|&npixels| npixels <= MAX_PIXELS)?;
+        let mut pixels = Vec::with_capacity(npixels * 3);
+
+        for _ in 0..npixels {
+            pixels.extend_from_slice(&background);
+        }
+
+        Some(Raster {
+            width: width,
+            height: height,
+            pixels: pixels,
+        })
+    }
+
+    fn blend(&mut self, x: i64, y: i64, color: Rgb, alpha: f64) {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return;
+        }
+
+        let alpha = alpha.clamp(0.0, 1.0);
+        let offs = ((y as usize * self.width as usize) + x as usize) * 3;
+
+        for (p, &c) in self.pixels[offs..offs + 3].iter_mut().zip(&color) {
+            *p = (*p as f64 + (c as f64 - *p as f64) * alpha).round() as u8;
+        }
+    }
+
+    /*
+     * Fill the specified rectangle, blending any pixels that are only
+     * partially covered.
+     */
+    fn fill(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, color: Rgb,
+        alpha: f64)
+    {
+        if x1 <= x0 || y1 <= y0 {
+            return;
+        }
+
+        for y in y0.floor() as i64..y1.ceil() as i64 {
+            let ycov = y1.min(y as f64 + 1.0) - y0.max(y as f64);
+
+            for x in x0.floor() as i64..x1.ceil() as i64 {
+                let xcov = x1.min(x as f64 + 1.0) - x0.max(x as f64);
+                self.blend(x, y, color, xcov * ycov * alpha);
+            }
+        }
+    }
+
+    /*
+     * Draw a strip of horizontally adjacent spans, each denoted by its
+     * left and right edges and its color.  Because adjacent spans can
+     * share a pixel, we accumulate each column before drawing it to
+     * prevent the background from bleeding through between spans.
+     */
+    fn strip(&mut self, x: f64, y0: f64, y1: f64, width: u32,
+        spans: &[(f64, f64, Rgb)])
+    {
+        let mut columns = vec![([0.0f64; 3], 0.0f64); width as usize + 1];
+
+        for &(left, right, color) in spans {
+            let left = left.max(0.0);
+            let right = right.min(width as f64);
+
+            if right <= left {
+                continue;
+            }
+
+            let first = left.floor() as usize;
+            let last = right.ceil() as usize;
+
+            for (col, column) in columns[first..last].iter_mut().enumerate() {
+                let col = (first + col) as f64;
+                let cov = right.min(col + 1.0) - left.max(col);
+
+                for (sum, &c) in column.0.iter_mut().zip(&color) {
+                    *sum += c as f64 * cov;
+                }
+
+                column.1 += cov;
+            }
+        }
+
+        for (col, &(sum, cov)) in columns.iter().enumerate() {
+            if cov <= 0.0 {
+                continue;
+            }
+
+            let color = [(sum[0] / cov).round() as u8,
+                (sum[1] / cov).round() as u8, (sum[2] / cov).round() as u8];
+
+            let left = x + col as f64;
+            self.fill(left, y0, left + 1.0, y1, color, cov);
+        }
+    }
+
+    fn text_width(text: &str, scale: u32) -> u32 {
+        let n = text.chars().count() as u32;
+
+        if n == 0 {
+            0
+        } else {
+            (n * CELL_WIDTH - (CELL_WIDTH - GLYPH_WIDTH)) * scale
+        }
+    }
+
+    /*
+     * Draw the specified text centered on the specified x coordinate, with
+     * its top at the specified y coordinate.
+     */
+    fn text(&mut self, x: i64, y: i64, text: &str, scale: u32, color: Rgb) {
+        let width = Raster::text_width(text, scale) as i64;
+        let scale = scale as i64;
+        let mut x = x - width / 2;
+
+        for c in text.chars() {
+            let rows = glyph(c);
+
+            for row in 0..GLYPH_HEIGHT as i64 {
+                for col in 0..GLYPH_WIDTH as i64 {
+                    if rows[row as usize] & (1 << (4 - col)) == 0 {
+                        continue;
+                    }
+
+                    for dy in 0..scale {
+                        for dx in 0..scale {
+                            self.blend(x + col * scale + dx,
+                                y + row * scale + dy, color, 1.0);
+                        }
+                    }
+                }
+            }
+
+            x += CELL_WIDTH as i64 * scale;
+        }
+    }
+
+    fn output(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let mut encoder = png::Encoder::new(out, self.width, self.height);
+
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+
+        Ok(())
+    }
+}
+
+/*
+ * Return the interval between ticks on our time axis:  the smallest
+ * interval of the form 1, 2 or 5 times a power of ten that results in no
+ * more than the specified number of ticks.
+ */
+fn tick_interval(width: u64, nticks: u64) -> u64 {
+    let target = cmp::max(width / cmp::max(nticks, 1), 1);
+    let mut magnitude = 1;
+
+    while magnitude * 10 <= target {
+        magnitude *= 10;
+    }
+
+    for &mult in &[1, 2, 5, 10] {
+        if magnitude * mult >= target {
+            return magnitude * mult;
+        }
+    }
+
+    unreachable!();
+}
+
+/*
+ * Label a tick on our time axis.  Our interval is a multiple of the unit
+ * that we pick, so our labels are always integral.
+ */
+fn tick_label(time: u64, interval: u64) -> String {
+    let units: &[(u64, &str)] = &[
+        (1_000_000_000, "s"), (1_000_000, "ms"), (1_000, "\u{3bc}s"), (1, "ns")
+    ];
+
+    if time == 0 {
+        return "0".to_string();
+    }
+
+    for &(unit, suffix) in units {
+        if interval >= unit {
+            return format!("{}{}", time / unit, suffix);
+        }
+    }
+
+    format!("{}ns", time)
+}
+
+fn rgb(color: &StatemapColor) -> Rgb {
+    let (r, g, b) = color.rgb();
+    [r, g, b]
+}
+
+impl<'a> StatemapPNG<'a> {
+    /**
+     * Create a renderer of (possibly stacked) statemaps as PNG.
+     */
+    pub fn new(config: &'a StatemapSVGConfig, png: &'a StatemapPNGConfig)
+        -> Self
+    {
+        StatemapPNG {
+            config: config,
+            png: png,
+        }
+    }
+
+    /**
+     * Render the specified statemaps as a single PNG to `out`.  As with
+     * `StatemapSVG::output`, the first statemap dictates the time bounds of
+     * the rendering.
+     */
+    pub fn output<W: Write>(&self, statemaps: &Vec<Statemap>, out: &mut W)
+        -> Result<(), Box<dyn Error>>
+    {
+        self.output_png(statemaps, out)
+    }
+
+    fn output_png(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        let svg = StatemapSVG::new(self.config);
+        let base = &statemaps[0];
+
+        if base.metadata.is_none() {
+            return base.err("metadata not found in data stream");
+        }
+
+        let lmargin = self.config.legendWidth;
+        let tmargin = 60;
+        let rmargin = 20;
+        let bmargin = 10;
+        let smargin = self.config.stripHeight;
+
+        let nentities = statemaps.iter()
+            .fold(0, |total, s| total + s.entities.len()) as u32;
+        let gaps = (statemaps.len() as u32 - 1) * smargin;
+
+        let pixel_width = if self.png.width != 0 {
+            if self.png.width <= lmargin + rmargin {
+                return base.err(&format!("width must exceed {} pixels",
+                    lmargin + rmargin));
+            }
+
+            self.png.width - lmargin - rmargin
+        } else {
+            self.config.stripWidth
+        };
+
+        let strip_height = if self.png.height != 0 {
+            let fixed = tmargin + bmargin + gaps;
+
+            if self.png.height < fixed + nentities {
+                return base.err(&format!(concat!("height must be at least ",
+                    "{} pixels"), fixed + nentities));
+            }
+
+            (self.png.height - fixed) as f64 / cmp::max(nentities, 1) as f64
+        } else {
+            self.config.stripHeight as f64
+        };
+
+        let colors = svg.colors(statemaps)?;
+        let sharedlegend = statemaps.windows(2)
+            .all(|pair| pair[1].legend_shared(&pair[0]));
+        let sorted = svg.stacksort(statemaps, sharedlegend)?;
+
+        let background = match self.config.background.parse() {
+            Ok(color) => rgb(&color),
+            Err(_) => {
+                return base.err(&format!("illegal background color \"{}\"",
+                    self.config.background));
+            }
+        };
+
+        /*
+         * Our legend is as in the SVG, but without the space reserved for
+         * our controls or any listing of events.
+         */
+        let legend = (20, 15, lmargin.saturating_sub(50), 10);
+        let mut lheight = tmargin;
+
+        for i in 0..statemaps.len() {
+            if i == 0 || !statemaps[i].legend_shared(&statemaps[i - 1]) {
+                lheight += statemaps[i].states.len() as u32 *
+                    (legend.1 + legend.3 * 2);
+
+                if i > 0 {
+                    lheight += legend.3 * 2;
+                }
+            }
+        }
+
+        let width = lmargin + pixel_width + rmargin;
+        let height = if self.png.height != 0 {
+            self.png.height
+        } else {
+            cmp::max(tmargin + (nentities * self.config.stripHeight) +
+                gaps + bmargin, lheight)
+        };
+
+        let mut raster = match Raster::new(width, height, WHITE) {
+            Some(raster) => raster,
+            None => {
+                return base.err(&format!(concat!("{}x{} pixels exceeds ",
+                    "maximum PNG size of {} pixels"), width, height,
+                    MAX_PIXELS));
+            }
+        };
+        let time_width = cmp::max(base.time_width(), 1) as f64;
+        let scale = pixel_width as f64 / time_width;
+        let left = lmargin as f64;
+        let mut y = tmargin as f64;
+
+        for &i in &sorted {
+            let statemap = &statemaps[i];
+            let top = y;
+            let begin = statemap.config.begin;
+
+            raster.fill(left, top, left + pixel_width as f64,
+                top + statemap.entities.len() as f64 * strip_height,
+                background, 1.0);
+
+            for e in statemap.sortby(self.config)? {
+                let entity = statemap.entities
+                    .get(statemap.byid.get(e).unwrap()).unwrap();
+                let mut spans = vec![];
+                let mut markers = vec![];
+
+                for rect in entity.rects() {
+                    let x0 = (rect.start as i64 - begin) as f64 * scale;
+                    let x1 = x0 + rect.duration as f64 * scale;
+                    let color = rect_color(&rect, &colors[i]);
+
+                    spans.push((x0, x1, rgb(&color)));
+
+                    /*
+                     * As in the SVG, a blended rectangle with time in a
+                     * salient state is marked with a bar.
+                     */
+                    if let Some(salient) =
+                        rect_salient(&rect, &statemap.states) {
+                        markers.push((x0, x0 + (x1 - x0).max(1.0),
+                            rgb(&colors[i][salient])));
+                    }
+                }
+
+                raster.strip(left, y, y + strip_height, pixel_width, &spans);
+
+                let mheight = (strip_height / 4.0).max(1.0);
+
+                for (x0, x1, color) in markers {
+                    raster.fill(left + x0, y, left + x1, y + mheight,
+                        color, 1.0);
+                }
+
+                y += strip_height;
+            }
+
+            /*
+             * The border around this statemap.
+             */
+            let right = left + pixel_width as f64;
+            raster.fill(left - 1.0, top - 1.0, right + 1.0, top, BLACK, 0.5);
+            raster.fill(left - 1.0, y, right + 1.0, y + 1.0, BLACK, 0.5);
+            raster.fill(left - 1.0, top, left, y, BLACK, 0.5);
+            raster.fill(right, top, right + 1.0, y, BLACK, 0.5);
+
+            y += smargin as f64;
+        }
+
+        /*
+         * Our title and time axis.  Rather than a span that varies as the
+         * statemap is zoomed, the axis denotes the time offsets spanned.
+         */
+        let title = svg.title(statemaps);
+        let tscale = if Raster::text_width(&title, 2) < width { 2 } else { 1 };
+        let twidth = Raster::text_width(&title, tscale) as i64;
+        let center = cmp::max(cmp::min((lmargin + pixel_width / 2) as i64,
+            width as i64 - (twidth / 2) - 1), twidth / 2);
+        raster.text(center, 4, &title, tscale, BLACK);
+
+        let axis = 46.0;
+        raster.fill(left, axis, left + pixel_width as f64, axis + 1.0,
+            BLACK, 1.0);
+
+        let interval = tick_interval(time_width as u64,
+            cmp::max(pixel_width / 80, 1) as u64);
+        let mut tick = 0;
+
+        while tick <= time_width as u64 {
+            let x = left + (tick as f64 * scale);
+            let label = tick_label(tick, interval);
+            let lwidth = Raster::text_width(&label, 1) as i64;
+
+            raster.fill(x - 0.5, axis + 1.0, x + 0.5, axis + 5.0, BLACK, 1.0);
+
+            /*
+             * Keep our labels from running off the edge of the image.
+             */
+            let lx = cmp::max(cmp::min(x.round() as i64,
+                width as i64 - (lwidth / 2) - 1), lwidth / 2);
+            raster.text(lx, 36, &label, 1, BLACK);
+
+            tick += interval;
+        }
+
+        /*
+         * And finally, our legend.
+         */
+        let mut y = tmargin as i64;
+        let (lx, lh, lw, spacing) = legend;
+
+        for i in 0..statemaps.len() {
+            if i > 0 && statemaps[i].legend_shared(&statemaps[i - 1]) {
+                continue;
+            }
+
+            if i > 0 {
+                y += (spacing * 2) as i64;
+            }
+
+            for (state, color) in statemaps[i].states.iter().zip(&colors[i]) {
+                raster.fill(lx as f64, y as f64, (lx + lw) as f64,
+                    (y + lh as i64) as f64, rgb(color), 1.0);
+                y += (lh + spacing) as i64;
+
+                /*
+                 * Trim our label to fit within our legend.
+                 */
+                let mut label = state.name.clone();
+
+                while !label.is_empty() &&
+                    Raster::text_width(&label, 1) > lmargin.saturating_sub(4) {
+                    label.pop();
+                }
+
+                raster.text((lx + lw / 2) as i64, y - GLYPH_HEIGHT as i64,
+                    &label, 1, BLACK);
+                y += spacing as i64;
+            }
+        }
+
+        raster.output(out)
+    }
+}
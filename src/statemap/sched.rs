@@ -0,0 +1,605 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * Importing of Linux scheduler traces.  The text output of ftrace (either
+ * as read from the trace file in tracefs or as reported by trace-cmd) or of
+ * perf script is parsed for sched_switch, sched_wakeup and
+ * sched_process_exit events, from which we build a statemap of either CPUs
+ * or threads.  For CPUs, the state is either idle or running, with the
+ * running state tagged with the thread on CPU (much as
+ * contrib/cpu-statemap-tagged.d does); for threads, the state is whether the
+ * thread is running, runnable, sleeping, blocked on I/O or dead.  Lines that
+ * are not scheduler events are ignored.
+ */
+
+use std::io::BufRead;
+use std::error::Error;
+use std::str;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use super::Statemap;
+use super::trace_title;
+
+/**
+ * The entities in a statemap imported from a scheduler trace.
+ */
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum StatemapSchedEntity {
+    CPU,                                    // each CPU, idle or running
+    Thread,                                 // each thread, by its state
+}
+
+const CPU_STATES: &[(&str, &str)] = &[
+    ("idle", "#e0e0e0"),
+    ("running", "#9BC362"),
+];
+
+const CPU_IDLE: u32 = 0;
+const CPU_RUNNING: u32 = 1;
+
+const THREAD_STATES: &[(&str, &str)] = &[
+    ("running", "#DAF7A6"),
+    ("runnable", "#C70039"),
+    ("sleeping", "#f9f9f9"),
+    ("blocked-io", "#FFC300"),
+    ("dead", "#581845"),
+];
+
+const THREAD_RUNNING: u32 = 0;
+const THREAD_RUNNABLE: u32 = 1;
+const THREAD_SLEEPING: u32 = 2;
+const THREAD_BLOCKED: u32 = 3;
+const THREAD_DEAD: u32 = 4;
+
+/*
+ * A single event from a trace, as found on a line from ftrace of the form:
+ *
+ *     bash-1234  [001] d..2.  1234.567890: sched_switch: prev_comm=...
+ *
+ * or on a line from perf script of the form:
+ *
+ *     bash  1234 [001] 1234.567890: sched:sched_switch: prev_comm=...
+ *
+ * The flags (here, "d..2.") are optional.  The thread that was on CPU may
+ * additionally have its thread group ID -- that is, its process ID -- in
+ * parentheses (from ftrace) or as "pid/tid" (from perf script).
+ */
+struct SchedEvent<'a> {
+    pid: u32,                               // thread that was on CPU
+    tgid: Option<u32>,                      // its thread group, if known
+    cpu: u32,                               // CPU of event
+    time: u64,                              // time, in nanoseconds
+    event: &'a str,                         // name of event
+    payload: &'a str,                       // remainder of line
+}
+
+/*
+ * A thread as described in an event payload.
+ */
+struct SchedThread<'a> {
+    comm: &'a str,
+    pid: u32,
+}
+
+/*
+ * Parse a timestamp in seconds (with up to nanosecond precision) into
+ * nanoseconds.
+ */
+fn sched_time(time: &str) -> Option<u64> {
+    let (secs, frac) = match time.find('.') {
+        Some(ndx) => (&time[..ndx], &time[ndx + 1..]),
+        None => (time, "")
+    };
+
+    if secs.is_empty() || frac.len() > 9 ||
+        !secs.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut nsecs = 0;
+
+    for (ndx, b) in frac.bytes().enumerate() {
+        nsecs += (b - b'0') as u64 * 10_u64.pow(8 - ndx as u32);
+    }
+
+    Some(secs.parse::<u64>().ok()? * 1_000_000_000 + nsecs)
+}
+
+/*
+ * The number of lines at the beginning of our input in which we look for
+ * evidence that it is a scheduler trace, and the number of bytes beyond
+ * which we stop looking.
+ */
+const SCHED_LINES: usize = 32;
+const SCHED_HEADER: usize = 8192;
+
+/*
+ * Determine if the beginning of our input is a scheduler trace:  it must
+ * have an ftrace header, or a scheduler event among its first lines (which
+ * may be preceded by comments or by the header of trace-cmd report).  If
+ * `complete` is set, the header is all of the input; otherwise, None is
+ * returned if more of it is needed to decide.
+ */
+pub(super) fn sched_detect(header: &[u8], complete: bool) -> Option<bool> {
+    let complete = complete || header.len() >= SCHED_HEADER;
+    let mut lines = header.split(|&b| b == b'\n').peekable();
+    let mut nlines = 0;
+
+    while let Some(line) = lines.next() {
+        if lines.peek().is_none() && !complete {
+            return None;
+        }
+
+        let line = match str::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        if line.starts_with("# tracer:") || sched_event(line).is_some() {
+            return Some(true);
+        }
+
+        nlines += 1;
+
+        if nlines == SCHED_LINES {
+            break;
+        }
+    }
+
+    Some(false)
+}
+
+fn sched_event<'a>(line: &'a str) -> Option<SchedEvent<'a>> {
+    /*
+     * The CPU is the first bracketed number on the line; before it is the
+     * thread that was on CPU.
+     */
+    let (open, close) = line.match_indices(" [").find_map(|(ndx, _)| {
+        let close = ndx + line[ndx..].find(']')?;
+        let cpu = &line[ndx + 2..close];
+
+        if !cpu.is_empty() && cpu.bytes().all(|b| b.is_ascii_digit()) {
+            Some((ndx, close))
+        } else {
+            None
+        }
+    })?;
+
+    let mut task = line[..open].trim();
+    let mut tgid = None;
+
+    if task.ends_with(')') {
+        let paren = task.rfind('(')?;
+        tgid = task[paren + 1..task.len() - 1].trim().parse::<u32>().ok();
+        task = task[..paren].trim_end();
+    }
+
+    let id = task.rsplit(|c: char| c == '-' || c.is_whitespace()).next()?;
+
+    let pid = match id.find('/') {
+        Some(slash) => {
+            tgid = Some(id[..slash].parse::<u32>().ok()?);
+            id[slash + 1..].parse::<u32>().ok()?
+        }
+        None => id.parse::<u32>().ok()?
+    };
+
+    let cpu = line[open + 2..close].parse::<u32>().ok()?;
+
+    /*
+     * The timestamp is the last field before the first colon; the event
+     * name (less any subsystem, as perf script includes) is the last field
+     * before the next.
+     */
+    let rest = &line[close + 1..];
+    let ndx = rest.find(": ")?;
+    let time = sched_time(rest[..ndx].split_whitespace().last()?)?;
+
+    let rest = &rest[ndx + 2..];
+    let ndx = rest.find(": ")?;
+    let event = rest[..ndx].split_whitespace().last()?.rsplit(':').next()?;
+
+    Some(SchedEvent {
+        pid: pid,
+        tgid: tgid,
+        cpu: cpu,
+        time: time,
+        event: event,
+        payload: rest[ndx + 2..].trim(),
+    })
+}
+
+/*
+ * Return the value of the specified field in a payload of "key=value"
+ * pairs.  If the value might contain spaces (as a comm can), the key of the
+ * field that follows it must be provided.
+ */
+fn sched_field<'a>(payload: &'a str, key: &str, next: Option<&str>)
+    -> Option<&'a str>
+{
+    let ndx = payload.match_indices(key)
+        .find(|&(ndx, _)| ndx == 0 || payload.as_bytes()[ndx - 1] == b' ')
+        .map(|(ndx, _)| ndx + key.len())?;
+
+    let value = &payload[ndx..];
+
+    match next {
+        Some(next) => Some(&value[..value.find(next)?]),
+        None => value.split_whitespace().next()
+    }
+}
+
+/*
+ * Parse a thread as reported by trace-cmd:  "comm:pid [prio]", returning
+ * the thread and whatever follows the priority.
+ */
+fn sched_thread<'a>(field: &'a str) -> Option<(SchedThread<'a>, &'a str)> {
+    let ndx = field.rfind(" [")?;
+    let thread = &field[..ndx];
+    let colon = thread.rfind(':')?;
+    let rest = &field[ndx + 2..];
+
+    Some((SchedThread {
+        comm: &thread[..colon],
+        pid: thread[colon + 1..].parse::<u32>().ok()?,
+    }, rest[rest.find(']')? + 1..].trim()))
+}
+
+/*
+ * Parse the payload of a sched_switch event, returning the thread leaving
+ * the CPU, its state, and the thread coming on to the CPU.  This payload
+ * is either the raw payload as formatted by the kernel:
+ *
+ *     prev_comm=bash prev_pid=1234 prev_prio=120 prev_state=S ==>
+ *         next_comm=swapper/1 next_pid=0 next_prio=120
+ *
+ * or the payload as formatted by trace-cmd:
+ *
+ *     bash:1234 [120] S ==> swapper/1:0 [120]
+ */
+fn sched_switch<'a>(payload: &'a str)
+    -> Option<(SchedThread<'a>, &'a str, SchedThread<'a>)>
+{
+    let ndx = payload.find(" ==> ")?;
+    let (prev, next) = (&payload[..ndx], &payload[ndx + 5..]);
+
+    if prev.starts_with("prev_comm=") {
+        return Some((SchedThread {
+            comm: sched_field(prev, "prev_comm=", Some(" prev_pid="))?,
+            pid: sched_field(prev, "prev_pid=", None)?.parse().ok()?,
+        }, sched_field(prev, "prev_state=", None)?, SchedThread {
+            comm: sched_field(next, "next_comm=", Some(" next_pid="))?,
+            pid: sched_field(next, "next_pid=", None)?.parse().ok()?,
+        }));
+    }
+
+    let (prev, state) = sched_thread(prev)?;
+    let (next, _) = sched_thread(next)?;
+
+    Some((prev, state, next))
+}
+
+/*
+ * Parse the payload of a sched_wakeup or sched_process_exit event,
+ * returning the thread being awoken (or exiting) and, for a wakeup, the
+ * CPU it will run on (if known).
+ */
+fn sched_task<'a>(payload: &'a str)
+    -> Option<(SchedThread<'a>, Option<u32>)>
+{
+    if payload.starts_with("comm=") {
+        let target = sched_field(payload, "target_cpu=", None)
+            .and_then(|cpu| cpu.parse::<u32>().ok());
+
+        return Some((SchedThread {
+            comm: sched_field(payload, "comm=", Some(" pid="))?,
+            pid: sched_field(payload, "pid=", None)?.parse().ok()?,
+        }, target));
+    }
+
+    let (thread, rest) = sched_thread(payload)?;
+    let target = sched_field(rest, "CPU:", None)
+        .and_then(|cpu| cpu.parse::<u32>().ok());
+
+    Some((thread, target))
+}
+
+/*
+ * The state that a thread is in when it leaves the CPU, as indicated by the
+ * first character of its state in a sched_switch event.
+ */
+fn sched_offcpu(state: &str) -> u32 {
+    match state.chars().next() {
+        Some('R') => THREAD_RUNNABLE,
+        Some('D') => THREAD_BLOCKED,
+        Some('X') | Some('Z') | Some('x') => THREAD_DEAD,
+        _ => THREAD_SLEEPING,
+    }
+}
+
+/*
+ * The state of an import in progress.
+ */
+struct SchedImport {
+    entity: StatemapSchedEntity,            // kind of entity
+    origin: Option<u64>,                    // time of first event
+    comms: HashMap<u32, String>,            // last known comm of each thread
+    tgids: HashMap<u32, u32>,               // thread group of each thread
+    states: HashMap<u32, u32>,              // last known state of each thread
+    cpus: HashSet<u32>,                     // CPUs seen
+}
+
+impl SchedImport {
+    /*
+     * Note the comm of the specified thread, returning true if it differs
+     * from what we last knew it to be.
+     */
+    fn comm(&mut self, thread: &SchedThread) -> bool {
+        if let Some(comm) = self.comms.get(&thread.pid) {
+            if comm == thread.comm {
+                return false;
+            }
+        }
+
+        self.comms.insert(thread.pid, thread.comm.to_string());
+        true
+    }
+}
+
+impl Statemap {
+    /*
+     * Describe a thread that we have seen in the trace:  in a statemap of
+     * CPUs, this is a tag definition for the running state; in a statemap
+     * of threads, this is the description of the entity.  A thread is
+     * described once its comm is known, and again whenever its comm or
+     * thread group changes.
+     */
+    fn sched_describe(&mut self, import: &SchedImport, pid: u32)
+        -> Result<(), Box<dyn Error>>
+    {
+        let comm = match import.comms.get(&pid) {
+            Some(comm) => comm,
+            None => return Ok(()),
+        };
+
+        let tgid = import.tgids.get(&pid);
+
+        match import.entity {
+            StatemapSchedEntity::CPU => {
+                let mut fields = json!({ "pid": pid, "comm": comm });
+
+                if let Some(tgid) = tgid {
+                    fields["tgid"] = json!(tgid);
+                }
+
+                self.define_tag(CPU_RUNNING, &pid.to_string(), fields)
+            }
+            StatemapSchedEntity::Thread => {
+                let description = match tgid {
+                    Some(&tgid) if tgid != pid => {
+                        format!("{} (process {})", comm, tgid)
+                    }
+                    _ => comm.to_string()
+                };
+
+                self.describe_entity(&pid.to_string(), &description);
+                Ok(())
+            }
+        }
+    }
+
+    fn sched_comm(&mut self, import: &mut SchedImport, thread: &SchedThread)
+        -> Result<(), Box<dyn Error>>
+    {
+        if thread.pid == 0 || !import.comm(thread) {
+            return Ok(());
+        }
+
+        self.sched_describe(import, thread.pid)
+    }
+
+    fn sched_tgid(&mut self, import: &mut SchedImport, pid: u32, tgid: u32)
+        -> Result<(), Box<dyn Error>>
+    {
+        if pid == 0 || import.tgids.insert(pid, tgid) == Some(tgid) {
+            return Ok(());
+        }
+
+        self.sched_describe(import, pid)
+    }
+
+    fn sched_thread(&mut self, import: &mut SchedImport, pid: u32,
+        time: u64, state: u32) -> Result<(), Box<dyn Error>>
+    {
+        if pid == 0 {
+            return Ok(());
+        }
+
+        import.states.insert(pid, state);
+        self.transition(&pid.to_string(), time, state, None)
+    }
+
+    fn sched_ingest(&mut self, import: &mut SchedImport, event: &SchedEvent)
+        -> Result<(), Box<dyn Error>>
+    {
+        let time = match import.origin {
+            Some(origin) if event.time < origin => {
+                return self.err("events are not in time order");
+            }
+            Some(origin) => event.time - origin,
+            None => return self.err("missing start of trace"),
+        };
+
+        let cpu = event.cpu.to_string();
+
+        /*
+         * In a statemap of CPUs, each CPU is described when first seen.
+         */
+        if import.entity == StatemapSchedEntity::CPU &&
+            import.cpus.insert(event.cpu) {
+            self.describe_entity(&cpu, &format!("CPU {}", cpu));
+        }
+
+        if let Some(tgid) = event.tgid {
+            self.sched_tgid(import, event.pid, tgid)?;
+        }
+
+        match event.event {
+            "sched_switch" => {
+                let (prev, state, next) = match sched_switch(event.payload) {
+                    Some(switch) => switch,
+                    None => return self.err("malformed sched_switch event"),
+                };
+
+                self.sched_comm(import, &prev)?;
+                self.sched_comm(import, &next)?;
+
+                match import.entity {
+                    StatemapSchedEntity::CPU if next.pid == 0 => {
+                        self.transition(&cpu, time, CPU_IDLE, None)
+                    }
+                    StatemapSchedEntity::CPU => {
+                        let tag = next.pid.to_string();
+                        self.transition(&cpu, time, CPU_RUNNING, Some(&tag))
+                    }
+                    StatemapSchedEntity::Thread => {
+                        let offcpu = sched_offcpu(state);
+                        self.sched_thread(import, prev.pid, time, offcpu)?;
+                        self.sched_thread(import,
+                            next.pid, time, THREAD_RUNNING)
+                    }
+                }
+            }
+
+            "sched_wakeup" | "sched_wakeup_new" => {
+                let (thread, target) = match sched_task(event.payload) {
+                    Some(wakeup) => wakeup,
+                    None => return self.err("malformed sched_wakeup event"),
+                };
+
+                self.sched_comm(import, &thread)?;
+
+                match import.entity {
+                    StatemapSchedEntity::CPU => {
+                        let target = target.map(|t| t.to_string());
+                        self.event(&cpu, time, "wakeup", target.as_deref());
+                        Ok(())
+                    }
+                    StatemapSchedEntity::Thread => {
+                        let woken = thread.pid.to_string();
+
+                        if event.pid != 0 {
+                            self.event(&event.pid.to_string(),
+                                time, "wakeup", Some(&woken));
+                        }
+
+                        /*
+                         * A thread that is already running or runnable
+                         * remains so.
+                         */
+                        match import.states.get(&thread.pid) {
+                            Some(&THREAD_RUNNING) => Ok(()),
+                            Some(&THREAD_RUNNABLE) => Ok(()),
+                            _ => self.sched_thread(import,
+                                thread.pid, time, THREAD_RUNNABLE)
+                        }
+                    }
+                }
+            }
+
+            "sched_process_exit" => {
+                let (thread, _) = match sched_task(event.payload) {
+                    Some(exit) => exit,
+                    None => {
+                        return self.err("malformed sched_process_exit event");
+                    }
+                };
+
+                self.sched_comm(import, &thread)?;
+
+                match import.entity {
+                    StatemapSchedEntity::CPU => {
+                        self.event(&cpu, time, "exit", None);
+                        Ok(())
+                    }
+                    StatemapSchedEntity::Thread => {
+                        self.sched_thread(import, thread.pid, time, THREAD_DEAD)
+                    }
+                }
+            }
+
+            _ => Ok(())
+        }
+    }
+
+    /*
+     * Import a scheduler trace, building a statemap of the entities
+     * specified in our configuration.  Trace timestamps are relative to
+     * boot rather than to the Epoch, so the statemap starts at zero (plus
+     * any clock offset in our configuration), with the time of the first
+     * event in the trace noted in its title.
+     */
+    pub(super) fn ingest_sched<R: BufRead>(&mut self, reader: R)
+        -> Result<(), Box<dyn Error>>
+    {
+        let mut import = SchedImport {
+            entity: self.config.sched,
+            origin: None,
+            comms: HashMap::new(),
+            tgids: HashMap::new(),
+            states: HashMap::new(),
+            cpus: HashSet::new(),
+        };
+
+        let (title, states) = match import.entity {
+            StatemapSchedEntity::CPU => {
+                self.set_entity_kind("CPU");
+                ("CPU activity", CPU_STATES)
+            }
+            StatemapSchedEntity::Thread => {
+                self.set_entity_kind("Thread");
+                ("Thread states", THREAD_STATES)
+            }
+        };
+
+        if self.config.offset < 0 {
+            return self.err("clock offset precedes the Epoch");
+        }
+
+        self.set_title(title);
+        self.set_start(self.config.offset as u64)?;
+
+        for &(name, color) in states {
+            self.add_state(name, Some(color))?;
+        }
+
+        for (ndx, line) in reader.lines().enumerate() {
+            let line = line?;
+
+            let event = match sched_event(&line) {
+                Some(event) => event,
+                None => continue,
+            };
+
+            if import.origin.is_none() {
+                self.set_title(&trace_title(title, event.time));
+                import.origin = Some(event.time);
+            }
+
+            if let Err(err) = self.sched_ingest(&mut import, &event) {
+                return self.err(&format!(
+                    "illegal event on line {}: {}", ndx + 1, err));
+            }
+        }
+
+        if import.origin.is_none() {
+            return self.err("no scheduler events found");
+        }
+
+        self.finish();
+        Ok(())
+    }
+}
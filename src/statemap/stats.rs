@@ -0,0 +1,338 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * Reporting of the total time that each entity spent in each state, both per
+ * entity and in aggregate.  The totals are those tallied by each entity as
+ * its transitions are ingested (see `StatemapEntity::totals`), and are
+ * therefore exact regardless of any coalescing.
+ */
+
+use std::io::Write;
+use std::error::Error;
+use std::cmp;
+
+use super::serde_json;
+use super::{Statemap, StatemapDistribution};
+use super::{csv_field, distribution_datum, duration_text};
+
+/**
+ * The format of a report of state totals.
+ */
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum StatemapStatsFormat {
+    Text,                                   // human-readable table
+    CSV,                                    // one row per entity and state
+    JSON,                                   // JSON document
+}
+
+/**
+ * A reporter of the total time spent in each state by the entities in
+ * (possibly stacked) statemaps.
+ */
+pub struct StatemapStats {
+    format: StatemapStatsFormat,
+}
+
+/*
+ * The totals for a single statemap:  the time in each state for each entity
+ * (sorted by entity name), along with the time in each state in aggregate.
+ */
+struct StatemapTotals<'a> {
+    entities: Vec<(&'a str, &'a [u64])>,
+    aggregate: Vec<u64>,
+}
+
+impl<'a> StatemapTotals<'a> {
+    fn new(statemap: &'a Statemap) -> Self {
+        let entities: Vec<(&str, &[u64])> = statemap.entities().iter()
+            .map(|e| (e.name(), e.totals()))
+            .collect();
+
+        let mut aggregate = vec![0; statemap.states.len()];
+
+        for &(_, totals) in &entities {
+            for (total, t) in aggregate.iter_mut().zip(totals) {
+                *total += t;
+            }
+        }
+
+        StatemapTotals {
+            entities: entities,
+            aggregate: aggregate,
+        }
+    }
+}
+
+/*
+ * Return the percentage of each total with respect to their sum.
+ */
+fn percentages(totals: &[u64]) -> Vec<f64> {
+    let sum = totals.iter().sum::<u64>();
+
+    totals.iter().map(|&t| {
+        if sum == 0 { 0.0 } else { (t as f64 * 100.0) / sum as f64 }
+    }).collect()
+}
+
+impl StatemapStats {
+    /**
+     * Create a reporter of state totals in the specified format.
+     */
+    pub fn new(format: StatemapStatsFormat) -> Self {
+        StatemapStats {
+            format: format,
+        }
+    }
+
+    /**
+     * Report on the specified statemaps to `out`.
+     */
+    pub fn output<W: Write>(&self, statemaps: &Vec<Statemap>, out: &mut W)
+        -> Result<(), Box<dyn Error>>
+    {
+        for statemap in statemaps {
+            if statemap.metadata.is_none() {
+                return statemap.err("metadata not found in data stream");
+            }
+        }
+
+        match self.format {
+            StatemapStatsFormat::Text => self.output_text(statemaps, out),
+            StatemapStatsFormat::CSV => self.output_csv(statemaps, out),
+            StatemapStatsFormat::JSON => self.output_json(statemaps, out),
+        }
+    }
+
+    /*
+     * If durations are being recorded, our text output has a second table
+     * for each statemap, with a row for the durations of the visits to each
+     * state that each entity visited, followed by a row for the visits to
+     * each state in aggregate.
+     */
+    fn output_text_durations(&self, statemap: &Statemap, kind: &str,
+        out: &mut dyn Write) -> Result<(), Box<dyn Error>>
+    {
+        let entities: Vec<(&str, &[StatemapDistribution])> =
+            statemap.entities().iter()
+            .map(|e| (e.name(), e.durations()))
+            .collect();
+
+        let aggregate = statemap.durations();
+        let header = ["COUNT", "MEAN", "MIN", "P50", "P90", "P99", "MAX"];
+        let mut rows: Vec<(&str, &str, Vec<String>)> = vec![];
+
+        for &(name, durations) in entities.iter()
+            .chain(Some(("all", &aggregate[..])).iter()) {
+            for (state, dist) in statemap.states.iter().zip(durations) {
+                if dist.count() == 0 {
+                    continue;
+                }
+
+                let mut fields = vec![dist.count().to_string(),
+                    duration_text(dist.mean()),
+                    duration_text(dist.min() as f64)];
+
+                for p in dist.percentiles(&[50.0, 90.0, 99.0]) {
+                    fields.push(duration_text(p as f64));
+                }
+
+                fields.push(duration_text(dist.max() as f64));
+                rows.push((name, &state.name, fields));
+            }
+        }
+
+        let ewidth = rows.iter()
+            .fold(kind.len(), |w, r| cmp::max(w, r.0.len()));
+        let swidth = rows.iter()
+            .fold("STATE".len(), |w, r| cmp::max(w, r.1.len()));
+        let widths: Vec<usize> = header.iter().enumerate()
+            .map(|(i, h)| rows.iter()
+                .fold(h.len(), |w, r| cmp::max(w, r.2[i].len())))
+            .collect();
+
+        let line = |out: &mut dyn Write, name: &str, state: &str,
+            fields: &[&str]| -> Result<(), Box<dyn Error>> {
+            write!(out, "{:<ew$}  {:<sw$}", name, state,
+                ew = ewidth, sw = swidth)?;
+
+            for (field, width) in fields.iter().zip(&widths) {
+                write!(out, "  {:>w$}", field, w = width)?;
+            }
+
+            writeln!(out)?;
+            Ok(())
+        };
+
+        writeln!(out)?;
+        line(out, kind, "STATE", &header)?;
+
+        for (name, state, fields) in &rows {
+            let fields: Vec<&str> = fields.iter().map(|f| f.as_str()).collect();
+            line(out, name, state, &fields)?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Our text output has a table for each statemap, with a row for each
+     * state that each entity spent time in, followed by a row for each
+     * state in aggregate.  Times are in milliseconds.
+     */
+    fn output_text(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        for (i, statemap) in statemaps.iter().enumerate() {
+            let metadata = statemap.metadata.as_ref().unwrap();
+            let totals = StatemapTotals::new(statemap);
+            let all = "all";
+
+            let kind = match metadata.entityKind {
+                Some(ref kind) => kind.to_uppercase(),
+                None => "ENTITY".to_string()
+            };
+
+            let mut rows: Vec<(&str, &str, String, f64)> = vec![];
+
+            for &(name, entity) in totals.entities.iter()
+                .chain(Some((all, &totals.aggregate[..])).iter()) {
+                for ((state, &t), p) in statemap.states.iter()
+                    .zip(entity).zip(percentages(entity)) {
+                    if t != 0 {
+                        let ms = format!("{}.{:06}", t / 1_000_000,
+                            t % 1_000_000);
+                        rows.push((name, &state.name, ms, p));
+                    }
+                }
+            }
+
+            let ewidth = rows.iter()
+                .fold(kind.len(), |w, r| cmp::max(w, r.0.len()));
+            let swidth = rows.iter()
+                .fold("STATE".len(), |w, r| cmp::max(w, r.1.len()));
+            let twidth = rows.iter()
+                .fold("TIME(ms)".len(), |w, r| cmp::max(w, r.2.len()));
+
+            let mut title = metadata.title.clone();
+
+            if let Some(ref host) = metadata.host {
+                title.push_str(&format!(" on {}", host));
+            }
+
+            if i > 0 {
+                writeln!(out)?;
+            }
+
+            writeln!(out, "{}\n", title)?;
+            writeln!(out, "{:<ew$}  {:<sw$}  {:>tw$}  {:>7}", kind, "STATE",
+                "TIME(ms)", "PERCENT", ew = ewidth, sw = swidth,
+                tw = twidth)?;
+
+            for (name, state, ms, p) in rows {
+                writeln!(out, "{:<ew$}  {:<sw$}  {:>tw$}  {:>6.2}%",
+                    name, state, ms, p, ew = ewidth, sw = swidth,
+                    tw = twidth)?;
+            }
+
+            if statemap.config.durations.is_some() {
+                self.output_text_durations(statemap, &kind, out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Our CSV output has a row for every state for every entity (including
+     * states in which no time was spent), with the time in nanoseconds.
+     * The rows for the aggregate have an empty entity.
+     */
+    fn output_csv(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        writeln!(out, "statemap,entity,state,time,percent")?;
+
+        for (i, statemap) in statemaps.iter().enumerate() {
+            let totals = StatemapTotals::new(statemap);
+
+            for &(name, entity) in totals.entities.iter()
+                .chain(Some(("", &totals.aggregate[..])).iter()) {
+                for ((state, t), p) in statemap.states.iter()
+                    .zip(entity).zip(percentages(entity)) {
+                    writeln!(out, "{},{},{},{},{:.4}", i, csv_field(name),
+                        csv_field(&state.name), t, p)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Our JSON output is an object with a "statemaps" member that has an
+     * element for each statemap, with times in nanoseconds.  If durations
+     * are being recorded, each entity (and the aggregate) additionally has
+     * a summary of the durations of its visits to each state.
+     */
+    fn output_json(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        let mut docs = vec![];
+
+        for statemap in statemaps {
+            let metadata = statemap.metadata.as_ref().unwrap();
+            let totals = StatemapTotals::new(statemap);
+            let (begin, end) = statemap.timebounds();
+
+            let states: Vec<&str> = statemap.states.iter()
+                .map(|s| s.name.as_str())
+                .collect();
+
+            let mut entities: Vec<serde_json::Value> = totals.entities.iter()
+                .map(|&(name, entity)| json!({
+                    "name": name,
+                    "time": entity,
+                    "percent": percentages(entity),
+                })).collect();
+
+            let mut aggregate = json!({
+                "time": totals.aggregate,
+                "percent": percentages(&totals.aggregate),
+            });
+
+            if statemap.config.durations.is_some() {
+                for (doc, entity) in entities.iter_mut()
+                    .zip(statemap.entities()) {
+                    doc["durations"] = json!(entity.durations().iter()
+                        .map(distribution_datum)
+                        .collect::<Vec<_>>());
+                }
+
+                aggregate["durations"] = json!(statemap.durations().iter()
+                    .map(distribution_datum)
+                    .collect::<Vec<_>>());
+            }
+
+            docs.push(json!({
+                "metadata": {
+                    "title": metadata.title,
+                    "host": metadata.host,
+                    "entityKind": metadata.entityKind,
+                    "begin": begin,
+                    "end": end,
+                },
+                "states": states,
+                "entities": entities,
+                "aggregate": aggregate,
+            }));
+        }
+
+        serde_json::to_writer_pretty(&mut *out,
+            &json!({ "statemaps": docs }))?;
+        writeln!(out)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * Reporting of the transitions between states:  how often each state is
+ * followed by each other state -- and how long was spent in the former
+ * before the transition -- both per entity and in aggregate.  The
+ * transitions are counted by each entity as its data is ingested (see
+ * `StatemapEntity::transitions`).
+ */
+
+use std::io::Write;
+use std::error::Error;
+use std::cmp;
+
+use super::serde_json;
+use super::serde_json::Value;
+use super::{Statemap, StatemapTransition};
+use super::duration_text;
+
+/**
+ * The format of a report of transitions.
+ */
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum StatemapTransitionsFormat {
+    Text,                                   // human-readable matrices
+    JSON,                                   // JSON document
+    DOT,                                    // Graphviz state machine
+}
+
+/**
+ * A reporter of the transitions between states of the entities in
+ * (possibly stacked) statemaps.
+ */
+pub struct StatemapTransitions {
+    format: StatemapTransitionsFormat,
+}
+
+/*
+ * Return the transitions as matrices of counts and of mean dwell times,
+ * indexed by the state transitioned from and then by the state transitioned
+ * to.  Mean dwell times are None where there are no transitions.
+ */
+fn matrices(transitions: &[StatemapTransition], nstates: usize)
+    -> (Vec<Vec<u64>>, Vec<Vec<Option<f64>>>)
+{
+    let mut counts = vec![vec![0; nstates]; nstates];
+    let mut dwells = vec![vec![None; nstates]; nstates];
+
+    for t in transitions {
+        counts[t.from()][t.to()] = t.count();
+        dwells[t.from()][t.to()] = Some(t.mean_dwell());
+    }
+
+    (counts, dwells)
+}
+
+/*
+ * Quote a string as an identifier in DOT.
+ */
+fn dot_id(id: &str) -> String {
+    format!("\"{}\"", id.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl StatemapTransitions {
+    /**
+     * Create a reporter of transitions in the specified format.
+     */
+    pub fn new(format: StatemapTransitionsFormat) -> Self {
+        StatemapTransitions {
+            format: format,
+        }
+    }
+
+    /**
+     * Report on the specified statemaps to `out`.
+     */
+    pub fn output<W: Write>(&self, statemaps: &Vec<Statemap>, out: &mut W)
+        -> Result<(), Box<dyn Error>>
+    {
+        for statemap in statemaps {
+            if statemap.metadata.is_none() {
+                return statemap.err("metadata not found in data stream");
+            }
+        }
+
+        match self.format {
+            StatemapTransitionsFormat::Text => {
+                self.output_text(statemaps, out)
+            }
+            StatemapTransitionsFormat::JSON => {
+                self.output_json(statemaps, out)
+            }
+            StatemapTransitionsFormat::DOT => self.output_dot(statemaps, out),
+        }
+    }
+
+    /*
+     * Output a matrix as text, with a row for each of the specified states
+     * transitioned from and a column for each transitioned to.
+     */
+    fn output_matrix(&self, out: &mut dyn Write, header: &str,
+        names: &[&str], states: &[usize],
+        cell: &dyn Fn(usize, usize) -> String) -> Result<(), Box<dyn Error>>
+    {
+        let width = states.iter()
+            .fold(header.len(), |w, &s| cmp::max(w, names[s].len()));
+
+        let cells: Vec<Vec<String>> = states.iter()
+            .map(|&from| states.iter().map(|&to| {
+                if from == to { "-".to_string() } else { cell(from, to) }
+            }).collect())
+            .collect();
+
+        let widths: Vec<usize> = states.iter().enumerate()
+            .map(|(i, &to)| cells.iter()
+                .fold(names[to].len(), |w, row| cmp::max(w, row[i].len())))
+            .collect();
+
+        write!(out, "{:<w$}", header, w = width)?;
+
+        for (&to, w) in states.iter().zip(&widths) {
+            write!(out, "  {:>w$}", names[to], w = w)?;
+        }
+
+        writeln!(out)?;
+
+        for (&from, row) in states.iter().zip(&cells) {
+            write!(out, "{:<w$}", names[from], w = width)?;
+
+            for (cell, w) in row.iter().zip(&widths) {
+                write!(out, "  {:>w$}", cell, w = w)?;
+            }
+
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Our text output has, for each statemap, a matrix of the number of
+     * transitions and a matrix of the mean dwell times -- first for all
+     * entities in aggregate and then for each entity.  To keep the matrices
+     * manageable, they only include states that have been transitioned to
+     * or from.
+     */
+    fn output_text(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        let mut first = true;
+
+        for statemap in statemaps {
+            let metadata = statemap.metadata.as_ref().unwrap();
+            let nstates = statemap.states.len();
+            let aggregate = statemap.transitions();
+
+            let names: Vec<&str> = statemap.states.iter()
+                .map(|s| s.name.as_str())
+                .collect();
+
+            let states: Vec<usize> = (0..nstates)
+                .filter(|&s| aggregate.iter().any(|t| t.from() == s ||
+                    t.to() == s))
+                .collect();
+
+            let kind = match metadata.entityKind {
+                Some(ref kind) => kind.as_str(),
+                None => "Entity"
+            };
+
+            let mut title = metadata.title.clone();
+
+            if let Some(ref host) = metadata.host {
+                title.push_str(&format!(" on {}", host));
+            }
+
+            let mut sections = vec![(format!("{}: all entities", title),
+                aggregate.clone())];
+
+            for entity in statemap.entities() {
+                sections.push((format!("{}: {} {}", title, kind,
+                    entity.name()), entity.transitions()));
+            }
+
+            for (header, transitions) in sections {
+                let (counts, dwells) = matrices(&transitions, nstates);
+
+                if !first {
+                    writeln!(out)?;
+                }
+
+                first = false;
+                writeln!(out, "{}\n", header)?;
+
+                self.output_matrix(out, "TRANSITIONS", &names, &states,
+                    &|from, to| counts[from][to].to_string())?;
+                writeln!(out)?;
+
+                self.output_matrix(out, "MEAN DWELL", &names, &states,
+                    &|from, to| match dwells[from][to] {
+                        Some(dwell) => duration_text(dwell),
+                        None => "-".to_string()
+                    })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Our JSON output is an object with a "statemaps" member that has an
+     * element for each statemap.  Each entity (and the aggregate) has a
+     * "count" matrix and a "dwell" matrix (with the mean dwell time in
+     * nanoseconds, or null where there are no transitions), indexed by the
+     * state transitioned from and then by the state transitioned to.
+     */
+    fn output_json(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        let mut docs = vec![];
+
+        for statemap in statemaps {
+            let metadata = statemap.metadata.as_ref().unwrap();
+            let nstates = statemap.states.len();
+            let (begin, end) = statemap.timebounds();
+
+            let states: Vec<&str> = statemap.states.iter()
+                .map(|s| s.name.as_str())
+                .collect();
+
+            let matrix = |transitions: &[StatemapTransition]| -> Value {
+                let (counts, dwells) = matrices(transitions, nstates);
+                json!({ "count": counts, "dwell": dwells })
+            };
+
+            let entities: Vec<Value> = statemap.entities().iter()
+                .map(|entity| {
+                    let mut doc = matrix(&entity.transitions());
+                    doc["name"] = json!(entity.name());
+                    doc
+                }).collect();
+
+            docs.push(json!({
+                "metadata": {
+                    "title": metadata.title,
+                    "host": metadata.host,
+                    "entityKind": metadata.entityKind,
+                    "begin": begin,
+                    "end": end,
+                },
+                "states": states,
+                "entities": entities,
+                "aggregate": matrix(&statemap.transitions()),
+            }));
+        }
+
+        serde_json::to_writer_pretty(&mut *out,
+            &json!({ "statemaps": docs }))?;
+        writeln!(out)?;
+
+        Ok(())
+    }
+
+    /*
+     * Our DOT output is a directed graph of the state machine for all
+     * entities in aggregate, with a node for each state that has been
+     * transitioned to or from and an edge for each transition, labelled
+     * with its count and mean dwell time and weighted by its count.  If
+     * there are multiple statemaps, each is in its own cluster.
+     */
+    fn output_dot(&self, statemaps: &Vec<Statemap>, out: &mut dyn Write)
+        -> Result<(), Box<dyn Error>>
+    {
+        let stacked = statemaps.len() > 1;
+
+        writeln!(out, "digraph statemap {{")?;
+        writeln!(out, "    node [shape=ellipse, style=filled, \
+            fillcolor=white];")?;
+
+        for (i, statemap) in statemaps.iter().enumerate() {
+            let metadata = statemap.metadata.as_ref().unwrap();
+            let transitions = statemap.transitions();
+            let max = transitions.iter()
+                .fold(0, |m, t| cmp::max(m, t.count()));
+            let indent = if stacked { "        " } else { "    " };
+
+            let mut title = metadata.title.clone();
+
+            if let Some(ref host) = metadata.host {
+                title.push_str(&format!(" on {}", host));
+            }
+
+            let node = |state: usize| -> String {
+                let name = &statemap.states[state].name;
+
+                if stacked {
+                    dot_id(&format!("{}:{}", i, name))
+                } else {
+                    dot_id(name)
+                }
+            };
+
+            if stacked {
+                writeln!(out, "    subgraph cluster_{} {{", i)?;
+            }
+
+            writeln!(out, "{}label={};", indent, dot_id(&title))?;
+
+            for (value, state) in statemap.states.iter().enumerate() {
+                if !transitions.iter().any(|t| t.from() == value ||
+                    t.to() == value) {
+                    continue;
+                }
+
+                write!(out, "{}{} [label={}", indent, node(value),
+                    dot_id(&state.name))?;
+
+                if let Some(ref color) = state.color {
+                    write!(out, ", fillcolor={}", dot_id(color))?;
+                }
+
+                writeln!(out, "];")?;
+            }
+
+            for t in &transitions {
+                writeln!(out, "{}{} -> {} [label={}, weight={}, \
+                    penwidth={:.2}];", indent, node(t.from()), node(t.to()),
+                    dot_id(&format!("{} ({})", t.count(),
+                    duration_text(t.mean_dwell()))), t.count(),
+                    1.0 + (4.0 * t.count() as f64) / max as f64)?;
+            }
+
+            if stacked {
+                writeln!(out, "    }}")?;
+            }
+        }
+
+        writeln!(out, "}}")?;
+
+        Ok(())
+    }
+}
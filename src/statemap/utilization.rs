@@ -0,0 +1,107 @@
+/*
+ * Copyright 2020 Joyent, Inc. and other contributors
+ */
+
+/*
+ * The occupancy of each state over time:  how many of a statemap's entities
+ * are in each state at any given time.  If so configured, the time that
+ * each entity spends in each state is added to fixed-width buckets as it is
+ * ingested (and therefore before it is coalesced); the time in a state in
+ * a bucket divided by the width of the bucket is then the mean number of
+ * entities in that state over the bucket.  The buckets are those of the
+ * statemap's coarsest level of detail, and are widened along with it.
+ */
+
+use std::cmp;
+
+use super::fold_buckets;
+
+/**
+ * The occupancy of each state of a statemap over time.
+ */
+#[derive(Clone,Debug)]
+pub struct StatemapUtilization {
+    origin: u64,                            // start of first bucket
+    resolution: u64,                        // width of each bucket
+    nstates: usize,                         // number of states
+    end: u64,                               // end of recorded time
+    buckets: Vec<Vec<u64>>,                 // time in each state, by bucket
+}
+
+impl StatemapUtilization {
+    /**
+     * Create an empty record of the occupancy of the specified number of
+     * states, with buckets of width `resolution` beginning at `origin`.
+     */
+    pub fn new(origin: u64, resolution: u64, nstates: usize) -> Self {
+        StatemapUtilization {
+            origin: origin,
+            resolution: cmp::max(resolution, 1),
+            nstates: nstates,
+            end: origin,
+            buckets: vec![],
+        }
+    }
+
+    /*
+     * Double our resolution by folding each pair of buckets together.
+     */
+    pub(super) fn rebucket(&mut self) {
+        fold_buckets(&mut self.buckets, |merged, bucket| {
+            for (m, b) in merged.iter_mut().zip(bucket) {
+                *m += b;
+            }
+        });
+
+        self.resolution *= 2;
+    }
+
+    /**
+     * Record that an entity was in the specified state from `start` to
+     * `end`.
+     */
+    pub fn record(&mut self, start: u64, end: u64, state: usize) {
+        let mut start = cmp::max(start, self.origin);
+
+        if start >= end {
+            return;
+        }
+
+        while start < end {
+            let ndx = ((start - self.origin) / self.resolution) as usize;
+            let edge = cmp::min(self.origin +
+                (ndx as u64 + 1) * self.resolution, end);
+
+            if ndx >= self.buckets.len() {
+                self.buckets.resize(ndx + 1, vec![0; self.nstates]);
+            }
+
+            self.buckets[ndx][state] += edge - start;
+            start = edge;
+        }
+
+        self.end = cmp::max(self.end, end);
+    }
+
+    /**
+     * The width of each bucket, in nanoseconds.
+     */
+    pub fn resolution(&self) -> u64 {
+        self.resolution
+    }
+
+    /**
+     * Return the occupancy over time as a vector with an element for each
+     * bucket:  its start and end (as nanosecond offsets), and the mean
+     * number of entities in each state over it.
+     */
+    pub fn occupancy(&self) -> Vec<(u64, u64, Vec<f64>)> {
+        self.buckets.iter().enumerate().map(|(ndx, bucket)| {
+            let start = self.origin + ndx as u64 * self.resolution;
+            let end = cmp::min(start + self.resolution, self.end);
+            let width = (end - start) as f64;
+
+            (start, end, bucket.iter().map(|&t| t as f64 / width).collect())
+        }).collect()
+    }
+}